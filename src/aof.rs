@@ -0,0 +1,150 @@
+use anyhow::{Context, Result};
+use log::warn;
+use std::path::Path;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::time::{Duration, Instant};
+
+use crate::client::select_frame;
+use crate::config::AppendFsync;
+use crate::parser::{Payload, RedisProtocolParser};
+use crate::store::rdb;
+
+/// How long `everysec` lets appended writes wait before flushing them to disk.
+const FSYNC_INTERVAL: Duration = Duration::from_secs(1);
+
+/// The append-only file, which logs every write command in RESP as it runs so that the store
+/// can be rebuilt by running them again.
+///
+/// A file starts with an RDB snapshot of the dataset at the time it was created, followed by the
+/// commands that ran since.
+pub struct AppendOnlyFile {
+    file: File,
+    last_fsync: Instant,
+    /// The database the commands appended last ran against, or `None` if replaying the file
+    /// so far wouldn't have selected one.
+    db: Option<usize>,
+}
+
+impl AppendOnlyFile {
+    /// Opens the file at `path` to append to it, creating it if it doesn't exist.
+    pub async fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .with_context(|| format!("ERR Failed to open {}", path.display()))?;
+        Ok(Self {
+            file,
+            last_fsync: Instant::now(),
+            db: None,
+        })
+    }
+
+    /// Starts the file at `path` over with `snapshot`, an RDB snapshot of the current dataset.
+    /// The snapshot is written under a temporary name first, so the old file stays intact until
+    /// the new one is complete.
+    pub async fn create(path: &Path, snapshot: &[u8]) -> Result<Self> {
+        let temp = path.with_file_name(format!("temp-rewriteaof-{}.aof", std::process::id()));
+        let mut file = File::create(&temp)
+            .await
+            .with_context(|| format!("ERR Failed to create {}", temp.display()))?;
+        file.write_all(snapshot).await?;
+        file.sync_data().await?;
+        tokio::fs::rename(&temp, path).await.with_context(|| {
+            format!(
+                "ERR Failed to rename {} to {}",
+                temp.display(),
+                path.display()
+            )
+        })?;
+        Self::open(path).await
+    }
+
+    /// Appends `frame`, a write command in RESP that ran against database `db`, flushing it
+    /// to disk as `fsync` asks.
+    pub async fn append(&mut self, db: usize, frame: &[u8], fsync: AppendFsync) -> Result<()> {
+        if self.db != Some(db) {
+            self.file.write_all(&select_frame(db)).await?;
+            self.db = Some(db);
+        }
+        self.file.write_all(frame).await?;
+        self.file.flush().await?;
+        let due = match fsync {
+            AppendFsync::Always => true,
+            AppendFsync::EverySec => self.last_fsync.elapsed() >= FSYNC_INTERVAL,
+            AppendFsync::No => false,
+        };
+        if due {
+            self.file.sync_data().await?;
+            self.last_fsync = Instant::now();
+        }
+        Ok(())
+    }
+
+    /// Flushes every command appended so far to disk, whatever `appendfsync` is set to.
+    pub async fn sync(&mut self) -> Result<()> {
+        self.file.sync_data().await?;
+        self.last_fsync = Instant::now();
+        Ok(())
+    }
+}
+
+/// Splits the contents of an append-only file into its RDB preamble, if it has one, and the
+/// commands appended after it. A command cut short by a crash mid-append is dropped.
+pub fn split(contents: &[u8]) -> Result<(Option<&[u8]>, Vec<Payload>)> {
+    let (preamble, mut rest) = if contents.starts_with(rdb::MAGIC) {
+        let (_, len) = rdb::decode_prefix(contents).context("Bad RDB preamble in the AOF")?;
+        (Some(&contents[..len]), &contents[len..])
+    } else {
+        (None, contents)
+    };
+
+    let mut commands = Vec::new();
+    while !rest.is_empty() {
+        let Some((payload, consumed)) = RedisProtocolParser::parse_frame(rest)? else {
+            warn!("[AOF] - Dropping a truncated command at the end of the file.");
+            break;
+        };
+        commands.push(payload);
+        rest = &rest[consumed..];
+    }
+    Ok((preamble, commands))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::RedisEncodable;
+    use crate::store::RedisType;
+
+    fn command(args: &[&str]) -> Vec<u8> {
+        Payload::build_bulk_string_array(args.to_vec()).redis_encode()
+    }
+
+    #[test]
+    fn test_split_preamble_and_commands() {
+        let value = RedisType::String(b"value".to_vec());
+        let preamble = rdb::encode([(0, "key", &value, None)]);
+        let first = command(&["SET", "a", "1"]);
+        let second = command(&["DEL", "key"]);
+        let contents = [preamble.as_slice(), &first, &second].concat();
+
+        let (found, commands) = split(&contents).unwrap();
+        assert_eq!(found, Some(preamble.as_slice()));
+        let encoded: Vec<Vec<u8>> = commands.iter().map(|c| c.redis_encode()).collect();
+        assert_eq!(encoded, [first, second]);
+    }
+
+    #[test]
+    fn test_split_drops_truncated_command() {
+        let first = command(&["SET", "a", "1"]);
+        let second = command(&["SET", "b", "2"]);
+        let contents = [first.as_slice(), &second[..second.len() - 3]].concat();
+
+        let (preamble, commands) = split(&contents).unwrap();
+        assert!(preamble.is_none());
+        assert_eq!(commands.len(), 1);
+    }
+}
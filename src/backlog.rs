@@ -0,0 +1,65 @@
+use std::collections::VecDeque;
+
+/// How many bytes of the command stream a master keeps, as `repl-backlog-size` defaults to in
+/// Redis.
+pub const BACKLOG_SIZE: usize = 1024 * 1024;
+
+/// The latest bytes of the command stream a master propagated, so that a replica reconnecting
+/// after losing its link can be sent the part it missed with `+CONTINUE`, rather than a whole
+/// new snapshot.
+#[derive(Debug)]
+pub struct Backlog {
+    bytes: VecDeque<u8>,
+    /// The offset in the command stream just past the last byte kept.
+    end: usize,
+    capacity: usize,
+}
+
+impl Backlog {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            bytes: VecDeque::new(),
+            end: 0,
+            capacity,
+        }
+    }
+
+    /// Keeps `bytes`, just propagated, dropping the oldest ones once past capacity.
+    pub fn append(&mut self, bytes: &[u8]) {
+        self.bytes.extend(bytes);
+        self.end += bytes.len();
+        let excess = self.bytes.len().saturating_sub(self.capacity);
+        self.bytes.drain(..excess);
+    }
+
+    /// The bytes of the command stream from `offset` on, or `None` if some of them are no
+    /// longer kept, or `offset` is past the end of the stream.
+    pub fn since(&self, offset: usize) -> Option<Vec<u8>> {
+        let start = self.end - self.bytes.len();
+        if offset < start || offset > self.end {
+            return None;
+        }
+        Some(self.bytes.iter().skip(offset - start).copied().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_backlog_keeps_the_latest_bytes() {
+        let mut backlog = Backlog::new(8);
+        assert_eq!(backlog.since(0), Some(vec![]));
+        backlog.append(b"abcde");
+        assert_eq!(backlog.since(2).as_deref(), Some(&b"cde"[..]));
+        assert_eq!(backlog.since(5), Some(vec![]));
+        assert_eq!(backlog.since(6), None);
+
+        // Past capacity, the oldest bytes can no longer be resent.
+        backlog.append(b"fghij");
+        assert_eq!(backlog.since(1), None);
+        assert_eq!(backlog.since(2).as_deref(), Some(&b"cdefghij"[..]));
+        assert_eq!(backlog.since(9).as_deref(), Some(&b"j"[..]));
+    }
+}
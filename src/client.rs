@@ -1,73 +1,274 @@
-use crate::parser::{Command, Payload, PayloadVec, RedisEncodable, Value, DELIMITER};
-use crate::store::redis_type::Stream;
-use crate::store::{KeyValueStore, RedisType};
-use anyhow::{bail, Context, Result};
+use crate::aof::{self, AppendOnlyFile};
+use crate::backlog::{Backlog, BACKLOG_SIZE};
+use crate::config::Config;
+use crate::connections::Connections;
+use crate::parser::{Command, Payload, ProtocolVersion, RedisEncodable, Value, DELIMITER};
+use crate::pubsub::{PubSub, Subscriber};
+use crate::store::redis_type::{ListEnd, NewStreamId, SetOperation, StreamId};
+use crate::store::sorted_set::{
+    format_score, parse_score, LexBound, RangeOptions, ScoreBound, ScoreEnd, ZSetOperationArgs,
+};
+use crate::store::store::NOT_AN_INTEGER;
+use crate::store::{
+    rdb, BitFieldOp, ExpireCondition, KeyValueStore, RedisType, SetOptions, ZAddOptions,
+};
+use crate::tracking::{Tracking, TrackingClient};
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::{DateTime, Utc};
 use hex_literal::hex;
-use log::{debug, warn};
-use std::collections::HashMap;
+use log::{debug, info, warn};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
+use std::future::Future;
+use std::io::ErrorKind;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::sync::Arc;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
-use tokio::sync::{Mutex, RwLock};
+use tokio::sync::{Mutex, Notify, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::task::JoinHandle;
+use tokio::time::{Duration, Instant};
+
+/// The channel a connection subscribes to in order to receive the invalidations redirected to it
+/// with `CLIENT TRACKING on REDIRECT`.
+const INVALIDATE_CHANNEL: &str = "__redis__:invalidate";
+
+/// The sections `INFO` knows, in the order it renders them.
+const INFO_SECTIONS: [&str; 4] = ["server", "replication", "stats", "keyspace"];
 
 static DEFAULT_ID: [u8;88] = hex!("524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2");
 
 #[derive(Clone)]
 pub(crate) struct RedisClient {
-    store: Arc<RwLock<KeyValueStore>>,
+    /// The logical databases, selected by their index with `SELECT`.
+    databases: Vec<Arc<RwLock<KeyValueStore>>>,
+    config: Arc<RwLock<Config>>,
+    pubsub: Arc<PubSub>,
+    connections: Arc<RwLock<Connections>>,
+    /// The keys read by connections with `CLIENT TRACKING` on, to invalidate when written.
+    tracking: Arc<RwLock<Tracking>>,
+    /// Held for reading while a command runs and for writing while `EXEC` runs a transaction,
+    /// so that no other command interleaves with the transaction's.
+    transaction_gate: Arc<RwLock<()>>,
+    /// Set while `SAVE` or `BGSAVE` writes the RDB file, so that two saves never write it at
+    /// once.
+    saving: Arc<AtomicBool>,
+    /// Open while `appendonly` is on, and logs every write that runs.
+    aof: Arc<Mutex<Option<AppendOnlyFile>>>,
+    /// The database replicas last had selected in the command stream, or `None` when they need
+    /// to be told with `SELECT` before the next write.
+    propagated_db: Arc<Mutex<Option<usize>>>,
+    /// Woken once `SHUTDOWN` has persisted the dataset, to stop the server.
+    shutdown: Arc<Notify>,
+    /// Woken whenever a list may have gained elements, so that `BLPOP` and `BRPOP` calls
+    /// waiting on an empty one look again.
+    list_pushed: Arc<Notify>,
     pub role: ClientRole,
 }
 
 impl RedisClient {
-    pub async fn setup_client(replicaof: Option<String>) -> Self {
-        if let Some(address) = replicaof {
+    pub async fn setup_client(replicaof: Option<String>, config: Config) -> Self {
+        let appendonly = config.appendonly;
+        let aof_path = config.aof_path();
+        let rdb_path = config.rdb_path();
+        let databases = config.databases;
+        let port = config.port;
+        let client = if let Some(address) = replicaof {
             let address = address.replace(' ', ":").replace("localhost", "127.0.0.1");
-            println!("Setting up client on address: {}", address);
+            info!("Setting up client on address: {}", address);
             // let address = address.join(":").replace("localhost", "127.0.0.1");
-            let (r, w) = RedisClient::handshake(&address).await.unwrap();
+            let (r, w, master_id, master_offset, master_address) =
+                RedisClient::handshake(&address, port, None).await.unwrap();
 
             Self {
-                store: Arc::new(RwLock::new(KeyValueStore::new())),
+                databases: (0..databases)
+                    .map(|_| Arc::new(RwLock::new(KeyValueStore::new_replica())))
+                    .collect(),
+                config: Arc::new(RwLock::new(config)),
+                pubsub: Arc::new(PubSub::default()),
+                connections: Arc::new(RwLock::new(Connections::default())),
+                tracking: Arc::new(RwLock::new(Tracking::default())),
+                transaction_gate: Arc::new(RwLock::new(())),
+                saving: Arc::new(AtomicBool::new(false)),
+                aof: Arc::new(Mutex::new(None)),
+                propagated_db: Arc::new(Mutex::new(None)),
+                list_pushed: Arc::new(Notify::new()),
+                shutdown: Arc::new(Notify::new()),
                 role: ClientRole::Slave {
                     master_stream_w: Arc::new(Mutex::new(w)),
                     master_stream_r: Arc::new(Mutex::new(r)),
-                    master_id: "?".to_string(),
-                    master_address: address,
-                    master_offset: -1,
+                    master_id: Arc::new(std::sync::Mutex::new(master_id)),
+                    master_address,
+                    master_offset: Arc::new(AtomicUsize::new(master_offset)),
+                    master_state: Arc::new(Mutex::new(ConnectionState::default())),
+                    slave_connections: Arc::new(Mutex::new(HashMap::new())),
                 },
             }
         } else {
             Self {
-                store: Arc::new(RwLock::new(KeyValueStore::new())),
+                databases: (0..databases)
+                    .map(|_| Arc::new(RwLock::new(KeyValueStore::new())))
+                    .collect(),
+                config: Arc::new(RwLock::new(config)),
+                pubsub: Arc::new(PubSub::default()),
+                connections: Arc::new(RwLock::new(Connections::default())),
+                tracking: Arc::new(RwLock::new(Tracking::default())),
+                transaction_gate: Arc::new(RwLock::new(())),
+                saving: Arc::new(AtomicBool::new(false)),
+                aof: Arc::new(Mutex::new(None)),
+                propagated_db: Arc::new(Mutex::new(None)),
+                list_pushed: Arc::new(Notify::new()),
+                shutdown: Arc::new(Notify::new()),
                 role: ClientRole::new_master(),
             }
+        };
+
+        // With the AOF on, the databases are rebuilt from it below instead.
+        if client.role.is_master() && !appendonly {
+            client.load_rdb_file(&rdb_path).await.unwrap();
+        }
+        if appendonly {
+            // A replica is sent the whole dataset by its master, which replaces the AOF anyway.
+            if client.role.is_master() {
+                client.replay_aof(&aof_path).await.unwrap();
+            }
+            let aof = AppendOnlyFile::open(&aof_path).await.unwrap();
+            *client.aof.lock().await = Some(aof);
         }
+        client
     }
 
     pub(crate) async fn process_command(
         &self,
         command: Command,
         contents: Value,
-        stream: ClientWrite,
+        stream: Option<ClientWrite>,
         addr: &SocketAddr,
-        reply: bool
+        state: &mut ConnectionState,
+        reply: bool,
     ) -> Result<()> {
         debug!("[PROCESS_COMMAND] - START");
+        let response = match command {
+            _ if state.queues(command) => state.queue(command, contents)?,
+            // EXEC takes the gate for writing itself, and WAIT, DEBUG SLEEP and the blocking pops
+            // can block for as long as they were told to, which must not hold up a transaction.
+            // The blocking pops take the gate themselves whenever they look at their lists.
+            Command::Exec | Command::Wait | Command::Debug | Command::Blpop | Command::Brpop => {
+                self.execute(command, contents, stream.clone(), addr, state)
+                    .await?
+            }
+            _ => {
+                let _gate = self.transaction_gate.read().await;
+                self.execute(command, contents, stream.clone(), addr, state)
+                    .await?
+            }
+        };
+
+        debug!("[PROCESS_COMMAND] - Writing response to stream.");
+        if let (true, Some(stream)) = (reply, stream) {
+            stream.lock().await.write_all(&response).await?;
+        }
+        debug!("[PROCESS_COMMAND] - END.");
+
+        Ok(())
+    }
+
+    /// Runs a single command, returning its reply. While the AOF is on, a write is logged to it
+    /// before the reply goes out.
+    async fn execute(
+        &self,
+        command: Command,
+        contents: Value,
+        stream: Option<ClientWrite>,
+        addr: &SocketAddr,
+        state: &mut ConnectionState,
+    ) -> Result<Vec<u8>> {
+        // PUBLISH only counts as a write so that replicas relay it, and changes nothing to log.
+        let logged = command.is_write()
+            && command != Command::Publish
+            && self.config.read().await.appendonly;
+        let frame = logged.then(|| command_frame(command, &contents));
+        let modifies = command.is_write() || matches!(command, Command::Blpop | Command::Brpop);
+        // The keys are only worth finding when someone tracks them.
+        let tracked = (state.tracking || (modifies && !self.tracking.read().await.is_empty()))
+            .then(|| contents.clone().into_args());
+        let reply = self.run(command, contents, stream, addr, state).await;
+        // Keys the write found expired are deleted before it, wherever it is replayed.
+        if command.is_write() {
+            self.propagate_expired().await?;
+        }
+        if let Some(args) = tracked {
+            if modifies {
+                self.invalidate(command, &command.keys(&args), state.id)
+                    .await;
+            } else if reply.is_ok() {
+                let mut tracking = self.tracking.write().await;
+                tracking.track(state.id, command.keys(&args));
+            }
+        }
+        let reply = reply?;
+        if let Some(frame) = frame {
+            self.append_to_aof(state.db, &frame).await?;
+        }
+        Ok(reply)
+    }
+
+    async fn run(
+        &self,
+        command: Command,
+        contents: Value,
+        stream: Option<ClientWrite>,
+        addr: &SocketAddr,
+        state: &mut ConnectionState,
+    ) -> Result<Vec<u8>> {
+        // RESP3 tells messages apart from replies by their push type, so only a RESP2
+        // connection is restricted while subscribed.
+        if state.subscriptions() > 0
+            && state.protocol == ProtocolVersion::Resp2
+            && !matches!(
+                command,
+                Command::Subscribe
+                    | Command::Unsubscribe
+                    | Command::PSubscribe
+                    | Command::PUnsubscribe
+                    | Command::Ping
+                    | Command::Hello
+                    | Command::Reset
+            )
+        {
+            bail!(
+                "ERR Can't execute '{}': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context",
+                command.to_string().to_lowercase()
+            );
+        }
+        let store = &self.databases[state.db];
         let response = match command {
             Command::Echo => {
                 debug!("[PROCESS_COMMAND] - Processing 'Echo' Command");
-                let value = match contents {
-                    Value::String(s) => s,
-                    Value::Array(x) => PayloadVec(x).redis_encode(),
-                    Value::Empty => "".to_string(),
-                };
-                value.to_string()
+                match contents {
+                    Value::Array(mut args) if args.len() == 1 => args.remove(0).redis_encode(),
+                    _ => return Err(arity_error(command)),
+                }
             }
             Command::Ping => {
                 debug!("[PROCESS_COMMAND] - Processing 'Ping' Command");
-                Payload::SimpleString("PONG".to_string()).redis_encode()
+                let message = match contents {
+                    Value::Array(mut args) if args.len() <= 1 => args.pop(),
+                    Value::Empty => None,
+                    _ => return Err(arity_error(command)),
+                };
+                if state.subscriptions() > 0 && state.protocol == ProtocolVersion::Resp2 {
+                    // Subscribed connections receive messages as arrays, so PING answers in kind.
+                    let message = message.unwrap_or(Payload::BulkString(vec![]));
+                    Payload::Array(vec![Payload::BulkString(b"pong".to_vec()), message])
+                        .redis_encode()
+                } else if let Some(message) = message {
+                    message.redis_encode()
+                } else {
+                    Payload::SimpleString("PONG".to_string()).redis_encode()
+                }
             }
             Command::Get => {
                 debug!("[PROCESS_COMMAND] - Processing 'Get' Command");
@@ -76,43 +277,54 @@ impl RedisClient {
                     Value::Array(x) => x[0].to_string(),
                     _ => bail!("unimplemented"),
                 };
-                self.store.write().await.get(&value)
+                store
+                    .read()
+                    .await
+                    .get(&value)?
+                    .redis_encode_for(state.protocol)
             }
             Command::Set => {
                 debug!("[PROCESS_COMMAND] - Processing 'Set' Command");
-                let (key, value, arg, arg_value) = match contents {
-                    Value::Array(x) => (
-                        x[0].to_string(),
-                        RedisType::String(x[1].to_string()),
-                        x.get(2).cloned(),
-                        x.get(3).cloned(),
-                    ),
-                    _ => bail!("Cant store data in given format."),
+                let args = match contents {
+                    Value::Array(x) => x,
+                    _ => return Err(arity_error(command)),
                 };
-                match &self.role {
-                    ClientRole::Master {
-                        slave_connections, ..
-                    } => {
-                        debug!(
-                            "[PROCESS_COMMAND] - Slave connections status: {:?}.",
-                            slave_connections
-                        );
-                        debug!("[PROCESS_COMMAND] - Processing 'Set' as Master.");
-                        let payload =
-                            Payload::build_bulk_string_array(vec!["SET", &key, value.as_inner()])
-                                .redis_encode();
-                        debug!("[PROCESS_COMMAND] - Encoded payload: {:?}.", payload);
-
-                        debug!("[PROCESS_COMMAND] - Propagating payload to slaves.");
-                        self.propagate(payload.as_bytes()).await?;
-                        debug!("[PROCESS_COMMAND] - Processing set locally.");
-                        self.process_set(key, value, arg, arg_value).await?
-                    }
-                    ClientRole::Slave { .. } => {
-                        debug!("[PROCESS_COMMAND] - Processing 'Set' locally as a Slave.");
-                        self.process_set(key, value, arg, arg_value).await?
-                    }
+                if self.process_set(state.db, &args).await? {
+                    Payload::SimpleString("OK".to_string()).redis_encode()
+                } else {
+                    Payload::Null.redis_encode_for(state.protocol)
+                }
+            }
+            Command::SetNx => {
+                debug!("[PROCESS_COMMAND] - Processing 'SetNx' Command");
+                let Value::Array(args) = contents else {
+                    return Err(arity_error(command));
+                };
+                if args.len() != 2 {
+                    return Err(arity_error(command));
+                }
+                let nx = Payload::BulkString(b"NX".to_vec());
+                let stored = self
+                    .process_set(state.db, &[args[0].clone(), args[1].clone(), nx])
+                    .await?;
+                Payload::Integer(stored as i64).redis_encode()
+            }
+            Command::SetEx => {
+                debug!("[PROCESS_COMMAND] - Processing 'SetEx' Command");
+                let Value::Array(args) = contents else {
+                    return Err(arity_error(command));
+                };
+                if args.len() != 3 {
+                    return Err(arity_error(command));
                 }
+                let seconds = args[1].to_string().parse::<i64>().context(NOT_AN_INTEGER)?;
+                if seconds <= 0 {
+                    bail!("ERR invalid expire time in 'setex' command");
+                }
+                let ex = Payload::BulkString(b"EX".to_vec());
+                let set_args = [args[0].clone(), args[2].clone(), ex, args[1].clone()];
+                self.process_set(state.db, &set_args).await?;
+                Payload::SimpleString("OK".to_string()).redis_encode()
             }
             Command::Type => {
                 debug!("[PROCESS_COMMAND] - Processing 'Type' Command");
@@ -121,275 +333,2969 @@ impl RedisClient {
                     Value::Array(x) => x[0].to_string(),
                     _ => bail!("unimplemented"),
                 };
-                self.store.read().await.get_type(&value)
+                let type_name = store.read().await.get_type(&value);
+                Payload::SimpleString(type_name.to_string()).redis_encode()
             }
             Command::XAdd => {
                 debug!("[PROCESS_COMMAND] - Processing 'XAdd' Command");
-                match contents {
-                    Value::Array(x) => {
-                        let (key, value) = x.split_at(2);
-                        let stream_key = key[0].to_string();
-                        let entry_id = key[1].to_string();
-                        let value =
-                            RedisType::Stream(Stream::new(&entry_id, &mut value[1..].to_vec()));
-                        self.store.write().await.set(&stream_key, value, None)?;
-                        Payload::BulkString(entry_id).redis_encode()
-                    }
-                    _ => bail!("Incorrect input type."),
+                let args = contents.into_args();
+                if args.len() < 4 || !args.len().is_multiple_of(2) {
+                    return Err(arity_error(command));
                 }
+                let id = NewStreamId::parse(&args[1])?;
+                let entry = args[2..]
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                let id = store.write().await.xadd(&args[0], id, entry)?;
+                Payload::BulkString(id.to_string().into_bytes()).redis_encode()
+            }
+            Command::XRange => {
+                debug!("[PROCESS_COMMAND] - Processing 'XRange' Command");
+                let args = contents.into_args();
+                if args.len() != 3 {
+                    return Err(arity_error(command));
+                }
+                let start = StreamId::parse_range_bound(&args[1], 0)?;
+                let end = StreamId::parse_range_bound(&args[2], u64::MAX)?;
+                let entries = store.read().await.xrange(&args[0], start, end)?;
+                let entries = entries
+                    .into_iter()
+                    .map(|(id, entry)| {
+                        let fields: Vec<String> = entry
+                            .into_iter()
+                            .flat_map(|(field, value)| [field, value])
+                            .collect();
+                        Payload::Array(vec![
+                            Payload::BulkString(id.to_string().into_bytes()),
+                            Payload::build_bulk_string_array(fields),
+                        ])
+                    })
+                    .collect();
+                Payload::Array(entries).redis_encode()
+            }
+            Command::XLen => {
+                debug!("[PROCESS_COMMAND] - Processing 'XLen' Command");
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                let len = store.read().await.xlen(&args[0])?;
+                Payload::Integer(len as i64).redis_encode()
             }
             Command::Info => {
                 debug!("[PROCESS_COMMAND] - Processing 'Info' Command");
-                let value = match contents {
-                    Value::String(s) => s,
-                    Value::Array(x) => x[0].to_string(),
-                    _ => bail!("unimplemented"),
-                };
-                match value.as_str() {
-                    "replication" => Payload::BulkString(self.role.to_string()).redis_encode(),
-                    _ => bail!("Unimplemented"),
+                let mut sections: Vec<String> = contents
+                    .into_args()
+                    .iter()
+                    .map(|section| section.to_lowercase())
+                    .collect();
+                if sections.is_empty()
+                    || sections
+                        .iter()
+                        .any(|section| matches!(section.as_str(), "all" | "default" | "everything"))
+                {
+                    sections = INFO_SECTIONS.iter().map(|s| s.to_string()).collect();
+                }
+                // Unknown sections are left out rather than rejected, as in Redis.
+                let mut rendered = vec![];
+                for section in INFO_SECTIONS {
+                    if !sections.iter().any(|s| s == section) {
+                        continue;
+                    }
+                    let body = match section {
+                        "server" => server_info(),
+                        "replication" => self.replication_info().await,
+                        "stats" => self.keyspace_stats().await,
+                        _ => self.keyspace_info().await,
+                    };
+                    let mut title = section.to_string();
+                    title[..1].make_ascii_uppercase();
+                    rendered.push(format!("# {}\n{}", title, body));
                 }
+                Payload::BulkString(rendered.join("\n\n").into_bytes()).redis_encode()
             }
             Command::ReplConf => {
                 debug!("[PROCESS_COMMAND] - Processing 'ReplConf' Command");
-                match &self.role {
-                    ClientRole::Master { .. } => {}
-                    _ => unimplemented!(),
+                let args = contents.into_args();
+                match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+                    Some("ack") => {
+                        let offset = args
+                            .get(1)
+                            .and_then(|offset| offset.parse::<usize>().ok())
+                            .context(NOT_AN_INTEGER)?;
+                        if let ClientRole::Master {
+                            slave_acks,
+                            ack_notify,
+                            ..
+                        } = &self.role
+                        {
+                            let ack = (offset, Instant::now());
+                            slave_acks.lock().await.insert(addr.to_string(), ack);
+                            ack_notify.notify_waiters();
+                        }
+                        // Replicas do not expect a reply to their acknowledgements.
+                        vec![]
+                    }
+                    Some("listening-port") => {
+                        let port = args
+                            .get(1)
+                            .and_then(|port| port.parse::<u16>().ok())
+                            .context(NOT_AN_INTEGER)?;
+                        if let ClientRole::Master { slave_ports, .. } = &self.role {
+                            slave_ports.lock().await.insert(addr.to_string(), port);
+                        }
+                        Payload::SimpleString("OK".to_string()).redis_encode()
+                    }
+                    _ => Payload::SimpleString("OK".to_string()).redis_encode(),
                 }
-                Payload::SimpleString("OK".to_string()).redis_encode()
             }
             Command::PSync => {
+                // Holding the store until the replica is registered keeps writes from landing
+                // after the snapshot but before they would be propagated to it.
+                let args = contents.into_args();
+                let stream = stream.context("ERR PSYNC needs a connection to replicate to")?;
+                let databases = self.read_databases().await;
+                let mut propagated_db = self.propagated_db.lock().await;
                 let mut lock = stream.lock().await;
-                lock.write_all(self.role.psync().as_bytes()).await?;
-
-                let byte_vec = get_empty_rdb();
-                lock.write_all(&byte_vec).await?;
-
-                let new_stream = stream.clone();
-                match &self.role {
-                    ClientRole::Slave {
-                        ..
-                    } => {
-                        debug!("[PROCESS_COMMAND] - As Slave.");
-                        debug!(
-                            "[PROCESS_COMMAND] - Setting master stream to {:?}.",
-                            new_stream
-                        );
-                        // *master_stream = new_stream;
-                        debug!("Idk what i was supposed to do here")
-                    }
-                    ClientRole::Master {
-                        slave_connections, ..
-                    } => {
-                        debug!("[PROCESS_COMMAND] - As Master.");
-                        debug!("[PROCESS_COMMAND] - Adding stream {:?} to slave connections with key: '{}'.", new_stream, addr);
-                        slave_connections
-                            .lock()
-                            .await
-                            .insert(addr.to_string(), stream.clone());
+                let acked = match self.role.missed_by(&args).await {
+                    // The replica carries on from where its stream stopped, in the database it
+                    // had selected, which is where the others are.
+                    Some((offset, missed)) => {
+                        let reply = format!("CONTINUE {}", self.role.replication_id());
+                        lock.write_all(&Payload::SimpleString(reply).redis_encode())
+                            .await?;
+                        lock.write_all(&missed).await?;
+                        offset
+                    }
+                    None => {
+                        let snapshot = Payload::RdbFile(dump_rdb(&databases)).redis_encode();
+                        lock.write_all(&self.role.psync()).await?;
+                        lock.write_all(&snapshot).await?;
+                        // The snapshot leaves the replica in database 0, whatever the others
+                        // have selected.
+                        *propagated_db = None;
+                        0
                     }
+                };
+                drop(lock);
+
+                debug!(
+                    "[PROCESS_COMMAND] - Adding stream {:?} to slave connections with key: '{}'.",
+                    stream, addr
+                );
+                self.role
+                    .slave_connections()
+                    .lock()
+                    .await
+                    .insert(addr.to_string(), stream.clone());
+                drop(propagated_db);
+                if let ClientRole::Master { slave_acks, .. } = &self.role {
+                    let ack = (acked, Instant::now());
+                    slave_acks.lock().await.insert(addr.to_string(), ack);
                 }
+                drop(databases);
                 debug!("[PROCESS_COMMAND] - Finished processing command.");
-                String::default()
+                vec![]
             }
-        };
-
-        debug!("[PROCESS_COMMAND] - Writing response to stream.");
-        if reply {
-            stream.lock().await.write_all(response.as_bytes()).await?;
-        }
-        debug!("[PROCESS_COMMAND] - END.");
-
-        Ok(())
-    }
-
-    pub async fn handshake(addr: &str) -> Result<(ReadHalf<TcpStream>, WriteHalf<TcpStream>)> {
-        debug!("[HANDSHAKE] - START.");
-        let payload = Payload::build_bulk_string_array(vec!["ping"]).redis_encode();
-        let psync = ClientRole::init_psync();
-
-        debug!("[HANDSHAKE] - Creating messages.");
-        let messages = [
-            payload.as_bytes(),
-            "*3\r\n$8\r\nREPLCONF\r\n$14\r\nlistening-port\r\n$4\r\n6380\r\n".as_bytes(),
-            "*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n".as_bytes(),
-            psync.as_bytes(),
-        ];
-        debug!("[HANDSHAKE] - Establishing Stream.");
-        let (mut r, mut w) = Self::connect_to_master(addr).await?;
-
-        let mut buf = vec![0; 1024];
-        for msg in messages {
-            w.write_all(msg).await?;
-            let _ = r.read(&mut buf).await?;
-        }
-
-        debug!("[HANDSHAKE] - END.");
-        Ok((r, w))
-    }
+            Command::Client => {
+                debug!("[PROCESS_COMMAND] - Processing 'Client' Command");
+                let args = contents.into_args();
+                if args.is_empty() {
+                    return Err(arity_error(command));
+                }
+                match args[0].to_lowercase().as_str() {
+                    "tracking" => {
+                        let options = parse_client_tracking(&args[1..])?;
+                        if let Some(redirect) = options.as_ref().and_then(|o| o.redirect) {
+                            if self.connections.read().await.addr(redirect).is_none() {
+                                bail!("ERR The client ID you want redirect to does not exist");
+                            }
+                        }
+                        let mut tracking = self.tracking.write().await;
+                        match (options, stream.clone()) {
+                            (Some(options), Some(stream)) => {
+                                let client = TrackingClient {
+                                    stream,
+                                    protocol: state.protocol,
+                                    redirect: options.redirect,
+                                    noloop: options.noloop,
+                                    bcast: options.bcast,
+                                    prefixes: options.prefixes,
+                                };
+                                tracking.enable(state.id, client);
+                                state.tracking = true;
+                            }
+                            _ => {
+                                tracking.disable(state.id);
+                                state.tracking = false;
+                            }
+                        }
+                        Payload::SimpleString("OK".to_string()).redis_encode()
+                    }
+                    "setname" if args.len() == 2 => {
+                        let name = &args[1];
+                        if name.chars().any(|c| !c.is_ascii_graphic()) {
+                            bail!("ERR Client names cannot contain spaces, newlines or special characters.");
+                        }
+                        // An empty name removes the current one.
+                        let name = (!name.is_empty()).then(|| name.clone());
+                        self.connections.write().await.set_name(state.id, name);
+                        Payload::SimpleString("OK".to_string()).redis_encode()
+                    }
+                    "getname" if args.len() == 1 => {
+                        match self.connections.read().await.name(state.id) {
+                            Some(name) => Payload::BulkString(name.as_bytes().to_vec()),
+                            None => Payload::Null,
+                        }
+                        .redis_encode_for(state.protocol)
+                    }
+                    "id" if args.len() == 1 => Payload::Integer(state.id as i64).redis_encode(),
+                    "list" if args.len() == 1 => {
+                        let list = self.connections.read().await.list();
+                        Payload::BulkString(list.into_bytes()).redis_encode()
+                    }
+                    "setname" | "getname" | "id" | "list" => return Err(arity_error(command)),
+                    subcommand => bail!("ERR unknown subcommand '{}'", subcommand),
+                }
+            }
+            Command::Config => {
+                debug!("[PROCESS_COMMAND] - Processing 'Config' Command");
+                let args = contents.into_args();
+                match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+                    Some("get") if args.len() > 1 => {
+                        let config = self.config.read().await;
+                        let parameters: Vec<String> = args[1..]
+                            .iter()
+                            .flat_map(|pattern| config.get(pattern))
+                            .flat_map(|(name, value)| [name, value])
+                            .collect();
+                        Payload::build_bulk_string_array(parameters).redis_encode()
+                    }
+                    Some("set") if args.len() > 1 && args.len() % 2 == 1 => {
+                        // Applied to a copy first, so one invalid value rejects the whole call.
+                        let mut config = self.config.write().await;
+                        let mut updated = config.clone();
+                        for pair in args[1..].chunks(2) {
+                            updated.set(&pair[0], &pair[1])?;
+                        }
+                        if updated.appendonly != config.appendonly {
+                            self.set_aof(updated.appendonly.then(|| updated.aof_path()))
+                                .await?;
+                        }
+                        *config = updated;
+                        Payload::SimpleString("OK".to_string()).redis_encode()
+                    }
+                    Some("get" | "set") => return Err(arity_error(command)),
+                    Some(subcommand) => bail!("ERR unknown subcommand '{}'", subcommand),
+                    None => return Err(arity_error(command)),
+                }
+            }
+            Command::Hello => {
+                debug!("[PROCESS_COMMAND] - Processing 'Hello' Command");
+                let args = contents.into_args();
+                if let Some(version) = args.first() {
+                    state.protocol = match version.parse::<i64>() {
+                        Ok(2) => ProtocolVersion::Resp2,
+                        Ok(3) => ProtocolVersion::Resp3,
+                        Ok(_) => bail!("NOPROTO unsupported protocol version"),
+                        Err(_) => bail!("ERR Protocol version is not an integer or out of range"),
+                    };
+                }
+                if let Some(option) = args.get(1) {
+                    bail!("ERR Syntax error in HELLO option '{}'", option);
+                }
+                if state.subscriptions() > 0 {
+                    self.pubsub.set_protocol(addr, state.protocol);
+                }
+                if state.tracking {
+                    let mut tracking = self.tracking.write().await;
+                    tracking.set_protocol(state.id, state.protocol);
+                }
 
-    async fn connect_to_master(
-        master_address: &str,
-    ) -> Result<(ReadHalf<TcpStream>, WriteHalf<TcpStream>)> {
-        debug!("[CONNECT_TO_MASTER] - START..");
-        let timeout_duration = tokio::time::Duration::from_secs(2);
-        let connect_future = TcpStream::connect(master_address);
-        match tokio::time::timeout(timeout_duration, connect_future).await {
-            Ok(Ok(stream)) => {
-                debug!("[CONNECT_TO_MASTER] - Successfully Connected..");
-                debug!("[CONNECT_TO_MASTER] - END..");
-                let (r, w) = tokio::io::split(stream);
-                Ok((r, w))
+                let role = if self.role.is_master() {
+                    "master"
+                } else {
+                    "replica"
+                };
+                let field = |name: &str| Payload::BulkString(name.as_bytes().to_vec());
+                Payload::Map(vec![
+                    (field("server"), field("redis")),
+                    (field("version"), field(env!("CARGO_PKG_VERSION"))),
+                    (field("proto"), Payload::Integer(state.protocol.number())),
+                    (field("role"), field(role)),
+                    (field("modules"), Payload::Array(vec![])),
+                ])
+                .redis_encode_for(state.protocol)
             }
-            Ok(Err(e)) => {
-                warn!(
-                    "[CONNECT_TO_MASTER] - Failed to connect to {}: {}.",
-                    master_address, e
-                );
-                warn!("[CONNECT_TO_MASTER] - Terminating..");
-                bail!(e)
+            Command::Subscribe | Command::PSubscribe => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let names = contents.into_args();
+                if names.is_empty() {
+                    return Err(arity_error(command));
+                }
+                let subscriber = Subscriber {
+                    stream: stream.context("ERR SUBSCRIBE needs a connection to deliver to")?,
+                    protocol: state.protocol,
+                };
+                let kind = command.to_string().to_lowercase();
+                let mut response = vec![];
+                for name in names {
+                    if command == Command::Subscribe {
+                        self.pubsub.subscribe(&name, *addr, subscriber.clone());
+                        state.channels.insert(name.clone());
+                    } else {
+                        self.pubsub.psubscribe(&name, *addr, subscriber.clone());
+                        state.patterns.insert(name.clone());
+                    }
+                    response.extend(subscription_reply(
+                        &kind,
+                        Some(name),
+                        state.subscriptions(),
+                        state.protocol,
+                    ));
+                }
+                response
             }
-            Err(e) => {
-                warn!(
-                    "[CONNECT_TO_MASTER] - Connection attempt to {} timed out: {}.",
-                    master_address, e
-                );
-                warn!("[CONNECT_TO_MASTER] - Terminating..");
-                bail!(e)
+            Command::Unsubscribe | Command::PUnsubscribe => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let kind = command.to_string().to_lowercase();
+                let mut names = contents.into_args();
+                if names.is_empty() {
+                    names = match command {
+                        Command::Unsubscribe => state.channels.iter().cloned().collect(),
+                        _ => state.patterns.iter().cloned().collect(),
+                    };
+                }
+                if names.is_empty() {
+                    subscription_reply(&kind, None, state.subscriptions(), state.protocol)
+                } else {
+                    let mut response = vec![];
+                    for name in names {
+                        if command == Command::Unsubscribe {
+                            self.pubsub.unsubscribe(&name, addr);
+                            state.channels.remove(&name);
+                        } else {
+                            self.pubsub.punsubscribe(&name, addr);
+                            state.patterns.remove(&name);
+                        }
+                        response.extend(subscription_reply(
+                            &kind,
+                            Some(name),
+                            state.subscriptions(),
+                            state.protocol,
+                        ));
+                    }
+                    response
+                }
             }
-        }
-    }
-
-    pub async fn propagate(&self, message: &[u8]) -> Result<()> {
-        debug!("[PROPAGATE] - START");
-        let res = match &self.role {
-            ClientRole::Master {
-                slave_connections, ..
-            } => {
-                let connections = slave_connections.lock().await;
+            Command::Publish => {
+                debug!("[PROCESS_COMMAND] - Processing 'Publish' Command");
+                let args = contents.into_args();
+                if args.len() != 2 {
+                    return Err(arity_error(command));
+                }
+                let receivers = self.publish(&args[0], &args[1]).await;
+                Payload::Integer(receivers as i64).redis_encode()
+            }
+            Command::Reset => {
+                debug!("[PROCESS_COMMAND] - Processing 'Reset' Command");
+                // Back to how a new connection starts: out of any transaction and subscription,
+                // on RESP2 and database 0, without a name and with tracking off.
+                if state.subscriptions() > 0 {
+                    self.remove_subscriber(addr).await;
+                }
+                self.tracking.write().await.disable(state.id);
+                self.connections.write().await.set_name(state.id, None);
+                *state = ConnectionState {
+                    id: state.id,
+                    ..Default::default()
+                };
+                Payload::SimpleString("RESET".to_string()).redis_encode()
+            }
+            Command::Multi => {
+                debug!("[PROCESS_COMMAND] - Processing 'Multi' Command");
+                if state.transaction.is_some() {
+                    bail!("ERR MULTI calls can not be nested");
+                }
+                state.transaction = Some(Transaction::default());
+                Payload::SimpleString("OK".to_string()).redis_encode()
+            }
+            Command::Exec => {
+                debug!("[PROCESS_COMMAND] - Processing 'Exec' Command");
+                let transaction = state.transaction.take().context("ERR EXEC without MULTI")?;
+                if transaction.aborted {
+                    bail!("EXECABORT Transaction discarded because of previous errors.");
+                }
+                let _gate = self.transaction_gate.write().await;
+                let mut response =
+                    format!("*{}{}", transaction.queued.len(), DELIMITER).into_bytes();
+                let mut propagated = Ok(());
+                // Replicas and the AOF get the writes wrapped in MULTI/EXEC, so that they are
+                // applied all at once there too. MULTI goes out just before the first of them.
+                let mut wrapped = false;
+                state.executing = true;
+                for (command, contents) in transaction.queued {
+                    let writes =
+                        command.is_write() || matches!(command, Command::Blpop | Command::Brpop);
+                    if writes && !wrapped {
+                        wrapped = true;
+                        let multi = command_frame(Command::Multi, &Value::Empty);
+                        propagated = self.replicate(state.db, &multi).await;
+                        if propagated.is_err() {
+                            break;
+                        }
+                    }
+                    let frame = (command.is_write() && self.role.is_master())
+                        .then(|| command_frame(command, &contents));
+                    let result =
+                        Box::pin(self.execute(command, contents, stream.clone(), addr, state))
+                            .await;
+                    match result {
+                        Ok(reply) => {
+                            response.extend(reply);
+                            if let Some(frame) = frame {
+                                propagated = self.propagate_write(state.db, &frame).await;
+                                if propagated.is_err() {
+                                    break;
+                                }
+                            }
+                        }
+                        // A failing command doesn't stop the rest of the transaction.
+                        Err(e) => response.extend(Payload::error(e).redis_encode()),
+                    }
+                }
+                if wrapped && propagated.is_ok() {
+                    let exec = command_frame(Command::Exec, &Value::Empty);
+                    propagated = self.replicate(state.db, &exec).await;
+                }
+                state.executing = false;
+                propagated?;
+                response
+            }
+            Command::Discard => {
+                debug!("[PROCESS_COMMAND] - Processing 'Discard' Command");
+                state
+                    .transaction
+                    .take()
+                    .context("ERR DISCARD without MULTI")?;
+                Payload::SimpleString("OK".to_string()).redis_encode()
+            }
+            Command::FlushDb | Command::FlushAll => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let args = contents.into_args();
+                // The keyspace is cleared in place either way, so ASYNC behaves like SYNC.
+                match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+                    None | Some("sync" | "async") if args.len() <= 1 => {}
+                    _ => bail!("ERR syntax error"),
+                }
+                if command == Command::FlushAll {
+                    for store in self.write_databases().await.iter_mut() {
+                        store.flush();
+                    }
+                } else {
+                    store.write().await.flush();
+                }
+                Payload::SimpleString("OK".to_string()).redis_encode()
+            }
+            Command::Select => {
+                debug!("[PROCESS_COMMAND] - Processing 'Select' Command");
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                state.db = self.database_index(&args[0])?;
+                Payload::SimpleString("OK".to_string()).redis_encode()
+            }
+            Command::SwapDb => {
+                debug!("[PROCESS_COMMAND] - Processing 'SwapDb' Command");
+                let args = contents.into_args();
+                if args.len() != 2 {
+                    return Err(arity_error(command));
+                }
+                let first = self.database_index(&args[0])?;
+                let second = self.database_index(&args[1])?;
+                // Databases are locked in index order, so two swaps can't wait on each other.
+                if first != second {
+                    let (low, high) = (first.min(second), first.max(second));
+                    let mut low = self.databases[low].write().await;
+                    let mut high = self.databases[high].write().await;
+                    std::mem::swap(&mut *low, &mut *high);
+                    self.list_pushed.notify_waiters();
+                }
+                Payload::SimpleString("OK".to_string()).redis_encode()
+            }
+            Command::Move => {
+                debug!("[PROCESS_COMMAND] - Processing 'Move' Command");
+                let args = contents.into_args();
+                if args.len() != 2 {
+                    return Err(arity_error(command));
+                }
+                let target = self.database_index(&args[1])?;
+                if target == state.db {
+                    bail!("ERR source and destination objects are the same");
+                }
+                let (mut source, mut destination) = self.write_pair(state.db, target).await;
+                let moved = destination.exists(&args[..1]) == 0
+                    && match source.take(&args[0]) {
+                        Some((value, expire_at)) => {
+                            destination.restore(args[0].clone(), value, expire_at);
+                            self.list_pushed.notify_waiters();
+                            true
+                        }
+                        None => false,
+                    };
+                Payload::Integer(moved as i64).redis_encode()
+            }
+            Command::Copy => {
+                debug!("[PROCESS_COMMAND] - Processing 'Copy' Command");
+                let args = contents.into_args();
+                if args.len() < 2 {
+                    return Err(arity_error(command));
+                }
+                let (mut target, mut replace) = (state.db, false);
+                let mut options = args[2..].iter();
+                while let Some(option) = options.next() {
+                    match option.to_lowercase().as_str() {
+                        "db" => match options.next() {
+                            Some(index) => target = self.database_index(index)?,
+                            None => bail!("ERR syntax error"),
+                        },
+                        "replace" => replace = true,
+                        _ => bail!("ERR syntax error"),
+                    }
+                }
+                if target == state.db && args[0] == args[1] {
+                    bail!("ERR source and destination objects are the same");
+                }
+                let copied = if target == state.db {
+                    let mut store = store.write().await;
+                    let entry = store.copy_of(&args[0]);
+                    copy_entry(entry, &mut store, &args[1], replace)
+                } else {
+                    let (source, mut destination) = self.write_pair(state.db, target).await;
+                    copy_entry(
+                        source.copy_of(&args[0]),
+                        &mut destination,
+                        &args[1],
+                        replace,
+                    )
+                };
+                if copied {
+                    self.list_pushed.notify_waiters();
+                }
+                Payload::Integer(copied as i64).redis_encode()
+            }
+            Command::Save => {
+                debug!("[PROCESS_COMMAND] - Processing 'Save' Command");
+                self.save().await?;
+                Payload::SimpleString("OK".to_string()).redis_encode()
+            }
+            Command::Shutdown => {
+                debug!("[PROCESS_COMMAND] - Processing 'Shutdown' Command");
+                let args = contents.into_args();
+                let save = match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+                    None => None,
+                    Some("save") if args.len() == 1 => Some(true),
+                    Some("nosave") if args.len() == 1 => Some(false),
+                    _ => bail!("ERR syntax error"),
+                };
+                // A failure to persist leaves the server running, as with Redis.
+                self.prepare_shutdown(save).await?;
+                self.shutdown.notify_one();
+                // The connection closes instead of replying.
+                vec![]
+            }
+            Command::BgSave => {
+                debug!("[PROCESS_COMMAND] - Processing 'BgSave' Command");
+                let args = contents.into_args();
+                // Saves never wait for each other, so SCHEDULE has nothing to defer.
+                match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+                    None | Some("schedule") if args.len() <= 1 => {}
+                    _ => bail!("ERR syntax error"),
+                }
+                let save = self.start_save().await?;
+                tokio::spawn(async move {
+                    if let Err(e) = save.await {
+                        warn!("Background saving failed {}", e);
+                    }
+                });
+                Payload::SimpleString("Background saving started".to_string()).redis_encode()
+            }
+            Command::DbSize => {
+                debug!("[PROCESS_COMMAND] - Processing 'DbSize' Command");
+                let size = store.read().await.dbsize();
+                Payload::Integer(size as i64).redis_encode()
+            }
+            Command::RandomKey => {
+                debug!("[PROCESS_COMMAND] - Processing 'RandomKey' Command");
+                if !contents.into_args().is_empty() {
+                    return Err(arity_error(command));
+                }
+                match store.read().await.random_key() {
+                    Some(key) => Payload::BulkString(key.into_bytes()).redis_encode(),
+                    None => Payload::Null.redis_encode_for(state.protocol),
+                }
+            }
+            Command::Command => {
+                debug!("[PROCESS_COMMAND] - Processing 'Command' Command");
+                let args = contents.into_args();
+                match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+                    None => {
+                        let info = Command::ALL
+                            .iter()
+                            .map(|command| {
+                                Payload::Array(vec![
+                                    Payload::BulkString(
+                                        command.to_string().to_lowercase().into_bytes(),
+                                    ),
+                                    Payload::Integer(command.arity()),
+                                ])
+                            })
+                            .collect();
+                        Payload::Array(info).redis_encode()
+                    }
+                    Some("count") => Payload::Integer(Command::ALL.len() as i64).redis_encode(),
+                    // Clients such as redis-cli only use the docs for hints, so none is fine.
+                    Some("docs") => Payload::Array(vec![]).redis_encode(),
+                    Some(subcommand) => bail!("ERR unknown subcommand '{}'", subcommand),
+                }
+            }
+            Command::BitField => {
+                debug!("[PROCESS_COMMAND] - Processing 'BitField' Command");
+                let args = contents.into_args();
+                if args.is_empty() {
+                    return Err(arity_error(command));
+                }
+                let ops = BitFieldOp::parse_all(&args[1..])?;
+                let results = store.write().await.bitfield(&args[0], &ops)?;
 
-                let futures: Vec<_> = connections
-                    .iter()
-                    .map(|(_, stream)| async move {
-                        let mut stream = stream.lock().await;
-                        debug!("[PROPAGATE] - Writing to Stream.");
-                        stream.write_all(message).await
-                    })
+                let results = results
+                    .into_iter()
+                    .map(|result| result.map_or(Payload::Null, Payload::Integer))
                     .collect();
-
-                debug!("[PROPAGATE] - Joining and awaiting all connection propagations.");
-                futures::future::join_all(futures).await;
-                Ok(())
+                Payload::Array(results).redis_encode_for(state.protocol)
+            }
+            Command::Del => {
+                debug!("[PROCESS_COMMAND] - Processing 'Del' Command");
+                let keys = contents.into_args();
+                if keys.is_empty() {
+                    return Err(arity_error(command));
+                }
+                let removed = store.write().await.del(&keys);
+                Payload::Integer(removed as i64).redis_encode()
             }
-            ClientRole::Slave { .. } => {
-                debug!("[PROPAGATE] - Slave currently can't propagate.");
-                debug!("[PROPAGATE] - Terminating..");
-                bail!("Slave currently can't propagate")
+            Command::Unlink => {
+                debug!("[PROCESS_COMMAND] - Processing 'Unlink' Command");
+                let keys = contents.into_args();
+                if keys.is_empty() {
+                    return Err(arity_error(command));
+                }
+                let values = store.write().await.unlink(&keys);
+                let removed = values.len();
+                // Freeing a large value takes a while, so it's done off the connection's task.
+                tokio::task::spawn_blocking(move || drop(values));
+                Payload::Integer(removed as i64).redis_encode()
             }
-        };
-        debug!("[PROPAGATE] - END");
-        res
-    }
-    pub async fn process_set(
-        &self,
-        key: String,
-        value: RedisType,
-        arg: Option<Payload>,
-        arg_value: Option<Payload>,
-    ) -> Result<String> {
-        if let Some(arg) = arg {
-            let arg_value = arg_value
-                .context("Missing arg specifier")?
-                .to_string()
-                .parse::<i64>()
-                .context("Incorrect arg type expected an integer.")?;
-            match arg.to_string().to_lowercase().as_str() {
-                "px" => self
-                    .store
+            Command::Touch => {
+                debug!("[PROCESS_COMMAND] - Processing 'Touch' Command");
+                let keys = contents.into_args();
+                if keys.is_empty() {
+                    return Err(arity_error(command));
+                }
+                // Access times aren't tracked, so touching a key only checks that it exists.
+                let count = store.read().await.exists(&keys);
+                Payload::Integer(count as i64).redis_encode()
+            }
+            Command::Exists => {
+                debug!("[PROCESS_COMMAND] - Processing 'Exists' Command");
+                let keys = contents.into_args();
+                if keys.is_empty() {
+                    return Err(arity_error(command));
+                }
+                let count = store.read().await.exists(&keys);
+                Payload::Integer(count as i64).redis_encode()
+            }
+            Command::Incr | Command::Decr => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                let delta = if matches!(command, Command::Incr) {
+                    1
+                } else {
+                    -1
+                };
+                let value = store.write().await.incr_by(&args[0], delta)?;
+                Payload::Integer(value).redis_encode()
+            }
+            Command::Ttl | Command::Pttl => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                let ttl_ms = store.read().await.ttl_ms(&args[0]);
+                let ttl = match command {
+                    Command::Ttl if ttl_ms >= 0 => ttl_ms / 1000,
+                    _ => ttl_ms,
+                };
+                Payload::Integer(ttl).redis_encode()
+            }
+            Command::GetDel => {
+                debug!("[PROCESS_COMMAND] - Processing 'GetDel' Command");
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                let value = store.write().await.getdel(&args[0])?;
+                value
+                    .map_or(Payload::Null, Payload::BulkString)
+                    .redis_encode_for(state.protocol)
+            }
+            Command::GetEx => {
+                debug!("[PROCESS_COMMAND] - Processing 'GetEx' Command");
+                let args = contents.into_args();
+                if args.is_empty() {
+                    return Err(arity_error(command));
+                }
+                // GETEX takes the expiry options of SET, plus PERSIST on its own.
+                let persist = args.len() == 2 && args[1].eq_ignore_ascii_case("persist");
+                let options = if persist {
+                    SetOptions::default()
+                } else {
+                    SetOptions::parse(&args[1..])?
+                };
+                if options.condition.is_some() || options.keep_ttl {
+                    bail!("ERR syntax error");
+                }
+                let value = store
                     .write()
                     .await
-                    .set(&key.to_string(), value, Some(arg_value)),
-                _ => bail!("unimplemented arg."),
+                    .getex(&args[0], options.expire_at, persist)?;
+                value
+                    .map_or(Payload::Null, Payload::BulkString)
+                    .redis_encode_for(state.protocol)
             }
-        } else {
-            self.store.write().await.set(&key.to_string(), value, None)
-        }
-    }
-}
-
-type ClientWrite = Arc<Mutex<WriteHalf<TcpStream>>>;
-
-#[derive(Clone)]
-pub enum ClientRole {
-    Master {
-        replication_id: String,
-        replication_offset: usize,
-        slave_connections: Arc<Mutex<HashMap<String, ClientWrite>>>,
-    },
-    Slave {
-        master_stream_w: ClientWrite,
-        master_stream_r: Arc<Mutex<ReadHalf<TcpStream>>>,
-        master_address: String,
-        master_id: String,
-        master_offset: i32,
-    },
-}
-
-impl ClientRole {
-    pub fn new_master() -> Self {
+            Command::Object => {
+                debug!("[PROCESS_COMMAND] - Processing 'Object' Command");
+                let args = contents.into_args();
+                match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+                    Some("encoding") if args.len() == 2 => {
+                        let encoding = store
+                            .read()
+                            .await
+                            .object_encoding(&args[1])
+                            .context("ERR no such key")?;
+                        Payload::BulkString(encoding.into()).redis_encode()
+                    }
+                    Some("encoding") => return Err(arity_error(command)),
+                    Some(subcommand) => bail!("ERR unknown subcommand '{}'", subcommand),
+                    None => return Err(arity_error(command)),
+                }
+            }
+            Command::Debug => {
+                debug!("[PROCESS_COMMAND] - Processing 'Debug' Command");
+                let args = contents.into_args();
+                match args.first().map(|arg| arg.to_lowercase()).as_deref() {
+                    Some("sleep") if args.len() == 2 => {
+                        let seconds = args[1]
+                            .parse::<f64>()
+                            .ok()
+                            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                            .context("ERR value is not a valid float")?;
+                        // Only this connection waits; every other one keeps being served.
+                        tokio::time::sleep(seconds).await;
+                        Payload::SimpleString("OK".to_string()).redis_encode()
+                    }
+                    Some("object") if args.len() == 2 => {
+                        let (encoding, length, last_id) = store
+                            .read()
+                            .await
+                            .debug_object(&args[1])
+                            .context("ERR no such key")?;
+                        let mut reply = format!(
+                            "Value refcount:1 encoding:{} serializedlength:{}",
+                            encoding, length
+                        );
+                        if let Some(last_id) = last_id {
+                            reply.push_str(&format!(" last-id:{}", last_id));
+                        }
+                        Payload::SimpleString(reply).redis_encode()
+                    }
+                    Some("change-repl-id") if args.len() == 1 => {
+                        self.change_replication_id().await?;
+                        Payload::SimpleString("OK".to_string()).redis_encode()
+                    }
+                    Some("reload") if args.len() == 1 => {
+                        // Nothing may write between the save and the load, or it would be lost.
+                        let _gate = self.transaction_gate.write().await;
+                        self.save().await?;
+                        let path = self.config.read().await.rdb_path();
+                        self.load_rdb_file(&path).await?;
+                        Payload::SimpleString("OK".to_string()).redis_encode()
+                    }
+                    Some("sleep" | "object" | "change-repl-id" | "reload") => {
+                        return Err(arity_error(command))
+                    }
+                    Some(subcommand) => bail!("ERR unknown subcommand '{}'", subcommand),
+                    None => return Err(arity_error(command)),
+                }
+            }
+            Command::Persist => {
+                debug!("[PROCESS_COMMAND] - Processing 'Persist' Command");
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                let persisted = store.write().await.persist(&args[0]);
+                Payload::Integer(persisted as i64).redis_encode()
+            }
+            Command::Expire | Command::Pexpire | Command::ExpireAt | Command::PExpireAt => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let args = contents.into_args();
+                if args.len() < 2 {
+                    return Err(arity_error(command));
+                }
+                let time = args[1].parse::<i64>().context(NOT_AN_INTEGER)?;
+                let condition = ExpireCondition::parse(&args[2..])?;
+                let invalid = || {
+                    format!(
+                        "ERR invalid expire time in '{}' command",
+                        command.to_string().to_lowercase()
+                    )
+                };
+                let millis = match command {
+                    Command::Expire | Command::ExpireAt => time.checked_mul(1000),
+                    _ => Some(time),
+                }
+                .with_context(invalid)?;
+                let expire_at = match command {
+                    Command::Expire | Command::Pexpire => {
+                        chrono::Duration::try_milliseconds(millis)
+                            .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+                    }
+                    _ => DateTime::from_timestamp_millis(millis),
+                }
+                .with_context(invalid)?;
+                let updated = store
+                    .write()
+                    .await
+                    .set_expiry_on_existing(&args[0], expire_at, condition);
+                Payload::Integer(updated as i64).redis_encode()
+            }
+            Command::Lpush | Command::Rpush => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let args = contents.into_args();
+                if args.len() < 2 {
+                    return Err(arity_error(command));
+                }
+                let end = list_end(command);
+                let len = store.write().await.push(&args[0], &args[1..], end)?;
+                self.list_pushed.notify_waiters();
+                Payload::Integer(len as i64).redis_encode()
+            }
+            Command::Blpop | Command::Brpop => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let args = contents.into_args();
+                if args.len() < 2 {
+                    return Err(arity_error(command));
+                }
+                let (keys, timeout) = args.split_at(args.len() - 1);
+                let timeout = match timeout[0].parse::<f64>() {
+                    Ok(seconds) if seconds < 0.0 => bail!("ERR timeout is negative"),
+                    Ok(0.0) => None,
+                    seconds => Some(
+                        seconds
+                            .ok()
+                            .and_then(|seconds| Duration::try_from_secs_f64(seconds).ok())
+                            .context("ERR timeout is not a float or out of range")?,
+                    ),
+                };
+                let (pop, end) = match command {
+                    Command::Blpop => (Command::Lpop, ListEnd::Left),
+                    _ => (Command::Rpop, ListEnd::Right),
+                };
+                // EXEC already holds the gate, and a transaction never waits for other clients.
+                let popped = if state.executing {
+                    store.write().await.pop_first(keys, end)?
+                } else {
+                    self.blocking_pop(state.db, keys, end, timeout).await?
+                };
+                let Some((key, element)) = popped else {
+                    return Ok(null_array(state.protocol));
+                };
+                // Replicas and the AOF are told which element was popped, as replaying the
+                // blocking command could block or pop from another key.
+                let frame = Payload::build_bulk_string_array(vec![pop.to_string(), key.clone()])
+                    .redis_encode();
+                self.replicate(state.db, &frame).await?;
+                Payload::build_bulk_string_array(vec![key, element]).redis_encode()
+            }
+            Command::Lpop | Command::Rpop => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let args = contents.into_args();
+                if args.is_empty() || args.len() > 2 {
+                    return Err(arity_error(command));
+                }
+                let count = args
+                    .get(1)
+                    .map(|count| {
+                        count
+                            .parse::<usize>()
+                            .context("ERR value is out of range, must be positive")
+                    })
+                    .transpose()?;
+                let end = list_end(command);
+                let popped = store.write().await.pop(&args[0], count.unwrap_or(1), end)?;
+                match (popped, count) {
+                    (Some(popped), Some(_)) => {
+                        Payload::build_bulk_string_array(popped).redis_encode()
+                    }
+                    (Some(popped), None) => match popped.into_iter().next() {
+                        Some(element) => Payload::BulkString(element.into_bytes()).redis_encode(),
+                        None => Payload::Null.redis_encode_for(state.protocol),
+                    },
+                    (None, Some(_)) => null_array(state.protocol),
+                    (None, None) => Payload::Null.redis_encode_for(state.protocol),
+                }
+            }
+            Command::Lrange => {
+                debug!("[PROCESS_COMMAND] - Processing 'Lrange' Command");
+                let args = contents.into_args();
+                if args.len() != 3 {
+                    return Err(arity_error(command));
+                }
+                let start = args[1].parse::<i64>().context(NOT_AN_INTEGER)?;
+                let stop = args[2].parse::<i64>().context(NOT_AN_INTEGER)?;
+                let elements = store.read().await.lrange(&args[0], start, stop)?;
+                Payload::build_bulk_string_array(elements).redis_encode()
+            }
+            Command::Llen => {
+                debug!("[PROCESS_COMMAND] - Processing 'Llen' Command");
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                let len = store.read().await.llen(&args[0])?;
+                Payload::Integer(len as i64).redis_encode()
+            }
+            Command::Lmpop => {
+                debug!("[PROCESS_COMMAND] - Processing 'Lmpop' Command");
+                let args = contents.into_args();
+                if args.len() < 3 {
+                    return Err(arity_error(command));
+                }
+                let (keys, end, count) = parse_multi_pop(&args)?;
+                let end = parse_list_end(end)?;
+                match store.write().await.lmpop(keys, end, count)? {
+                    Some((key, popped)) => Payload::Array(vec![
+                        Payload::BulkString(key.into_bytes()),
+                        Payload::build_bulk_string_array(popped),
+                    ])
+                    .redis_encode(),
+                    None => null_array(state.protocol),
+                }
+            }
+            Command::Rpoplpush | Command::Lmove => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let args = contents.into_args();
+                let (from, to) = match command {
+                    Command::Rpoplpush if args.len() == 2 => (ListEnd::Right, ListEnd::Left),
+                    Command::Lmove if args.len() == 4 => {
+                        (parse_list_end(&args[2])?, parse_list_end(&args[3])?)
+                    }
+                    _ => return Err(arity_error(command)),
+                };
+                let moved = store.write().await.lmove(&args[0], &args[1], from, to)?;
+                match moved {
+                    Some(element) => {
+                        self.list_pushed.notify_waiters();
+                        Payload::BulkString(element.into_bytes()).redis_encode()
+                    }
+                    None => Payload::Null.redis_encode_for(state.protocol),
+                }
+            }
+            Command::Lpos => {
+                debug!("[PROCESS_COMMAND] - Processing 'Lpos' Command");
+                let args = contents.into_args();
+                if args.len() < 2 {
+                    return Err(arity_error(command));
+                }
+                let mut rank = 1;
+                let mut count = None;
+                let mut options = args[2..].iter();
+                while let Some(option) = options.next() {
+                    let value = options
+                        .next()
+                        .context("ERR syntax error")?
+                        .parse::<i64>()
+                        .context(NOT_AN_INTEGER)?;
+                    match option.to_lowercase().as_str() {
+                        "rank" if value == 0 || value == i64::MIN => bail!(
+                            "ERR RANK can't be zero: use 1 to start from the first match, 2 from the second ... or use negative to start from the end of the list"
+                        ),
+                        "rank" => rank = value,
+                        "count" => {
+                            count = Some(
+                                usize::try_from(value).context("ERR COUNT can't be negative")?,
+                            )
+                        }
+                        _ => bail!("ERR syntax error"),
+                    }
+                }
+                // Without COUNT only the first match is replied with.
+                let limit = count.unwrap_or(1);
+                let indices = store.read().await.lpos(&args[0], &args[1], rank, limit)?;
+                match (count, indices.first()) {
+                    (Some(_), _) => Payload::Array(
+                        indices
+                            .iter()
+                            .map(|&index| Payload::Integer(index as i64))
+                            .collect(),
+                    )
+                    .redis_encode(),
+                    (None, Some(&index)) => Payload::Integer(index as i64).redis_encode(),
+                    (None, None) => Payload::Null.redis_encode_for(state.protocol),
+                }
+            }
+            Command::Linsert => {
+                debug!("[PROCESS_COMMAND] - Processing 'Linsert' Command");
+                let args = contents.into_args();
+                if args.len() != 4 {
+                    return Err(arity_error(command));
+                }
+                let before = match args[1].to_lowercase().as_str() {
+                    "before" => true,
+                    "after" => false,
+                    _ => bail!("ERR syntax error"),
+                };
+                let len = store
+                    .write()
+                    .await
+                    .linsert(&args[0], before, &args[2], &args[3])?;
+                Payload::Integer(len).redis_encode()
+            }
+            Command::Lset => {
+                debug!("[PROCESS_COMMAND] - Processing 'Lset' Command");
+                let args = contents.into_args();
+                if args.len() != 3 {
+                    return Err(arity_error(command));
+                }
+                let index = args[1].parse::<i64>().context(NOT_AN_INTEGER)?;
+                store.write().await.lset(&args[0], index, &args[2])?;
+                Payload::SimpleString("OK".to_string()).redis_encode()
+            }
+            Command::Hset => {
+                debug!("[PROCESS_COMMAND] - Processing 'Hset' Command");
+                let args = contents.into_args();
+                if args.len() < 3 || args.len().is_multiple_of(2) {
+                    return Err(arity_error(command));
+                }
+                let pairs: Vec<(String, String)> = args[1..]
+                    .chunks(2)
+                    .map(|pair| (pair[0].clone(), pair[1].clone()))
+                    .collect();
+                let added = store.write().await.hset(&args[0], &pairs)?;
+                Payload::Integer(added as i64).redis_encode()
+            }
+            Command::Hget => {
+                debug!("[PROCESS_COMMAND] - Processing 'Hget' Command");
+                let args = contents.into_args();
+                if args.len() != 2 {
+                    return Err(arity_error(command));
+                }
+                match store.read().await.hget(&args[0], &args[1])? {
+                    Some(value) => Payload::BulkString(value.into_bytes()).redis_encode(),
+                    None => Payload::Null.redis_encode_for(state.protocol),
+                }
+            }
+            Command::Hgetall => {
+                debug!("[PROCESS_COMMAND] - Processing 'Hgetall' Command");
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                let pairs = store.read().await.hgetall(&args[0])?;
+                let pairs = pairs
+                    .into_iter()
+                    .map(|(field, value)| {
+                        (
+                            Payload::BulkString(field.into_bytes()),
+                            Payload::BulkString(value.into_bytes()),
+                        )
+                    })
+                    .collect();
+                Payload::Map(pairs).redis_encode_for(state.protocol)
+            }
+            Command::Hdel => {
+                debug!("[PROCESS_COMMAND] - Processing 'Hdel' Command");
+                let args = contents.into_args();
+                if args.len() < 2 {
+                    return Err(arity_error(command));
+                }
+                let removed = store.write().await.hdel(&args[0], &args[1..])?;
+                Payload::Integer(removed as i64).redis_encode()
+            }
+            Command::Hlen => {
+                debug!("[PROCESS_COMMAND] - Processing 'Hlen' Command");
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                let len = store.read().await.hlen(&args[0])?;
+                Payload::Integer(len as i64).redis_encode()
+            }
+            Command::Hexpire => {
+                debug!("[PROCESS_COMMAND] - Processing 'Hexpire' Command");
+                let args = contents.into_args();
+                if args.len() < 5 {
+                    return Err(arity_error(command));
+                }
+                let seconds = args[1].parse::<i64>().context(NOT_AN_INTEGER)?;
+                if seconds < 0 {
+                    bail!("ERR invalid expire time, must be >= 0")
+                }
+                let (condition, rest) = if args[2].eq_ignore_ascii_case("fields") {
+                    (ExpireCondition::default(), &args[2..])
+                } else {
+                    (ExpireCondition::parse(&args[2..3])?, &args[3..])
+                };
+                let fields = parse_hash_fields(rest)?;
+                let expire_at = seconds
+                    .checked_mul(1000)
+                    .and_then(chrono::Duration::try_milliseconds)
+                    .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+                    .context("ERR invalid expire time in 'hexpire' command")?;
+                let statuses = store
+                    .write()
+                    .await
+                    .hexpire(&args[0], fields, expire_at, condition)?;
+                Payload::Array(statuses.into_iter().map(Payload::Integer).collect()).redis_encode()
+            }
+            Command::Httl => {
+                debug!("[PROCESS_COMMAND] - Processing 'Httl' Command");
+                let args = contents.into_args();
+                if args.len() < 4 {
+                    return Err(arity_error(command));
+                }
+                let fields = parse_hash_fields(&args[1..])?;
+                let ttls = store.read().await.hfield_ttl_ms(&args[0], fields)?;
+                let ttls = ttls
+                    .into_iter()
+                    .map(|ttl_ms| {
+                        Payload::Integer(if ttl_ms >= 0 { ttl_ms / 1000 } else { ttl_ms })
+                    })
+                    .collect();
+                Payload::Array(ttls).redis_encode()
+            }
+            Command::Hpersist => {
+                debug!("[PROCESS_COMMAND] - Processing 'Hpersist' Command");
+                let args = contents.into_args();
+                if args.len() < 4 {
+                    return Err(arity_error(command));
+                }
+                let fields = parse_hash_fields(&args[1..])?;
+                let statuses = store.write().await.hpersist(&args[0], fields)?;
+                Payload::Array(statuses.into_iter().map(Payload::Integer).collect()).redis_encode()
+            }
+            Command::Sadd | Command::Srem => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let args = contents.into_args();
+                if args.len() < 2 {
+                    return Err(arity_error(command));
+                }
+                let mut store = store.write().await;
+                let changed = match command {
+                    Command::Sadd => store.sadd(&args[0], &args[1..])?,
+                    _ => store.srem(&args[0], &args[1..])?,
+                };
+                Payload::Integer(changed as i64).redis_encode()
+            }
+            Command::Smembers => {
+                debug!("[PROCESS_COMMAND] - Processing 'Smembers' Command");
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                let members = store.read().await.smembers(&args[0])?;
+                let members = members
+                    .into_iter()
+                    .map(|member| Payload::BulkString(member.into_bytes()))
+                    .collect();
+                Payload::SetType(members).redis_encode_for(state.protocol)
+            }
+            Command::Sismember => {
+                debug!("[PROCESS_COMMAND] - Processing 'Sismember' Command");
+                let args = contents.into_args();
+                if args.len() != 2 {
+                    return Err(arity_error(command));
+                }
+                let is_member = store.read().await.sismember(&args[0], &args[1])?;
+                Payload::Integer(is_member as i64).redis_encode()
+            }
+            Command::Scard => {
+                debug!("[PROCESS_COMMAND] - Processing 'Scard' Command");
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                let len = store.read().await.scard(&args[0])?;
+                Payload::Integer(len as i64).redis_encode()
+            }
+            Command::Sinter | Command::Sunion | Command::Sdiff => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let args = contents.into_args();
+                if args.is_empty() {
+                    return Err(arity_error(command));
+                }
+                let members = store
+                    .read()
+                    .await
+                    .set_operation(set_operation(command), &args)?;
+                let members = members
+                    .into_iter()
+                    .map(|member| Payload::BulkString(member.into_bytes()))
+                    .collect();
+                Payload::SetType(members).redis_encode_for(state.protocol)
+            }
+            Command::SinterStore | Command::SunionStore | Command::SdiffStore => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let args = contents.into_args();
+                if args.len() < 2 {
+                    return Err(arity_error(command));
+                }
+                let len = store.write().await.set_operation_store(
+                    set_operation(command),
+                    &args[0],
+                    &args[1..],
+                )?;
+                Payload::Integer(len as i64).redis_encode()
+            }
+            Command::Zadd => {
+                debug!("[PROCESS_COMMAND] - Processing 'Zadd' Command");
+                let args = contents.into_args();
+                if args.len() < 3 {
+                    return Err(arity_error(command));
+                }
+                let (options, rest) = ZAddOptions::parse(&args[1..])?;
+                if rest.is_empty() || rest.len() % 2 != 0 {
+                    bail!("ERR syntax error")
+                }
+                if options.incr && rest.len() > 2 {
+                    bail!("ERR INCR option supports a single increment-element pair")
+                }
+                let pairs = rest
+                    .chunks(2)
+                    .map(|pair| Ok((parse_score(&pair[0])?, pair[1].clone())))
+                    .collect::<Result<Vec<_>>>()?;
+                let (count, score) = store.write().await.zadd(&args[0], &pairs, &options)?;
+                match (options.incr, score) {
+                    (false, _) => Payload::Integer(count as i64).redis_encode(),
+                    (true, Some(score)) => {
+                        Payload::BulkString(format_score(score).into_bytes()).redis_encode()
+                    }
+                    (true, None) => Payload::Null.redis_encode_for(state.protocol),
+                }
+            }
+            Command::Zmpop => {
+                debug!("[PROCESS_COMMAND] - Processing 'Zmpop' Command");
+                let args = contents.into_args();
+                if args.len() < 3 {
+                    return Err(arity_error(command));
+                }
+                let (keys, end, count) = parse_multi_pop(&args)?;
+                let end = match end.to_lowercase().as_str() {
+                    "min" => ScoreEnd::Min,
+                    "max" => ScoreEnd::Max,
+                    _ => bail!("ERR syntax error"),
+                };
+                match store.write().await.zmpop(keys, end, count)? {
+                    Some((key, popped)) => {
+                        let popped = popped
+                            .into_iter()
+                            .map(|(member, score)| {
+                                Payload::build_bulk_string_array(vec![member, format_score(score)])
+                            })
+                            .collect();
+                        Payload::Array(vec![
+                            Payload::BulkString(key.into_bytes()),
+                            Payload::Array(popped),
+                        ])
+                        .redis_encode()
+                    }
+                    None => null_array(state.protocol),
+                }
+            }
+            Command::Zunion | Command::Zinter | Command::Zdiff => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let args = contents.into_args();
+                if args.len() < 2 {
+                    return Err(arity_error(command));
+                }
+                let operation = set_operation(command);
+                let args = ZSetOperationArgs::parse(&args, command != Command::Zdiff)?;
+                let result = store.read().await.zset_operation(operation, &args)?;
+                let members = result
+                    .iter()
+                    .map(|(member, score)| (member.to_string(), score))
+                    .collect();
+                sorted_set_range_reply(members, args.with_scores).redis_encode()
+            }
+            Command::ZunionStore | Command::ZinterStore | Command::ZdiffStore => {
+                debug!("[PROCESS_COMMAND] - Processing '{}' Command", command);
+                let args = contents.into_args();
+                if args.len() < 3 {
+                    return Err(arity_error(command));
+                }
+                let operation = set_operation(command);
+                let (destination, args) = (&args[0], &args[1..]);
+                let args = ZSetOperationArgs::parse(args, command != Command::ZdiffStore)?;
+                if args.with_scores {
+                    bail!("ERR syntax error")
+                }
+                let len =
+                    store
+                        .write()
+                        .await
+                        .zset_operation_store(operation, destination, &args)?;
+                Payload::Integer(len as i64).redis_encode()
+            }
+            Command::Zscore => {
+                debug!("[PROCESS_COMMAND] - Processing 'Zscore' Command");
+                let args = contents.into_args();
+                if args.len() != 2 {
+                    return Err(arity_error(command));
+                }
+                match store.read().await.zscore(&args[0], &args[1])? {
+                    Some(score) => {
+                        Payload::BulkString(format_score(score).into_bytes()).redis_encode()
+                    }
+                    None => Payload::Null.redis_encode_for(state.protocol),
+                }
+            }
+            Command::Zrange => {
+                debug!("[PROCESS_COMMAND] - Processing 'Zrange' Command");
+                let args = contents.into_args();
+                if args.len() < 3 {
+                    return Err(arity_error(command));
+                }
+                let with_scores = match &args[3..] {
+                    [] => false,
+                    [option] if option.eq_ignore_ascii_case("withscores") => true,
+                    _ => bail!("ERR syntax error"),
+                };
+                let start = args[1].parse::<i64>().context(NOT_AN_INTEGER)?;
+                let stop = args[2].parse::<i64>().context(NOT_AN_INTEGER)?;
+                let members = store.read().await.zrange(&args[0], start, stop)?;
+                sorted_set_range_reply(members, with_scores).redis_encode()
+            }
+            Command::ZrangeByScore => {
+                debug!("[PROCESS_COMMAND] - Processing 'ZrangeByScore' Command");
+                let args = contents.into_args();
+                if args.len() < 3 {
+                    return Err(arity_error(command));
+                }
+                let (min, max) = (ScoreBound::parse(&args[1])?, ScoreBound::parse(&args[2])?);
+                let options = RangeOptions::parse(&args[3..], true)?;
+                let members = store
+                    .read()
+                    .await
+                    .zrange_by_score(&args[0], min, max, &options)?;
+                sorted_set_range_reply(members, options.with_scores).redis_encode()
+            }
+            Command::ZrangeByLex => {
+                debug!("[PROCESS_COMMAND] - Processing 'ZrangeByLex' Command");
+                let args = contents.into_args();
+                if args.len() < 3 {
+                    return Err(arity_error(command));
+                }
+                let (min, max) = (LexBound::parse(&args[1])?, LexBound::parse(&args[2])?);
+                let options = RangeOptions::parse(&args[3..], false)?;
+                let members = store
+                    .read()
+                    .await
+                    .zrange_by_lex(&args[0], min, max, &options)?;
+                sorted_set_range_reply(members, false).redis_encode()
+            }
+            Command::Zrank => {
+                debug!("[PROCESS_COMMAND] - Processing 'Zrank' Command");
+                let args = contents.into_args();
+                if args.len() != 2 {
+                    return Err(arity_error(command));
+                }
+                match store.read().await.zrank(&args[0], &args[1])? {
+                    Some(rank) => Payload::Integer(rank as i64).redis_encode(),
+                    None => Payload::Null.redis_encode_for(state.protocol),
+                }
+            }
+            Command::Zcard => {
+                debug!("[PROCESS_COMMAND] - Processing 'Zcard' Command");
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                let len = store.read().await.zcard(&args[0])?;
+                Payload::Integer(len as i64).redis_encode()
+            }
+            Command::Keys => {
+                debug!("[PROCESS_COMMAND] - Processing 'Keys' Command");
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                let keys = store.read().await.keys(&args[0]);
+                Payload::build_bulk_string_array(keys).redis_encode()
+            }
+            Command::Scan => {
+                debug!("[PROCESS_COMMAND] - Processing 'Scan' Command");
+                let args = contents.into_args();
+                if args.is_empty() {
+                    return Err(arity_error(command));
+                }
+                let cursor = args[0].parse::<u64>().context("ERR invalid cursor")?;
+                let mut pattern = None;
+                let mut count = 10;
+                let mut options = args[1..].iter();
+                while let Some(option) = options.next() {
+                    let value = options.next().context("ERR syntax error")?;
+                    match option.to_lowercase().as_str() {
+                        "match" => pattern = Some(value.as_str()),
+                        "count" => {
+                            count = value.parse::<usize>().context(NOT_AN_INTEGER)?;
+                            if count == 0 {
+                                bail!("ERR syntax error")
+                            }
+                        }
+                        _ => bail!("ERR syntax error"),
+                    }
+                }
+                let (cursor, keys) = store.read().await.scan(cursor, pattern, count);
+                Payload::Array(vec![
+                    Payload::BulkString(cursor.to_string().into_bytes()),
+                    Payload::build_bulk_string_array(keys),
+                ])
+                .redis_encode()
+            }
+            Command::Mget => {
+                debug!("[PROCESS_COMMAND] - Processing 'Mget' Command");
+                let args = contents.into_args();
+                if args.is_empty() {
+                    return Err(arity_error(command));
+                }
+                let values = store.read().await.mget(&args);
+                let values = values
+                    .into_iter()
+                    .map(|value| value.map_or(Payload::Null, Payload::BulkString))
+                    .collect();
+                Payload::Array(values).redis_encode_for(state.protocol)
+            }
+            Command::Mset => {
+                debug!("[PROCESS_COMMAND] - Processing 'Mset' Command");
+                let Value::Array(args) = contents else {
+                    return Err(arity_error(command));
+                };
+                if args.is_empty() || !args.len().is_multiple_of(2) {
+                    return Err(arity_error(command));
+                }
+                let pairs = args
+                    .chunks(2)
+                    .map(|pair| (pair[0].to_string(), pair[1].to_bytes()))
+                    .collect();
+                store.write().await.mset(pairs);
+                Payload::SimpleString("OK".to_string()).redis_encode()
+            }
+            Command::Append => {
+                debug!("[PROCESS_COMMAND] - Processing 'Append' Command");
+                let Value::Array(args) = contents else {
+                    return Err(arity_error(command));
+                };
+                if args.len() != 2 {
+                    return Err(arity_error(command));
+                }
+                let key = args[0].to_string();
+                let len = store.write().await.append(&key, &args[1].to_bytes())?;
+                Payload::Integer(len as i64).redis_encode()
+            }
+            Command::Strlen => {
+                debug!("[PROCESS_COMMAND] - Processing 'Strlen' Command");
+                let args = contents.into_args();
+                if args.len() != 1 {
+                    return Err(arity_error(command));
+                }
+                let len = store.read().await.strlen(&args[0])?;
+                Payload::Integer(len as i64).redis_encode()
+            }
+            Command::Getrange => {
+                debug!("[PROCESS_COMMAND] - Processing 'Getrange' Command");
+                let args = contents.into_args();
+                if args.len() != 3 {
+                    return Err(arity_error(command));
+                }
+                let start = args[1].parse::<i64>().context(NOT_AN_INTEGER)?;
+                let end = args[2].parse::<i64>().context(NOT_AN_INTEGER)?;
+                let bytes = store.read().await.getrange(&args[0], start, end)?;
+                Payload::BulkString(bytes).redis_encode()
+            }
+            Command::Setrange => {
+                debug!("[PROCESS_COMMAND] - Processing 'Setrange' Command");
+                let Value::Array(args) = contents else {
+                    return Err(arity_error(command));
+                };
+                if args.len() != 3 {
+                    return Err(arity_error(command));
+                }
+                let key = args[0].to_string();
+                let offset = args[1].to_string().parse::<i64>().context(NOT_AN_INTEGER)?;
+                let offset = usize::try_from(offset).context("ERR offset is out of range")?;
+                let len = store
+                    .write()
+                    .await
+                    .setrange(&key, offset, &args[2].to_bytes())?;
+                Payload::Integer(len as i64).redis_encode()
+            }
+            Command::Setbit => {
+                debug!("[PROCESS_COMMAND] - Processing 'Setbit' Command");
+                let args = contents.into_args();
+                if args.len() != 3 {
+                    return Err(arity_error(command));
+                }
+                let offset = parse_bit_offset(&args[1])?;
+                let bit = match args[2].as_str() {
+                    "0" => false,
+                    "1" => true,
+                    _ => bail!("ERR bit is not an integer or out of range"),
+                };
+                let previous = store.write().await.setbit(&args[0], offset, bit)?;
+                Payload::Integer(previous as i64).redis_encode()
+            }
+            Command::Getbit => {
+                debug!("[PROCESS_COMMAND] - Processing 'Getbit' Command");
+                let args = contents.into_args();
+                if args.len() != 2 {
+                    return Err(arity_error(command));
+                }
+                let offset = parse_bit_offset(&args[1])?;
+                let bit = store.read().await.getbit(&args[0], offset)?;
+                Payload::Integer(bit as i64).redis_encode()
+            }
+            Command::Bitcount => {
+                debug!("[PROCESS_COMMAND] - Processing 'Bitcount' Command");
+                let args = contents.into_args();
+                if args.is_empty() {
+                    return Err(arity_error(command));
+                }
+                let (start, end, in_bits) = match &args[1..] {
+                    [] => (0, -1, false),
+                    [start, end, unit @ ..] => {
+                        let in_bits = match unit {
+                            [] => false,
+                            [unit] if unit.eq_ignore_ascii_case("byte") => false,
+                            [unit] if unit.eq_ignore_ascii_case("bit") => true,
+                            _ => bail!("ERR syntax error"),
+                        };
+                        let start = start.parse::<i64>().context(NOT_AN_INTEGER)?;
+                        let end = end.parse::<i64>().context(NOT_AN_INTEGER)?;
+                        (start, end, in_bits)
+                    }
+                    _ => bail!("ERR syntax error"),
+                };
+                let count = store.read().await.bitcount(&args[0], start, end, in_bits)?;
+                Payload::Integer(count as i64).redis_encode()
+            }
+            Command::Wait => {
+                debug!("[PROCESS_COMMAND] - Processing 'Wait' Command");
+                let args = contents.into_args();
+                if args.len() != 2 {
+                    return Err(arity_error(command));
+                }
+                let numreplicas = args[0].parse::<usize>().context(NOT_AN_INTEGER)?;
+                let timeout = args[1].parse::<i64>().context(NOT_AN_INTEGER)?;
+                let timeout = u64::try_from(timeout).context("ERR timeout is negative")?;
+                let acked = self
+                    .wait_for_replicas(numreplicas, Duration::from_millis(timeout))
+                    .await?;
+                Payload::Integer(acked as i64).redis_encode()
+            }
+        };
+        Ok(response)
+    }
+
+    /// Performs the replication handshake with a master, advertising `listening_port` as the
+    /// port this replica accepts connections on. Returns the stream halves along with the
+    /// replication id and offset announced in its `FULLRESYNC` reply, and the master's resolved
+    /// address.
+    pub async fn handshake(
+        addr: &str,
+        listening_port: u16,
+        synced: Option<(&str, usize)>,
+    ) -> Result<(
+        ReadHalf<TcpStream>,
+        WriteHalf<TcpStream>,
+        String,
+        usize,
+        SocketAddr,
+    )> {
+        debug!("[HANDSHAKE] - START.");
+        let payload = Payload::build_bulk_string_array(vec!["ping"]).redis_encode();
+        let listening_port = listening_port.to_string();
+        let listening_port =
+            Payload::build_bulk_string_array(vec!["REPLCONF", "listening-port", &listening_port])
+                .redis_encode();
+        let psync = ClientRole::init_psync(synced);
+
+        debug!("[HANDSHAKE] - Creating messages.");
+        let messages = [
+            payload.as_slice(),
+            listening_port.as_slice(),
+            "*3\r\n$8\r\nREPLCONF\r\n$4\r\ncapa\r\n$6\r\npsync2\r\n".as_bytes(),
+            psync.as_slice(),
+        ];
+        debug!("[HANDSHAKE] - Establishing Stream.");
+        let (mut r, mut w, master_address) = Self::connect_to_master(addr).await?;
+
+        let mut reply = String::new();
+        for msg in messages {
+            w.write_all(msg).await?;
+            reply = read_line(&mut r).await?;
+        }
+        // With `+CONTINUE`, the stream carries on from the offset synced so far.
+        let continued = synced.zip(ClientRole::parse_continue(&reply));
+        let (master_id, master_offset) = match continued {
+            Some(((id, offset), continued_id)) => {
+                (continued_id.unwrap_or_else(|| id.to_string()), offset)
+            }
+            None => ClientRole::parse_fullresync(&reply)
+                .context("Master did not reply to PSYNC with FULLRESYNC or CONTINUE")?,
+        };
+
+        debug!("[HANDSHAKE] - END.");
+        Ok((r, w, master_id, master_offset, master_address))
+    }
+
+    /// Connects to the master again after the replication link closed, and asks to carry on
+    /// from the offset processed so far. The master either sends what was missed, or a full
+    /// resync whose snapshot replaces the dataset, when its replication id changed or it no
+    /// longer has all of it. `reader` is where the old link was read from.
+    pub async fn resync_with_master(&self, reader: &mut ReadHalf<TcpStream>) -> Result<()> {
+        let ClientRole::Slave {
+            master_stream_w,
+            master_address,
+            master_id,
+            master_offset,
+            ..
+        } = &self.role
+        else {
+            bail!("Only a replica resyncs with a master")
+        };
+        let synced_id = self.role.replication_id();
+        let synced = (synced_id.as_str(), master_offset.load(Ordering::SeqCst));
+        let port = self.config.read().await.port;
+        let (r, w, id, offset, _) =
+            Self::handshake(&master_address.to_string(), port, Some(synced)).await?;
+        info!(
+            "Reconnected to the master, from replication id {} and offset {} to {} and {}",
+            synced_id, synced.1, id, offset
+        );
+        *reader = r;
+        *master_stream_w.lock().await = w;
+        *master_id.lock().unwrap() = id;
+        master_offset.store(offset, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Gives the master a new replication id, and closes the link to every replica so that
+    /// each resyncs with it.
+    async fn change_replication_id(&self) -> Result<()> {
+        let ClientRole::Master {
+            replication_id,
+            slave_acks,
+            slave_ports,
+            ..
+        } = &self.role
+        else {
+            bail!("ERR CHANGE-REPL-ID can only be used on a master")
+        };
+        *replication_id.lock().unwrap() = random_replication_id();
+        let replicas: Vec<_> = self.role.slave_connections().lock().await.drain().collect();
+        for (addr, stream) in replicas {
+            if let Err(e) = stream.lock().await.shutdown().await {
+                warn!("[CHANGE_REPL_ID] - Failed to close replica {}: {}", addr, e);
+            }
+            slave_acks.lock().await.remove(&addr);
+            slave_ports.lock().await.remove(&addr);
+        }
+        Ok(())
+    }
+
+    async fn connect_to_master(
+        master_address: &str,
+    ) -> Result<(ReadHalf<TcpStream>, WriteHalf<TcpStream>, SocketAddr)> {
+        debug!("[CONNECT_TO_MASTER] - START..");
+        let timeout_duration = tokio::time::Duration::from_secs(2);
+        let connect_future = TcpStream::connect(master_address);
+        match tokio::time::timeout(timeout_duration, connect_future).await {
+            Ok(Ok(stream)) => {
+                debug!("[CONNECT_TO_MASTER] - Successfully Connected..");
+                debug!("[CONNECT_TO_MASTER] - END..");
+                let peer_address = stream.peer_addr()?;
+                let (r, w) = tokio::io::split(stream);
+                Ok((r, w, peer_address))
+            }
+            Ok(Err(e)) => {
+                warn!(
+                    "[CONNECT_TO_MASTER] - Failed to connect to {}: {}.",
+                    master_address, e
+                );
+                warn!("[CONNECT_TO_MASTER] - Terminating..");
+                bail!(e)
+            }
+            Err(e) => {
+                warn!(
+                    "[CONNECT_TO_MASTER] - Connection attempt to {} timed out: {}.",
+                    master_address, e
+                );
+                warn!("[CONNECT_TO_MASTER] - Terminating..");
+                bail!(e)
+            }
+        }
+    }
+
+    /// Spawns the task actively removing expired keys `hz` times per second, so keys that are
+    /// never read again do not stay in memory.
+    pub fn spawn_active_expiry(self: &Arc<Self>, hz: u32) -> JoinHandle<()> {
+        let client = self.clone();
+        tokio::spawn(async move {
+            let period = Duration::from_millis(1000 / hz.max(1) as u64);
+            let mut interval = tokio::time::interval(period);
+            loop {
+                interval.tick().await;
+                for store in &client.databases {
+                    if let Err(e) = store.write().await.clean_expiries() {
+                        warn!("[ACTIVE_EXPIRY] - Failed cleaning expired keys: {}", e);
+                    }
+                }
+                if let Err(e) = client.propagate_expired().await {
+                    warn!("[ACTIVE_EXPIRY] - Failed propagating expired keys: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Spawns the task acknowledging the offset processed so far to the master once a second,
+    /// which is how the master tells how far behind a replica is. Masters have nothing to
+    /// acknowledge.
+    pub fn spawn_master_acks(self: &Arc<Self>) -> Option<JoinHandle<()>> {
+        if self.role.is_master() {
+            return None;
+        }
+        let client = self.clone();
+        Some(tokio::spawn(async move {
+            let mut interval = tokio::time::interval(Duration::from_secs(1));
+            loop {
+                interval.tick().await;
+                if let Err(e) = client.acknowledge_master().await {
+                    warn!("[MASTER_ACKS] - Failed acknowledging the master: {}", e);
+                }
+            }
+        }))
+    }
+
+    /// Logs and propagates a `DEL` for every key deleted for having expired since the last call.
+    /// Replicas never delete expired keys on their own, so this is what removes them there.
+    async fn propagate_expired(&self) -> Result<()> {
+        for (db, store) in self.databases.iter().enumerate() {
+            let evicted = store.write().await.take_evicted();
+            for key in evicted {
+                let frame = Payload::build_bulk_string_array(vec!["DEL", &key]).redis_encode();
+                self.append_to_aof(db, &frame).await?;
+                self.propagate_write(db, &frame).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Pops an element from the first of `keys` holding a list in database `db`. While they are
+    /// all empty, waits for a push to one of them for as long as `timeout`, or indefinitely if
+    /// it is `None`, returning `None` once it runs out.
+    async fn blocking_pop(
+        &self,
+        db: usize,
+        keys: &[String],
+        end: ListEnd,
+        timeout: Option<Duration>,
+    ) -> Result<Option<(String, String)>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            // Created before looking, so a push landing in between still wakes it.
+            let pushed = self.list_pushed.notified();
+            {
+                let _gate = self.transaction_gate.read().await;
+                if let Some(popped) = self.databases[db].write().await.pop_first(keys, end)? {
+                    return Ok(Some(popped));
+                }
+            }
+            match deadline {
+                Some(deadline) => {
+                    if tokio::time::timeout_at(deadline, pushed).await.is_err() {
+                        return Ok(None);
+                    }
+                }
+                None => pushed.await,
+            }
+        }
+    }
+
+    /// Parses a database index, checking that such a database exists.
+    fn database_index(&self, index: &str) -> Result<usize> {
+        let index = index.parse::<i64>().context(NOT_AN_INTEGER)?;
+        usize::try_from(index)
+            .ok()
+            .filter(|index| *index < self.databases.len())
+            .context("ERR DB index is out of range")
+    }
+
+    /// Locks every database for reading, in index order.
+    async fn read_databases(&self) -> Vec<RwLockReadGuard<'_, KeyValueStore>> {
+        let mut guards = Vec::with_capacity(self.databases.len());
+        for store in &self.databases {
+            guards.push(store.read().await);
+        }
+        guards
+    }
+
+    /// Locks every database for writing, in index order.
+    async fn write_databases(&self) -> Vec<RwLockWriteGuard<'_, KeyValueStore>> {
+        let mut guards = Vec::with_capacity(self.databases.len());
+        for store in &self.databases {
+            guards.push(store.write().await);
+        }
+        guards
+    }
+
+    /// Locks two different databases for writing, in index order, and returns the guards of
+    /// `source` and `destination` in that order.
+    async fn write_pair(
+        &self,
+        source: usize,
+        destination: usize,
+    ) -> (
+        RwLockWriteGuard<'_, KeyValueStore>,
+        RwLockWriteGuard<'_, KeyValueStore>,
+    ) {
+        if source < destination {
+            let source = self.databases[source].write().await;
+            (source, self.databases[destination].write().await)
+        } else {
+            let destination = self.databases[destination].write().await;
+            (self.databases[source].write().await, destination)
+        }
+    }
+
+    /// Renders the `INFO replication` section. A master lists each replica with the port it
+    /// announced during the handshake, the offset it last acknowledged, and its lag: the
+    /// seconds since that acknowledgement.
+    async fn replication_info(&self) -> String {
+        let ClientRole::Master {
+            replication_offset,
+            slave_acks,
+            slave_ports,
+            ..
+        } = &self.role
+        else {
+            return self.role.to_string();
+        };
+        let mut replicas = self
+            .role
+            .slave_connections()
+            .lock()
+            .await
+            .keys()
+            .filter_map(|replica| replica.parse::<SocketAddr>().ok())
+            .collect::<Vec<_>>();
+        replicas.sort();
+        let ports = slave_ports.lock().await.clone();
+        let acks = slave_acks.lock().await.clone();
+
+        let mut lines = vec![
+            "role:master".to_string(),
+            format!("connected_slaves:{}", replicas.len()),
+        ];
+        for (i, replica) in replicas.iter().enumerate() {
+            let key = replica.to_string();
+            let (offset, lag) = acks
+                .get(&key)
+                .map(|(offset, acked_at)| (*offset, acked_at.elapsed().as_secs()))
+                .unwrap_or_default();
+            lines.push(format!(
+                "slave{}:ip={},port={},state=online,offset={},lag={}",
+                i,
+                replica.ip(),
+                ports.get(&key).copied().unwrap_or_default(),
+                offset,
+                lag
+            ));
+        }
+        lines.push(format!("master_replid:{}", self.role.replication_id()));
+        lines.push(format!(
+            "master_repl_offset:{}",
+            replication_offset.load(Ordering::SeqCst)
+        ));
+        lines.join("\n")
+    }
+
+    /// Renders the `INFO stats` section, counting the keyspace hits and misses of every
+    /// database.
+    async fn keyspace_stats(&self) -> String {
+        let (mut hits, mut misses) = (0, 0);
+        for store in &self.databases {
+            let (store_hits, store_misses) = store.read().await.keyspace_stats();
+            hits += store_hits;
+            misses += store_misses;
+        }
+        format!("keyspace_hits:{}\nkeyspace_misses:{}", hits, misses)
+    }
+
+    /// Renders the `INFO keyspace` section, with a line for every database holding keys.
+    async fn keyspace_info(&self) -> String {
+        let mut lines = vec![];
+        for (db, store) in self.databases.iter().enumerate() {
+            lines.extend(store.read().await.keyspace(db));
+        }
+        lines.join("\n")
+    }
+
+    /// Replaces the contents of every database with the keys of an RDB snapshot.
+    async fn load_rdb(&self, snapshot: &[u8]) -> Result<()> {
+        let entries = rdb::decode(snapshot)?;
+        if let Some(entry) = entries
+            .iter()
+            .find(|entry| entry.db >= self.databases.len())
+        {
+            bail!(
+                "The RDB snapshot holds database {}, but only {} are configured",
+                entry.db,
+                self.databases.len()
+            );
+        }
+        let mut databases = self.write_databases().await;
+        for store in databases.iter_mut() {
+            store.flush();
+        }
+        for entry in entries {
+            databases[entry.db].restore(entry.key, entry.value, entry.expire_at);
+        }
+        Ok(())
+    }
+
+    /// Loads the databases from the RDB file at `path`, leaving them empty if there is none.
+    async fn load_rdb_file(&self, path: &Path) -> Result<()> {
+        match tokio::fs::read(path).await {
+            Ok(rdb) => {
+                self.load_rdb(&rdb)
+                    .await
+                    .with_context(|| format!("Failed to load {}", path.display()))?;
+                info!("Loaded {}", path.display());
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        }
+        Ok(())
+    }
+
+    /// Propagates a write that ran against database `db`, first switching replicas over to it
+    /// with `SELECT` when the previous write propagated ran against another one.
+    pub async fn propagate_write(&self, db: usize, frame: &[u8]) -> Result<()> {
+        let mut propagated_db = self.propagated_db.lock().await;
+        if *propagated_db != Some(db) {
+            self.propagate(&select_frame(db)).await?;
+            *propagated_db = Some(db);
+        }
+        self.propagate(frame).await
+    }
+
+    /// Writes `message` to every connected replica. A slave uses this to forward the command
+    /// stream it receives from its own master to its sub-replicas.
+    pub async fn propagate(&self, message: &[u8]) -> Result<()> {
+        debug!("[PROPAGATE] - START");
+        if let ClientRole::Master {
+            replication_offset,
+            backlog,
+            ..
+        } = &self.role
+        {
+            let mut backlog = backlog.lock().await;
+            replication_offset.fetch_add(message.len(), Ordering::SeqCst);
+            backlog.append(message);
+        }
+        let connections = self.role.slave_connections().lock().await;
+
+        let futures: Vec<_> = connections
+            .values()
+            .map(|stream| async move {
+                let mut stream = stream.lock().await;
+                debug!("[PROPAGATE] - Writing to Stream.");
+                stream.write_all(message).await
+            })
+            .collect();
+
+        debug!("[PROPAGATE] - Joining and awaiting all connection propagations.");
+        futures::future::join_all(futures).await;
+        debug!("[PROPAGATE] - END");
+        Ok(())
+    }
+    /// Applies a command a replica received from its master. Nothing is replied, except for
+    /// the acknowledgement `REPLCONF GETACK` asks for.
+    pub async fn apply_from_master(&self, command: Command, contents: Value) -> Result<()> {
+        let ClientRole::Slave {
+            master_stream_w,
+            master_address,
+            master_state,
+            ..
+        } = &self.role
+        else {
+            bail!("Only a replica applies commands from a master")
+        };
+        let is_getack = match &contents {
+            Value::Array(args) => args
+                .first()
+                .is_some_and(|arg| arg.to_string().eq_ignore_ascii_case("getack")),
+            _ => false,
+        };
+        if matches!(command, Command::ReplConf) && is_getack {
+            return self.acknowledge_master().await;
+        }
+        let mut state = master_state.lock().await;
+        self.process_command(
+            command,
+            contents,
+            Some(master_stream_w.clone()),
+            master_address,
+            &mut state,
+            false,
+        )
+        .await
+    }
+
+    /// Sends `message` to every subscriber of `channel`, directly or through a matching
+    /// pattern, returning how many deliveries were made. Subscribers whose connection can no
+    /// longer be written to are dropped.
+    async fn publish(&self, channel: &str, message: &str) -> usize {
+        let deliveries: Vec<(SocketAddr, Subscriber, Payload)> = {
+            let direct = self
+                .pubsub
+                .subscribers(channel)
+                .into_iter()
+                .map(|(addr, subscriber)| {
+                    let message = ["message", channel, message]
+                        .map(|s| Payload::BulkString(s.as_bytes().to_vec()));
+                    (addr, subscriber, Payload::Push(message.into()))
+                });
+            let matched = self.pubsub.pattern_subscribers(channel).into_iter().map(
+                |(pattern, addr, subscriber)| {
+                    let message = ["pmessage", &pattern, channel, message]
+                        .map(|s| Payload::BulkString(s.as_bytes().to_vec()));
+                    (addr, subscriber, Payload::Push(message.into()))
+                },
+            );
+            direct.chain(matched).collect()
+        };
+
+        let mut receivers = 0;
+        for (addr, subscriber, message) in deliveries {
+            let message = message.redis_encode_for(subscriber.protocol);
+            match subscriber.stream.lock().await.write_all(&message).await {
+                Ok(()) => receivers += 1,
+                Err(e) => {
+                    warn!("[PUBLISH] - Dropping subscriber {}: {}", addr, e);
+                    self.remove_subscriber(&addr).await;
+                }
+            }
+        }
+        receivers
+    }
+
+    /// Registers a connection accepted from `addr`, returning the id `CLIENT ID` reports for it.
+    pub async fn register_connection(&self, addr: SocketAddr) -> u64 {
+        self.connections.write().await.register(addr)
+    }
+
+    /// Forgets the replica at `addr`, if it is one, for when its connection closes.
+    pub async fn remove_replica(&self, addr: &SocketAddr) {
+        let addr = addr.to_string();
+        if self
+            .role
+            .slave_connections()
+            .lock()
+            .await
+            .remove(&addr)
+            .is_none()
+        {
+            return;
+        }
+        if let ClientRole::Master {
+            slave_acks,
+            slave_ports,
+            ..
+        } = &self.role
+        {
+            slave_acks.lock().await.remove(&addr);
+            slave_ports.lock().await.remove(&addr);
+        }
+    }
+
+    pub async fn unregister_connection(&self, id: u64) {
+        self.connections.write().await.unregister(id);
+        self.tracking.write().await.disable(id);
+    }
+
+    /// Tells the connections tracking `keys` that `command`, sent by the connection `writer`,
+    /// modified them. The commands replacing whole databases invalidate every key instead.
+    async fn invalidate(&self, command: Command, keys: &[&String], writer: u64) {
+        let deliveries: Vec<(TrackingClient, Option<Vec<String>>)> = {
+            let mut tracking = self.tracking.write().await;
+            if matches!(
+                command,
+                Command::FlushDb | Command::FlushAll | Command::SwapDb
+            ) {
+                let clients = tracking.invalidate_all().into_iter();
+                clients.map(|client| (client, None)).collect()
+            } else {
+                let invalidated = tracking.invalidate(keys.iter().copied(), writer);
+                invalidated
+                    .into_iter()
+                    .map(|(client, keys)| (client, Some(keys)))
+                    .collect()
+            }
+        };
+
+        for (client, keys) in deliveries {
+            // A flush is told with a null instead of the keys.
+            let keys = match keys {
+                Some(keys) => Payload::build_bulk_string_array(keys),
+                None => Payload::Null,
+            };
+            let Some(redirect) = client.redirect else {
+                // RESP2 has no way to tell a message from a reply on the same connection.
+                if client.protocol == ProtocolVersion::Resp3 {
+                    let message =
+                        Payload::Push(vec![Payload::BulkString(b"invalidate".to_vec()), keys]);
+                    let message = message.redis_encode_for(client.protocol);
+                    if let Err(e) = client.stream.lock().await.write_all(&message).await {
+                        warn!("[INVALIDATE] - Failed to notify a tracking client: {}", e);
+                    }
+                }
+                continue;
+            };
+            let Some(target) = self.connections.read().await.addr(redirect) else {
+                continue;
+            };
+            let subscriber = self
+                .pubsub
+                .subscribers(INVALIDATE_CHANNEL)
+                .into_iter()
+                .find_map(|(addr, subscriber)| (addr == target).then_some(subscriber));
+            if let Some(subscriber) = subscriber {
+                let message = Payload::Push(vec![
+                    Payload::BulkString(b"message".to_vec()),
+                    Payload::BulkString(INVALIDATE_CHANNEL.as_bytes().to_vec()),
+                    keys,
+                ]);
+                let message = message.redis_encode_for(subscriber.protocol);
+                if let Err(e) = subscriber.stream.lock().await.write_all(&message).await {
+                    warn!("[INVALIDATE] - Failed to notify {}: {}", target, e);
+                }
+            }
+        }
+    }
+
+    /// Removes the connection at `addr` from every channel and pattern, for when it goes away.
+    pub async fn remove_subscriber(&self, addr: &SocketAddr) {
+        self.pubsub.remove_subscriber(addr);
+    }
+
+    /// Resolves once `SHUTDOWN` asks for the server to stop.
+    pub async fn shutdown_requested(&self) {
+        self.shutdown.notified().await
+    }
+
+    /// Flushes the AOF to disk and writes a final RDB snapshot, so that no acknowledged write
+    /// is lost once the process exits. Without `save` saying otherwise, the snapshot is only
+    /// written when persistence was configured.
+    pub async fn prepare_shutdown(&self, save: Option<bool>) -> Result<()> {
+        if let Some(aof) = self.aof.lock().await.as_mut() {
+            aof.sync().await?;
+        }
+        if save.unwrap_or(self.config.read().await.persistent) {
+            self.save().await?;
+        }
+        Ok(())
+    }
+
+    /// Writes an RDB snapshot of the store to the file configured with `dir` and `dbfilename`.
+    pub async fn save(&self) -> Result<()> {
+        self.start_save().await?.await
+    }
+
+    /// Takes an RDB snapshot of the store and returns the write of it to disk, so that
+    /// `BGSAVE` can reply before the file is written. Fails if another save is still running.
+    async fn start_save(&self) -> Result<impl Future<Output = Result<()>>> {
+        if self.saving.swap(true, Ordering::SeqCst) {
+            bail!("ERR Background save already in progress");
+        }
+        let snapshot = dump_rdb(&self.read_databases().await);
+        let path = self.config.read().await.rdb_path();
+        let saving = self.saving.clone();
+        Ok(async move {
+            let written = write_rdb_file(&path, &snapshot).await;
+            saving.store(false, Ordering::SeqCst);
+            written
+        })
+    }
+
+    /// Replaces the contents of the store with the RDB snapshot a master sent after
+    /// `FULLRESYNC`.
+    pub async fn load_snapshot(&self, snapshot: &[u8]) -> Result<()> {
+        self.load_rdb(snapshot).await?;
+        if let ClientRole::Slave { master_state, .. } = &self.role {
+            *master_state.lock().await = ConnectionState::default();
+        }
+        // None of the logged commands led to the master's dataset, so the AOF starts over from
+        // it.
+        let path = self.config.read().await.aof_path();
+        let mut aof = self.aof.lock().await;
+        if aof.is_some() {
+            *aof = Some(AppendOnlyFile::create(&path, snapshot).await?);
+        }
+        Ok(())
+    }
+
+    /// Starts logging writes to a new AOF at `path`, beginning with a snapshot of the current
+    /// dataset, or stops logging them when `path` is `None`.
+    async fn set_aof(&self, path: Option<PathBuf>) -> Result<()> {
+        let mut aof = self.aof.lock().await;
+        *aof = match path {
+            Some(path) => {
+                let snapshot = dump_rdb(&self.read_databases().await);
+                Some(AppendOnlyFile::create(&path, &snapshot).await?)
+            }
+            None => None,
+        };
+        Ok(())
+    }
+
+    /// Sends `frame` to the replicas of a master, and logs it to the AOF while it is on, as a
+    /// write to database `db` would be.
+    async fn replicate(&self, db: usize, frame: &[u8]) -> Result<()> {
+        if self.role.is_master() {
+            self.propagate_write(db, frame).await?;
+        }
+        if self.config.read().await.appendonly {
+            self.append_to_aof(db, frame).await?;
+        }
+        Ok(())
+    }
+
+    async fn append_to_aof(&self, db: usize, frame: &[u8]) -> Result<()> {
+        let fsync = self.config.read().await.appendfsync;
+        if let Some(aof) = self.aof.lock().await.as_mut() {
+            aof.append(db, frame, fsync).await?;
+        }
+        Ok(())
+    }
+
+    /// Rebuilds the store from the AOF at `path`, by loading its snapshot and running every
+    /// command logged after it as though a client without a connection sent them.
+    pub async fn replay_aof(&self, path: &Path) -> Result<()> {
+        let contents = match tokio::fs::read(path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e).with_context(|| format!("Failed to read {}", path.display())),
+        };
+        let (preamble, commands) = aof::split(&contents)?;
+        if let Some(preamble) = preamble {
+            self.load_rdb(preamble).await?;
+        }
+        let addr = SocketAddr::from(([0, 0, 0, 0], 0));
+        let mut state = ConnectionState::default();
+        let count = commands.len();
+        for payload in commands {
+            let (command, contents) = payload.retrieve_content()?;
+            let command = command.context("Unknown command in the AOF")?;
+            self.process_command(command, contents, None, &addr, &mut state, false)
+                .await
+                .with_context(|| format!("Failed to replay '{}' from the AOF", command))?;
+        }
+        info!("Replayed {} commands from {}", count, path.display());
+        Ok(())
+    }
+
+    /// Acknowledges the offset of the command stream processed so far to the master, as a reply
+    /// to its `REPLCONF GETACK` or unprompted once a second.
+    pub async fn acknowledge_master(&self) -> Result<()> {
+        let ClientRole::Slave {
+            master_stream_w,
+            master_offset,
+            ..
+        } = &self.role
+        else {
+            bail!("ERR GETACK can only be answered by a replica")
+        };
+        let offset = master_offset.load(Ordering::SeqCst).to_string();
+        let ack = Payload::build_bulk_string_array(vec!["REPLCONF", "ACK", &offset]);
+        master_stream_w
+            .lock()
+            .await
+            .write_all(&ack.redis_encode())
+            .await?;
+        Ok(())
+    }
+
+    /// Waits until at least `numreplicas` replicas have acknowledged every write propagated so
+    /// far, or until `timeout` runs out, and returns how many have. A zero `timeout` waits
+    /// indefinitely. Replicas are asked for an acknowledgement with `REPLCONF GETACK *` unless
+    /// enough of them are already up to date.
+    pub async fn wait_for_replicas(&self, numreplicas: usize, timeout: Duration) -> Result<usize> {
+        let ClientRole::Master {
+            replication_offset,
+            slave_acks,
+            ack_notify,
+            ..
+        } = &self.role
+        else {
+            bail!("ERR WAIT cannot be used with replica instances")
+        };
+        let target = replication_offset.load(Ordering::SeqCst);
+        let up_to_date = || async {
+            let acks = slave_acks.lock().await;
+            acks.values()
+                .filter(|(offset, _)| *offset >= target)
+                .count()
+        };
+
+        let acked = up_to_date().await;
+        if acked >= numreplicas {
+            return Ok(acked);
+        }
+        let getack = Payload::build_bulk_string_array(vec!["REPLCONF", "GETACK", "*"]);
+        self.propagate(&getack.redis_encode()).await?;
+
+        let deadline = Instant::now() + timeout;
+        loop {
+            // Created before counting, so an acknowledgement arriving in between still wakes it.
+            let notified = ack_notify.notified();
+            let acked = up_to_date().await;
+            if acked >= numreplicas {
+                return Ok(acked);
+            }
+            if timeout.is_zero() {
+                notified.await;
+            } else if tokio::time::timeout_at(deadline, notified).await.is_err() {
+                return Ok(up_to_date().await);
+            }
+        }
+    }
+
+    /// Applies a `SET` given the arguments following the command name, returning whether the
+    /// value was stored, which it is not when an `NX` or `XX` condition fails.
+    pub async fn process_set(&self, db: usize, args: &[Payload]) -> Result<bool> {
+        if args.len() < 2 {
+            return Err(arity_error(Command::Set));
+        }
+        let key = args[0].to_string();
+        let value = RedisType::String(args[1].to_bytes());
+        let options: Vec<String> = args[2..].iter().map(Payload::to_string).collect();
+        let options = SetOptions::parse(&options)?;
+        self.databases[db].write().await.set(&key, value, options)
+    }
+}
+
+pub(crate) type ClientWrite = Arc<Mutex<WriteHalf<TcpStream>>>;
+
+/// Reads a single reply line from the master one byte at a time, so that nothing following it,
+/// like the RDB snapshot sent right after `FULLRESYNC`, is consumed from the stream.
+async fn read_line(reader: &mut ReadHalf<TcpStream>) -> Result<String> {
+    let mut line = Vec::new();
+    while !line.ends_with(DELIMITER.as_bytes()) {
+        let byte = reader.read_u8().await?;
+        line.push(byte);
+    }
+    Ok(String::from_utf8_lossy(&line).to_string())
+}
+
+/// State scoped to a single client connection, owned by its connection handler.
+#[derive(Debug, Default)]
+pub struct ConnectionState {
+    /// The id the connection was registered with, or 0 for commands not sent by a client.
+    pub id: u64,
+    /// Whether the client enabled client-side caching with `CLIENT TRACKING on`.
+    pub tracking: bool,
+    /// The protocol version negotiated with `HELLO`.
+    pub protocol: ProtocolVersion,
+    /// The channels the connection subscribed to with `SUBSCRIBE`.
+    pub channels: HashSet<String>,
+    /// The patterns the connection subscribed to with `PSUBSCRIBE`.
+    pub patterns: HashSet<String>,
+    /// The transaction opened with `MULTI`, if any.
+    pub transaction: Option<Transaction>,
+    /// Index of the database selected with `SELECT`.
+    pub db: usize,
+    /// Set while `EXEC` runs the queued commands, which must not block.
+    pub executing: bool,
+}
+
+/// The commands a connection queued after `MULTI`, waiting for `EXEC`.
+#[derive(Debug, Default)]
+pub struct Transaction {
+    queued: Vec<(Command, Value)>,
+    /// Set when a command failed to queue, which makes `EXEC` discard the transaction.
+    aborted: bool,
+}
+
+impl ConnectionState {
+    /// Whether `command` is to be queued rather than run, because a transaction is open.
+    pub fn queues(&self, command: Command) -> bool {
+        self.transaction.is_some()
+            && !matches!(
+                command,
+                Command::Multi | Command::Exec | Command::Discard | Command::Reset
+            )
+    }
+
+    /// Queues `command` in the open transaction, replying `QUEUED`. A command called with the
+    /// wrong number of arguments is rejected and aborts the transaction.
+    pub fn queue(&mut self, command: Command, contents: Value) -> Result<Vec<u8>> {
+        let arguments = match &contents {
+            Value::Array(x) => x.len(),
+            Value::String(_) => 1,
+            Value::Empty => 0,
+        } as i64
+            + 1;
+        let arity = command.arity();
+        if (arity >= 0 && arguments != arity) || arguments < -arity {
+            self.abort_transaction();
+            return Err(arity_error(command));
+        }
+        if let Some(transaction) = self.transaction.as_mut() {
+            transaction.queued.push((command, contents));
+        }
+        Ok(Payload::SimpleString("QUEUED".to_string()).redis_encode())
+    }
+
+    /// Marks the open transaction, if any, so that `EXEC` discards it.
+    pub fn abort_transaction(&mut self) {
+        if let Some(transaction) = self.transaction.as_mut() {
+            transaction.aborted = true;
+        }
+    }
+
+    /// How many channels and patterns the connection is subscribed to. While there are any, the
+    /// connection only accepts pub-sub commands and `PING`.
+    pub fn subscriptions(&self) -> usize {
+        self.channels.len() + self.patterns.len()
+    }
+}
+
+/// Builds the confirmation `SUBSCRIBE` and `UNSUBSCRIBE` send for each channel, carrying the
+/// number of channels the connection is still subscribed to.
+fn subscription_reply(
+    kind: &str,
+    channel: Option<String>,
+    count: usize,
+    protocol: ProtocolVersion,
+) -> Vec<u8> {
+    let channel = channel.map_or(Payload::Null, |channel| {
+        Payload::BulkString(channel.into_bytes())
+    });
+    Payload::Push(vec![
+        Payload::BulkString(kind.as_bytes().to_vec()),
+        channel,
+        Payload::Integer(count as i64),
+    ])
+    .redis_encode_for(protocol)
+}
+
+/// Renders the `INFO server` section.
+fn server_info() -> String {
+    format!(
+        "redis_version:{}\nredis_mode:standalone\nprocess_id:{}",
+        env!("CARGO_PKG_VERSION"),
+        std::process::id()
+    )
+}
+
+/// Encodes the `SELECT` switching the receiving end of a command stream to database `db`.
+pub(crate) fn select_frame(db: usize) -> Vec<u8> {
+    Payload::build_bulk_string_array(vec!["SELECT".to_string(), db.to_string()]).redis_encode()
+}
+
+/// Re-encodes a command for propagation to replicas, for when the frame the client sent is no
+/// longer at hand.
+fn command_frame(command: Command, contents: &Value) -> Vec<u8> {
+    let mut frame = vec![Payload::BulkString(command.to_string().into_bytes())];
+    match contents {
+        Value::Array(x) => frame.extend(x.iter().cloned()),
+        Value::String(s) => frame.push(Payload::BulkString(s.clone().into_bytes())),
+        Value::Empty => {}
+    }
+    Payload::Array(frame).redis_encode()
+}
+
+fn arity_error(command: Command) -> anyhow::Error {
+    anyhow!(
+        "ERR wrong number of arguments for '{}' command",
+        command.to_string().to_lowercase()
+    )
+}
+
+/// The end of the list a list command operates on: the left for `LPUSH` and `LPOP`, the
+/// right otherwise.
+fn list_end(command: Command) -> ListEnd {
+    match command {
+        Command::Lpush | Command::Lpop => ListEnd::Left,
+        _ => ListEnd::Right,
+    }
+}
+
+/// Parses the `LEFT` or `RIGHT` argument of `LMPOP` and `LMOVE`.
+fn parse_list_end(end: &str) -> Result<ListEnd> {
+    match end.to_lowercase().as_str() {
+        "left" => Ok(ListEnd::Left),
+        "right" => Ok(ListEnd::Right),
+        _ => bail!("ERR syntax error"),
+    }
+}
+
+/// Stores the value and expiry `COPY` read from its source at `key` in `destination`, unless
+/// the source is missing or `key` exists and `replace` is not set. Returns whether it did.
+fn copy_entry(
+    entry: Option<(RedisType, Option<DateTime<Utc>>)>,
+    destination: &mut KeyValueStore,
+    key: &str,
+    replace: bool,
+) -> bool {
+    match entry {
+        Some((value, expire_at)) if replace || destination.exists(&[key.to_string()]) == 0 => {
+            destination.restore(key.to_string(), value, expire_at);
+            true
+        }
+        _ => false,
+    }
+}
+
+/// Parses the bit offset of `SETBIT` and `GETBIT`, which may not be negative.
+fn parse_bit_offset(offset: &str) -> Result<u64> {
+    offset
+        .parse::<u64>()
+        .context("ERR bit offset is not an integer or out of range")
+}
+
+/// Encodes a null array reply. RESP2 has a distinct null array, which RESP3 folds into its
+/// single null.
+fn null_array(protocol: ProtocolVersion) -> Vec<u8> {
+    match protocol {
+        ProtocolVersion::Resp2 => format!("*-1{}", DELIMITER).into_bytes(),
+        ProtocolVersion::Resp3 => Payload::Null.redis_encode_for(protocol),
+    }
+}
+
+/// Parses the `numkeys key [key ...] end [COUNT count]` arguments of `LMPOP` and `ZMPOP`,
+/// returning the keys, the end to pop from, still to be interpreted, and the count.
+fn parse_multi_pop(args: &[String]) -> Result<(&[String], &str, usize)> {
+    let numkeys = args[0]
+        .parse::<usize>()
+        .ok()
+        .filter(|numkeys| *numkeys > 0)
+        .context("ERR numkeys should be greater than 0")?;
+    let Some((keys, rest)) = args[1..].split_at_checked(numkeys) else {
+        bail!("ERR syntax error")
+    };
+    match rest {
+        [end] => Ok((keys, end, 1)),
+        [end, option, count] if option.eq_ignore_ascii_case("count") => {
+            let count = count
+                .parse::<usize>()
+                .ok()
+                .filter(|count| *count > 0)
+                .context("ERR count should be greater than 0")?;
+            Ok((keys, end, count))
+        }
+        _ => bail!("ERR syntax error"),
+    }
+}
+
+/// Parses the `FIELDS numfields field [field ...]` arguments the hash field expiry commands end
+/// with, returning the fields.
+fn parse_hash_fields(args: &[String]) -> Result<&[String]> {
+    match args {
+        [keyword, numfields, fields @ ..] if keyword.eq_ignore_ascii_case("fields") => {
+            let numfields = numfields
+                .parse::<usize>()
+                .ok()
+                .filter(|numfields| *numfields > 0)
+                .context("ERR Parameter `numFields` should be greater than 0")?;
+            if numfields != fields.len() {
+                bail!("ERR The `numfields` parameter must match the number of arguments")
+            }
+            Ok(fields)
+        }
+        _ => bail!("ERR Mandatory argument FIELDS is missing or not at the right position"),
+    }
+}
+
+/// The way a set algebra command combines its sets, with or without `STORE`.
+fn set_operation(command: Command) -> SetOperation {
+    match command {
+        Command::Sinter | Command::SinterStore | Command::Zinter | Command::ZinterStore => {
+            SetOperation::Intersection
+        }
+        Command::Sunion | Command::SunionStore | Command::Zunion | Command::ZunionStore => {
+            SetOperation::Union
+        }
+        _ => SetOperation::Difference,
+    }
+}
+
+/// Builds the array a sorted set range replies with, with each member followed by its score
+/// when `with_scores` is set.
+fn sorted_set_range_reply(members: Vec<(String, f64)>, with_scores: bool) -> Payload {
+    let reply = members
+        .into_iter()
+        .flat_map(|(member, score)| {
+            let score = with_scores.then(|| format_score(score));
+            std::iter::once(member).chain(score)
+        })
+        .collect();
+    Payload::build_bulk_string_array(reply)
+}
+
+/// The options of `CLIENT TRACKING on`.
+#[derive(Debug, Default, PartialEq)]
+struct TrackingOptions {
+    redirect: Option<u64>,
+    noloop: bool,
+    bcast: bool,
+    prefixes: Vec<String>,
+}
+
+/// Parses the arguments of
+/// `CLIENT TRACKING on|off [REDIRECT id] [BCAST] [PREFIX prefix ...] [NOLOOP]`, returning the
+/// options tracking gets enabled with, or `None` when it gets disabled.
+///
+/// The default and broadcasting modes are supported, while the opt-in and opt-out ones, which
+/// need `CLIENT CACHING`, are rejected.
+fn parse_client_tracking(args: &[String]) -> Result<Option<TrackingOptions>> {
+    let mut args = args.iter();
+    let enabled = match args.next().map(|s| s.to_lowercase()).as_deref() {
+        Some("on") => true,
+        Some("off") => false,
+        _ => bail!("ERR syntax error"),
+    };
+    let mut options = TrackingOptions::default();
+    while let Some(option) = args.next() {
+        match option.to_lowercase().as_str() {
+            "redirect" => {
+                let id = args
+                    .next()
+                    .and_then(|id| id.parse::<u64>().ok())
+                    .context("ERR value is not an integer or out of range")?;
+                options.redirect = Some(id);
+            }
+            "noloop" => options.noloop = true,
+            "bcast" => options.bcast = true,
+            "prefix" => {
+                let prefix = args.next().context("ERR syntax error")?;
+                options.prefixes.push(prefix.clone());
+            }
+            "optin" | "optout" => {
+                bail!(
+                    "ERR CLIENT TRACKING {} is not supported",
+                    option.to_uppercase()
+                )
+            }
+            _ => bail!("ERR syntax error"),
+        }
+    }
+    if !options.prefixes.is_empty() && !options.bcast {
+        bail!("ERR PREFIX option requires BCAST mode to be enabled");
+    }
+    Ok(enabled.then_some(options))
+}
+
+#[derive(Clone)]
+pub enum ClientRole {
+    Master {
+        /// Changed with `DEBUG CHANGE-REPL-ID`, which has every replica resync.
+        replication_id: Arc<std::sync::Mutex<String>>,
+        /// Bytes of the command stream propagated to replicas so far.
+        replication_offset: Arc<AtomicUsize>,
+        /// The end of the command stream, for replicas that reconnect to carry on from.
+        /// Locked while the offset is advanced, so the two never disagree.
+        backlog: Arc<Mutex<Backlog>>,
+        slave_connections: Arc<Mutex<HashMap<String, ClientWrite>>>,
+        /// The offset each replica last acknowledged with `REPLCONF ACK` and when, keyed like
+        /// `slave_connections`.
+        slave_acks: Arc<Mutex<HashMap<String, (usize, Instant)>>>,
+        /// Woken whenever a replica acknowledges an offset.
+        ack_notify: Arc<Notify>,
+        /// The port each replica announced with `REPLCONF listening-port`, keyed like
+        /// `slave_connections`.
+        slave_ports: Arc<Mutex<HashMap<String, u16>>>,
+    },
+    Slave {
+        master_stream_w: ClientWrite,
+        master_stream_r: Arc<Mutex<ReadHalf<TcpStream>>>,
+        master_address: SocketAddr,
+        /// The replication id the master sent with its last `FULLRESYNC`.
+        master_id: Arc<std::sync::Mutex<String>>,
+        /// The master's offset at the time of the sync, plus every byte of the command stream
+        /// processed since.
+        master_offset: Arc<AtomicUsize>,
+        /// The state of the master's command stream, as for any other connection: the database
+        /// it last selected, and the transaction it has open.
+        master_state: Arc<Mutex<ConnectionState>>,
+        slave_connections: Arc<Mutex<HashMap<String, ClientWrite>>>,
+    },
+}
+
+impl ClientRole {
+    pub fn new_master() -> Self {
         Self::Master {
             slave_connections: Arc::new(Mutex::new(HashMap::new())),
-            replication_id: String::from_utf8_lossy(&DEFAULT_ID).to_string(),
-            replication_offset: 0,
+            replication_id: Arc::new(std::sync::Mutex::new(
+                String::from_utf8_lossy(&DEFAULT_ID).to_string(),
+            )),
+            replication_offset: Arc::new(AtomicUsize::new(0)),
+            backlog: Arc::new(Mutex::new(Backlog::new(BACKLOG_SIZE))),
+            slave_acks: Arc::new(Mutex::new(HashMap::new())),
+            ack_notify: Arc::new(Notify::new()),
+            slave_ports: Arc::new(Mutex::new(HashMap::new())),
         }
     }
-    pub fn init_psync() -> String {
+    /// Builds the `PSYNC` a replica starts replicating with: asking to carry on from `synced`,
+    /// the master's replication id and the offset processed so far, when it synced before.
+    pub fn init_psync(synced: Option<(&str, usize)>) -> Vec<u8> {
         debug!("[PSYNC] - Creating psync payload.");
-        Payload::build_bulk_string_array(vec!["PSYNC", "?", "-1"]).redis_encode()
+        let (id, offset) = match synced {
+            Some((id, offset)) => (id.to_string(), (offset + 1).to_string()),
+            None => ("?".to_string(), "-1".to_string()),
+        };
+        Payload::build_bulk_string_array(vec!["PSYNC", &id, &offset]).redis_encode()
     }
 
-    pub fn psync(&self) -> String {
+    /// The id of the replication stream: a master's own, or the one a replica got from its
+    /// master.
+    pub fn replication_id(&self) -> String {
         match self {
+            Self::Master { replication_id, .. } => replication_id.lock().unwrap().clone(),
+            Self::Slave { master_id, .. } => master_id.lock().unwrap().clone(),
+        }
+    }
+
+    /// The `+FULLRESYNC` reply to a `PSYNC` that can't carry on from the backlog.
+    pub fn psync(&self) -> Vec<u8> {
+        let offset = match self {
             Self::Master {
-                replication_id,
-                replication_offset,
-                ..
-            } => Payload::SimpleString(format!(
-                "FULLRESYNC {} {}",
-                replication_id, replication_offset
-            ))
-            .redis_encode(),
-            Self::Slave { .. } => panic!("Slave can only initialize psync, not reply to it"),
+                replication_offset, ..
+            } => replication_offset,
+            Self::Slave { master_offset, .. } => master_offset,
+        };
+        Payload::SimpleString(format!(
+            "FULLRESYNC {} {}",
+            self.replication_id(),
+            offset.load(Ordering::SeqCst)
+        ))
+        .redis_encode()
+    }
+
+    /// Finds what a replica asking to `PSYNC <id> <offset>` missed of the command stream,
+    /// returning the offset it stopped at along with the bytes since. `None` means it has to
+    /// be sent a full resync instead: it never synced, synced under another replication id,
+    /// or missed more than the backlog keeps.
+    pub async fn missed_by(&self, args: &[String]) -> Option<(usize, Vec<u8>)> {
+        let Self::Master { backlog, .. } = self else {
+            return None;
+        };
+        let [id, offset] = args else {
+            return None;
+        };
+        // The offset asked for is that of the first byte the replica lacks, counted from 1.
+        let offset = offset.parse::<usize>().ok()?.checked_sub(1)?;
+        if *id != self.replication_id() {
+            return None;
+        }
+        let missed = backlog.lock().await.since(offset)?;
+        Some((offset, missed))
+    }
+
+    /// Extracts the replication id, if it is given, from a `+CONTINUE [<id>]` reply.
+    pub fn parse_continue(reply: &str) -> Option<Option<String>> {
+        let line = reply.strip_prefix('+')?.split(DELIMITER).next()?;
+        match line.split(' ').collect::<Vec<_>>()[..] {
+            ["CONTINUE"] => Some(None),
+            ["CONTINUE", id] => Some(Some(id.to_string())),
+            _ => None,
+        }
+    }
+
+    /// Extracts the replication id and offset from a `+FULLRESYNC <id> <offset>` reply.
+    pub fn parse_fullresync(reply: &str) -> Option<(String, usize)> {
+        let line = reply.strip_prefix('+')?.split(DELIMITER).next()?;
+        match line.split(' ').collect::<Vec<_>>()[..] {
+            ["FULLRESYNC", id, offset] => Some((id.to_string(), offset.parse().ok()?)),
+            _ => None,
         }
     }
-}
 
-fn get_empty_rdb() -> Vec<u8> {
-    let empty_rdb_data = DEFAULT_ID;
+    pub fn is_master(&self) -> bool {
+        matches!(self, Self::Master { .. })
+    }
 
-    let mut arr = vec![];
-    let len = empty_rdb_data.len();
-    let rdb_length = format!("${}{}", len, DELIMITER);
+    /// Counts `bytes` of the master's command stream as processed. Does nothing on a master.
+    pub fn advance_master_offset(&self, bytes: usize) {
+        if let Self::Slave { master_offset, .. } = self {
+            master_offset.fetch_add(bytes, Ordering::SeqCst);
+        }
+    }
 
-    arr.extend_from_slice(rdb_length.as_bytes());
-    arr.extend_from_slice(&empty_rdb_data);
-    println!("DEBUG EMPTY RDB: {}", String::from_utf8_lossy(&arr));
-    arr
+    pub fn slave_connections(&self) -> &Arc<Mutex<HashMap<String, ClientWrite>>> {
+        match self {
+            Self::Master {
+                slave_connections, ..
+            }
+            | Self::Slave {
+                slave_connections, ..
+            } => slave_connections,
+        }
+    }
 }
 
 impl Display for ClientRole {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Master {
-                replication_offset,
-                replication_id,
-                ..
+                replication_offset, ..
             } => write!(
                 f,
                 "role:master\nmaster_replid:{}\nmaster_repl_offset:{}",
-                replication_id, replication_offset
+                self.replication_id(),
+                replication_offset.load(Ordering::SeqCst)
             ),
             Self::Slave { .. } => write!(f, "role:slave"),
         }
     }
 }
+
+/// Generates a replication id of 40 random hex digits, as Redis does.
+fn random_replication_id() -> String {
+    use std::collections::hash_map::RandomState;
+    use std::hash::{BuildHasher, Hasher};
+
+    // Every `RandomState` is seeded with new random keys, so what it hashes to is random.
+    (0..3)
+        .map(|_| format!("{:016x}", RandomState::new().build_hasher().finish()))
+        .collect::<String>()[..40]
+        .to_string()
+}
+
+/// Serializes the given databases, in index order, into an RDB snapshot.
+fn dump_rdb(databases: &[RwLockReadGuard<'_, KeyValueStore>]) -> Vec<u8> {
+    rdb::encode(databases.iter().enumerate().flat_map(|(db, store)| {
+        store
+            .entries()
+            .map(move |(key, value, expire_at)| (db, key, value, expire_at))
+    }))
+}
+
+/// Writes `rdb` to a temporary file next to `path` and renames it into place, so a save that
+/// fails halfway never leaves a truncated file behind.
+async fn write_rdb_file(path: &Path, rdb: &[u8]) -> Result<()> {
+    let temp = path.with_file_name(format!("temp-{}.rdb", std::process::id()));
+    tokio::fs::write(&temp, rdb)
+        .await
+        .with_context(|| format!("ERR Failed to write {}", temp.display()))?;
+    tokio::fs::rename(&temp, path).await.with_context(|| {
+        format!(
+            "ERR Failed to rename {} to {}",
+            temp.display(),
+            path.display()
+        )
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_parse_client_tracking() {
+        assert_eq!(
+            parse_client_tracking(&args(&["on"])).unwrap(),
+            Some(TrackingOptions::default())
+        );
+        assert_eq!(parse_client_tracking(&args(&["OFF"])).unwrap(), None);
+        assert_eq!(
+            parse_client_tracking(&args(&["on", "REDIRECT", "7", "noloop"])).unwrap(),
+            Some(TrackingOptions {
+                redirect: Some(7),
+                noloop: true,
+                ..TrackingOptions::default()
+            })
+        );
+        assert_eq!(
+            parse_client_tracking(&args(&["on", "BCAST", "PREFIX", "user:", "prefix", "a"]))
+                .unwrap(),
+            Some(TrackingOptions {
+                bcast: true,
+                prefixes: args(&["user:", "a"]),
+                ..TrackingOptions::default()
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rdb_snapshot_keeps_keys_in_their_database() {
+        // An empty directory, so that no RDB file is loaded on setup.
+        let dir = std::env::temp_dir().join(format!("rust-redis-snapshot-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = Config {
+            dir: dir.to_str().unwrap().to_string(),
+            ..Config::default()
+        };
+        let client = RedisClient::setup_client(None, config.clone()).await;
+        let pair = |key: &str| [key, "value"].map(|s| Payload::BulkString(s.into()));
+        client.process_set(0, &pair("first")).await.unwrap();
+        client.process_set(5, &pair("second")).await.unwrap();
+        let snapshot = dump_rdb(&client.read_databases().await);
+
+        let restored = RedisClient::setup_client(None, config.clone()).await;
+        restored.load_rdb(&snapshot).await.unwrap();
+        let databases = restored.read_databases().await;
+        assert_eq!(databases[0].keys("*"), ["first"]);
+        assert_eq!(databases[5].keys("*"), ["second"]);
+
+        let config = Config {
+            databases: 2,
+            ..config
+        };
+        let smaller = RedisClient::setup_client(None, config).await;
+        assert!(smaller.load_rdb(&snapshot).await.is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_client_tracking_rejects_invalid_arguments() {
+        assert!(parse_client_tracking(&args(&[])).is_err());
+        assert!(parse_client_tracking(&args(&["maybe"])).is_err());
+        assert!(parse_client_tracking(&args(&["on", "REDIRECT"])).is_err());
+        assert!(parse_client_tracking(&args(&["on", "SOMETIMES"])).is_err());
+        assert!(parse_client_tracking(&args(&["on", "BCAST", "PREFIX"])).is_err());
+        assert!(parse_client_tracking(&args(&["on", "PREFIX", "user:"])).is_err());
+        assert!(parse_client_tracking(&args(&["on", "OPTIN"])).is_err());
+    }
+}
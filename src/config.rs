@@ -0,0 +1,232 @@
+use anyhow::{bail, Context, Result};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+
+use crate::store::glob::glob_match;
+
+/// Server parameters readable and writable at runtime with `CONFIG GET` and `CONFIG SET`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Config {
+    /// Directory the RDB file is written to and loaded from.
+    pub dir: String,
+    /// Name of the RDB file within `dir`.
+    pub dbfilename: String,
+    /// Memory limit in bytes, where 0 means no limit.
+    pub maxmemory: u64,
+    /// Whether writes are logged to the append-only file.
+    pub appendonly: bool,
+    /// How often the append-only file is flushed to disk.
+    pub appendfsync: AppendFsync,
+    /// Number of logical databases, fixed at startup.
+    pub databases: usize,
+    /// Port the server listens on, which a replica advertises to its master.
+    pub port: u16,
+    /// Whether `dir` or `dbfilename` was given, in which case the dataset is saved to the RDB
+    /// file on shutdown, as Redis does when save points are configured.
+    pub persistent: bool,
+}
+
+/// When writes logged to the append-only file are flushed to disk.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum AppendFsync {
+    /// After every write, so that none is ever lost.
+    Always,
+    /// At most once a second, so that a crash loses about a second of writes.
+    #[default]
+    EverySec,
+    /// Whenever the operating system gets to it.
+    No,
+}
+
+impl Display for AppendFsync {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Always => write!(f, "always"),
+            Self::EverySec => write!(f, "everysec"),
+            Self::No => write!(f, "no"),
+        }
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            dir: ".".to_string(),
+            dbfilename: "dump.rdb".to_string(),
+            maxmemory: 0,
+            appendonly: false,
+            appendfsync: AppendFsync::default(),
+            databases: 16,
+            port: 6379,
+            persistent: false,
+        }
+    }
+}
+
+impl Config {
+    const PARAMETERS: [&'static str; 6] = [
+        "dir",
+        "dbfilename",
+        "maxmemory",
+        "appendonly",
+        "appendfsync",
+        "databases",
+    ];
+
+    /// Name of the append-only file within `dir`.
+    const AOF_FILENAME: &'static str = "appendonly.aof";
+
+    /// Returns the name and value of every parameter matching the glob `pattern`.
+    pub fn get(&self, pattern: &str) -> Vec<(String, String)> {
+        let pattern = pattern.to_lowercase();
+        Self::PARAMETERS
+            .iter()
+            .filter(|name| glob_match(pattern.as_bytes(), name.as_bytes()))
+            .map(|name| (name.to_string(), self.value(name)))
+            .collect()
+    }
+
+    /// Sets the parameter `name` to `value`, validating the value first so an invalid one
+    /// leaves the configuration unchanged.
+    pub fn set(&mut self, name: &str, value: &str) -> Result<()> {
+        let invalid = || format!("ERR Invalid argument '{}' for CONFIG SET '{}'", value, name);
+        match name.to_lowercase().as_str() {
+            "dir" => {
+                if !Path::new(value).is_dir() {
+                    bail!("ERR CONFIG SET failed (possibly related to argument 'dir') - No such file or directory")
+                }
+                self.dir = value.to_string();
+                self.persistent = true;
+            }
+            "dbfilename" => {
+                if value.contains('/') {
+                    bail!(
+                        "{} - dbfilename can't be a path, just a filename",
+                        invalid()
+                    )
+                }
+                self.dbfilename = value.to_string();
+                self.persistent = true;
+            }
+            "maxmemory" => self.maxmemory = parse_memory(value).with_context(invalid)?,
+            "appendonly" => {
+                self.appendonly = match value.to_lowercase().as_str() {
+                    "yes" => true,
+                    "no" => false,
+                    _ => bail!("{} - argument must be 'yes' or 'no'", invalid()),
+                }
+            }
+            "appendfsync" => {
+                self.appendfsync = match value.to_lowercase().as_str() {
+                    "always" => AppendFsync::Always,
+                    "everysec" => AppendFsync::EverySec,
+                    "no" => AppendFsync::No,
+                    _ => bail!(invalid()),
+                }
+            }
+            "databases" => bail!(
+                "ERR CONFIG SET failed (possibly related to argument 'databases') - can't set immutable config"
+            ),
+            _ => bail!(
+                "ERR Unknown option or number of arguments for CONFIG SET - '{}'",
+                name
+            ),
+        }
+        Ok(())
+    }
+
+    /// Path of the RDB file, `dbfilename` within `dir`.
+    pub fn rdb_path(&self) -> PathBuf {
+        Path::new(&self.dir).join(&self.dbfilename)
+    }
+
+    /// Path of the append-only file within `dir`.
+    pub fn aof_path(&self) -> PathBuf {
+        Path::new(&self.dir).join(Self::AOF_FILENAME)
+    }
+
+    fn value(&self, name: &str) -> String {
+        match name {
+            "dir" => self.dir.clone(),
+            "dbfilename" => self.dbfilename.clone(),
+            "maxmemory" => self.maxmemory.to_string(),
+            "appendonly" => if self.appendonly { "yes" } else { "no" }.to_string(),
+            "appendfsync" => self.appendfsync.to_string(),
+            "databases" => self.databases.to_string(),
+            _ => unreachable!("unknown parameter '{}'", name),
+        }
+    }
+}
+
+/// Parses a memory amount such as `100`, `64kb` or `1gb`. As in Redis, `k`, `m` and `g` are
+/// powers of 1000 while `kb`, `mb` and `gb` are powers of 1024.
+fn parse_memory(value: &str) -> Option<u64> {
+    let value = value.to_lowercase();
+    let digits = value.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+    let multiplier = match &value[digits.len()..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => return None,
+    };
+    digits.parse::<u64>().ok()?.checked_mul(multiplier)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_get_matches_patterns() {
+        let config = Config::default();
+        assert_eq!(
+            config.get("dbfilename"),
+            vec![("dbfilename".to_string(), "dump.rdb".to_string())]
+        );
+        assert_eq!(config.get("*").len(), 6);
+        let names: Vec<String> = config.get("d*").into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["dir", "dbfilename", "databases"]);
+        assert!(config.get("unknown").is_empty());
+    }
+
+    #[test]
+    fn test_set_validates_values() {
+        let mut config = Config::default();
+        config.set("MAXMEMORY", "64mb").unwrap();
+        assert_eq!(config.maxmemory, 64 * 1024 * 1024);
+        config.set("maxmemory", "2k").unwrap();
+        assert_eq!(config.get("maxmemory")[0].1, "2000");
+        config.set("appendonly", "yes").unwrap();
+        assert_eq!(config.get("appendonly")[0].1, "yes");
+        config.set("appendfsync", "ALWAYS").unwrap();
+        assert_eq!(config.get("appendfsync")[0].1, "always");
+
+        let before = config.clone();
+        assert!(config.set("maxmemory", "lots").is_err());
+        assert!(config.set("appendonly", "maybe").is_err());
+        assert!(config.set("appendfsync", "sometimes").is_err());
+        assert!(config.set("databases", "4").is_err());
+        assert!(config.set("dir", "/definitely/not/a/directory").is_err());
+        assert!(config.set("dbfilename", "../dump.rdb").is_err());
+        assert!(config.set("nonexistent", "1").is_err());
+        assert_eq!(config, before);
+    }
+
+    #[test]
+    fn test_setting_the_rdb_file_makes_the_dataset_persistent() {
+        let mut config = Config::default();
+        assert!(!config.persistent);
+        config.set("maxmemory", "1mb").unwrap();
+        assert!(!config.persistent);
+        config.set("dbfilename", "other.rdb").unwrap();
+        assert!(config.persistent);
+
+        let mut config = Config::default();
+        config.set("dir", ".").unwrap();
+        assert!(config.persistent);
+    }
+}
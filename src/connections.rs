@@ -0,0 +1,89 @@
+use std::collections::BTreeMap;
+use std::net::SocketAddr;
+use tokio::time::Instant;
+
+/// A connected client, as `CLIENT LIST` shows it.
+struct Connection {
+    addr: SocketAddr,
+    name: Option<String>,
+    connected_at: Instant,
+}
+
+/// The connections currently open, keyed by the id each was assigned when accepted.
+///
+/// Ids start at 1 and only ever grow, so one is never reused for a later connection even
+/// after its own connection closes.
+#[derive(Default)]
+pub struct Connections {
+    last_id: u64,
+    connections: BTreeMap<u64, Connection>,
+}
+
+impl Connections {
+    /// Registers a connection accepted from `addr`, returning its id.
+    pub fn register(&mut self, addr: SocketAddr) -> u64 {
+        self.last_id += 1;
+        let connection = Connection {
+            addr,
+            name: None,
+            connected_at: Instant::now(),
+        };
+        self.connections.insert(self.last_id, connection);
+        self.last_id
+    }
+
+    /// Forgets the connection `id`, for when it closes.
+    pub fn unregister(&mut self, id: u64) {
+        self.connections.remove(&id);
+    }
+
+    /// Names the connection `id`, or removes its name when `name` is `None`.
+    pub fn set_name(&mut self, id: u64, name: Option<String>) {
+        if let Some(connection) = self.connections.get_mut(&id) {
+            connection.name = name;
+        }
+    }
+
+    pub fn addr(&self, id: u64) -> Option<SocketAddr> {
+        Some(self.connections.get(&id)?.addr)
+    }
+
+    pub fn name(&self, id: u64) -> Option<&str> {
+        self.connections.get(&id)?.name.as_deref()
+    }
+
+    /// Renders the `CLIENT LIST` reply, a line per connection in the order they were accepted.
+    pub fn list(&self) -> String {
+        self.connections
+            .iter()
+            .map(|(id, connection)| {
+                format!(
+                    "id={} addr={} name={} age={}\n",
+                    id,
+                    connection.addr,
+                    connection.name.as_deref().unwrap_or_default(),
+                    connection.connected_at.elapsed().as_secs()
+                )
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids_are_never_reused() {
+        let mut connections = Connections::default();
+        let addr: SocketAddr = "127.0.0.1:6000".parse().unwrap();
+        let first = connections.register(addr);
+        connections.unregister(first);
+        let second = connections.register(addr);
+        assert!(second > first);
+        assert_eq!(
+            connections.list(),
+            format!("id={} addr=127.0.0.1:6000 name= age=0\n", second)
+        );
+    }
+}
@@ -1,22 +1,34 @@
+mod aof;
+mod backlog;
 mod client;
+mod config;
+mod connections;
 mod parser;
+mod pubsub;
 mod store;
-use anyhow::{bail, Result};
+mod tracking;
+use anyhow::{anyhow, bail, Result};
 use clap::Parser;
-use client::{ClientRole, RedisClient};
+use client::{ClientRole, ConnectionState, RedisClient};
+use config::Config;
 use core::net::SocketAddr;
 use log::{debug, info, warn};
 use parser::RedisProtocolParser;
-use std::{io::Cursor, sync::Arc};
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+#[cfg(unix)]
+use tokio::signal::unix::{signal, SignalKind};
 use tokio::{
-    io::{split, AsyncReadExt, ReadHalf, WriteHalf},
-    net::{TcpListener, TcpStream},
+    io::{split, AsyncReadExt, AsyncWriteExt, ReadHalf, WriteHalf},
+    net::{TcpListener, TcpSocket, TcpStream},
     select,
-    sync::Mutex,
+    sync::{mpsc, watch, Mutex},
+    task::JoinSet,
+    time::{timeout, Duration},
 };
 
-use crate::{parser::Value, store::RedisType};
-static PSYNC_IGNORE: [u8; 1024] = [36, 56, 56, 13, 10, 82, 69, 68, 73, 83, 48, 48, 49, 49, 250, 9, 114, 101, 100, 105, 115, 45, 118, 101, 114, 5, 55, 46, 50, 46, 48, 250, 10, 114, 101, 100, 105, 115, 45, 98, 105, 116, 115, 192, 64, 250, 5, 99, 116, 105, 109, 101, 194, 109, 8, 188, 101, 250, 8, 117, 115, 101, 100, 45, 109, 101, 109, 194, 176, 196, 16, 0, 250, 8, 97, 111, 102, 45, 98, 97, 115, 101, 192, 0, 255, 240, 110, 59, 254, 192, 255, 90, 162, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+use crate::parser::{Payload, RedisEncodable, Value};
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about)]
@@ -24,8 +36,36 @@ struct Args {
     #[clap(short, long, default_value_t = 6379)]
     port: u16,
 
+    /// Addresses of the interfaces to listen on, each getting its own listener.
+    #[clap(long, num_args = 1.., default_value = "127.0.0.1")]
+    bind: Vec<IpAddr>,
+
     #[clap(long, num_args = 1)]
     replicaof: Option<String>,
+
+    /// Directory the RDB file is loaded from and saved to on shutdown.
+    #[clap(long)]
+    dir: Option<String>,
+
+    /// Name of the RDB file within `dir`.
+    #[clap(long)]
+    dbfilename: Option<String>,
+
+    #[clap(long, default_value_t = 511)]
+    tcp_backlog: u32,
+
+    /// How many times per second expired keys are actively removed.
+    #[clap(long, default_value_t = 10, value_parser = clap::value_parser!(u32).range(1..=500))]
+    hz: u32,
+
+    /// Whether writes are logged to the append-only file, which then rebuilds the store on
+    /// startup instead of the RDB file.
+    #[clap(long, default_value = "no")]
+    appendonly: String,
+
+    /// Number of logical databases clients can switch between with `SELECT`.
+    #[clap(long, default_value_t = 16, value_parser = clap::value_parser!(u32).range(1..))]
+    databases: u32,
 }
 
 #[tokio::main]
@@ -33,32 +73,110 @@ async fn main() {
     env_logger::init();
 
     let args = Args::parse();
-    let address = format!("127.0.0.1:{}", args.port);
-    info!("Booting server at: {}", &address);
-
-    let listener = TcpListener::bind(address).await.unwrap();
-    info!("Binding listener was successful");
+    let listeners = args
+        .bind
+        .iter()
+        .map(|&ip| {
+            let address = SocketAddr::new(ip, args.port);
+            info!("Booting server at: {}", &address);
+            bind_listener(address, args.tcp_backlog)
+                .unwrap_or_else(|e| panic!("Failed to bind {}: {}", address, e))
+        })
+        .collect();
+    info!("Binding listeners was successful");
 
-    let client = RedisClient::setup_client(args.replicaof).await;
+    let mut config = Config::default();
+    let rdb_file = [("dir", &args.dir), ("dbfilename", &args.dbfilename)];
+    for (name, value) in rdb_file {
+        if let Some(value) = value {
+            config.set(name, value).unwrap_or_else(|e| panic!("Invalid --{}: {}", name, e));
+        }
+    }
+    config.set("appendonly", &args.appendonly).unwrap();
+    config.databases = args.databases as usize;
+    config.port = args.port;
+    let client = RedisClient::setup_client(args.replicaof, config).await;
     let client = Arc::new(client);
+    let active_expiry = client.spawn_active_expiry(args.hz);
+    let master_acks = client.spawn_master_acks();
+
+    let mut requested = false;
+    let shutdown = async {
+        select! {
+            _ = shutdown_signal() => {}
+            _ = client.shutdown_requested() => requested = true,
+        }
+    };
+    serve(listeners, client.clone(), shutdown).await;
+    active_expiry.abort();
+    if let Some(master_acks) = master_acks {
+        master_acks.abort();
+    }
+    // SHUTDOWN persists the dataset itself before the server stops.
+    if !requested {
+        if let Err(e) = client.prepare_shutdown(None).await {
+            warn!("Failed to persist the dataset before exiting {:#}", e);
+        }
+    }
+}
+
+/// How long open connections get to finish their current command once shutdown starts.
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Resolves once the process is asked to stop, by Ctrl-C or, on Unix, by `SIGTERM`.
+async fn shutdown_signal() {
+    let interrupt = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            warn!("Failed to listen for SIGINT {}", e);
+            std::future::pending::<()>().await
+        }
+    };
+    #[cfg(unix)]
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                warn!("Failed to listen for SIGTERM {}", e);
+                std::future::pending::<()>().await
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    select! {
+        _ = interrupt => info!("Received SIGINT"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
 
+/// Accepts connections on every listener until `shutdown` resolves. The listeners are then
+/// closed and every open connection is told to stop once the command it is running has replied.
+async fn serve(
+    listeners: Vec<TcpListener>,
+    client: Arc<RedisClient>,
+    shutdown: impl Future<Output = ()>,
+) {
+    let (closing, closed) = watch::channel(false);
+    let (accepted_tx, mut accepted_rx) = mpsc::channel(1);
+    let mut accept_loops = JoinSet::new();
+    for listener in listeners {
+        accept_loops.spawn(accept_connections(listener, accepted_tx.clone()));
+    }
+    let mut connections = JoinSet::new();
+    let mut master_buffer = Vec::new();
+    tokio::pin!(shutdown);
+    info!("Listening for connections...");
     loop {
-        info!("Listening for connections...");
-        let client_clone = client.clone();
         let mut buf = [0; 1024];
 
-        match &client.role {
-            ClientRole::Master {..} => {
-                let (stream, addr) = listener.accept().await.unwrap();
-                    let (mut read, write) = split(stream);
-                    let write = Arc::new(write.into());
-
-                    info!("Accepted new connection: {}", addr);
-                    tokio::spawn(async move {
-                        if let Err(e) = handle_connection(write, &mut read, addr, client_clone).await {
-                            warn!("Failed to handle connection {}", e)
-                        }
-                    });
+        let accepted = match &client.role {
+            ClientRole::Master {..} => select! {
+                _ = &mut shutdown => break,
+                Some(_) = connections.join_next() => continue,
+                Some(accepted) = accepted_rx.recv() => accepted,
             },
             ClientRole::Slave {
                master_stream_r,
@@ -66,81 +184,125 @@ async fn main() {
             } => {
                 let mut lock = master_stream_r.lock().await;
                 select! {
-                    Ok((stream, addr)) = listener.accept() => {
-                            let (mut read, write) = split(stream);
-                            let write = Arc::new(write.into());
-                            info!("Accepted new connection: {}", addr);
-                            tokio::spawn(async move {
-                                if let Err(e) = handle_connection(write, &mut read, addr, client_clone).await {
-                                    warn!("Failed to handle connection {}", e)
-                                }
-                            });
+                    _ = &mut shutdown => break,
+                    Some(_) = connections.join_next() => continue,
+                    Some(accepted) = accepted_rx.recv() => accepted,
+                    read = lock.read(&mut buf) => {
+                        let read_bytes = read.unwrap_or_else(|e| {
+                            warn!("Lost the link to the master {}", e);
+                            0
+                        });
+                        if read_bytes == 0 {
+                            // The master closed the link, so the stream read so far is stale.
+                            debug!("[HANDLE_CONNECTION] - Master link closed, resyncing");
+                            master_buffer.clear();
+                            if let Err(e) = client.resync_with_master(&mut lock).await {
+                                warn!("Failed to resync with the master {}", e);
+                                break
+                            }
+                            continue
+                        }
+                        master_buffer.extend_from_slice(&buf[..read_bytes]);
+                        let applied =
+                            handle_propagation_from_master(&mut master_buffer, client.clone()).await;
+                        if let Err(e) = applied {
+                            warn!("Failed to handle propagation from master {}", e);
+                            master_buffer.clear();
                         }
-                    Ok(read_bytes) = lock.read(&mut buf) => {
-                    if read_bytes == 0 {
-                        debug!("[HANDLE_CONNECTION] - Read zero bytes, returning");
-                        return
-                    }
-                    println!("{:?}", &buf);
-                    if buf == PSYNC_IGNORE {
-                        println!("Ignoring RDB COMMAND");
                         continue
                     }
-                    let mut received_data = Cursor::new(std::str::from_utf8(&buf[..read_bytes]).expect("Should never be wrong format."));
-                    let _ = handle_propagation_from_master(&mut received_data, client_clone).await;
-
-                    }
                 }
             }
-        }
-        // 2. Select Statement
+        };
+        let (stream, addr) = accepted;
+        let (mut read, write) = split(stream);
+        let write = Arc::new(write.into());
+        let client = client.clone();
+        let closed = closed.clone();
 
-        // let mut master_lock = master_stream_r
-        //     .as_ref()
-        //     .expect("Slave should be set.")
-        //     .lock()
-        //     .await;
+        info!("Accepted new connection: {}", addr);
+        connections.spawn(async move {
+            if let Err(e) = handle_connection(write, &mut read, addr, client, closed).await {
+                warn!("Failed to handle connection {}", e)
+            }
+        });
+    }
 
-        // select! {
-        //     read_result = stream_read.read(&mut buf) => {
-        //         read_bytes = read_result?;
-        //         received_data = Cursor::new(std::str::from_utf8(&buf[..read_bytes])?);
-        //     }
+    info!("Shutting down");
+    accept_loops.shutdown().await;
+    let _ = closing.send(true);
+    let drained = async { while connections.join_next().await.is_some() {} };
+    if timeout(SHUTDOWN_GRACE, drained).await.is_err() {
+        warn!("Connections still busy after {:?}, closing them", SHUTDOWN_GRACE);
+        connections.shutdown().await;
+    }
+}
 
-        //     read_result = master_lock.read(&mut buf_master) => {
-        //         read_bytes = read_result?;
-        //         received_data = Cursor::new(std::str::from_utf8(&buf_master[..read_bytes])?);
-        //     }
-        // }
+/// Hands the connections `listener` accepts over to `serve`, until `serve` stops taking them.
+async fn accept_connections(
+    listener: TcpListener,
+    accepted: mpsc::Sender<(TcpStream, SocketAddr)>,
+) {
+    loop {
+        match listener.accept().await {
+            Ok(connection) => {
+                if accepted.send(connection).await.is_err() {
+                    return;
+                }
+            }
+            Err(e) => warn!("Failed to accept connection {}", e),
+        }
     }
 }
 
-async fn handle_propagation_from_master(data: &mut Cursor<&str>, client: Arc<RedisClient>) -> Result<()> {
-    let payloads = RedisProtocolParser::parse(data)?;
-    for payload in payloads {
-        let (command, contents) = payload.retrieve_content()?;
-        debug!(
-            "[HANDLE_CONNECTION] - Retrieved master propagation command: {:?}, contents: {:?}",
-            command, contents
-        );
+/// Binds a listener with `SO_REUSEADDR` set, so a restarted server can rebind its port while
+/// connections from the previous run are still in `TIME_WAIT`.
+fn bind_listener(address: SocketAddr, backlog: u32) -> Result<TcpListener> {
+    let socket = match address {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(address)?;
+    Ok(socket.listen(backlog)?)
+}
 
-        if command.is_some() {
-            let (key, value, arg, arg_value) = match contents {
-                Value::Array(x) => (
-                    x[0].to_string(),
-                    RedisType::String(x[1].to_string()),
-                    x.get(2).cloned(),
-                    x.get(3).cloned(),
-                ),
-                _ => bail!("Cant store data in given format."),
+/// Applies every complete command buffered from the master, leaving a trailing partial one in
+/// `buffer` until the rest of it arrives.
+async fn handle_propagation_from_master(buffer: &mut Vec<u8>, client: Arc<RedisClient>) -> Result<()> {
+    loop {
+        // Commands from the master are always arrays, so a leading bulk string is the RDB
+        // snapshot that follows `FULLRESYNC`.
+        if buffer.first() == Some(&b'$') {
+            let Some((snapshot, consumed)) = RedisProtocolParser::parse_rdb_frame(buffer)? else {
+                return Ok(());
             };
-            let _ = client.process_set(key, value, arg, arg_value).await?;
-        } else {
-            bail!("Handling inputs without commands is not supported.")
+            buffer.drain(..consumed);
+            client.load_snapshot(&snapshot.to_bytes()).await?;
+            continue;
+        }
+        let Some((payload, consumed)) = RedisProtocolParser::parse_frame(buffer)? else {
+            return Ok(());
         };
-    };
-    Ok(())
+        let frame: Vec<u8> = buffer.drain(..consumed).collect();
+        client.propagate(&frame).await?;
+        apply_propagated_payload(payload, &client).await?;
+        // Counted only once applied, so an ACK reports the offset before its own GETACK.
+        client.role.advance_master_offset(frame.len());
+    }
+}
+
+async fn apply_propagated_payload(payload: Payload, client: &RedisClient) -> Result<()> {
+    let (command, contents) = payload.retrieve_content()?;
+    debug!(
+        "[HANDLE_CONNECTION] - Retrieved master propagation command: {:?}, contents: {:?}",
+        command, contents
+    );
 
+    match command {
+        Some(command) => client.apply_from_master(command, contents).await,
+        None => bail!("Handling inputs without commands is not supported."),
+    }
 }
 
 async fn handle_connection(
@@ -148,41 +310,2504 @@ async fn handle_connection(
     stream_read: &mut ReadHalf<TcpStream>,
     addr: SocketAddr,
     client: Arc<RedisClient>,
+    closed: watch::Receiver<bool>,
 ) -> Result<()> {
     debug!("[HANDLE_CONNECTION] - START");
-    let mut buf = [0; 1024];
-    let mut received_data: Cursor<&str>;
-    let mut read_bytes: usize;
+    let mut state = ConnectionState {
+        id: client.register_connection(addr).await,
+        ..Default::default()
+    };
+    let result =
+        run_commands(stream_write, stream_read, addr, &client, &mut state, closed).await;
+    // Cleaned up however the connection ended, so a failed one doesn't linger in the registries.
+    if state.subscriptions() > 0 {
+        client.remove_subscriber(&addr).await;
+    }
+    client.remove_replica(&addr).await;
+    client.unregister_connection(state.id).await;
+    debug!("[HANDLE_CONNECTION] - Connection closed, returning");
+    result
+}
 
+/// Runs the commands read from a connection until it closes or the server shuts down.
+async fn run_commands(
+    stream_write: Arc<Mutex<WriteHalf<TcpStream>>>,
+    stream_read: &mut ReadHalf<TcpStream>,
+    addr: SocketAddr,
+    client: &RedisClient,
+    state: &mut ConnectionState,
+    mut closed: watch::Receiver<bool>,
+) -> Result<()> {
+    let mut buffer = Vec::new();
     loop {
-        read_bytes = stream_read.read(&mut buf).await?;
-        if read_bytes == 0 {
-            debug!("[HANDLE_CONNECTION] - Read zero bytes, returning");
-            return Ok(());
+        // Shutdown is only noticed between commands, so one already read still gets its reply.
+        let payload = select! {
+            payload = RedisProtocolParser::read_frame(stream_read, &mut buffer) => payload,
+            _ = closed.changed() => Ok(None),
+        };
+        let payload = match payload {
+            Ok(payload) => payload,
+            Err(e) if e.is::<std::io::Error>() => return Err(e),
+            // What follows a malformed request can't be told apart from it, so the connection
+            // is closed once the client has been told why.
+            Err(e) => {
+                warn!("[HANDLE_CONNECTION] - Protocol error: {}", e);
+                let reply = Payload::error(e).redis_encode();
+                stream_write.lock().await.write_all(&reply).await?;
+                break;
+            }
+        };
+        let Some(payload) = payload else {
+            break;
+        };
+        // Writes reach replicas exactly as the client sent them, so the frame is encoded
+        // before it is taken apart.
+        let frame = client.role.is_master().then(|| payload.redis_encode());
+        let (command, contents) = payload.retrieve_content()?;
+        debug!(
+            "[HANDLE_CONNECTION] - Retrieved command: {:?}, contents: {:?}",
+            command, contents
+        );
+
+        // Commands queued in a transaction are propagated by EXEC once they actually run.
+        let queued = state.transaction.is_some();
+        let result = match command {
+            Some(command) => {
+                let stream = Some(stream_write.clone());
+                client
+                    .process_command(command, contents, stream, &addr, state, true)
+                    .await
+            }
+            None => {
+                state.abort_transaction();
+                Err(unknown_command(&contents))
+            }
+        };
+        if let (Ok(()), Some(command), Some(frame)) = (&result, command, frame) {
+            if command.is_write() && !queued {
+                client.propagate_write(state.db, &frame).await?;
+            }
+        }
+        if let Err(e) = result {
+            warn!("[HANDLE_CONNECTION] - Command failed: {}", e);
+            let reply = Payload::error(e).redis_encode();
+            stream_write.lock().await.write_all(&reply).await?;
         }
+        debug!("[HANDLE_CONNECTION] - NEXT LOOP");
+    }
+    Ok(())
+}
 
-        received_data = Cursor::new(std::str::from_utf8(&buf[..read_bytes])?);
+fn unknown_command(contents: &Value) -> anyhow::Error {
+    let name = match contents {
+        Value::String(s) => s.clone(),
+        Value::Array(x) => x.first().map(|p| p.to_string()).unwrap_or_default(),
+        Value::Empty => String::new(),
+    };
+    anyhow!("ERR unknown command '{}'", name)
+}
 
-        let payloads = RedisProtocolParser::parse(&mut received_data)?;
-        let payload_len = payloads.len() - 1;
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::Command;
+    use crate::store::{rdb, RedisType};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use tokio::time::{sleep, Duration};
 
-        for (index, payload) in payloads.into_iter().enumerate() {
-            let last = index == payload_len;
-            let (command, contents) = payload.retrieve_content()?;
-            debug!(
-                "[HANDLE_CONNECTION] - Retrieved command: {:?}, contents: {:?}",
-                command, contents
-            );
+    /// Creates a directory of its own for a test's RDB and AOF files, so that tests running
+    /// at the same time never load or overwrite each other's.
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        static CREATED: AtomicUsize = AtomicUsize::new(0);
+        let unique = format!("{}-{}", std::process::id(), CREATED.fetch_add(1, Ordering::SeqCst));
+        let dir = std::env::temp_dir().join(format!("rust-redis-{}-{}", name, unique));
+        std::fs::create_dir_all(&dir).unwrap();
+        dir
+    }
 
-            if let Some(command) = command {
-                client
-                    .process_command(command, contents, stream_write.clone(), &addr, last)
-                    .await?;
-            } else {
-                bail!("Handling inputs without commands is not supported.")
+    async fn spawn_server(replicaof: Option<String>) -> SocketAddr {
+        let config = Config {
+            dir: temp_dir("server").to_str().unwrap().to_string(),
+            ..Config::default()
+        };
+        spawn_configured_server(replicaof, config).await
+    }
+
+    async fn spawn_configured_server(replicaof: Option<String>, mut config: Config) -> SocketAddr {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), 511).unwrap();
+        let address = listener.local_addr().unwrap();
+        config.port = address.port();
+        let client = Arc::new(RedisClient::setup_client(replicaof, config).await);
+        client.spawn_active_expiry(10);
+        client.spawn_master_acks();
+        let requested = client.clone();
+        let shutdown = async move { requested.shutdown_requested().await };
+        tokio::spawn(serve(vec![listener], client, shutdown));
+        address
+    }
+
+    async fn send(stream: &mut TcpStream, command: &[&str]) -> String {
+        let payload = Payload::build_bulk_string_array(command.to_vec());
+        stream
+            .write_all(&payload.redis_encode())
+            .await
+            .unwrap();
+        let mut buf = [0; 1024];
+        let read_bytes = stream.read(&mut buf).await.unwrap();
+        String::from_utf8_lossy(&buf[..read_bytes]).to_string()
+    }
+
+    /// Like `send`, for commands whose arguments aren't valid UTF-8, returning the raw reply.
+    async fn send_bytes(stream: &mut TcpStream, command: &[&[u8]]) -> Vec<u8> {
+        let payload = Payload::build_bulk_string_array(command.to_vec());
+        stream.write_all(&payload.redis_encode()).await.unwrap();
+        let mut buf = [0; 1024];
+        let read_bytes = stream.read(&mut buf).await.unwrap();
+        buf[..read_bytes].to_vec()
+    }
+
+    async fn request(address: SocketAddr, command: &[&str]) -> String {
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        send(&mut stream, command).await
+    }
+
+    /// Spawns a master that completes the replication handshake at offset 100, sending
+    /// `stream` in the same write as its `FULLRESYNC` reply, and then only reads what the
+    /// replica sends. The task resolves to the connection once the handshake is done.
+    async fn fake_master(stream: Vec<u8>) -> (SocketAddr, tokio::task::JoinHandle<TcpStream>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let handle = tokio::spawn(async move {
+            let (mut connection, _) = listener.accept().await.unwrap();
+            let fullresync = [b"+FULLRESYNC abc 100\r\n".as_slice(), &stream].concat();
+            for reply in [b"+PONG\r\n".as_slice(), b"+OK\r\n", b"+OK\r\n", &fullresync] {
+                let mut buf = [0; 1024];
+                assert!(connection.read(&mut buf).await.unwrap() > 0);
+                connection.write_all(reply).await.unwrap();
             }
+            connection
+        });
+        (address, handle)
+    }
+
+    #[tokio::test]
+    async fn test_info_stats_counts_keyspace_hits_and_misses() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "foo", "bar"]).await;
+        request(server, &["GET", "foo"]).await;
+        request(server, &["GET", "foo"]).await;
+        request(server, &["GET", "missing"]).await;
+
+        let info = request(server, &["INFO", "stats"]).await;
+        assert!(info.contains("keyspace_hits:2\n"), "{info}");
+        assert!(info.contains("keyspace_misses:1"), "{info}");
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_replied_without_closing_the_connection() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+
+        assert_eq!(
+            send(&mut stream, &["NOTACOMMAND", "foo"]).await,
+            "-ERR unknown command 'NOTACOMMAND'\r\n"
+        );
+        assert_eq!(
+            send(&mut stream, &["CLIENT", "TRACKING", "maybe"]).await,
+            "-ERR syntax error\r\n"
+        );
+        assert_eq!(send(&mut stream, &["PING"]).await, "+PONG\r\n");
+    }
 
+    #[tokio::test]
+    async fn test_ping_and_echo_arguments() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        assert_eq!(send(&mut stream, &["PING", "foo"]).await, "$3\r\nfoo\r\n");
+        assert_eq!(send(&mut stream, &["ECHO", "hello"]).await, "$5\r\nhello\r\n");
+        for wrong in [&["PING", "a", "b"][..], &["ECHO"], &["ECHO", "hello", "world"]] {
+            let name = wrong[0].to_lowercase();
+            let expected = format!("-ERR wrong number of arguments for '{}' command\r\n", name);
+            assert_eq!(send(&mut stream, wrong).await, expected);
         }
-        debug!("[HANDLE_CONNECTION] - NEXT LOOP");
+        assert_eq!(send(&mut stream, &["PING"]).await, "+PONG\r\n");
+
+        // Each reply has to be exactly one RESP payload, whatever ECHO was given.
+        for echo in [&["ECHO", "a, b"][..], &["ECHO", "foo", "bar"]] {
+            let reply = send(&mut stream, echo).await;
+            let (_, consumed) = RedisProtocolParser::parse_frame(reply.as_bytes())
+                .unwrap()
+                .unwrap();
+            assert_eq!(consumed, reply.len(), "{}", reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_too_big_inline_request_closes_the_connection() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        // One byte over the limit, so the server has read everything by the time it closes.
+        stream.write_all(&[b'a'; 64 * 1024 + 1]).await.unwrap();
+        let mut reply = vec![];
+        stream.read_to_end(&mut reply).await.unwrap();
+        assert_eq!(reply, b"-ERR Protocol error: too big inline request\r\n");
+        assert_eq!(request(server, &["PING"]).await, "+PONG\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_del_replies_with_removed_count() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "a", "1"]).await;
+        request(server, &["SET", "b", "2"]).await;
+        assert_eq!(request(server, &["DEL", "a", "b", "c"]).await, ":2\r\n");
+        assert_eq!(request(server, &["GET", "a"]).await, "$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_touch_and_unlink() {
+        let server = spawn_server(None).await;
+        for key in ["a", "b", "c"] {
+            request(server, &["SET", key, "1"]).await;
+        }
+        request(server, &["SET", "expiring", "1", "PX", "1"]).await;
+        sleep(Duration::from_millis(10)).await;
+        assert_eq!(request(server, &["TOUCH", "a", "c", "missing"]).await, ":2\r\n");
+        assert_eq!(request(server, &["TOUCH", "expiring"]).await, ":0\r\n");
+
+        let unlink = ["UNLINK", "a", "b", "expiring", "missing"];
+        assert_eq!(request(server, &unlink).await, ":2\r\n");
+        assert_eq!(request(server, &["EXISTS", "a", "b", "c"]).await, ":1\r\n");
+        assert!(request(server, &["UNLINK"]).await.starts_with("-ERR wrong number"));
+    }
+
+    #[tokio::test]
+    async fn test_randomkey() {
+        let server = spawn_server(None).await;
+        assert_eq!(request(server, &["RANDOMKEY"]).await, "$-1\r\n");
+        request(server, &["SET", "only", "1"]).await;
+        assert_eq!(request(server, &["RANDOMKEY"]).await, "$4\r\nonly\r\n");
+        assert!(request(server, &["RANDOMKEY", "x"]).await.starts_with("-ERR wrong number"));
+    }
+
+    #[tokio::test]
+    async fn test_binary_values_round_trip() {
+        let server = spawn_server(None).await;
+        let value = b"\x00\xff\xfe\r\nbin";
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let set = Payload::build_bulk_string_array(vec![b"SET".as_slice(), b"key", value]);
+        stream.write_all(&set.redis_encode()).await.unwrap();
+        let mut buf = [0; 1024];
+        let read_bytes = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..read_bytes], b"+OK\r\n");
+
+        let get = Payload::build_bulk_string_array(vec!["GET", "key"]);
+        stream.write_all(&get.redis_encode()).await.unwrap();
+        let read_bytes = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..read_bytes], b"$8\r\n\x00\xff\xfe\r\nbin\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_append_and_setrange_keep_binary_values() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        assert_eq!(send_bytes(&mut stream, &[b"APPEND", b"key", b"\xff"]).await, b":1\r\n");
+        assert_eq!(send_bytes(&mut stream, &[b"APPEND", b"key", b"\xfe\x00"]).await, b":3\r\n");
+        let reply = send_bytes(&mut stream, &[b"SETRANGE", b"key", b"1", b"\xff"]).await;
+        assert_eq!(reply, b":3\r\n");
+        let reply = send_bytes(&mut stream, &[b"GET", b"key"]).await;
+        assert_eq!(reply, b"$3\r\n\xff\xff\x00\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_command_split_across_writes() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        stream.write_all(b"*2\r\n$4\r\nECHO\r\n$5\r\nhe").await.unwrap();
+        stream.flush().await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        stream.write_all(b"llo\r\n").await.unwrap();
+
+        let mut buf = [0; 1024];
+        let read_bytes = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..read_bytes], b"$5\r\nhello\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_replicas_advertise_their_listening_port() {
+        let master = spawn_server(None).await;
+        let replica = spawn_server(Some(master.to_string())).await;
+
+        let info = request(master, &["INFO", "replication"]).await;
+        assert!(info.contains("connected_slaves:1\n"), "{}", info);
+        let listed = format!("slave0:ip=127.0.0.1,port={},state=online,", replica.port());
+        assert!(info.contains(&listed), "{}", info);
+    }
+
+    #[tokio::test]
+    async fn test_large_values_are_stored_and_replicated() {
+        let master = spawn_server(None).await;
+        let replica = spawn_server(Some(master.to_string())).await;
+        sleep(Duration::from_millis(100)).await;
+
+        let value = "v".repeat(64 * 1024);
+        assert_eq!(request(master, &["SET", "big", &value]).await, "+OK\r\n");
+        sleep(Duration::from_millis(100)).await;
+
+        let expected = format!("${}\r\n{}\r\n", value.len(), value);
+        for server in [master, replica] {
+            let mut stream = TcpStream::connect(server).await.unwrap();
+            let get = Payload::build_bulk_string_array(vec!["GET", "big"]);
+            stream.write_all(&get.redis_encode()).await.unwrap();
+            let mut reply = vec![0; expected.len()];
+            stream.read_exact(&mut reply).await.unwrap();
+            assert_eq!(reply, expected.as_bytes());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ttl_and_pttl() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "volatile", "1", "px", "5500"]).await;
+        request(server, &["SET", "persistent", "1"]).await;
+
+        assert_eq!(request(server, &["TTL", "volatile"]).await, ":5\r\n");
+        let pttl = request(server, &["PTTL", "volatile"]).await;
+        let pttl: i64 = pttl.trim_start_matches(':').trim_end().parse().unwrap();
+        assert!((5000..=5500).contains(&pttl));
+        assert_eq!(request(server, &["TTL", "persistent"]).await, ":-1\r\n");
+        assert_eq!(request(server, &["PTTL", "missing"]).await, ":-2\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_expire_existing_and_missing_keys() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "key", "value"]).await;
+
+        assert_eq!(request(server, &["EXPIRE", "key", "100"]).await, ":1\r\n");
+        assert_eq!(request(server, &["TTL", "key"]).await, ":99\r\n");
+        assert_eq!(request(server, &["PEXPIRE", "missing", "100"]).await, ":0\r\n");
+        assert_eq!(request(server, &["EXISTS", "missing"]).await, ":0\r\n");
+        assert_eq!(request(server, &["EXPIRE", "key", "-1"]).await, ":1\r\n");
+        assert_eq!(request(server, &["GET", "key"]).await, "$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_expire_in_the_past_propagates_a_del() {
+        let master = spawn_server(None).await;
+        let mut replica = TcpStream::connect(master).await.unwrap();
+        send(&mut replica, &["PSYNC", "?", "-1"]).await;
+        sleep(Duration::from_millis(50)).await;
+        request(master, &["SET", "key", "value"]).await;
+        assert_eq!(request(master, &["EXPIRE", "key", "-1"]).await, ":1\r\n");
+
+        // The replica is told to delete the key, rather than to expire it in the past.
+        let set = Payload::build_bulk_string_array(vec!["SET", "key", "value"]).redis_encode();
+        let del = Payload::build_bulk_string_array(vec!["DEL", "key"]).redis_encode();
+        let stream = read_until(&mut replica, &del).await;
+        assert!(stream.contains(&String::from_utf8_lossy(&[set, del].concat()).to_string()));
+        assert_eq!(request(master, &["EXISTS", "key"]).await, ":0\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_expire_flags() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "key", "value"]).await;
+
+        assert_eq!(request(server, &["EXPIRE", "key", "100", "XX"]).await, ":0\r\n");
+        assert_eq!(request(server, &["EXPIRE", "key", "100", "GT"]).await, ":0\r\n");
+        assert_eq!(request(server, &["TTL", "key"]).await, ":-1\r\n");
+        assert_eq!(request(server, &["EXPIRE", "key", "100", "NX"]).await, ":1\r\n");
+        assert_eq!(request(server, &["EXPIRE", "key", "200", "NX"]).await, ":0\r\n");
+        assert_eq!(request(server, &["EXPIRE", "key", "200", "LT"]).await, ":0\r\n");
+        assert_eq!(request(server, &["EXPIRE", "key", "50", "LT"]).await, ":1\r\n");
+        assert_eq!(request(server, &["TTL", "key"]).await, ":49\r\n");
+        assert_eq!(request(server, &["PEXPIRE", "key", "10000", "GT"]).await, ":0\r\n");
+        assert_eq!(request(server, &["PEXPIRE", "key", "80000", "xx", "gt"]).await, ":1\r\n");
+        assert_eq!(request(server, &["TTL", "key"]).await, ":79\r\n");
+
+        let reply = request(server, &["EXPIRE", "key", "10", "NX", "XX"]).await;
+        assert!(reply.starts_with("-ERR NX and XX, GT or LT"), "{}", reply);
+        let reply = request(server, &["EXPIRE", "key", "10", "SOON"]).await;
+        assert_eq!(reply, "-ERR Unsupported option SOON\r\n");
+        assert_eq!(request(server, &["TTL", "key"]).await, ":79\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_expireat_with_absolute_timestamps() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "key", "value"]).await;
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap();
+
+        let at = (now.as_secs() + 100).to_string();
+        assert_eq!(request(server, &["EXPIREAT", "key", &at]).await, ":1\r\n");
+        assert_eq!(request(server, &["TTL", "key"]).await, ":99\r\n");
+        let at = (now.as_millis() + 50_000).to_string();
+        assert_eq!(request(server, &["PEXPIREAT", "key", &at]).await, ":1\r\n");
+        assert_eq!(request(server, &["TTL", "key"]).await, ":49\r\n");
+        assert_eq!(request(server, &["EXPIREAT", "missing", &at]).await, ":0\r\n");
+
+        let past = (now.as_secs() - 10).to_string();
+        assert_eq!(request(server, &["EXPIREAT", "key", &past]).await, ":1\r\n");
+        assert_eq!(request(server, &["EXISTS", "key"]).await, ":0\r\n");
+        assert_eq!(request(server, &["PEXPIREAT", "key", "1"]).await, ":0\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_persist_clears_ttl() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "key", "value", "EX", "100"]).await;
+
+        assert_eq!(request(server, &["PERSIST", "key"]).await, ":1\r\n");
+        assert_eq!(request(server, &["TTL", "key"]).await, ":-1\r\n");
+        assert_eq!(request(server, &["PERSIST", "key"]).await, ":0\r\n");
+        assert_eq!(request(server, &["PERSIST", "missing"]).await, ":0\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_getdel_and_getex() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "key", "value"]).await;
+
+        assert_eq!(request(server, &["GETDEL", "key"]).await, "$5\r\nvalue\r\n");
+        assert_eq!(request(server, &["EXISTS", "key"]).await, ":0\r\n");
+        assert_eq!(request(server, &["GETDEL", "key"]).await, "$-1\r\n");
+
+        request(server, &["SET", "key", "value"]).await;
+        assert_eq!(request(server, &["GETEX", "key", "EX", "100"]).await, "$5\r\nvalue\r\n");
+        assert_eq!(request(server, &["TTL", "key"]).await, ":99\r\n");
+        assert_eq!(request(server, &["GETEX", "key", "PERSIST"]).await, "$5\r\nvalue\r\n");
+        assert_eq!(request(server, &["TTL", "key"]).await, ":-1\r\n");
+        assert_eq!(request(server, &["GETEX", "key"]).await, "$5\r\nvalue\r\n");
+        assert_eq!(request(server, &["GETEX", "key", "NX"]).await, "-ERR syntax error\r\n");
+        assert_eq!(request(server, &["GETEX", "missing", "PX", "100"]).await, "$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_setnx_and_setex() {
+        let server = spawn_server(None).await;
+        assert_eq!(request(server, &["SETNX", "key", "first"]).await, ":1\r\n");
+        assert_eq!(request(server, &["SETNX", "key", "second"]).await, ":0\r\n");
+        assert_eq!(request(server, &["GET", "key"]).await, "$5\r\nfirst\r\n");
+
+        assert_eq!(request(server, &["SETEX", "key", "100", "third"]).await, "+OK\r\n");
+        assert_eq!(request(server, &["GET", "key"]).await, "$5\r\nthird\r\n");
+        assert_eq!(request(server, &["TTL", "key"]).await, ":99\r\n");
+
+        for seconds in ["0", "-5"] {
+            assert_eq!(
+                request(server, &["SETEX", "other", seconds, "value"]).await,
+                "-ERR invalid expire time in 'setex' command\r\n"
+            );
+        }
+        assert!(request(server, &["SETEX", "other", "soon", "value"]).await.starts_with("-ERR"));
+        assert_eq!(request(server, &["EXISTS", "other"]).await, ":0\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_set_options() {
+        let server = spawn_server(None).await;
+        assert_eq!(request(server, &["SET", "key", "1", "XX"]).await, "$-1\r\n");
+        assert_eq!(request(server, &["SET", "key", "1", "NX", "EX", "10"]).await, "+OK\r\n");
+        assert_eq!(request(server, &["SET", "key", "2", "NX"]).await, "$-1\r\n");
+        assert_eq!(request(server, &["SET", "key", "3", "KEEPTTL"]).await, "+OK\r\n");
+        assert_eq!(request(server, &["TTL", "key"]).await, ":9\r\n");
+        assert_eq!(request(server, &["SET", "key", "4"]).await, "+OK\r\n");
+        assert_eq!(request(server, &["TTL", "key"]).await, ":-1\r\n");
+        assert_eq!(
+            request(server, &["SET", "key", "5", "EX", "10", "PX", "10"]).await,
+            "-ERR syntax error\r\n"
+        );
+        assert_eq!(request(server, &["GET", "key"]).await, "$1\r\n4\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_list_commands() {
+        let server = spawn_server(None).await;
+        assert_eq!(request(server, &["RPUSH", "list", "a", "b", "c"]).await, ":3\r\n");
+        assert_eq!(request(server, &["LPUSH", "list", "z"]).await, ":4\r\n");
+        assert_eq!(
+            request(server, &["LRANGE", "list", "-3", "-1"]).await,
+            "*3\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(request(server, &["LPOP", "list"]).await, "$1\r\nz\r\n");
+        assert_eq!(
+            request(server, &["RPOP", "list", "2"]).await,
+            "*2\r\n$1\r\nc\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(request(server, &["LLEN", "list"]).await, ":1\r\n");
+        assert_eq!(request(server, &["RPOP", "missing", "2"]).await, "*-1\r\n");
+        assert_eq!(request(server, &["LPOP", "missing"]).await, "$-1\r\n");
+
+        request(server, &["SET", "string", "value"]).await;
+        assert_eq!(
+            request(server, &["LPUSH", "string", "a"]).await,
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_list_editing_commands() {
+        let server = spawn_server(None).await;
+        request(server, &["RPUSH", "list", "a", "b", "c", "b"]).await;
+
+        assert_eq!(request(server, &["LPOS", "list", "b"]).await, ":1\r\n");
+        assert_eq!(request(server, &["LPOS", "list", "b", "RANK", "-1"]).await, ":3\r\n");
+        assert_eq!(request(server, &["LPOS", "list", "z"]).await, "$-1\r\n");
+        assert_eq!(
+            request(server, &["LPOS", "list", "b", "COUNT", "0"]).await,
+            "*2\r\n:1\r\n:3\r\n"
+        );
+        assert_eq!(request(server, &["LPOS", "list", "z", "COUNT", "2"]).await, "*0\r\n");
+        let reply = request(server, &["LPOS", "list", "b", "RANK", "0"]).await;
+        assert!(reply.starts_with("-ERR RANK can't be zero"), "{}", reply);
+
+        assert_eq!(request(server, &["LINSERT", "list", "BEFORE", "c", "x"]).await, ":5\r\n");
+        assert_eq!(request(server, &["LINSERT", "list", "AFTER", "nope", "x"]).await, ":-1\r\n");
+        assert_eq!(request(server, &["LINSERT", "missing", "AFTER", "a", "x"]).await, ":0\r\n");
+        assert_eq!(request(server, &["LSET", "list", "-1", "d"]).await, "+OK\r\n");
+        assert_eq!(
+            request(server, &["LSET", "list", "5", "e"]).await,
+            "-ERR index out of range\r\n"
+        );
+        assert_eq!(
+            request(server, &["LRANGE", "list", "0", "-1"]).await,
+            "*5\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nx\r\n$1\r\nc\r\n$1\r\nd\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_rpoplpush_and_lmove() {
+        let server = spawn_server(None).await;
+        let replica = spawn_server(Some(server.to_string())).await;
+        request(server, &["RPUSH", "src", "a", "b", "c", "d"]).await;
+        request(server, &["RPUSH", "dst", "x"]).await;
+
+        assert_eq!(request(server, &["RPOPLPUSH", "src", "dst"]).await, "$1\r\nd\r\n");
+        assert_eq!(request(server, &["LMOVE", "src", "dst", "LEFT", "LEFT"]).await, "$1\r\na\r\n");
+        assert_eq!(request(server, &["LMOVE", "src", "dst", "LEFT", "RIGHT"]).await, "$1\r\nb\r\n");
+        assert_eq!(
+            request(server, &["LMOVE", "src", "dst", "RIGHT", "RIGHT"]).await,
+            "$1\r\nc\r\n"
+        );
+        assert_eq!(request(server, &["EXISTS", "src"]).await, ":0\r\n");
+        assert_eq!(request(server, &["RPOPLPUSH", "src", "dst"]).await, "$-1\r\n");
+
+        request(server, &["RPUSH", "dst", "y"]).await;
+        assert_eq!(request(server, &["LMOVE", "dst", "dst", "RIGHT", "LEFT"]).await, "$1\r\ny\r\n");
+        assert_eq!(request(server, &["RPOPLPUSH", "dst", "dst"]).await, "$1\r\nc\r\n");
+        let expected = "*6\r\n$1\r\nc\r\n$1\r\ny\r\n$1\r\na\r\n$1\r\nd\r\n$1\r\nx\r\n$1\r\nb\r\n";
+        assert_eq!(request(server, &["LRANGE", "dst", "0", "-1"]).await, expected);
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(request(replica, &["LRANGE", "dst", "0", "-1"]).await, expected);
+
+        request(server, &["SET", "string", "value"]).await;
+        assert_eq!(
+            request(server, &["RPOPLPUSH", "dst", "string"]).await,
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+        assert_eq!(
+            request(server, &["LMOVE", "dst", "string", "UP", "LEFT"]).await,
+            "-ERR syntax error\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blpop_waits_for_a_push() {
+        let server = spawn_server(None).await;
+        let replica = spawn_server(Some(server.to_string())).await;
+        request(server, &["RPUSH", "ready", "first"]).await;
+        assert_eq!(
+            request(server, &["BLPOP", "empty", "ready", "0"]).await,
+            "*2\r\n$5\r\nready\r\n$5\r\nfirst\r\n"
+        );
+
+        let blocked = tokio::spawn(request(server, &["BRPOP", "empty", "queue", "0"]));
+        sleep(Duration::from_millis(50)).await;
+        assert!(!blocked.is_finished());
+        assert_eq!(request(server, &["LPUSH", "queue", "a", "b"]).await, ":2\r\n");
+        assert_eq!(blocked.await.unwrap(), "*2\r\n$5\r\nqueue\r\n$1\r\na\r\n");
+
+        // Replicas pop the same element, rather than blocking on the command themselves.
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            request(replica, &["LRANGE", "queue", "0", "-1"]).await,
+            "*1\r\n$1\r\nb\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_blpop_timeout() {
+        let server = spawn_server(None).await;
+        let started = std::time::Instant::now();
+        assert_eq!(request(server, &["BLPOP", "empty", "0.1"]).await, "*-1\r\n");
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(
+            request(server, &["BLPOP", "empty", "-1"]).await,
+            "-ERR timeout is negative\r\n"
+        );
+
+        // Inside a transaction the pop never waits.
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        send(&mut stream, &["MULTI"]).await;
+        send(&mut stream, &["BLPOP", "empty", "0"]).await;
+        assert_eq!(send(&mut stream, &["EXEC"]).await, "*1\r\n*-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_hash_commands() {
+        let server = spawn_server(None).await;
+        assert_eq!(request(server, &["HSET", "hash", "a", "1", "b", "2"]).await, ":2\r\n");
+        assert_eq!(request(server, &["HSET", "hash", "a", "3"]).await, ":0\r\n");
+        assert_eq!(request(server, &["HGET", "hash", "a"]).await, "$1\r\n3\r\n");
+        assert_eq!(request(server, &["HGET", "hash", "z"]).await, "$-1\r\n");
+        assert_eq!(request(server, &["HLEN", "hash"]).await, ":2\r\n");
+        assert_eq!(request(server, &["HDEL", "hash", "a"]).await, ":1\r\n");
+        assert_eq!(
+            request(server, &["HGETALL", "hash"]).await,
+            "*2\r\n$1\r\nb\r\n$1\r\n2\r\n"
+        );
+        assert!(request(server, &["HSET", "hash", "a"]).await.starts_with("-ERR"));
+
+        request(server, &["SET", "string", "value"]).await;
+        assert_eq!(
+            request(server, &["HGET", "string", "a"]).await,
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hash_field_expiry() {
+        let server = spawn_server(None).await;
+        let replica = spawn_server(Some(server.to_string())).await;
+        request(server, &["HSET", "hash", "a", "1", "b", "2", "c", "3"]).await;
+
+        let expire = ["HEXPIRE", "hash", "100", "FIELDS", "3", "a", "b", "z"];
+        assert_eq!(request(server, &expire).await, "*3\r\n:1\r\n:1\r\n:-2\r\n");
+        let expire = ["HEXPIRE", "hash", "50", "GT", "FIELDS", "2", "a", "c"];
+        assert_eq!(request(server, &expire).await, "*2\r\n:0\r\n:0\r\n");
+        let ttl = ["HTTL", "hash", "FIELDS", "3", "a", "c", "z"];
+        assert_eq!(request(server, &ttl).await, "*3\r\n:99\r\n:-1\r\n:-2\r\n");
+        let persist = ["HPERSIST", "hash", "FIELDS", "2", "b", "c"];
+        assert_eq!(request(server, &persist).await, "*2\r\n:1\r\n:-1\r\n");
+        let ttl = ["HTTL", "missing", "FIELDS", "1", "a"];
+        assert_eq!(request(server, &ttl).await, "*1\r\n:-2\r\n");
+
+        // A field expires on its own, leaving the rest of the hash, and on replicas too.
+        let expire = ["HEXPIRE", "hash", "1", "FIELDS", "1", "a"];
+        assert_eq!(request(server, &expire).await, "*1\r\n:1\r\n");
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(request(replica, &["HGET", "hash", "a"]).await, "$1\r\n1\r\n");
+        sleep(Duration::from_millis(1000)).await;
+        for server in [server, replica] {
+            assert_eq!(request(server, &["HGET", "hash", "a"]).await, "$-1\r\n");
+            assert_eq!(request(server, &["HLEN", "hash"]).await, ":2\r\n");
+        }
+
+        let expire = ["HEXPIRE", "hash", "0", "FIELDS", "2", "b", "c"];
+        assert_eq!(request(server, &expire).await, "*2\r\n:2\r\n:2\r\n");
+        assert_eq!(request(server, &["EXISTS", "hash"]).await, ":0\r\n");
+
+        let errors: [&[&str]; 3] = [
+            &["HEXPIRE", "hash", "10", "FIELDS", "2", "a"],
+            &["HEXPIRE", "hash", "10", "NX", "XX", "FIELDS", "1", "a"],
+            &["HTTL", "hash", "FIELD", "1", "a"],
+        ];
+        for args in errors {
+            assert!(request(server, args).await.starts_with("-ERR"), "{:?}", args);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_set_commands() {
+        let server = spawn_server(None).await;
+        assert_eq!(request(server, &["SADD", "letters", "a", "b", "a"]).await, ":2\r\n");
+        assert_eq!(request(server, &["SREM", "letters", "a", "z"]).await, ":1\r\n");
+        assert_eq!(request(server, &["SISMEMBER", "letters", "b"]).await, ":1\r\n");
+        assert_eq!(request(server, &["SISMEMBER", "letters", "a"]).await, ":0\r\n");
+        assert_eq!(request(server, &["SCARD", "letters"]).await, ":1\r\n");
+        assert_eq!(
+            request(server, &["SMEMBERS", "letters"]).await,
+            "*1\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(request(server, &["TYPE", "letters"]).await, "+set\r\n");
+
+        request(server, &["SET", "string", "value"]).await;
+        assert_eq!(
+            request(server, &["SADD", "string", "a"]).await,
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_set_algebra_commands() {
+        let server = spawn_server(None).await;
+        request(server, &["SADD", "a", "1", "2", "3", "4"]).await;
+        request(server, &["SADD", "b", "2", "3", "4", "5"]).await;
+        request(server, &["SADD", "c", "3", "4", "6"]).await;
+        let mut members = request(server, &["SINTER", "a", "b", "c"]).await;
+        assert!(members.starts_with("*2\r\n"), "{}", members);
+        members = members.replace("\r\n", " ");
+        assert!(members.contains("$1 3 ") && members.contains("$1 4 "), "{}", members);
+        assert_eq!(request(server, &["SINTER", "a", "missing"]).await, "*0\r\n");
+        assert_eq!(request(server, &["SDIFF", "a", "b", "c"]).await, "*1\r\n$1\r\n1\r\n");
+        assert!(request(server, &["SUNION", "missing", "c"]).await.starts_with("*3\r\n"));
+
+        assert_eq!(request(server, &["SUNIONSTORE", "all", "a", "b", "c"]).await, ":6\r\n");
+        assert_eq!(request(server, &["SCARD", "all"]).await, ":6\r\n");
+        assert_eq!(request(server, &["SINTERSTORE", "a", "a", "c"]).await, ":2\r\n");
+        // An empty result deletes the destination rather than leaving an empty set behind.
+        request(server, &["SET", "dest", "value"]).await;
+        assert_eq!(request(server, &["SDIFFSTORE", "dest", "a", "c"]).await, ":0\r\n");
+        assert_eq!(request(server, &["EXISTS", "dest"]).await, ":0\r\n");
+
+        request(server, &["SET", "string", "value"]).await;
+        assert_eq!(
+            request(server, &["SUNION", "a", "string"]).await,
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+        assert!(request(server, &["SINTERSTORE", "dest"]).await.starts_with("-ERR"));
+    }
+
+    #[tokio::test]
+    async fn test_multi_key_pops() {
+        let server = spawn_server(None).await;
+        request(server, &["RPUSH", "second", "a", "b", "c"]).await;
+        let lmpop = ["LMPOP", "2", "first", "second", "RIGHT", "COUNT", "2"];
+        assert_eq!(
+            request(server, &lmpop).await,
+            "*2\r\n$6\r\nsecond\r\n*2\r\n$1\r\nc\r\n$1\r\nb\r\n"
+        );
+        let lmpop = ["LMPOP", "2", "first", "second", "left"];
+        assert_eq!(
+            request(server, &lmpop).await,
+            "*2\r\n$6\r\nsecond\r\n*1\r\n$1\r\na\r\n"
+        );
+        assert_eq!(request(server, &lmpop).await, "*-1\r\n");
+
+        request(server, &["ZADD", "second", "1", "a", "2", "b", "3", "c"]).await;
+        let zmpop = ["ZMPOP", "2", "first", "second", "MAX", "COUNT", "2"];
+        assert_eq!(
+            request(server, &zmpop).await,
+            "*2\r\n$6\r\nsecond\r\n*2\r\n*2\r\n$1\r\nc\r\n$1\r\n3\r\n\
+             *2\r\n$1\r\nb\r\n$1\r\n2\r\n"
+        );
+        let zmpop = ["ZMPOP", "1", "second", "MIN"];
+        assert_eq!(
+            request(server, &zmpop).await,
+            "*2\r\n$6\r\nsecond\r\n*1\r\n*2\r\n$1\r\na\r\n$1\r\n1\r\n"
+        );
+        assert_eq!(request(server, &zmpop).await, "*-1\r\n");
+
+        let errors: [&[&str]; 5] = [
+            &["LMPOP", "0", "first", "LEFT"],
+            &["LMPOP", "3", "first", "LEFT"],
+            &["LMPOP", "1", "first", "UP"],
+            &["LMPOP", "1", "first", "LEFT", "COUNT", "0"],
+            &["ZMPOP", "1", "first", "LEFT"],
+        ];
+        for args in errors {
+            assert!(request(server, args).await.starts_with("-ERR"), "{:?}", args);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_zadd_rejects_incompatible_flags() {
+        let server = spawn_server(None).await;
+        let incompatible = "-ERR GT, LT, and/or NX options at the same time are not compatible\r\n";
+        for flags in [["GT", "NX"], ["GT", "LT"], ["LT", "NX"]] {
+            let args = [&["ZADD", "scores"], &flags[..], &["1", "a"]].concat();
+            assert_eq!(request(server, &args).await, incompatible, "{:?}", flags);
+        }
+        assert_eq!(request(server, &["EXISTS", "scores"]).await, ":0\r\n");
+
+        // Each flag is fine on its own, as are GT and LT along with XX.
+        for flags in [&["GT"][..], &["LT"], &["NX"], &["XX", "GT"], &["XX", "LT"]] {
+            let args = [&["ZADD", "scores"], flags, &["1", "a"]].concat();
+            assert!(request(server, &args).await.starts_with(':'), "{:?}", flags);
+        }
+        assert_eq!(request(server, &["ZSCORE", "scores", "a"]).await, "$1\r\n1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_sorted_set_commands() {
+        let server = spawn_server(None).await;
+        let add = ["ZADD", "scores", "1", "carol", "1", "alice", "0.5", "bob", "1", "bob"];
+        assert_eq!(request(server, &add).await, ":3\r\n");
+        // Members with equal scores are ordered lexicographically.
+        assert_eq!(
+            request(server, &["ZRANGE", "scores", "0", "-1"]).await,
+            "*3\r\n$5\r\nalice\r\n$3\r\nbob\r\n$5\r\ncarol\r\n"
+        );
+        assert_eq!(
+            request(server, &["ZRANGE", "scores", "1", "1", "WITHSCORES"]).await,
+            "*2\r\n$3\r\nbob\r\n$1\r\n1\r\n"
+        );
+        assert_eq!(request(server, &["ZRANGE", "scores", "5", "9"]).await, "*0\r\n");
+        assert_eq!(request(server, &["ZRANK", "scores", "carol"]).await, ":2\r\n");
+        assert_eq!(request(server, &["ZRANK", "scores", "dave"]).await, "$-1\r\n");
+        assert_eq!(request(server, &["ZSCORE", "scores", "alice"]).await, "$1\r\n1\r\n");
+        assert_eq!(request(server, &["ZCARD", "scores"]).await, ":3\r\n");
+        assert_eq!(request(server, &["TYPE", "scores"]).await, "+zset\r\n");
+
+        let ch = ["ZADD", "scores", "GT", "CH", "0", "alice", "2.5", "bob", "3", "dave"];
+        assert_eq!(request(server, &ch).await, ":2\r\n");
+        let incr = ["ZADD", "scores", "INCR", "-0.5", "bob"];
+        assert_eq!(request(server, &incr).await, "$1\r\n2\r\n");
+        let skipped = ["ZADD", "scores", "NX", "INCR", "1", "bob"];
+        assert_eq!(request(server, &skipped).await, "$-1\r\n");
+        assert_eq!(
+            request(server, &["ZRANGE", "scores", "-2", "-1", "WITHSCORES"]).await,
+            "*4\r\n$3\r\nbob\r\n$1\r\n2\r\n$4\r\ndave\r\n$1\r\n3\r\n"
+        );
+
+        let errors: [&[&str]; 5] = [
+            &["ZADD", "scores", "1", "a", "2"],
+            &["ZADD", "scores", "NX", "XX", "1", "a"],
+            &["ZADD", "scores", "INCR", "1", "a", "2", "b"],
+            &["ZADD", "scores", "one", "a"],
+            &["ZRANGE", "scores", "0", "-1", "LIMIT"],
+        ];
+        for args in errors {
+            assert!(request(server, args).await.starts_with("-ERR"), "{:?}", args);
+        }
+        request(server, &["SET", "string", "value"]).await;
+        assert_eq!(
+            request(server, &["ZADD", "string", "1", "a"]).await,
+            "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sorted_set_aggregates() {
+        let server = spawn_server(None).await;
+        request(server, &["ZADD", "a", "1", "x", "2", "y", "3", "z"]).await;
+        request(server, &["ZADD", "b", "4", "y", "1", "w"]).await;
+
+        let union = [
+            "ZUNION", "2", "a", "b", "WEIGHTS", "3", "1", "AGGREGATE", "MAX", "WITHSCORES",
+        ];
+        assert_eq!(
+            request(server, &union).await,
+            "*8\r\n$1\r\nw\r\n$1\r\n1\r\n$1\r\nx\r\n$1\r\n3\r\n\
+             $1\r\ny\r\n$1\r\n6\r\n$1\r\nz\r\n$1\r\n9\r\n"
+        );
+        assert_eq!(
+            request(server, &["ZINTER", "2", "a", "b", "WITHSCORES"]).await,
+            "*2\r\n$1\r\ny\r\n$1\r\n6\r\n"
+        );
+        assert_eq!(
+            request(server, &["ZDIFF", "2", "a", "b"]).await,
+            "*2\r\n$1\r\nx\r\n$1\r\nz\r\n"
+        );
+        assert_eq!(request(server, &["ZINTER", "2", "a", "missing"]).await, "*0\r\n");
+
+        let replica = spawn_server(Some(server.to_string())).await;
+        request(server, &["SADD", "set", "y", "v"]).await;
+        let store = ["ZUNIONSTORE", "u", "2", "a", "b", "WEIGHTS", "2", "1"];
+        assert_eq!(request(server, &store).await, ":4\r\n");
+        assert_eq!(request(server, &["ZSCORE", "u", "y"]).await, "$1\r\n8\r\n");
+        let store = ["ZINTERSTORE", "i", "2", "a", "set", "AGGREGATE", "MIN"];
+        assert_eq!(request(server, &store).await, ":1\r\n");
+        assert_eq!(request(server, &["ZSCORE", "i", "y"]).await, "$1\r\n1\r\n");
+        request(server, &["SET", "d", "value"]).await;
+        assert_eq!(request(server, &["ZDIFFSTORE", "d", "2", "a", "a"]).await, ":0\r\n");
+        assert_eq!(request(server, &["EXISTS", "d"]).await, ":0\r\n");
+        let store = ["ZUNIONSTORE", "u", "1", "a", "WITHSCORES"];
+        assert_eq!(request(server, &store).await, "-ERR syntax error\r\n");
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(request(replica, &["ZSCORE", "u", "y"]).await, "$1\r\n8\r\n");
+        assert_eq!(request(replica, &["ZCARD", "i"]).await, ":1\r\n");
+        assert_eq!(
+            request(server, &["ZDIFF", "1", "a", "WEIGHTS", "2"]).await,
+            "-ERR syntax error\r\n"
+        );
+        assert_eq!(
+            request(server, &["ZUNION", "0", "a"]).await,
+            "-ERR numkeys should be greater than 0\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_sorted_set_ranges_by_score_and_lex() {
+        let server = spawn_server(None).await;
+        let add = ["ZADD", "scores", "1", "a", "2", "b", "2", "c", "3", "d", "4", "e"];
+        request(server, &add).await;
+        let by_score = |min, max| ["ZRANGEBYSCORE", "scores", min, max];
+        assert_eq!(
+            request(server, &by_score("(1", "(4")).await,
+            "*3\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n"
+        );
+        assert_eq!(request(server, &by_score("(2", "3")).await, "*1\r\n$1\r\nd\r\n");
+        assert_eq!(request(server, &by_score("(3", "(3")).await, "*0\r\n");
+        let limited = ["ZRANGEBYSCORE", "scores", "-inf", "+inf", "WITHSCORES", "LIMIT", "1", "2"];
+        assert_eq!(
+            request(server, &limited).await,
+            "*4\r\n$1\r\nb\r\n$1\r\n2\r\n$1\r\nc\r\n$1\r\n2\r\n"
+        );
+        let unlimited = ["ZRANGEBYSCORE", "scores", "2", "+inf", "LIMIT", "2", "-1"];
+        assert_eq!(
+            request(server, &unlimited).await,
+            "*2\r\n$1\r\nd\r\n$1\r\ne\r\n"
+        );
+        let skipped = ["ZRANGEBYSCORE", "scores", "-inf", "+inf", "LIMIT", "-1", "2"];
+        assert_eq!(request(server, &skipped).await, "*0\r\n");
+        assert!(request(server, &by_score("one", "2")).await.starts_with("-ERR"));
+        let partial = ["ZRANGEBYSCORE", "scores", "0", "1", "LIMIT", "1"];
+        assert!(request(server, &partial).await.starts_with("-ERR syntax error"));
+
+        request(server, &["ZADD", "letters", "0", "c", "0", "a", "0", "bb", "0", "b"]).await;
+        assert_eq!(
+            request(server, &["ZRANGEBYLEX", "letters", "-", "+"]).await,
+            "*4\r\n$1\r\na\r\n$1\r\nb\r\n$2\r\nbb\r\n$1\r\nc\r\n"
+        );
+        assert_eq!(
+            request(server, &["ZRANGEBYLEX", "letters", "(a", "[bb"]).await,
+            "*2\r\n$1\r\nb\r\n$2\r\nbb\r\n"
+        );
+        assert_eq!(request(server, &["ZRANGEBYLEX", "letters", "+", "-"]).await, "*0\r\n");
+        assert!(request(server, &["ZRANGEBYLEX", "letters", "a", "+"]).await.starts_with("-ERR"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_range_commands() {
+        let server = spawn_server(None).await;
+        request(server, &["XADD", "events", "2-0", "temp", "10", "hum", "40"]).await;
+        assert_eq!(
+            request(server, &["XADD", "events", "5-1", "temp", "20"]).await,
+            "$3\r\n5-1\r\n"
+        );
+        request(server, &["XADD", "events", "9-0", "temp", "30"]).await;
+        assert_eq!(request(server, &["XLEN", "events"]).await, ":3\r\n");
+        assert_eq!(
+            request(server, &["XRANGE", "events", "-", "5"]).await,
+            "*2\r\n\
+             *2\r\n$3\r\n2-0\r\n*4\r\n$4\r\ntemp\r\n$2\r\n10\r\n$3\r\nhum\r\n$2\r\n40\r\n\
+             *2\r\n$3\r\n5-1\r\n*2\r\n$4\r\ntemp\r\n$2\r\n20\r\n"
+        );
+        assert_eq!(
+            request(server, &["XRANGE", "events", "6", "+"]).await,
+            "*1\r\n*2\r\n$3\r\n9-0\r\n*2\r\n$4\r\ntemp\r\n$2\r\n30\r\n"
+        );
+        assert_eq!(request(server, &["XRANGE", "missing", "-", "+"]).await, "*0\r\n");
+        assert_eq!(request(server, &["TYPE", "events"]).await, "+stream\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_xadd_assigns_increasing_ids() {
+        let server = spawn_server(None).await;
+        assert_eq!(
+            request(server, &["XADD", "events", "1-*", "temp", "10"]).await,
+            "$3\r\n1-0\r\n"
+        );
+        assert_eq!(
+            request(server, &["XADD", "events", "1-*", "temp", "20"]).await,
+            "$3\r\n1-1\r\n"
+        );
+        for id in ["1-1", "1-0"] {
+            let reply = request(server, &["XADD", "events", id, "temp", "30"]).await;
+            let expected = "-ERR The ID specified in XADD is equal or smaller";
+            assert!(reply.starts_with(expected), "{}", reply);
+        }
+        assert_eq!(
+            request(server, &["XADD", "other", "0-0", "temp", "30"]).await,
+            "-ERR The ID specified in XADD must be greater than 0-0\r\n"
+        );
+        assert_eq!(request(server, &["EXISTS", "other"]).await, ":0\r\n");
+
+        let generated = request(server, &["XADD", "events", "*", "temp", "40"]).await;
+        assert!(generated.ends_with("-0\r\n"), "{}", generated);
+        assert_eq!(request(server, &["XLEN", "events"]).await, ":3\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_save_and_bgsave_write_loadable_rdb_files() {
+        let dir = temp_dir("save");
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        send(&mut stream, &["CONFIG", "SET", "dir", dir.to_str().unwrap()]).await;
+        send(&mut stream, &["SET", "greeting", "hello"]).await;
+        send(&mut stream, &["SET", "session", "token", "EX", "100"]).await;
+        send(&mut stream, &["RPUSH", "list", "a", "b"]).await;
+
+        send(&mut stream, &["SELECT", "2"]).await;
+        send(&mut stream, &["SET", "elsewhere", "value"]).await;
+
+        assert_eq!(send(&mut stream, &["SAVE"]).await, "+OK\r\n");
+        let mut config = Config::default();
+        config.set("dir", dir.to_str().unwrap()).unwrap();
+        let loaded = spawn_configured_server(None, config.clone()).await;
+        assert_eq!(request(loaded, &["DBSIZE"]).await, ":3\r\n");
+        assert_eq!(request(loaded, &["GET", "greeting"]).await, "$5\r\nhello\r\n");
+        assert_eq!(request(loaded, &["GET", "session"]).await, "$5\r\ntoken\r\n");
+        assert!(request(loaded, &["TTL", "session"]).await.starts_with(":9"));
+        assert_eq!(
+            request(loaded, &["LRANGE", "list", "0", "-1"]).await,
+            "*2\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+        let mut selected = TcpStream::connect(loaded).await.unwrap();
+        send(&mut selected, &["SELECT", "2"]).await;
+        assert_eq!(send(&mut selected, &["GET", "elsewhere"]).await, "$5\r\nvalue\r\n");
+
+        send(&mut stream, &["CONFIG", "SET", "dbfilename", "background.rdb"]).await;
+        send(&mut stream, &["SET", "later", "value"]).await;
+        assert_eq!(send(&mut stream, &["BGSAVE"]).await, "+Background saving started\r\n");
+        let path = dir.join("background.rdb");
+        for _ in 0..50 {
+            if path.exists() {
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+        config.set("dbfilename", "background.rdb").unwrap();
+        let loaded = spawn_configured_server(None, config).await;
+        let mut selected = TcpStream::connect(loaded).await.unwrap();
+        send(&mut selected, &["SELECT", "2"]).await;
+        assert_eq!(send(&mut selected, &["DBSIZE"]).await, ":2\r\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_persists_the_dataset() {
+        let dir = temp_dir("shutdown");
+        let mut config = Config::default();
+        config.set("dir", dir.to_str().unwrap()).unwrap();
+        config.set("appendonly", "yes").unwrap();
+
+        let server = spawn_configured_server(None, config.clone()).await;
+        request(server, &["CONFIG", "SET", "appendfsync", "no"]).await;
+        request(server, &["SET", "key", "value"]).await;
+        assert_eq!(request(server, &["SHUTDOWN", "NOW"]).await, "-ERR syntax error\r\n");
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        // The connection closes without a reply once the dataset is on disk.
+        assert_eq!(send(&mut stream, &["SHUTDOWN", "SAVE"]).await, "");
+        sleep(Duration::from_millis(50)).await;
+        assert!(TcpStream::connect(server).await.is_err());
+
+        // Restarted both from the AOF, and from the RDB file.
+        let restarted = spawn_configured_server(None, config.clone()).await;
+        assert_eq!(request(restarted, &["GET", "key"]).await, "$5\r\nvalue\r\n");
+        config.set("appendonly", "no").unwrap();
+        let restarted = spawn_configured_server(None, config.clone()).await;
+        assert_eq!(request(restarted, &["GET", "key"]).await, "$5\r\nvalue\r\n");
+
+        // NOSAVE leaves the RDB file as it was.
+        request(restarted, &["SET", "key", "changed"]).await;
+        request(restarted, &["SHUTDOWN", "NOSAVE"]).await;
+        let restarted = spawn_configured_server(None, config).await;
+        assert_eq!(request(restarted, &["GET", "key"]).await, "$5\r\nvalue\r\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_saves_only_when_persistence_is_configured() {
+        let dir = temp_dir("persistent");
+        let rdb_path = dir.join("dump.rdb");
+        let mut config = Config {
+            dir: dir.to_str().unwrap().to_string(),
+            ..Config::default()
+        };
+
+        let server = spawn_configured_server(None, config.clone()).await;
+        request(server, &["SET", "key", "value"]).await;
+        request(server, &["SHUTDOWN"]).await;
+        assert!(!rdb_path.exists());
+
+        // Once the RDB file is given, it is saved even without being asked to.
+        config.set("dbfilename", "dump.rdb").unwrap();
+        let server = spawn_configured_server(None, config.clone()).await;
+        request(server, &["SET", "key", "value"]).await;
+        request(server, &["SHUTDOWN"]).await;
+        let restarted = spawn_configured_server(None, config).await;
+        assert_eq!(request(restarted, &["GET", "key"]).await, "$5\r\nvalue\r\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_aof_rebuilds_the_store() {
+        let dir = temp_dir("aof");
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        send(&mut stream, &["CONFIG", "SET", "dir", dir.to_str().unwrap()]).await;
+        send(&mut stream, &["SET", "before", "snapshot"]).await;
+        assert_eq!(send(&mut stream, &["CONFIG", "SET", "appendonly", "yes"]).await, "+OK\r\n");
+        send(&mut stream, &["SET", "counter", "1"]).await;
+        send(&mut stream, &["INCR", "counter"]).await;
+        send(&mut stream, &["RPUSH", "list", "a", "b"]).await;
+        send(&mut stream, &["HSET", "hash", "field", "value"]).await;
+        send(&mut stream, &["SET", "gone", "soon"]).await;
+        send(&mut stream, &["DEL", "gone"]).await;
+        send(&mut stream, &["XADD", "stream", "1-1", "field", "value"]).await;
+        send(&mut stream, &["MULTI"]).await;
+        send(&mut stream, &["SET", "transaction", "1"]).await;
+        send(&mut stream, &["INCR", "transaction"]).await;
+        assert_eq!(send(&mut stream, &["EXEC"]).await, "*2\r\n+OK\r\n:2\r\n");
+        // Failed writes change nothing, so they aren't logged.
+        assert!(send(&mut stream, &["INCR", "list"]).await.starts_with("-WRONGTYPE"));
+
+        let mut config = Config::default();
+        config.set("dir", dir.to_str().unwrap()).unwrap();
+        config.set("appendonly", "yes").unwrap();
+        let replayed = spawn_configured_server(None, config).await;
+
+        for command in [
+            &["DBSIZE"][..],
+            &["GET", "before"],
+            &["GET", "counter"],
+            &["LRANGE", "list", "0", "-1"],
+            &["HGETALL", "hash"],
+            &["XLEN", "stream"],
+            &["GET", "transaction"],
+        ] {
+            assert_eq!(request(replayed, command).await, request(server, command).await);
+        }
+        assert_eq!(request(replayed, &["DBSIZE"]).await, ":6\r\n");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_flush_clears_keyspace() {
+        let server = spawn_server(None).await;
+        for key in ["a", "b", "c"] {
+            request(server, &["SET", key, "value"]).await;
+        }
+        request(server, &["SET", "expiring", "value", "PX", "60000"]).await;
+        assert_eq!(request(server, &["DBSIZE"]).await, ":4\r\n");
+        assert_eq!(request(server, &["FLUSHDB"]).await, "+OK\r\n");
+        assert_eq!(request(server, &["DBSIZE"]).await, ":0\r\n");
+
+        request(server, &["SET", "a", "value"]).await;
+        assert_eq!(request(server, &["FLUSHALL", "ASYNC"]).await, "+OK\r\n");
+        assert_eq!(request(server, &["DBSIZE"]).await, ":0\r\n");
+        assert_eq!(request(server, &["FLUSHDB", "LATER"]).await, "-ERR syntax error\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_select_switches_database() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        send(&mut stream, &["SET", "key", "db0"]).await;
+        assert_eq!(send(&mut stream, &["SELECT", "1"]).await, "+OK\r\n");
+        assert_eq!(send(&mut stream, &["GET", "key"]).await, "$-1\r\n");
+        assert_eq!(send(&mut stream, &["DBSIZE"]).await, ":0\r\n");
+        send(&mut stream, &["SET", "key", "db1"]).await;
+        assert_eq!(request(server, &["GET", "key"]).await, "$3\r\ndb0\r\n");
+
+        let out_of_range = "-ERR DB index is out of range\r\n";
+        assert_eq!(send(&mut stream, &["SELECT", "16"]).await, out_of_range);
+        assert_eq!(send(&mut stream, &["SELECT", "-1"]).await, out_of_range);
+        assert!(send(&mut stream, &["SELECT", "one"]).await.starts_with("-ERR value is not"));
+        assert_eq!(send(&mut stream, &["GET", "key"]).await, "$3\r\ndb1\r\n");
+
+        let info = request(server, &["INFO", "keyspace"]).await;
+        assert!(info.contains("db0:keys=1") && info.contains("db1:keys=1"), "{}", info);
+        send(&mut stream, &["FLUSHDB"]).await;
+        assert_eq!(request(server, &["DBSIZE"]).await, ":1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_swapdb_and_move() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        send(&mut stream, &["SET", "moved", "value", "EX", "100"]).await;
+        send(&mut stream, &["SET", "kept", "value"]).await;
+        assert_eq!(send(&mut stream, &["MOVE", "moved", "2"]).await, ":1\r\n");
+        assert_eq!(send(&mut stream, &["MOVE", "missing", "2"]).await, ":0\r\n");
+        assert_eq!(
+            send(&mut stream, &["MOVE", "kept", "0"]).await,
+            "-ERR source and destination objects are the same\r\n"
+        );
+        assert_eq!(send(&mut stream, &["EXISTS", "moved"]).await, ":0\r\n");
+
+        let mut other = TcpStream::connect(server).await.unwrap();
+        send(&mut other, &["SELECT", "2"]).await;
+        assert!(send(&mut other, &["TTL", "moved"]).await.starts_with(":9"));
+        send(&mut other, &["SET", "kept", "db2"]).await;
+        // A key already in the destination stays where it is.
+        assert_eq!(send(&mut stream, &["MOVE", "kept", "2"]).await, ":0\r\n");
+
+        assert_eq!(send(&mut stream, &["SWAPDB", "0", "2"]).await, "+OK\r\n");
+        assert_eq!(send(&mut stream, &["GET", "kept"]).await, "$3\r\ndb2\r\n");
+        assert_eq!(send(&mut other, &["GET", "kept"]).await, "$5\r\nvalue\r\n");
+        assert_eq!(
+            send(&mut stream, &["SWAPDB", "0", "16"]).await,
+            "-ERR DB index is out of range\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_copy() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        send(&mut stream, &["RPUSH", "list", "a", "b"]).await;
+        assert_eq!(send(&mut stream, &["COPY", "list", "copy"]).await, ":1\r\n");
+        // The copy doesn't share anything with its source.
+        send(&mut stream, &["RPUSH", "list", "c"]).await;
+        assert_eq!(
+            send(&mut stream, &["LRANGE", "copy", "0", "-1"]).await,
+            "*2\r\n$1\r\na\r\n$1\r\nb\r\n"
+        );
+        assert_eq!(send(&mut stream, &["COPY", "list", "copy"]).await, ":0\r\n");
+        assert_eq!(send(&mut stream, &["COPY", "list", "copy", "REPLACE"]).await, ":1\r\n");
+        assert_eq!(send(&mut stream, &["LLEN", "copy"]).await, ":3\r\n");
+        assert_eq!(send(&mut stream, &["COPY", "missing", "copy", "REPLACE"]).await, ":0\r\n");
+
+        send(&mut stream, &["SET", "expiring", "value", "EX", "100"]).await;
+        assert_eq!(send(&mut stream, &["COPY", "expiring", "expiring", "DB", "3"]).await, ":1\r\n");
+        let mut other = TcpStream::connect(server).await.unwrap();
+        send(&mut other, &["SELECT", "3"]).await;
+        assert!(send(&mut other, &["TTL", "expiring"]).await.starts_with(":9"));
+        assert_eq!(send(&mut stream, &["EXISTS", "expiring"]).await, ":1\r\n");
+
+        assert_eq!(
+            send(&mut stream, &["COPY", "list", "list"]).await,
+            "-ERR source and destination objects are the same\r\n"
+        );
+        assert!(send(&mut stream, &["COPY", "list", "x", "DB"]).await.starts_with("-ERR syntax"));
+        assert_eq!(
+            send(&mut stream, &["COPY", "list", "x", "DB", "16"]).await,
+            "-ERR DB index is out of range\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_writes_are_propagated_with_their_database() {
+        let master = spawn_server(None).await;
+        let mut replica = TcpStream::connect(master).await.unwrap();
+        send(&mut replica, &["PSYNC", "?", "-1"]).await;
+        sleep(Duration::from_millis(50)).await;
+        let mut stream = TcpStream::connect(master).await.unwrap();
+        send(&mut stream, &["SELECT", "3"]).await;
+        send(&mut stream, &["SET", "foo", "bar"]).await;
+        send(&mut stream, &["DEL", "foo"]).await;
+        request(master, &["SET", "foo", "baz"]).await;
+
+        let expected = [
+            vec!["SELECT", "3"],
+            vec!["SET", "foo", "bar"],
+            vec!["DEL", "foo"],
+            vec!["SELECT", "0"],
+            vec!["SET", "foo", "baz"],
+        ]
+        .map(|command| Payload::build_bulk_string_array(command).redis_encode())
+        .concat();
+        let mut received = Vec::new();
+        while !received.ends_with(&expected) {
+            let mut buf = [0; 1024];
+            let read_bytes = replica.read(&mut buf).await.unwrap();
+            assert!(read_bytes > 0, "{:?}", String::from_utf8_lossy(&received));
+            received.extend_from_slice(&buf[..read_bytes]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transactions_are_propagated_as_a_whole() {
+        let master = spawn_server(None).await;
+        let replica = spawn_server(Some(master.to_string())).await;
+        let mut raw_replica = TcpStream::connect(master).await.unwrap();
+        send(&mut raw_replica, &["PSYNC", "?", "-1"]).await;
+        sleep(Duration::from_millis(50)).await;
+        let mut stream = TcpStream::connect(master).await.unwrap();
+        send(&mut stream, &["MULTI"]).await;
+        send(&mut stream, &["GET", "counter"]).await;
+        send(&mut stream, &["SET", "counter", "1"]).await;
+        send(&mut stream, &["SELECT", "2"]).await;
+        send(&mut stream, &["INCR", "counter"]).await;
+        send(&mut stream, &["EXEC"]).await;
+
+        let expected = [
+            vec!["SELECT", "0"],
+            vec!["MULTI"],
+            vec!["SET", "counter", "1"],
+            vec!["SELECT", "2"],
+            vec!["INCR", "counter"],
+            vec!["EXEC"],
+        ]
+        .map(|command| Payload::build_bulk_string_array(command).redis_encode())
+        .concat();
+        let mut received = Vec::new();
+        while !received.ends_with(&expected) {
+            let mut buf = [0; 1024];
+            let read_bytes = raw_replica.read(&mut buf).await.unwrap();
+            assert!(read_bytes > 0, "{:?}", String::from_utf8_lossy(&received));
+            received.extend_from_slice(&buf[..read_bytes]);
+        }
+
+        // A replica runs the transaction like the master did, rather than failing on EXEC.
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(request(replica, &["GET", "counter"]).await, "$1\r\n1\r\n");
+        let mut stream = TcpStream::connect(replica).await.unwrap();
+        send(&mut stream, &["SELECT", "2"]).await;
+        assert_eq!(send(&mut stream, &["GET", "counter"]).await, "$1\r\n1\r\n");
+        request(master, &["SET", "after", "transaction"]).await;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(request(replica, &["GET", "after"]).await, "$11\r\ntransaction\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_replicas_drop_keys_the_master_expired() {
+        let master = spawn_server(None).await;
+        let replica = spawn_server(Some(master.to_string())).await;
+        sleep(Duration::from_millis(100)).await;
+        request(master, &["SET", "expiring", "1", "PX", "200"]).await;
+        request(master, &["SET", "kept", "2"]).await;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(request(replica, &["DBSIZE"]).await, ":2\r\n");
+
+        sleep(Duration::from_millis(400)).await;
+        assert_eq!(request(master, &["DBSIZE"]).await, ":1\r\n");
+        assert_eq!(request(replica, &["DBSIZE"]).await, ":1\r\n");
+        assert_eq!(request(replica, &["EXISTS", "kept"]).await, ":1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_dbsize_and_info_keyspace() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "a", "1"]).await;
+        request(server, &["SET", "b", "2", "PX", "60000"]).await;
+        request(server, &["SET", "gone", "3", "PX", "1"]).await;
+        sleep(Duration::from_millis(5)).await;
+
+        assert_eq!(request(server, &["DBSIZE"]).await, ":2\r\n");
+        let keyspace = request(server, &["INFO", "keyspace"]).await;
+        assert!(keyspace.contains("# Keyspace\ndb0:keys=2,expires=1,avg_ttl=0"), "{}", keyspace);
+        assert!(!keyspace.contains("# Server"), "{}", keyspace);
+
+        let all = request(server, &["INFO"]).await;
+        for section in ["# Server", "# Replication", "# Stats", "# Keyspace"] {
+            assert!(all.contains(section), "{}", all);
+        }
+        assert!(all.contains("role:master"), "{}", all);
+        assert_eq!(request(server, &["INFO", "nonexistent"]).await, "$0\r\n\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_type_of_every_kind() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "string", "v"]).await;
+        request(server, &["LPUSH", "list", "v"]).await;
+        request(server, &["HSET", "hash", "f", "v"]).await;
+        request(server, &["SADD", "letters", "v"]).await;
+        request(server, &["XADD", "events", "*", "f", "v"]).await;
+
+        for (key, type_name) in [
+            ("string", "string"),
+            ("list", "list"),
+            ("hash", "hash"),
+            ("letters", "set"),
+            ("events", "stream"),
+            ("missing", "none"),
+        ] {
+            assert_eq!(request(server, &["TYPE", key]).await, format!("+{}\r\n", type_name));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_object_encoding() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "number", "42"]).await;
+        request(server, &["SET", "text", "hello"]).await;
+        request(server, &["LPUSH", "list", "a"]).await;
+
+        assert_eq!(request(server, &["OBJECT", "ENCODING", "number"]).await, "$3\r\nint\r\n");
+        assert_eq!(request(server, &["OBJECT", "ENCODING", "text"]).await, "$6\r\nembstr\r\n");
+        assert_eq!(request(server, &["OBJECT", "ENCODING", "list"]).await, "$8\r\nlistpack\r\n");
+
+        // A sorted set leaves its listpack once it has too many members, or too long a member.
+        request(server, &["ZADD", "small", "1", "a"]).await;
+        assert_eq!(request(server, &["OBJECT", "ENCODING", "small"]).await, "$8\r\nlistpack\r\n");
+        let members: Vec<String> =
+            (0..129).flat_map(|i| [i.to_string(), format!("m{}", i)]).collect();
+        let mut zadd = vec!["ZADD", "many"];
+        zadd.extend(members.iter().map(String::as_str));
+        request(server, &zadd).await;
+        assert_eq!(request(server, &["OBJECT", "ENCODING", "many"]).await, "$8\r\nskiplist\r\n");
+        let long = "m".repeat(65);
+        request(server, &["ZADD", "small", "2", &long]).await;
+        assert_eq!(request(server, &["OBJECT", "ENCODING", "small"]).await, "$8\r\nskiplist\r\n");
+        assert_eq!(
+            request(server, &["OBJECT", "ENCODING", "missing"]).await,
+            "-ERR no such key\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_object_encoding_is_kept_across_debug_reload() {
+        let dir = temp_dir("reload");
+        let server = spawn_server(None).await;
+        request(server, &["CONFIG", "SET", "dir", dir.to_str().unwrap()]).await;
+        let many: Vec<String> = (0..129).map(|i| format!("m{}", i)).collect();
+        let long = "m".repeat(65);
+        let mut commands = vec![
+            vec!["SET", "int", "42"],
+            vec!["SET", "embstr", "hello"],
+            vec!["SET", "raw", &long],
+            vec!["HSET", "small-hash", "field", "value"],
+            vec!["HSET", "big-hash", "field", &long],
+            vec!["SADD", "intset", "1", "2"],
+            vec!["SADD", "small-set", "a", "1"],
+            vec!["ZADD", "small-zset", "1", "a"],
+            vec!["ZADD", "big-zset", "1", &long],
+        ];
+        let mut sadd = vec!["SADD", "big-set"];
+        sadd.extend(many.iter().map(String::as_str));
+        commands.push(sadd);
+        for command in &commands {
+            request(server, command).await;
+        }
+
+        let keys = commands.iter().map(|command| command[1]).collect::<Vec<_>>();
+        let mut encodings = Vec::new();
+        for key in &keys {
+            encodings.push(request(server, &["OBJECT", "ENCODING", key]).await);
+        }
+        // int, embstr, raw, listpack, hashtable, intset and skiplist.
+        let distinct = encodings.iter().collect::<std::collections::HashSet<_>>();
+        assert_eq!(distinct.len(), 7, "{:?}", encodings);
+        assert_eq!(request(server, &["DEBUG", "RELOAD"]).await, "+OK\r\n");
+        for (key, encoding) in keys.iter().zip(encodings) {
+            assert_eq!(request(server, &["OBJECT", "ENCODING", key]).await, encoding, "{}", key);
+        }
+        assert_eq!(request(server, &["DBSIZE"]).await, format!(":{}\r\n", keys.len()));
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_debug_sleep_only_blocks_its_connection() {
+        let server = spawn_server(None).await;
+        let mut sleeper = TcpStream::connect(server).await.unwrap();
+        let started = std::time::Instant::now();
+        let sleeping = tokio::spawn(async move {
+            send(&mut sleeper, &["DEBUG", "SLEEP", "0.1"]).await
+        });
+
+        sleep(Duration::from_millis(20)).await;
+        assert_eq!(request(server, &["PING"]).await, "+PONG\r\n");
+        assert!(started.elapsed() < Duration::from_millis(100));
+        assert_eq!(sleeping.await.unwrap(), "+OK\r\n");
+        assert!(started.elapsed() >= Duration::from_millis(100));
+
+        let started = std::time::Instant::now();
+        assert_eq!(request(server, &["DEBUG", "SLEEP", "0.05"]).await, "+OK\r\n");
+        let elapsed = started.elapsed();
+        assert!((Duration::from_millis(50)..Duration::from_millis(500)).contains(&elapsed));
+
+        for invalid in ["-1", "abc"] {
+            let reply = request(server, &["DEBUG", "SLEEP", invalid]).await;
+            assert_eq!(reply, "-ERR value is not a valid float\r\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_debug_object() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "text", "hello"]).await;
+
+        let reply = request(server, &["DEBUG", "OBJECT", "text"]).await;
+        assert!(reply.starts_with("+Value "), "{}", reply);
+        assert!(reply.contains(" encoding:embstr serializedlength:6"), "{}", reply);
+        assert!(!reply.contains("last-id"), "{}", reply);
+        let reply = request(server, &["DEBUG", "OBJECT", "missing"]).await;
+        assert_eq!(reply, "-ERR no such key\r\n");
+
+        // A stream also reports the ID of the entry added last.
+        request(server, &["XADD", "events", "5-*", "a", "1"]).await;
+        request(server, &["XADD", "events", "5-*", "a", "2"]).await;
+        let reply = request(server, &["DEBUG", "OBJECT", "events"]).await;
+        assert!(reply.contains(" encoding:stream "), "{}", reply);
+        assert!(reply.ends_with(" last-id:5-1\r\n"), "{}", reply);
+        let reply = request(server, &["DEBUG", "JMAP"]).await;
+        assert_eq!(reply, "-ERR unknown subcommand 'jmap'\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_keys_command() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "user:1", "a"]).await;
+        request(server, &["SET", "session:1", "b"]).await;
+        assert_eq!(
+            request(server, &["KEYS", "user:*"]).await,
+            "*1\r\n$6\r\nuser:1\r\n"
+        );
+        assert_eq!(
+            request(server, &["KEYS", "s?ssion:[0-9]"]).await,
+            "*1\r\n$9\r\nsession:1\r\n"
+        );
+        assert_eq!(request(server, &["KEYS", "none*"]).await, "*0\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_scan_command() {
+        let server = spawn_server(None).await;
+        request(server, &["SET", "user:1", "a"]).await;
+        request(server, &["SET", "session:1", "b"]).await;
+        assert_eq!(
+            request(server, &["SCAN", "0", "MATCH", "user:*", "COUNT", "100"]).await,
+            "*2\r\n$1\r\n0\r\n*1\r\n$6\r\nuser:1\r\n"
+        );
+        assert_eq!(
+            request(server, &["SCAN", "0", "COUNT", "0"]).await,
+            "-ERR syntax error\r\n"
+        );
+        assert_eq!(
+            request(server, &["SCAN", "x"]).await,
+            "-ERR invalid cursor\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_mset_and_mget() {
+        let server = spawn_server(None).await;
+        assert_eq!(request(server, &["MSET", "a", "1", "b", "22"]).await, "+OK\r\n");
+        assert_eq!(
+            request(server, &["MGET", "a", "missing", "b"]).await,
+            "*3\r\n$1\r\n1\r\n$-1\r\n$2\r\n22\r\n"
+        );
+        assert_eq!(
+            request(server, &["MSET", "a", "1", "b"]).await,
+            "-ERR wrong number of arguments for 'mset' command\r\n"
+        );
+
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let reply = send_bytes(&mut stream, &[b"MSET", b"k", b"\xff\x00", b"l", b"\xfe"]).await;
+        assert_eq!(reply, b"+OK\r\n");
+        let reply = send_bytes(&mut stream, &[b"MGET", b"k", b"l"]).await;
+        assert_eq!(reply, b"*2\r\n$2\r\n\xff\x00\r\n$1\r\n\xfe\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_string_range_commands() {
+        let server = spawn_server(None).await;
+        assert_eq!(request(server, &["APPEND", "greeting", "Hello"]).await, ":5\r\n");
+        assert_eq!(request(server, &["APPEND", "greeting", " World"]).await, ":11\r\n");
+        assert_eq!(request(server, &["STRLEN", "greeting"]).await, ":11\r\n");
+        assert_eq!(
+            request(server, &["GETRANGE", "greeting", "-5", "-1"]).await,
+            "$5\r\nWorld\r\n"
+        );
+        assert_eq!(request(server, &["SETRANGE", "padded", "2", "ab"]).await, ":4\r\n");
+        assert_eq!(request(server, &["GET", "padded"]).await, "$4\r\n\0\0ab\r\n");
+        assert_eq!(
+            request(server, &["SETRANGE", "padded", "-1", "ab"]).await,
+            "-ERR offset is out of range\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_bit_commands() {
+        let server = spawn_server(None).await;
+        assert_eq!(request(server, &["SETBIT", "fresh", "7", "1"]).await, ":0\r\n");
+        assert_eq!(request(server, &["GETBIT", "fresh", "7"]).await, ":1\r\n");
+        assert_eq!(request(server, &["GETBIT", "fresh", "6"]).await, ":0\r\n");
+        assert_eq!(request(server, &["GET", "fresh"]).await, "$1\r\n\x01\r\n");
+        assert_eq!(request(server, &["SETBIT", "fresh", "7", "0"]).await, ":1\r\n");
+        assert_eq!(request(server, &["GETBIT", "missing", "100"]).await, ":0\r\n");
+
+        // 'p' is 0x70, so setting its top bit leaves 0xF0: four bits, all in its upper half.
+        request(server, &["SET", "byte", "p"]).await;
+        request(server, &["SETBIT", "byte", "0", "1"]).await;
+        assert_eq!(request(server, &["BITCOUNT", "byte"]).await, ":4\r\n");
+        assert_eq!(request(server, &["BITCOUNT", "byte", "0", "0", "BYTE"]).await, ":4\r\n");
+        assert_eq!(request(server, &["BITCOUNT", "byte", "2", "5", "BIT"]).await, ":2\r\n");
+        assert_eq!(request(server, &["BITCOUNT", "byte", "1", "-1"]).await, ":0\r\n");
+
+        assert_eq!(
+            request(server, &["SETBIT", "fresh", "-1", "1"]).await,
+            "-ERR bit offset is not an integer or out of range\r\n"
+        );
+        assert_eq!(
+            request(server, &["SETBIT", "fresh", "0", "2"]).await,
+            "-ERR bit is not an integer or out of range\r\n"
+        );
+        assert!(request(server, &["BITCOUNT", "byte", "0"]).await.starts_with("-ERR syntax"));
+        let unit = ["BITCOUNT", "byte", "0", "0", "WORD"];
+        assert!(request(server, &unit).await.starts_with("-ERR syntax"));
+    }
+
+    #[tokio::test]
+    async fn test_wait_times_out_without_replicas() {
+        let server = spawn_server(None).await;
+        let started = std::time::Instant::now();
+        assert_eq!(request(server, &["WAIT", "1", "100"]).await, ":0\r\n");
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(request(server, &["WAIT", "0", "0"]).await, ":0\r\n");
+        assert_eq!(
+            request(server, &["WAIT", "1", "-1"]).await,
+            "-ERR timeout is negative\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wait_counts_replicas_without_pending_writes() {
+        let master = spawn_server(None).await;
+        let _replica = spawn_server(Some(master.to_string())).await;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(request(master, &["WAIT", "1", "500"]).await, ":1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_replicas_resync_when_the_replication_id_changes() {
+        let master = spawn_server(None).await;
+        let replica = spawn_server(Some(master.to_string())).await;
+        request(master, &["SET", "before", "1"]).await;
+        let replid = |info: &str| {
+            let line = info.lines().find(|line| line.starts_with("master_replid:"));
+            line.map(str::to_string)
+        };
+        let old = replid(&request(master, &["INFO", "replication"]).await).unwrap();
+
+        assert_eq!(request(master, &["DEBUG", "CHANGE-REPL-ID"]).await, "+OK\r\n");
+        let info = request(master, &["INFO", "replication"]).await;
+        assert_ne!(replid(&info).unwrap(), old);
+        request(master, &["SET", "during", "2"]).await;
+        sleep(Duration::from_millis(300)).await;
+
+        // The replica synced again, under the new id, and keeps following the stream.
+        let info = request(master, &["INFO", "replication"]).await;
+        assert!(info.contains("connected_slaves:1\n"), "{}", info);
+        request(master, &["SET", "after", "3"]).await;
+        sleep(Duration::from_millis(100)).await;
+        for (key, value) in [("before", "1"), ("during", "2"), ("after", "3")] {
+            assert_eq!(request(replica, &["GET", key]).await, format!("$1\r\n{}\r\n", value));
+        }
+        assert_eq!(
+            request(replica, &["DEBUG", "CHANGE-REPL-ID"]).await,
+            "-ERR CHANGE-REPL-ID can only be used on a master\r\n"
+        );
+    }
+
+    /// Reads from `stream` until `expected` has arrived whole, which can take several reads.
+    async fn read_until(stream: &mut TcpStream, expected: &[u8]) -> String {
+        let mut received = Vec::new();
+        while !received.windows(expected.len()).any(|window| window == expected) {
+            let mut buf = [0; 1024];
+            let read_bytes = stream.read(&mut buf).await.unwrap();
+            assert!(read_bytes > 0, "{}", String::from_utf8_lossy(&received));
+            received.extend_from_slice(&buf[..read_bytes]);
+        }
+        String::from_utf8_lossy(&received).to_string()
+    }
+
+    #[tokio::test]
+    async fn test_reconnecting_replicas_continue_from_the_backlog() {
+        let master = spawn_server(None).await;
+        request(master, &["DEBUG", "CHANGE-REPL-ID"]).await;
+        let field = |info: &str, name: &str| {
+            let value = info.lines().find_map(|line| line.strip_prefix(name));
+            value.unwrap().trim().to_string()
+        };
+        let replid = field(&request(master, &["INFO", "replication"]).await, "master_replid:");
+
+        let mut replica = TcpStream::connect(master).await.unwrap();
+        send(&mut replica, &["PSYNC", "?", "-1"]).await;
+        request(master, &["SET", "a", "1"]).await;
+        let info = request(master, &["INFO", "replication"]).await;
+        let synced: usize = field(&info, "master_repl_offset:").parse().unwrap();
+        drop(replica);
+        sleep(Duration::from_millis(50)).await;
+        request(master, &["SET", "b", "2"]).await;
+
+        // Only the write made while it was away is sent again.
+        let mut replica = TcpStream::connect(master).await.unwrap();
+        let from = (synced + 1).to_string();
+        let psync = Payload::build_bulk_string_array(vec!["PSYNC", &replid, &from]);
+        replica.write_all(&psync.redis_encode()).await.unwrap();
+        let missed = Payload::build_bulk_string_array(vec!["SET", "b", "2"]).redis_encode();
+        let expected = [format!("+CONTINUE {}\r\n", replid).as_bytes(), &missed].concat();
+        assert_eq!(read_until(&mut replica, &expected).await.as_bytes(), expected);
+
+        // Acknowledging the end of the stream leaves no gap for WAIT.
+        let info = request(master, &["INFO", "replication"]).await;
+        assert!(info.contains("connected_slaves:1\n"), "{}", info);
+        let end = field(&info, "master_repl_offset:");
+        assert_eq!(end.parse::<usize>().unwrap(), synced + missed.len());
+        let ack = Payload::build_bulk_string_array(vec!["REPLCONF", "ACK", &end]);
+        replica.write_all(&ack.redis_encode()).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        assert_eq!(request(master, &["WAIT", "1", "100"]).await, ":1\r\n");
+        let info = request(master, &["INFO", "replication"]).await;
+        assert!(info.contains(&format!(",offset={},", end)), "{}", info);
+
+        // Another replication id, or an offset past the stream, needs a full resync.
+        for (id, offset) in [("0123", from.as_str()), (replid.as_str(), "1000000")] {
+            let reply = request(master, &["PSYNC", id, offset]).await;
+            assert!(reply.starts_with("+FULLRESYNC "), "{}", reply);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replica_continues_after_losing_its_link() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let frame = |args: &[&str]| Payload::build_bulk_string_array(args.to_vec()).redis_encode();
+        let set_a = frame(&["SET", "a", "1"]);
+        let set_b = frame(&["SET", "b", "2"]);
+        let getack = frame(&["REPLCONF", "GETACK", "*"]);
+        let snapshot = Payload::RdbFile(rdb::encode([])).redis_encode();
+        /// Answers the replica's handshake, ending with `last`, and returns its PSYNC.
+        async fn handshake(link: &mut TcpStream, last: Vec<u8>) -> String {
+            let replies = [b"+PONG\r\n".to_vec(), b"+OK\r\n".to_vec(), b"+OK\r\n".to_vec(), last];
+            let mut psync = String::new();
+            for reply in replies {
+                let mut buf = [0; 1024];
+                let read_bytes = link.read(&mut buf).await.unwrap();
+                psync = String::from_utf8_lossy(&buf[..read_bytes]).to_string();
+                link.write_all(&reply).await.unwrap();
+            }
+            psync
+        }
+        let fake_master = tokio::spawn({
+            let fullresync = [b"+FULLRESYNC abc 100\r\n".as_slice(), &snapshot, &set_a].concat();
+            let continued = [b"+CONTINUE\r\n".as_slice(), &set_b, &getack].concat();
+            async move {
+                // A full resync and a write, and then the link drops.
+                let (mut link, _) = listener.accept().await.unwrap();
+                handshake(&mut link, fullresync).await;
+                sleep(Duration::from_millis(100)).await;
+                drop(link);
+                let (mut link, _) = listener.accept().await.unwrap();
+                let psync = handshake(&mut link, continued).await;
+                (psync, link)
+            }
+        });
+        let replica = spawn_server(Some(address.to_string())).await;
+        let (psync, mut link) = fake_master.await.unwrap();
+
+        // The replica asks for the first byte it lacks, and counts on from there.
+        let resumed = 100 + set_a.len();
+        let expected = frame(&["PSYNC", "abc", &(resumed + 1).to_string()]);
+        assert_eq!(psync.as_bytes(), expected);
+        let ack = frame(&["REPLCONF", "ACK", &(resumed + set_b.len()).to_string()]);
+        read_until(&mut link, &ack).await;
+        assert_eq!(request(replica, &["GET", "a"]).await, "$1\r\n1\r\n");
+        assert_eq!(request(replica, &["GET", "b"]).await, "$1\r\n2\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_replica_lag_is_the_time_since_its_last_ack() {
+        let master = spawn_server(None).await;
+        let _replica = spawn_server(Some(master.to_string())).await;
+        let mut silent = TcpStream::connect(master).await.unwrap();
+        send(&mut silent, &["PSYNC", "?", "-1"]).await;
+        sleep(Duration::from_millis(2100)).await;
+
+        // The real replica acknowledges once a second, while the other never does.
+        let info = request(master, &["INFO", "replication"]).await;
+        let lags: Vec<u64> = info
+            .lines()
+            .filter(|line| line.starts_with("slave"))
+            .filter_map(|line| line.rsplit_once(",lag=")?.1.parse().ok())
+            .collect();
+        assert_eq!(lags.len(), 2, "{}", info);
+        assert_eq!(lags.iter().filter(|lag| **lag <= 1).count(), 1, "{}", info);
+        assert_eq!(lags.iter().filter(|lag| **lag >= 2).count(), 1, "{}", info);
+
+        // Acknowledging again brings the lag back down.
+        let ack = Payload::build_bulk_string_array(vec!["REPLCONF", "ACK", "0"]);
+        silent.write_all(&ack.redis_encode()).await.unwrap();
+        sleep(Duration::from_millis(50)).await;
+        let info = request(master, &["INFO", "replication"]).await;
+        assert!(!info.contains(",lag=2"), "{}", info);
+    }
+
+    #[tokio::test]
+    async fn test_wait_collects_replica_acks() {
+        let master = spawn_server(None).await;
+        let mut replica = TcpStream::connect(master).await.unwrap();
+        send(&mut replica, &["PSYNC", "?", "-1"]).await;
+        sleep(Duration::from_millis(50)).await;
+        request(master, &["SET", "foo", "bar"]).await;
+
+        let wait = tokio::spawn(request(master, &["WAIT", "1", "2000"]));
+        let mut received = Vec::new();
+        while !String::from_utf8_lossy(&received).contains("GETACK") {
+            let mut buf = [0; 1024];
+            let read_bytes = replica.read(&mut buf).await.unwrap();
+            assert!(read_bytes > 0);
+            received.extend_from_slice(&buf[..read_bytes]);
+        }
+        let ack = Payload::build_bulk_string_array(vec!["REPLCONF", "ACK", "1000"]);
+        replica.write_all(&ack.redis_encode()).await.unwrap();
+
+        assert_eq!(wait.await.unwrap(), ":1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_replica_acknowledges_processed_offset() {
+        let (master, fake_master) = fake_master(vec![]).await;
+        let replicaof = Some(master.to_string());
+        let client = Arc::new(RedisClient::setup_client(replicaof, Config::default()).await);
+        let mut master_stream = fake_master.await.unwrap();
+
+        let set = Payload::build_bulk_string_array(vec!["SET", "foo", "bar"]).redis_encode();
+        let getack =
+            Payload::build_bulk_string_array(vec!["REPLCONF", "GETACK", "*"]).redis_encode();
+        let mut buffer = [set.as_slice(), &getack, &getack].concat();
+        handle_propagation_from_master(&mut buffer, client.clone())
+            .await
+            .unwrap();
+        assert!(buffer.is_empty());
+
+        let expected = [100 + set.len(), 100 + set.len() + getack.len()]
+            .map(|offset| {
+                Payload::build_bulk_string_array(vec!["REPLCONF", "ACK", &offset.to_string()])
+                    .redis_encode()
+            })
+            .concat();
+        let mut received = vec![0; expected.len()];
+        master_stream.read_exact(&mut received).await.unwrap();
+        assert_eq!(String::from_utf8_lossy(&received), String::from_utf8_lossy(&expected));
+    }
+
+    #[tokio::test]
+    async fn test_wait_after_write_reaches_replica() {
+        let master = spawn_server(None).await;
+        let _replica = spawn_server(Some(master.to_string())).await;
+        sleep(Duration::from_millis(100)).await;
+        request(master, &["SET", "foo", "bar"]).await;
+        assert_eq!(request(master, &["WAIT", "1", "1000"]).await, ":1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_info_reports_growing_replication_offset() {
+        let server = spawn_server(None).await;
+        let offset = || async {
+            let info = request(server, &["INFO", "replication"]).await;
+            let line = info
+                .lines()
+                .find_map(|line| line.strip_prefix("master_repl_offset:"))
+                .unwrap();
+            line.parse::<usize>().unwrap()
+        };
+
+        let before = offset().await;
+        request(server, &["SET", "foo", "bar"]).await;
+        request(server, &["SET", "counter", "12345"]).await;
+        let written: usize = [["SET", "foo", "bar"], ["SET", "counter", "12345"]]
+            .map(|command| {
+                Payload::build_bulk_string_array(command.to_vec())
+                    .redis_encode()
+                    .len()
+            })
+            .iter()
+            .sum();
+        // The first write propagated is preceded by a SELECT of its database.
+        let select = client::select_frame(0).len();
+        assert_eq!(offset().await, before + select + written);
+    }
+
+    #[tokio::test]
+    async fn test_del_is_propagated_to_replicas() {
+        let master = spawn_server(None).await;
+        let mut replica = TcpStream::connect(master).await.unwrap();
+        send(&mut replica, &["PSYNC", "?", "-1"]).await;
+        sleep(Duration::from_millis(50)).await;
+        request(master, &["SET", "foo", "bar"]).await;
+        request(master, &["GET", "foo"]).await;
+        assert_eq!(request(master, &["DEL", "foo"]).await, ":1\r\n");
+
+        let expected = [vec!["SET", "foo", "bar"], vec!["DEL", "foo"]]
+            .map(|command| Payload::build_bulk_string_array(command).redis_encode())
+            .concat();
+        // Whatever is left of the RDB snapshot comes first.
+        let mut received = Vec::new();
+        while !received.ends_with(&expected) {
+            let mut buf = [0; 1024];
+            let read_bytes = replica.read(&mut buf).await.unwrap();
+            assert!(read_bytes > 0, "{:?}", String::from_utf8_lossy(&received));
+            received.extend_from_slice(&buf[..read_bytes]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_writes_other_than_set_reach_replicas() {
+        let master = spawn_server(None).await;
+        let replica = spawn_server(Some(master.to_string())).await;
+        sleep(Duration::from_millis(100)).await;
+
+        request(master, &["XADD", "events", "1-1", "temp", "20"]).await;
+        request(master, &["RPUSH", "queue", "a", "b"]).await;
+        request(master, &["SET", "gone", "soon"]).await;
+        request(master, &["DEL", "gone"]).await;
+        request(master, &["INCR", "counter"]).await;
+        assert_eq!(request(master, &["WAIT", "1", "1000"]).await, ":1\r\n");
+
+        assert_eq!(request(replica, &["XLEN", "events"]).await, ":1\r\n");
+        assert_eq!(request(replica, &["LLEN", "queue"]).await, ":2\r\n");
+        assert_eq!(request(replica, &["EXISTS", "gone"]).await, ":0\r\n");
+        assert_eq!(request(replica, &["GET", "counter"]).await, "$1\r\n1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_linsert_is_propagated_to_replicas() {
+        let master = spawn_server(None).await;
+        let replica = spawn_server(Some(master.to_string())).await;
+        sleep(Duration::from_millis(100)).await;
+
+        request(master, &["RPUSH", "list", "a", "c"]).await;
+        assert_eq!(request(master, &["LINSERT", "list", "BEFORE", "c", "b"]).await, ":3\r\n");
+        assert_eq!(request(master, &["LINSERT", "list", "AFTER", "c", "d"]).await, ":4\r\n");
+        assert_eq!(request(master, &["WAIT", "1", "1000"]).await, ":1\r\n");
+
+        assert_eq!(
+            request(replica, &["LRANGE", "list", "0", "-1"]).await,
+            "*4\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_replica_loads_existing_keys_from_snapshot() {
+        let master = spawn_server(None).await;
+        request(master, &["SET", "first", "1"]).await;
+        request(master, &["SET", "second", "2", "PX", "60000"]).await;
+        request(master, &["RPUSH", "queue", "a", "b"]).await;
+
+        let replica = spawn_server(Some(master.to_string())).await;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(request(replica, &["GET", "first"]).await, "$1\r\n1\r\n");
+        assert_eq!(request(replica, &["GET", "second"]).await, "$1\r\n2\r\n");
+        assert_eq!(request(replica, &["LLEN", "queue"]).await, ":2\r\n");
+        let ttl = request(replica, &["PTTL", "second"]).await;
+        assert!(ttl.starts_with(":59") || ttl == ":60000\r\n", "{}", ttl);
+    }
+
+    #[tokio::test]
+    async fn test_replica_frames_snapshot_and_following_commands() {
+        let seeded = RedisType::String(b"1".to_vec());
+        let snapshot = rdb::encode([(0, "seeded", &seeded, None)]);
+        let stream = [
+            Payload::RdbFile(snapshot).redis_encode(),
+            Payload::build_bulk_string_array(vec!["SET", "foo", "bar"]).redis_encode(),
+            Payload::build_bulk_string_array(vec!["SELECT", "4"]).redis_encode(),
+            Payload::build_bulk_string_array(vec!["SET", "elsewhere", "1"]).redis_encode(),
+        ]
+        .concat();
+        let (master, _connection) = fake_master(stream).await;
+
+        let replica = spawn_server(Some(master.to_string())).await;
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(request(replica, &["GET", "seeded"]).await, "$1\r\n1\r\n");
+        assert_eq!(request(replica, &["GET", "foo"]).await, "$3\r\nbar\r\n");
+        assert_eq!(request(replica, &["GET", "elsewhere"]).await, "$-1\r\n");
+        let mut stream = TcpStream::connect(replica).await.unwrap();
+        send(&mut stream, &["SELECT", "4"]).await;
+        assert_eq!(send(&mut stream, &["GET", "elsewhere"]).await, "$1\r\n1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_config_get_and_set() {
+        let server = spawn_server(None).await;
+        assert_eq!(
+            request(server, &["CONFIG", "GET", "dbfilename"]).await,
+            "*2\r\n$10\r\ndbfilename\r\n$8\r\ndump.rdb\r\n"
+        );
+        assert_eq!(request(server, &["CONFIG", "GET", "unknown"]).await, "*0\r\n");
+        assert_eq!(
+            request(server, &["CONFIG", "SET", "maxmemory", "1kb", "appendfsync", "no"]).await,
+            "+OK\r\n"
+        );
+        assert_eq!(
+            request(server, &["CONFIG", "GET", "maxmemory"]).await,
+            "*2\r\n$9\r\nmaxmemory\r\n$4\r\n1024\r\n"
+        );
+        assert!(request(server, &["CONFIG", "SET", "maxmemory", "2kb", "appendonly", "maybe"])
+            .await
+            .starts_with("-ERR"));
+        assert_eq!(
+            request(server, &["CONFIG", "GET", "maxmemory"]).await,
+            "*2\r\n$9\r\nmaxmemory\r\n$4\r\n1024\r\n"
+        );
+        let all = request(server, &["CONFIG", "GET", "*"]).await;
+        assert!(all.starts_with("*12\r\n"), "{}", all);
+    }
+
+    #[tokio::test]
+    async fn test_hello_negotiates_protocol() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+
+        let resp2 = send(&mut stream, &["HELLO"]).await;
+        assert!(resp2.starts_with("*10\r\n$6\r\nserver\r\n$5\r\nredis\r\n"), "{}", resp2);
+        assert!(resp2.contains("$5\r\nproto\r\n:2\r\n"), "{}", resp2);
+
+        let resp3 = send(&mut stream, &["HELLO", "3"]).await;
+        assert!(resp3.starts_with("%5\r\n"), "{}", resp3);
+        assert!(resp3.contains("$5\r\nproto\r\n:3\r\n$4\r\nrole\r\n$6\r\nmaster\r\n"));
+
+        // The version sticks to the connection until it is negotiated again.
+        assert!(send(&mut stream, &["HELLO"]).await.starts_with("%5\r\n"));
+        assert_eq!(
+            send(&mut stream, &["HELLO", "4"]).await,
+            "-NOPROTO unsupported protocol version\r\n"
+        );
+        assert!(send(&mut stream, &["HELLO", "2"]).await.starts_with("*10\r\n"));
+        assert!(request(server, &["HELLO"]).await.contains("proto\r\n:2\r\n"));
+    }
+
+    #[tokio::test]
+    async fn test_resp3_replies_use_resp3_types() {
+        let server = spawn_server(None).await;
+        request(server, &["HSET", "user", "name", "ada"]).await;
+        request(server, &["SADD", "letters", "a"]).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+
+        assert_eq!(
+            send(&mut stream, &["HGETALL", "user"]).await,
+            "*2\r\n$4\r\nname\r\n$3\r\nada\r\n"
+        );
+        assert_eq!(send(&mut stream, &["GET", "missing"]).await, "$-1\r\n");
+
+        send(&mut stream, &["HELLO", "3"]).await;
+        assert_eq!(
+            send(&mut stream, &["HGETALL", "user"]).await,
+            "%1\r\n$4\r\nname\r\n$3\r\nada\r\n"
+        );
+        assert_eq!(send(&mut stream, &["SMEMBERS", "letters"]).await, "~1\r\n$1\r\na\r\n");
+        assert_eq!(send(&mut stream, &["GET", "missing"]).await, "_\r\n");
+        assert_eq!(send(&mut stream, &["MGET", "missing", "user"]).await, "*2\r\n_\r\n_\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_publish_reaches_every_subscriber() {
+        let server = spawn_server(None).await;
+        let mut first = TcpStream::connect(server).await.unwrap();
+        let mut second = TcpStream::connect(server).await.unwrap();
+
+        assert_eq!(
+            send(&mut first, &["SUBSCRIBE", "news"]).await,
+            "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+        );
+        assert_eq!(
+            send(&mut second, &["SUBSCRIBE", "news", "sport"]).await,
+            "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n\
+             *3\r\n$9\r\nsubscribe\r\n$5\r\nsport\r\n:2\r\n"
+        );
+
+        assert_eq!(request(server, &["PUBLISH", "news", "hello"]).await, ":2\r\n");
+        let message = "*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n";
+        for subscriber in [&mut first, &mut second] {
+            let mut buf = [0; 1024];
+            let read_bytes = subscriber.read(&mut buf).await.unwrap();
+            assert_eq!(String::from_utf8_lossy(&buf[..read_bytes]), message);
+        }
+        assert_eq!(request(server, &["PUBLISH", "weather", "rain"]).await, ":0\r\n");
+
+        assert_eq!(
+            send(&mut first, &["UNSUBSCRIBE", "news"]).await,
+            "*3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:0\r\n"
+        );
+        assert_eq!(request(server, &["PUBLISH", "news", "again"]).await, ":1\r\n");
+        // Once unsubscribed from everything, the connection is back to normal.
+        assert_eq!(send(&mut first, &["GET", "missing"]).await, "$-1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_pattern_subscribers_receive_matching_channels() {
+        let server = spawn_server(None).await;
+        let mut pattern = TcpStream::connect(server).await.unwrap();
+        let mut direct = TcpStream::connect(server).await.unwrap();
+
+        assert_eq!(
+            send(&mut pattern, &["PSUBSCRIBE", "news.*"]).await,
+            "*3\r\n$10\r\npsubscribe\r\n$6\r\nnews.*\r\n:1\r\n"
+        );
+        send(&mut direct, &["SUBSCRIBE", "news.tech"]).await;
+
+        assert_eq!(request(server, &["PUBLISH", "news.tech", "rust"]).await, ":2\r\n");
+        let mut buf = [0; 1024];
+        let read_bytes = pattern.read(&mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..read_bytes]),
+            "*4\r\n$8\r\npmessage\r\n$6\r\nnews.*\r\n$9\r\nnews.tech\r\n$4\r\nrust\r\n"
+        );
+        let read_bytes = direct.read(&mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..read_bytes]),
+            "*3\r\n$7\r\nmessage\r\n$9\r\nnews.tech\r\n$4\r\nrust\r\n"
+        );
+        assert_eq!(request(server, &["PUBLISH", "sport.tennis", "ace"]).await, ":0\r\n");
+
+        assert_eq!(
+            send(&mut pattern, &["PUNSUBSCRIBE"]).await,
+            "*3\r\n$12\r\npunsubscribe\r\n$6\r\nnews.*\r\n:0\r\n"
+        );
+        assert_eq!(request(server, &["PUBLISH", "news.tech", "again"]).await, ":1\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_publish_counts_matching_pattern_subscribers() {
+        let server = spawn_server(None).await;
+        let mut direct = TcpStream::connect(server).await.unwrap();
+        let mut dotted = TcpStream::connect(server).await.unwrap();
+        let mut prefixed = TcpStream::connect(server).await.unwrap();
+        send(&mut direct, &["SUBSCRIBE", "news"]).await;
+        send(&mut dotted, &["PSUBSCRIBE", "news.*"]).await;
+        send(&mut prefixed, &["PSUBSCRIBE", "n*"]).await;
+
+        // `news.*` doesn't match `news` itself, so only the channel and `n*` receive it.
+        assert_eq!(request(server, &["PUBLISH", "news", "hello"]).await, ":2\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_subscribed_connection_is_restricted() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        send(&mut stream, &["SUBSCRIBE", "news"]).await;
+
+        let reply = send(&mut stream, &["GET", "key"]).await;
+        assert!(reply.starts_with("-ERR Can't execute 'get'"), "{}", reply);
+        assert_eq!(send(&mut stream, &["PING"]).await, "*2\r\n$4\r\npong\r\n$0\r\n\r\n");
+        let reply = send(&mut stream, &["PING", "foo"]).await;
+        assert_eq!(reply, "*2\r\n$4\r\npong\r\n$3\r\nfoo\r\n");
+        assert_eq!(
+            send(&mut stream, &["UNSUBSCRIBE"]).await,
+            "*3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:0\r\n"
+        );
+        assert_eq!(send(&mut stream, &["PING"]).await, "+PONG\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_subscribe_mode_applies_within_a_pipeline() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        let frame = |args: &[&str]| Payload::build_bulk_string_array(args.to_vec()).redis_encode();
+
+        // The GET is read along with the SUBSCRIBE, but only runs once it has subscribed.
+        let pipeline = [frame(&["SUBSCRIBE", "a"]), frame(&["GET", "foo"])].concat();
+        stream.write_all(&pipeline).await.unwrap();
+        let expected = "*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n\
+             -ERR Can't execute 'get': only (P|S)SUBSCRIBE / (P|S)UNSUBSCRIBE / PING / QUIT / \
+             RESET are allowed in this context\r\n";
+        assert_eq!(read_until(&mut stream, expected.as_bytes()).await, expected);
+
+        // Likewise, unsubscribing lifts the restriction for the rest of the pipeline.
+        let pipeline = [frame(&["UNSUBSCRIBE", "a"]), frame(&["GET", "foo"])].concat();
+        stream.write_all(&pipeline).await.unwrap();
+        let expected = "*3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:0\r\n$-1\r\n";
+        assert_eq!(read_until(&mut stream, expected.as_bytes()).await, expected);
+    }
+
+    #[tokio::test]
+    async fn test_resp3_subscribers_receive_pushes() {
+        let server = spawn_server(None).await;
+        let mut resp3 = TcpStream::connect(server).await.unwrap();
+        let mut resp2 = TcpStream::connect(server).await.unwrap();
+        send(&mut resp3, &["HELLO", "3"]).await;
+
+        assert_eq!(
+            send(&mut resp3, &["SUBSCRIBE", "news"]).await,
+            ">3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+        );
+        assert_eq!(
+            send(&mut resp2, &["SUBSCRIBE", "news"]).await,
+            "*3\r\n$9\r\nsubscribe\r\n$4\r\nnews\r\n:1\r\n"
+        );
+        assert_eq!(request(server, &["PUBLISH", "news", "hello"]).await, ":2\r\n");
+        let mut buf = [0; 1024];
+        let read_bytes = resp3.read(&mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..read_bytes]),
+            ">3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n"
+        );
+        let read_bytes = resp2.read(&mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..read_bytes]),
+            "*3\r\n$7\r\nmessage\r\n$4\r\nnews\r\n$5\r\nhello\r\n"
+        );
+
+        // Upgrading while subscribed turns the messages that follow into pushes.
+        send(&mut resp2, &["HELLO", "3"]).await;
+        request(server, &["PUBLISH", "news", "again"]).await;
+        for subscriber in [&mut resp2, &mut resp3] {
+            let read_bytes = subscriber.read(&mut buf).await.unwrap();
+            assert!(buf[..read_bytes].starts_with(b">3\r\n$7\r\nmessage\r\n"));
+        }
+        assert_eq!(
+            send(&mut resp3, &["UNSUBSCRIBE"]).await,
+            ">3\r\n$11\r\nunsubscribe\r\n$4\r\nnews\r\n:0\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tracking_clients_are_sent_invalidations() {
+        let server = spawn_server(None).await;
+        let mut tracker = TcpStream::connect(server).await.unwrap();
+        send(&mut tracker, &["HELLO", "3"]).await;
+        assert_eq!(send(&mut tracker, &["CLIENT", "TRACKING", "ON"]).await, "+OK\r\n");
+        request(server, &["SET", "key", "1"]).await;
+        send(&mut tracker, &["GET", "key"]).await;
+
+        request(server, &["SET", "key", "2"]).await;
+        let mut buf = [0; 1024];
+        let read_bytes = tracker.read(&mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..read_bytes]),
+            ">2\r\n$10\r\ninvalidate\r\n*1\r\n$3\r\nkey\r\n"
+        );
+        // The key was forgotten with the invalidation, so only reading it again tracks it.
+        request(server, &["SET", "key", "3"]).await;
+        assert_eq!(send(&mut tracker, &["GET", "key"]).await, "$1\r\n3\r\n");
+        request(server, &["FLUSHALL"]).await;
+        let read_bytes = tracker.read(&mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..read_bytes]),
+            ">2\r\n$10\r\ninvalidate\r\n_\r\n"
+        );
+
+        // A RESP2 connection has its invalidations redirected to a subscribed one instead.
+        let mut listener = TcpStream::connect(server).await.unwrap();
+        let id = send(&mut listener, &["CLIENT", "ID"]).await;
+        let id = id.trim_start_matches(':').trim_end();
+        send(&mut listener, &["SUBSCRIBE", "__redis__:invalidate"]).await;
+        let mut redirected = TcpStream::connect(server).await.unwrap();
+        assert_eq!(
+            send(&mut redirected, &["CLIENT", "TRACKING", "on", "REDIRECT", id]).await,
+            "+OK\r\n"
+        );
+        send(&mut redirected, &["MGET", "a", "b"]).await;
+        request(server, &["MSET", "a", "1", "b", "2"]).await;
+        let read_bytes = listener.read(&mut buf).await.unwrap();
+        let message = String::from_utf8_lossy(&buf[..read_bytes]).to_string();
+        let header = "*3\r\n$7\r\nmessage\r\n$20\r\n__redis__:invalidate\r\n*2\r\n";
+        assert!(message.starts_with(header));
+        assert!(message.contains("$1\r\na\r\n") && message.contains("$1\r\nb\r\n"));
+
+        assert_eq!(
+            send(&mut redirected, &["CLIENT", "TRACKING", "on", "REDIRECT", "999"]).await,
+            "-ERR The client ID you want redirect to does not exist\r\n"
+        );
+        assert!(send(&mut redirected, &["CLIENT", "TRACKING", "on", "OPTIN"])
+            .await
+            .starts_with("-ERR"));
+    }
+
+    #[tokio::test]
+    async fn test_broadcasting_clients_are_told_about_matching_keys() {
+        let server = spawn_server(None).await;
+        let mut tracker = TcpStream::connect(server).await.unwrap();
+        send(&mut tracker, &["HELLO", "3"]).await;
+        let tracking = ["CLIENT", "TRACKING", "on", "BCAST", "PREFIX", "user:"];
+        assert_eq!(send(&mut tracker, &tracking).await, "+OK\r\n");
+
+        // Keys it never read are invalidated too, as long as they match a prefix.
+        request(server, &["SET", "other", "1"]).await;
+        request(server, &["SET", "user:1", "ada"]).await;
+        let mut buf = [0; 1024];
+        let read_bytes = tracker.read(&mut buf).await.unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&buf[..read_bytes]),
+            ">2\r\n$10\r\ninvalidate\r\n*1\r\n$6\r\nuser:1\r\n"
+        );
+        assert_eq!(send(&mut tracker, &["CLIENT", "TRACKING", "off"]).await, "+OK\r\n");
+        assert_eq!(
+            send(&mut tracker, &["CLIENT", "TRACKING", "on", "PREFIX", "user:"]).await,
+            "-ERR PREFIX option requires BCAST mode to be enabled\r\n"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hello_and_reset_are_allowed_while_subscribed() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+        send(&mut stream, &["SUBSCRIBE", "news"]).await;
+        assert!(send(&mut stream, &["GET", "key"]).await.starts_with("-ERR Can't execute"));
+
+        // RESP3 has messages pushed apart from replies, which lifts the restriction.
+        assert!(send(&mut stream, &["HELLO", "3"]).await.starts_with("%5\r\n"));
+        assert_eq!(send(&mut stream, &["GET", "key"]).await, "_\r\n");
+        assert_eq!(send(&mut stream, &["PING"]).await, "+PONG\r\n");
+        assert!(send(&mut stream, &["HELLO", "2"]).await.starts_with("*10\r\n"));
+        assert!(send(&mut stream, &["GET", "key"]).await.starts_with("-ERR Can't execute"));
+
+        send(&mut stream, &["SELECT", "1"]).await;
+        send(&mut stream, &["MULTI"]).await;
+        assert_eq!(send(&mut stream, &["RESET"]).await, "+RESET\r\n");
+        assert_eq!(request(server, &["PUBLISH", "news", "hello"]).await, ":0\r\n");
+        request(server, &["SET", "key", "db0"]).await;
+        assert_eq!(send(&mut stream, &["GET", "key"]).await, "$3\r\ndb0\r\n");
+        assert_eq!(send(&mut stream, &["EXEC"]).await, "-ERR EXEC without MULTI\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_transaction_runs_queued_commands() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+
+        assert_eq!(send(&mut stream, &["MULTI"]).await, "+OK\r\n");
+        assert_eq!(send(&mut stream, &["SET", "counter", "41"]).await, "+QUEUED\r\n");
+        assert_eq!(send(&mut stream, &["INCR", "counter"]).await, "+QUEUED\r\n");
+        // Nothing runs before EXEC.
+        assert_eq!(request(server, &["GET", "counter"]).await, "$-1\r\n");
+        assert_eq!(send(&mut stream, &["EXEC"]).await, "*2\r\n+OK\r\n:42\r\n");
+        assert_eq!(request(server, &["GET", "counter"]).await, "$2\r\n42\r\n");
+
+        assert_eq!(send(&mut stream, &["EXEC"]).await, "-ERR EXEC without MULTI\r\n");
+        assert_eq!(send(&mut stream, &["DISCARD"]).await, "-ERR DISCARD without MULTI\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_transaction_errors() {
+        let server = spawn_server(None).await;
+        let mut stream = TcpStream::connect(server).await.unwrap();
+
+        // A command that fails while running doesn't stop the others.
+        send(&mut stream, &["MULTI"]).await;
+        send(&mut stream, &["LPUSH", "list", "a"]).await;
+        send(&mut stream, &["INCR", "list"]).await;
+        send(&mut stream, &["LLEN", "list"]).await;
+        assert_eq!(
+            send(&mut stream, &["EXEC"]).await,
+            "*3\r\n:1\r\n\
+             -WRONGTYPE Operation against a key holding the wrong kind of value\r\n:1\r\n"
+        );
+
+        // One that can't be queued aborts the whole transaction.
+        send(&mut stream, &["MULTI"]).await;
+        send(&mut stream, &["SET", "key", "value"]).await;
+        assert!(send(&mut stream, &["GET"]).await.starts_with("-ERR wrong number"));
+        assert!(send(&mut stream, &["NOTACOMMAND"]).await.starts_with("-ERR unknown command"));
+        assert_eq!(
+            send(&mut stream, &["EXEC"]).await,
+            "-EXECABORT Transaction discarded because of previous errors.\r\n"
+        );
+        assert_eq!(request(server, &["GET", "key"]).await, "$-1\r\n");
+
+        send(&mut stream, &["MULTI"]).await;
+        send(&mut stream, &["SET", "key", "value"]).await;
+        assert_eq!(send(&mut stream, &["DISCARD"]).await, "+OK\r\n");
+        assert_eq!(send(&mut stream, &["GET", "key"]).await, "$-1\r\n");
+    }
+
+    #[test]
+    fn test_bind_addresses_are_validated() {
+        let args = Args::try_parse_from(["redis"]).unwrap();
+        assert_eq!(args.bind, vec![IpAddr::from([127, 0, 0, 1])]);
+        let args = Args::try_parse_from(["redis", "--bind", "0.0.0.0", "::1"]).unwrap();
+        assert_eq!(args.bind, vec!["0.0.0.0".parse::<IpAddr>().unwrap(), "::1".parse().unwrap()]);
+        assert!(Args::try_parse_from(["redis", "--bind", "localhost:6379"]).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_serve_accepts_on_every_listener() {
+        let listeners = (0..2)
+            .map(|_| bind_listener("127.0.0.1:0".parse().unwrap(), 511).unwrap())
+            .collect::<Vec<_>>();
+        let addresses = listeners.iter().map(|listener| listener.local_addr().unwrap());
+        let addresses = addresses.collect::<Vec<_>>();
+        let client = Arc::new(RedisClient::setup_client(None, Config::default()).await);
+        tokio::spawn(serve(listeners, client, std::future::pending()));
+
+        for address in addresses {
+            assert_eq!(request(address, &["PING"]).await, "+PONG\r\n");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_stops_accepting_and_closes_connections() {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), 511).unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = Arc::new(RedisClient::setup_client(None, Config::default()).await);
+        let (trigger, shutdown) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(vec![listener], client, async {
+            let _ = shutdown.await;
+        }));
+
+        let mut stream = TcpStream::connect(address).await.unwrap();
+        assert_eq!(send(&mut stream, &["PING"]).await, "+PONG\r\n");
+
+        trigger.send(()).unwrap();
+        tokio::time::timeout(Duration::from_secs(1), server)
+            .await
+            .expect("server should stop once idle connections are closed")
+            .unwrap();
+        assert!(TcpStream::connect(address).await.is_err());
+        assert_eq!(stream.read(&mut [0; 16]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_command_introspection() {
+        let server = spawn_server(None).await;
+        let count = format!(":{}\r\n", Command::ALL.len());
+        assert_eq!(request(server, &["COMMAND", "COUNT"]).await, count);
+        assert_eq!(request(server, &["COMMAND", "DOCS"]).await, "*0\r\n");
+
+        let info = request(server, &["COMMAND"]).await;
+        assert!(info.starts_with(&format!("*{}\r\n", Command::ALL.len())), "{}", info);
+        assert!(info.contains("*2\r\n$3\r\nget\r\n:2\r\n"), "{}", info);
+        assert!(info.contains("*2\r\n$3\r\nset\r\n:-3\r\n"), "{}", info);
+        assert!(info.contains("*2\r\n$3\r\ndel\r\n:-2\r\n"), "{}", info);
+        assert!(info.contains("*2\r\n$4\r\nmset\r\n:-3\r\n"), "{}", info);
+    }
+
+    #[tokio::test]
+    async fn test_client_tracking_replies_ok() {
+        let server = spawn_server(None).await;
+        assert_eq!(request(server, &["CLIENT", "TRACKING", "on"]).await, "+OK\r\n");
+        assert_eq!(request(server, &["CLIENT", "TRACKING", "off"]).await, "+OK\r\n");
+
+        let mut redirected = TcpStream::connect(server).await.unwrap();
+        let id = send(&mut redirected, &["CLIENT", "ID"]).await;
+        let id = id.trim_start_matches(':').trim_end();
+        let tracking = ["CLIENT", "TRACKING", "on", "REDIRECT", id, "BCAST", "PREFIX", "p"];
+        assert_eq!(request(server, &tracking).await, "+OK\r\n");
+        let reply = request(server, &["CLIENT", "TRACKING", "on", "SOMETIMES"]).await;
+        assert_eq!(reply, "-ERR syntax error\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_client_names_ids_and_list() {
+        let server = spawn_server(None).await;
+        let mut first = TcpStream::connect(server).await.unwrap();
+        let mut second = TcpStream::connect(server).await.unwrap();
+        assert_eq!(send(&mut first, &["CLIENT", "GETNAME"]).await, "$-1\r\n");
+        assert_eq!(send(&mut first, &["CLIENT", "SETNAME", "worker"]).await, "+OK\r\n");
+        assert_eq!(send(&mut first, &["CLIENT", "GETNAME"]).await, "$6\r\nworker\r\n");
+        assert_eq!(send(&mut second, &["CLIENT", "GETNAME"]).await, "$-1\r\n");
+        assert!(send(&mut first, &["CLIENT", "SETNAME", "two words"]).await.starts_with("-ERR"));
+        assert_eq!(send(&mut first, &["CLIENT", "GETNAME"]).await, "$6\r\nworker\r\n");
+
+        let id = |reply: String| reply.trim()[1..].parse::<u64>().unwrap();
+        let first_id = id(send(&mut first, &["CLIENT", "ID"]).await);
+        let second_id = id(send(&mut second, &["CLIENT", "ID"]).await);
+        assert!(second_id > first_id);
+
+        let list = send(&mut second, &["CLIENT", "LIST"]).await;
+        assert!(list.contains(&format!("id={} ", first_id)), "{}", list);
+        assert!(list.contains(" name=worker "), "{}", list);
+        drop(first);
+        sleep(Duration::from_millis(50)).await;
+        let list = send(&mut second, &["CLIENT", "LIST"]).await;
+        assert!(!list.contains("name=worker"), "{}", list);
+        assert_eq!(send(&mut second, &["CLIENT", "SETNAME", ""]).await, "+OK\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_chained_replication_reaches_sub_replica() {
+        let master = spawn_server(None).await;
+        let replica = spawn_server(Some(master.to_string())).await;
+        let sub_replica = spawn_server(Some(replica.to_string())).await;
+        sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(request(master, &["SET", "foo", "bar"]).await, "+OK\r\n");
+        sleep(Duration::from_millis(100)).await;
+
+        assert_eq!(request(replica, &["GET", "foo"]).await, "$3\r\nbar\r\n");
+        assert_eq!(request(sub_replica, &["GET", "foo"]).await, "$3\r\nbar\r\n");
+    }
+
+    #[tokio::test]
+    async fn test_rebind_immediately_after_drop() {
+        let listener = bind_listener("127.0.0.1:0".parse().unwrap(), 511).unwrap();
+        let address = listener.local_addr().unwrap();
+
+        let client = TcpStream::connect(address).await.unwrap();
+        let (server_side, _) = listener.accept().await.unwrap();
+        drop(server_side);
+        drop(client);
+        drop(listener);
+
+        let listener = bind_listener(address, 511).unwrap();
+        assert_eq!(listener.local_addr().unwrap(), address);
     }
 }
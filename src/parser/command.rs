@@ -4,7 +4,7 @@ use std::fmt::Display;
 ///
 /// This enum encapsulates all supported commands, providing an easy reference
 /// to all functionality that can be invoked through textual input.
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Command {
     Ping,
     Echo,
@@ -15,9 +15,234 @@ pub enum Command {
     Info,
     ReplConf,
     PSync,
+    BitField,
+    Client,
+    Del,
+    Exists,
+    Incr,
+    Decr,
+    Ttl,
+    Pttl,
+    Expire,
+    Pexpire,
+    Lpush,
+    Rpush,
+    Lpop,
+    Rpop,
+    Lrange,
+    Llen,
+    Hset,
+    Hget,
+    Hgetall,
+    Hdel,
+    Hlen,
+    Sadd,
+    Srem,
+    Smembers,
+    Sismember,
+    Scard,
+    XRange,
+    XLen,
+    Keys,
+    Scan,
+    Mget,
+    Mset,
+    Append,
+    Strlen,
+    Getrange,
+    Setrange,
+    Wait,
+    Config,
+    #[allow(clippy::enum_variant_names)]
+    Command,
+    Hello,
+    Subscribe,
+    Unsubscribe,
+    Publish,
+    PSubscribe,
+    PUnsubscribe,
+    Multi,
+    Exec,
+    Discard,
+    FlushDb,
+    FlushAll,
+    DbSize,
+    Persist,
+    GetDel,
+    GetEx,
+    Object,
+    SetNx,
+    SetEx,
+    Save,
+    BgSave,
+    Select,
+    SwapDb,
+    Move,
+    ExpireAt,
+    PExpireAt,
+    Debug,
+    Lpos,
+    Linsert,
+    Lset,
+    Blpop,
+    Brpop,
+    Zadd,
+    Zscore,
+    Zrange,
+    Zrank,
+    Zcard,
+    ZrangeByScore,
+    ZrangeByLex,
+    Sinter,
+    Sunion,
+    Sdiff,
+    SinterStore,
+    SunionStore,
+    SdiffStore,
+    Copy,
+    Touch,
+    Unlink,
+    RandomKey,
+    Lmpop,
+    Zmpop,
+    Setbit,
+    Getbit,
+    Bitcount,
+    Rpoplpush,
+    Lmove,
+    Zunion,
+    Zinter,
+    Zdiff,
+    ZunionStore,
+    ZinterStore,
+    ZdiffStore,
+    Hexpire,
+    Httl,
+    Hpersist,
+    Reset,
+    Shutdown,
 }
 
 impl Command {
+    /// Every supported command, in declaration order. `COMMAND` and `COMMAND COUNT` report
+    /// from this list.
+    pub const ALL: [Command; 114] = [
+        Self::Ping,
+        Self::Echo,
+        Self::Get,
+        Self::Set,
+        Self::Type,
+        Self::XAdd,
+        Self::Info,
+        Self::ReplConf,
+        Self::PSync,
+        Self::BitField,
+        Self::Client,
+        Self::Del,
+        Self::Exists,
+        Self::Incr,
+        Self::Decr,
+        Self::Ttl,
+        Self::Pttl,
+        Self::Expire,
+        Self::Pexpire,
+        Self::Lpush,
+        Self::Rpush,
+        Self::Lpop,
+        Self::Rpop,
+        Self::Lrange,
+        Self::Llen,
+        Self::Hset,
+        Self::Hget,
+        Self::Hgetall,
+        Self::Hdel,
+        Self::Hlen,
+        Self::Sadd,
+        Self::Srem,
+        Self::Smembers,
+        Self::Sismember,
+        Self::Scard,
+        Self::XRange,
+        Self::XLen,
+        Self::Keys,
+        Self::Scan,
+        Self::Mget,
+        Self::Mset,
+        Self::Append,
+        Self::Strlen,
+        Self::Getrange,
+        Self::Setrange,
+        Self::Wait,
+        Self::Config,
+        Self::Command,
+        Self::Hello,
+        Self::Subscribe,
+        Self::Unsubscribe,
+        Self::Publish,
+        Self::PSubscribe,
+        Self::PUnsubscribe,
+        Self::Multi,
+        Self::Exec,
+        Self::Discard,
+        Self::FlushDb,
+        Self::FlushAll,
+        Self::DbSize,
+        Self::Persist,
+        Self::GetDel,
+        Self::GetEx,
+        Self::Object,
+        Self::SetNx,
+        Self::SetEx,
+        Self::Save,
+        Self::BgSave,
+        Self::Select,
+        Self::SwapDb,
+        Self::Move,
+        Self::ExpireAt,
+        Self::PExpireAt,
+        Self::Debug,
+        Self::Lpos,
+        Self::Linsert,
+        Self::Lset,
+        Self::Blpop,
+        Self::Brpop,
+        Self::Zadd,
+        Self::Zscore,
+        Self::Zrange,
+        Self::Zrank,
+        Self::Zcard,
+        Self::ZrangeByScore,
+        Self::ZrangeByLex,
+        Self::Sinter,
+        Self::Sunion,
+        Self::Sdiff,
+        Self::SinterStore,
+        Self::SunionStore,
+        Self::SdiffStore,
+        Self::Copy,
+        Self::Touch,
+        Self::Unlink,
+        Self::RandomKey,
+        Self::Lmpop,
+        Self::Zmpop,
+        Self::Setbit,
+        Self::Getbit,
+        Self::Bitcount,
+        Self::Rpoplpush,
+        Self::Lmove,
+        Self::Zunion,
+        Self::Zinter,
+        Self::Zdiff,
+        Self::ZunionStore,
+        Self::ZinterStore,
+        Self::ZdiffStore,
+        Self::Hexpire,
+        Self::Httl,
+        Self::Hpersist,
+        Self::Reset,
+        Self::Shutdown,
+    ];
+
     /// Parses a string reference into a corresponding `Command`.
     ///
     /// This method performs a case-insensitive comparison to match the input string
@@ -49,9 +274,352 @@ impl Command {
             "info" => Some(Self::Info),
             "replconf" => Some(Self::ReplConf),
             "psync" => Some(Self::PSync),
+            "bitfield" => Some(Self::BitField),
+            "client" => Some(Self::Client),
+            "del" => Some(Self::Del),
+            "exists" => Some(Self::Exists),
+            "incr" => Some(Self::Incr),
+            "decr" => Some(Self::Decr),
+            "ttl" => Some(Self::Ttl),
+            "pttl" => Some(Self::Pttl),
+            "expire" => Some(Self::Expire),
+            "pexpire" => Some(Self::Pexpire),
+            "lpush" => Some(Self::Lpush),
+            "rpush" => Some(Self::Rpush),
+            "lpop" => Some(Self::Lpop),
+            "rpop" => Some(Self::Rpop),
+            "lrange" => Some(Self::Lrange),
+            "llen" => Some(Self::Llen),
+            "hset" => Some(Self::Hset),
+            "hget" => Some(Self::Hget),
+            "hgetall" => Some(Self::Hgetall),
+            "hdel" => Some(Self::Hdel),
+            "hlen" => Some(Self::Hlen),
+            "sadd" => Some(Self::Sadd),
+            "srem" => Some(Self::Srem),
+            "smembers" => Some(Self::Smembers),
+            "sismember" => Some(Self::Sismember),
+            "scard" => Some(Self::Scard),
+            "xrange" => Some(Self::XRange),
+            "xlen" => Some(Self::XLen),
+            "keys" => Some(Self::Keys),
+            "scan" => Some(Self::Scan),
+            "mget" => Some(Self::Mget),
+            "mset" => Some(Self::Mset),
+            "append" => Some(Self::Append),
+            "strlen" => Some(Self::Strlen),
+            "getrange" => Some(Self::Getrange),
+            "setrange" => Some(Self::Setrange),
+            "wait" => Some(Self::Wait),
+            "config" => Some(Self::Config),
+            "command" => Some(Self::Command),
+            "hello" => Some(Self::Hello),
+            "subscribe" => Some(Self::Subscribe),
+            "unsubscribe" => Some(Self::Unsubscribe),
+            "publish" => Some(Self::Publish),
+            "psubscribe" => Some(Self::PSubscribe),
+            "punsubscribe" => Some(Self::PUnsubscribe),
+            "multi" => Some(Self::Multi),
+            "exec" => Some(Self::Exec),
+            "discard" => Some(Self::Discard),
+            "flushdb" => Some(Self::FlushDb),
+            "flushall" => Some(Self::FlushAll),
+            "dbsize" => Some(Self::DbSize),
+            "persist" => Some(Self::Persist),
+            "getdel" => Some(Self::GetDel),
+            "getex" => Some(Self::GetEx),
+            "object" => Some(Self::Object),
+            "setnx" => Some(Self::SetNx),
+            "setex" => Some(Self::SetEx),
+            "save" => Some(Self::Save),
+            "bgsave" => Some(Self::BgSave),
+            "select" => Some(Self::Select),
+            "swapdb" => Some(Self::SwapDb),
+            "move" => Some(Self::Move),
+            "expireat" => Some(Self::ExpireAt),
+            "pexpireat" => Some(Self::PExpireAt),
+            "debug" => Some(Self::Debug),
+            "lpos" => Some(Self::Lpos),
+            "linsert" => Some(Self::Linsert),
+            "lset" => Some(Self::Lset),
+            "blpop" => Some(Self::Blpop),
+            "brpop" => Some(Self::Brpop),
+            "zadd" => Some(Self::Zadd),
+            "zscore" => Some(Self::Zscore),
+            "zrange" => Some(Self::Zrange),
+            "zrank" => Some(Self::Zrank),
+            "zcard" => Some(Self::Zcard),
+            "zrangebyscore" => Some(Self::ZrangeByScore),
+            "zrangebylex" => Some(Self::ZrangeByLex),
+            "sinter" => Some(Self::Sinter),
+            "sunion" => Some(Self::Sunion),
+            "sdiff" => Some(Self::Sdiff),
+            "sinterstore" => Some(Self::SinterStore),
+            "sunionstore" => Some(Self::SunionStore),
+            "sdiffstore" => Some(Self::SdiffStore),
+            "copy" => Some(Self::Copy),
+            "touch" => Some(Self::Touch),
+            "unlink" => Some(Self::Unlink),
+            "randomkey" => Some(Self::RandomKey),
+            "lmpop" => Some(Self::Lmpop),
+            "zmpop" => Some(Self::Zmpop),
+            "setbit" => Some(Self::Setbit),
+            "getbit" => Some(Self::Getbit),
+            "bitcount" => Some(Self::Bitcount),
+            "rpoplpush" => Some(Self::Rpoplpush),
+            "lmove" => Some(Self::Lmove),
+            "zunion" => Some(Self::Zunion),
+            "zinter" => Some(Self::Zinter),
+            "zdiff" => Some(Self::Zdiff),
+            "zunionstore" => Some(Self::ZunionStore),
+            "zinterstore" => Some(Self::ZinterStore),
+            "zdiffstore" => Some(Self::ZdiffStore),
+            "hexpire" => Some(Self::Hexpire),
+            "httl" => Some(Self::Httl),
+            "hpersist" => Some(Self::Hpersist),
+            "reset" => Some(Self::Reset),
+            "shutdown" => Some(Self::Shutdown),
             _ => None,
         }
     }
+
+    /// The command's arity as reported by `COMMAND`, counting the command name itself. A
+    /// negative arity `-n` means at least `n` arguments.
+    pub fn arity(&self) -> i64 {
+        match self {
+            Self::Multi
+            | Self::Exec
+            | Self::Discard
+            | Self::DbSize
+            | Self::Save
+            | Self::Reset
+            | Self::RandomKey => 1,
+            Self::Ping
+            | Self::Info
+            | Self::ReplConf
+            | Self::Command
+            | Self::Hello
+            | Self::Unsubscribe
+            | Self::PUnsubscribe
+            | Self::FlushDb
+            | Self::FlushAll
+            | Self::Shutdown
+            | Self::BgSave => -1,
+            Self::Echo
+            | Self::Get
+            | Self::Type
+            | Self::Incr
+            | Self::Decr
+            | Self::Ttl
+            | Self::Pttl
+            | Self::Llen
+            | Self::Hgetall
+            | Self::Hlen
+            | Self::Smembers
+            | Self::Scard
+            | Self::Zcard
+            | Self::XLen
+            | Self::Keys
+            | Self::Strlen
+            | Self::Persist
+            | Self::GetDel
+            | Self::Select => 2,
+            Self::Set
+            | Self::PSync
+            | Self::Expire
+            | Self::Pexpire
+            | Self::ExpireAt
+            | Self::PExpireAt
+            | Self::Lpos
+            | Self::Blpop
+            | Self::Brpop
+            | Self::Lpush
+            | Self::Rpush
+            | Self::Hdel
+            | Self::Sadd
+            | Self::Srem
+            | Self::SinterStore
+            | Self::SunionStore
+            | Self::SdiffStore
+            | Self::Copy
+            | Self::Zunion
+            | Self::Zinter
+            | Self::Zdiff
+            | Self::Mset => -3,
+            Self::ZunionStore | Self::ZinterStore | Self::ZdiffStore => -4,
+            Self::XAdd | Self::Httl | Self::Hpersist => -5,
+            Self::Hexpire => -6,
+            Self::BitField
+            | Self::Client
+            | Self::Del
+            | Self::Unlink
+            | Self::Touch
+            | Self::Exists
+            | Self::Lpop
+            | Self::Rpop
+            | Self::Scan
+            | Self::Mget
+            | Self::Config
+            | Self::Subscribe
+            | Self::PSubscribe
+            | Self::GetEx
+            | Self::Object
+            | Self::Debug
+            | Self::Bitcount
+            | Self::Sinter
+            | Self::Sunion
+            | Self::Sdiff => -2,
+            Self::Lrange
+            | Self::Getrange
+            | Self::Setrange
+            | Self::SetEx
+            | Self::Lset
+            | Self::Setbit => 4,
+            Self::Linsert | Self::Lmove => 5,
+            Self::Hset | Self::XRange | Self::Zadd | Self::Zrange => -4,
+            Self::ZrangeByScore | Self::ZrangeByLex | Self::Lmpop | Self::Zmpop => -4,
+            Self::Hget
+            | Self::Sismember
+            | Self::Getbit
+            | Self::Zscore
+            | Self::Zrank
+            | Self::Append
+            | Self::Wait
+            | Self::Publish
+            | Self::SetNx
+            | Self::SwapDb
+            | Self::Move
+            | Self::Rpoplpush => 3,
+        }
+    }
+
+    /// Whether the command may modify the keyspace, and so has to be propagated to replicas.
+    /// New commands that write must be added here, or replicas will silently diverge.
+    pub fn is_write(&self) -> bool {
+        matches!(
+            self,
+            Self::Set
+                | Self::XAdd
+                | Self::BitField
+                | Self::Del
+                | Self::Unlink
+                | Self::Incr
+                | Self::Decr
+                | Self::Expire
+                | Self::Pexpire
+                | Self::ExpireAt
+                | Self::PExpireAt
+                | Self::Lpush
+                | Self::Rpush
+                | Self::Lpop
+                | Self::Rpop
+                | Self::Lmpop
+                | Self::Rpoplpush
+                | Self::Lmove
+                | Self::Linsert
+                | Self::Lset
+                | Self::Hset
+                | Self::Hdel
+                | Self::Hexpire
+                | Self::Hpersist
+                | Self::Sadd
+                | Self::Srem
+                | Self::SinterStore
+                | Self::SunionStore
+                | Self::SdiffStore
+                | Self::ZunionStore
+                | Self::ZinterStore
+                | Self::ZdiffStore
+                | Self::Copy
+                | Self::Zadd
+                | Self::Zmpop
+                | Self::Mset
+                | Self::Append
+                | Self::Setrange
+                | Self::Setbit
+                | Self::FlushDb
+                | Self::FlushAll
+                | Self::Persist
+                | Self::GetDel
+                | Self::GetEx
+                | Self::SetNx
+                | Self::SetEx
+                | Self::SwapDb
+                | Self::Move
+                // Not a write, but replicas relay messages to their own subscribers.
+                | Self::Publish
+        )
+    }
+
+    /// The keys among the command's arguments `args`, which client-side caching tracks when
+    /// they are read and invalidates when they are written.
+    pub fn keys<'a>(&self, args: &'a [String]) -> Vec<&'a String> {
+        // Commands naming a number of keys first, like `ZUNION numkeys key ...`.
+        let counted = |args: &'a [String]| match args.split_first() {
+            Some((count, rest)) => {
+                let count = count.parse().unwrap_or(0).min(rest.len());
+                rest[..count].iter().collect()
+            }
+            None => vec![],
+        };
+        match self {
+            Self::Multi
+            | Self::Exec
+            | Self::Discard
+            | Self::DbSize
+            | Self::Save
+            | Self::BgSave
+            | Self::Reset
+            | Self::RandomKey
+            | Self::Ping
+            | Self::Echo
+            | Self::Info
+            | Self::ReplConf
+            | Self::PSync
+            | Self::Command
+            | Self::Hello
+            | Self::Client
+            | Self::Config
+            | Self::Debug
+            | Self::Select
+            | Self::Keys
+            | Self::Scan
+            | Self::Wait
+            | Self::Publish
+            | Self::Subscribe
+            | Self::PSubscribe
+            | Self::Unsubscribe
+            | Self::PUnsubscribe
+            | Self::FlushDb
+            | Self::FlushAll
+            | Self::SwapDb
+            | Self::Shutdown => vec![],
+            Self::Del
+            | Self::Unlink
+            | Self::Touch
+            | Self::Exists
+            | Self::Mget
+            | Self::Sinter
+            | Self::Sunion
+            | Self::Sdiff
+            | Self::SinterStore
+            | Self::SunionStore
+            | Self::SdiffStore => args.iter().collect(),
+            // The last argument is the timeout.
+            Self::Blpop | Self::Brpop => args[..args.len().saturating_sub(1)].iter().collect(),
+            Self::Mset => args.iter().step_by(2).collect(),
+            Self::Copy | Self::Rpoplpush | Self::Lmove => args.iter().take(2).collect(),
+            Self::Object => args.iter().skip(1).take(1).collect(),
+            Self::Zunion | Self::Zinter | Self::Zdiff | Self::Lmpop | Self::Zmpop => counted(args),
+            Self::ZunionStore | Self::ZinterStore | Self::ZdiffStore => match args.split_first() {
+                Some((destination, rest)) => {
+                    std::iter::once(destination).chain(counted(rest)).collect()
+                }
+                None => vec![],
+            },
+            _ => args.iter().take(1).collect(),
+        }
+    }
 }
 
 impl Display for Command {
@@ -81,6 +649,148 @@ impl Display for Command {
             Self::Info => write!(f, "INFO"),
             Self::ReplConf => write!(f, "REPLCONF"),
             Self::PSync => write!(f, "PSYNC"),
+            Self::BitField => write!(f, "BITFIELD"),
+            Self::Client => write!(f, "CLIENT"),
+            Self::Del => write!(f, "DEL"),
+            Self::Exists => write!(f, "EXISTS"),
+            Self::Incr => write!(f, "INCR"),
+            Self::Decr => write!(f, "DECR"),
+            Self::Ttl => write!(f, "TTL"),
+            Self::Pttl => write!(f, "PTTL"),
+            Self::Expire => write!(f, "EXPIRE"),
+            Self::Pexpire => write!(f, "PEXPIRE"),
+            Self::Lpush => write!(f, "LPUSH"),
+            Self::Rpush => write!(f, "RPUSH"),
+            Self::Lpop => write!(f, "LPOP"),
+            Self::Rpop => write!(f, "RPOP"),
+            Self::Lrange => write!(f, "LRANGE"),
+            Self::Llen => write!(f, "LLEN"),
+            Self::Hset => write!(f, "HSET"),
+            Self::Hget => write!(f, "HGET"),
+            Self::Hgetall => write!(f, "HGETALL"),
+            Self::Hdel => write!(f, "HDEL"),
+            Self::Hlen => write!(f, "HLEN"),
+            Self::Sadd => write!(f, "SADD"),
+            Self::Srem => write!(f, "SREM"),
+            Self::Smembers => write!(f, "SMEMBERS"),
+            Self::Sismember => write!(f, "SISMEMBER"),
+            Self::Scard => write!(f, "SCARD"),
+            Self::XRange => write!(f, "XRANGE"),
+            Self::XLen => write!(f, "XLEN"),
+            Self::Keys => write!(f, "KEYS"),
+            Self::Scan => write!(f, "SCAN"),
+            Self::Mget => write!(f, "MGET"),
+            Self::Mset => write!(f, "MSET"),
+            Self::Append => write!(f, "APPEND"),
+            Self::Strlen => write!(f, "STRLEN"),
+            Self::Getrange => write!(f, "GETRANGE"),
+            Self::Setrange => write!(f, "SETRANGE"),
+            Self::Wait => write!(f, "WAIT"),
+            Self::Config => write!(f, "CONFIG"),
+            Self::Command => write!(f, "COMMAND"),
+            Self::Hello => write!(f, "HELLO"),
+            Self::Subscribe => write!(f, "SUBSCRIBE"),
+            Self::Unsubscribe => write!(f, "UNSUBSCRIBE"),
+            Self::Publish => write!(f, "PUBLISH"),
+            Self::PSubscribe => write!(f, "PSUBSCRIBE"),
+            Self::PUnsubscribe => write!(f, "PUNSUBSCRIBE"),
+            Self::Multi => write!(f, "MULTI"),
+            Self::Exec => write!(f, "EXEC"),
+            Self::Discard => write!(f, "DISCARD"),
+            Self::FlushDb => write!(f, "FLUSHDB"),
+            Self::FlushAll => write!(f, "FLUSHALL"),
+            Self::DbSize => write!(f, "DBSIZE"),
+            Self::Persist => write!(f, "PERSIST"),
+            Self::GetDel => write!(f, "GETDEL"),
+            Self::GetEx => write!(f, "GETEX"),
+            Self::Object => write!(f, "OBJECT"),
+            Self::SetNx => write!(f, "SETNX"),
+            Self::SetEx => write!(f, "SETEX"),
+            Self::Save => write!(f, "SAVE"),
+            Self::BgSave => write!(f, "BGSAVE"),
+            Self::Select => write!(f, "SELECT"),
+            Self::SwapDb => write!(f, "SWAPDB"),
+            Self::Move => write!(f, "MOVE"),
+            Self::ExpireAt => write!(f, "EXPIREAT"),
+            Self::PExpireAt => write!(f, "PEXPIREAT"),
+            Self::Debug => write!(f, "DEBUG"),
+            Self::Lpos => write!(f, "LPOS"),
+            Self::Linsert => write!(f, "LINSERT"),
+            Self::Lset => write!(f, "LSET"),
+            Self::Blpop => write!(f, "BLPOP"),
+            Self::Brpop => write!(f, "BRPOP"),
+            Self::Zadd => write!(f, "ZADD"),
+            Self::Zscore => write!(f, "ZSCORE"),
+            Self::Zrange => write!(f, "ZRANGE"),
+            Self::Zrank => write!(f, "ZRANK"),
+            Self::Zcard => write!(f, "ZCARD"),
+            Self::ZrangeByScore => write!(f, "ZRANGEBYSCORE"),
+            Self::ZrangeByLex => write!(f, "ZRANGEBYLEX"),
+            Self::Sinter => write!(f, "SINTER"),
+            Self::Sunion => write!(f, "SUNION"),
+            Self::Sdiff => write!(f, "SDIFF"),
+            Self::SinterStore => write!(f, "SINTERSTORE"),
+            Self::SunionStore => write!(f, "SUNIONSTORE"),
+            Self::SdiffStore => write!(f, "SDIFFSTORE"),
+            Self::Copy => write!(f, "COPY"),
+            Self::Touch => write!(f, "TOUCH"),
+            Self::Unlink => write!(f, "UNLINK"),
+            Self::RandomKey => write!(f, "RANDOMKEY"),
+            Self::Lmpop => write!(f, "LMPOP"),
+            Self::Zmpop => write!(f, "ZMPOP"),
+            Self::Setbit => write!(f, "SETBIT"),
+            Self::Getbit => write!(f, "GETBIT"),
+            Self::Bitcount => write!(f, "BITCOUNT"),
+            Self::Rpoplpush => write!(f, "RPOPLPUSH"),
+            Self::Lmove => write!(f, "LMOVE"),
+            Self::Zunion => write!(f, "ZUNION"),
+            Self::Zinter => write!(f, "ZINTER"),
+            Self::Zdiff => write!(f, "ZDIFF"),
+            Self::ZunionStore => write!(f, "ZUNIONSTORE"),
+            Self::ZinterStore => write!(f, "ZINTERSTORE"),
+            Self::ZdiffStore => write!(f, "ZDIFFSTORE"),
+            Self::Hexpire => write!(f, "HEXPIRE"),
+            Self::Httl => write!(f, "HTTL"),
+            Self::Hpersist => write!(f, "HPERSIST"),
+            Self::Reset => write!(f, "RESET"),
+            Self::Shutdown => write!(f, "SHUTDOWN"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_keys() {
+        let args = |args: &[&str]| -> Vec<String> { args.iter().map(|s| s.to_string()).collect() };
+        assert_eq!(Command::Get.keys(&args(&["a"])), ["a"]);
+        assert_eq!(Command::Set.keys(&args(&["a", "1", "EX", "10"])), ["a"]);
+        assert_eq!(Command::Mget.keys(&args(&["a", "b"])), ["a", "b"]);
+        assert_eq!(Command::Mset.keys(&args(&["a", "1", "b", "2"])), ["a", "b"]);
+        assert_eq!(Command::Blpop.keys(&args(&["a", "b", "0"])), ["a", "b"]);
+        assert_eq!(Command::Object.keys(&args(&["ENCODING", "a"])), ["a"]);
+        assert_eq!(
+            Command::Zunion.keys(&args(&["2", "a", "b", "WEIGHTS", "1", "2"])),
+            ["a", "b"]
+        );
+        assert_eq!(
+            Command::ZunionStore.keys(&args(&["d", "1", "a", "AGGREGATE", "MAX"])),
+            ["d", "a"]
+        );
+        assert!(Command::Lmpop.keys(&args(&["5", "a"])) == ["a"]);
+        assert!(Command::FlushAll.keys(&args(&["ASYNC"])).is_empty());
+    }
+
+    #[test]
+    fn test_all_lists_every_variant_once() {
+        // Fieldless variants are numbered in declaration order, so a variant missing from
+        // `ALL`, or listed out of order, shows up as a mismatched position.
+        for (index, command) in Command::ALL.iter().enumerate() {
+            assert_eq!(*command as usize, index);
+            assert_eq!(Command::parse(&command.to_string()), Some(*command));
         }
+        assert_eq!(Command::ALL.len(), Command::Shutdown as usize + 1);
     }
 }
@@ -4,6 +4,6 @@ pub mod protocol;
 pub mod traits;
 
 pub use command::Command;
-pub use payload::{Payload, PayloadVec, Value, DELIMITER};
+pub use payload::{Payload, ProtocolVersion, Value, DELIMITER};
 pub use protocol::RedisProtocolParser;
 pub use traits::RedisEncodable;
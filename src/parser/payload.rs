@@ -1,10 +1,13 @@
 pub const DELIMITER: &str = "\r\n";
 const TYPE_SPECIFIER_LEN: usize = 1;
+/// Most elements an aggregate may announce, mirroring Redis' multibulk limit, so a header alone
+/// can't make the server allocate unbounded memory.
+const MAX_AGGREGATE_LEN: usize = 1024 * 1024;
 
 use super::RedisEncodable;
 use crate::parser::Command;
 use anyhow::{anyhow, bail, Context, Result};
-use std::fmt::{Display, Write};
+use std::fmt::Display;
 
 /// Represents the various types of payloads that can be encoded and decoded within the Redis protocol.
 ///
@@ -20,9 +23,22 @@ use std::fmt::{Display, Write};
 /// - `BulkString`: Represents a bulk string in RESP, which is a length-prefixed binary-safe string.
 ///   Begins with '$' followed by the length of the string and "\r\n", then the string itself and another "\r\n".
 ///   This type is used for transmitting potentially large or binary data.
+/// - `Error`: Represents an error reply in RESP, encoded like a simple string but with a leading '-'.
+///   By convention the message starts with an uppercase error code such as `ERR` or `WRONGTYPE`.
+/// - `Integer`: Represents a signed 64-bit integer in RESP, encoded with a leading ':' and terminated
+///   by "\r\n". Used for replies such as counters and lengths.
 /// - `Array`: Represents an array of payloads in RESP, encoded with a leading '*' followed by the number
 ///   of elements in the array and "\r\n", followed by the serialization of each element. Arrays can nest
 ///   other arrays or different types of payloads, facilitating complex data structures or multiple commands.
+/// - `Map`: Represents a RESP3 map, encoded with a leading '%' followed by the number of key-value
+///   pairs and "\r\n", then each key followed by its value. Connections still speaking RESP2 receive
+///   it as a flat array of alternating keys and values, see `redis_encode_for`.
+/// - `SetType`: Represents a RESP3 set, encoded like an array but with a leading '~'. Sent to RESP2
+///   connections as an array.
+/// - `Push`: Represents a RESP3 push, out-of-band data such as pub/sub messages, encoded like an
+///   array but with a leading '>'. Sent to RESP2 connections as an array.
+/// - `Null`: Represents the RESP3 null, "_\r\n". Sent to RESP2 connections as the null bulk string
+///   "$-1\r\n".
 /// - `RdbFile`: Encapsulates raw binary data typically associated with Redis Database (RDB) files or snapshots.
 ///   This variant is not part of standard RESP but is used for handling RDB file transmissions in certain Redis
 ///   replication or persistence scenarios.
@@ -42,7 +58,7 @@ use std::fmt::{Display, Write};
 /// use crate::Payload;
 ///
 /// let data = "$6\r\nfoobar\r\n";
-/// let payload = Payload::BulkString("foobar".to_string());
+/// let payload = Payload::BulkString(b"foobar".to_vec());
 /// assert_eq!(format!("{}", payload), "foobar"); // Using Display trait for BulkString
 /// ```
 ///
@@ -58,15 +74,61 @@ use std::fmt::{Display, Write};
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Payload {
     SimpleString(String),
-    BulkString(String),
+    BulkString(Vec<u8>),
+    Error(String),
+    Integer(i64),
     Array(Vec<Payload>),
+    Map(Vec<(Payload, Payload)>),
+    SetType(Vec<Payload>),
+    Push(Vec<Payload>),
+    Null,
     RdbFile(Vec<u8>),
 }
 
+/// The RESP version a connection speaks, negotiated with `HELLO`. Connections start on RESP2.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ProtocolVersion {
+    #[default]
+    Resp2,
+    Resp3,
+}
+
+impl ProtocolVersion {
+    /// The version number clients pass to `HELLO` and receive back as `proto`.
+    pub fn number(&self) -> i64 {
+        match self {
+            Self::Resp2 => 2,
+            Self::Resp3 => 3,
+        }
+    }
+}
+
 impl Payload {
+    /// Creates a `Payload::Error` from an error message.
+    ///
+    /// Messages that already start with an uppercase error code (e.g. `WRONGTYPE ...`) are kept
+    /// as they are, anything else is prefixed with the generic `ERR` code.
+    ///
+    /// # Examples
+    /// ```
+    /// assert_eq!(Payload::error("syntax error"), Payload::Error("ERR syntax error".to_string()));
+    /// assert_eq!(Payload::error("WRONGTYPE bad"), Payload::Error("WRONGTYPE bad".to_string()));
+    /// ```
+    pub fn error<T: Display>(message: T) -> Self {
+        let message = message.to_string();
+        let has_code = message
+            .split(' ')
+            .next()
+            .is_some_and(|code| !code.is_empty() && code.chars().all(|c| c.is_ascii_uppercase()));
+        if has_code {
+            Payload::Error(message)
+        } else {
+            Payload::Error(format!("ERR {}", message))
+        }
+    }
     /// Creates a `Payload::Array` containing `Payload::BulkString` items from a list of string slices.
     ///
-    /// This method takes a vector of string slices and converts each into a `Payload::BulkString`,
+    /// This method takes a vector of strings or byte slices and converts each into a `Payload::BulkString`,
     /// collecting all of these into a `Payload::Array`. It is useful for constructing complex
     /// Redis commands that involve multiple bulk strings.
     ///
@@ -82,34 +144,70 @@ impl Payload {
     /// let payload = Payload::build_bulk_string_array(commands);
     /// assert!(matches!(payload, Payload::Array(_)));
     /// ```
-    pub fn build_bulk_string_array(strs: Vec<&str>) -> Self {
+    pub fn build_bulk_string_array<T: AsRef<[u8]>>(strs: Vec<T>) -> Self {
         let mut arr = vec![];
         strs.into_iter().for_each(|s| {
-            arr.push(Payload::BulkString(s.to_string()));
+            arr.push(Payload::BulkString(s.as_ref().to_vec()));
         });
         Payload::Array(arr)
     }
-    /// Determines whether the payload represents a command.
-    ///
-    /// This method checks if the payload is a bulk string that corresponds to a known Redis command.
-    /// It is primarily used to identify if payloads received from or prepared for Redis should
-    /// be treated as commands.
-    ///
-    /// # Returns
-    /// - `true` if the payload is a bulk string that matches a known Redis command.
-    /// - `false` otherwise, including for all other payload types.
+    /// Encodes the payload for a connection speaking `version`. Under RESP2 the RESP3-only types
+    /// degrade to their RESP2 equivalents: maps and sets to flat arrays, and nulls to `$-1`.
+    pub fn redis_encode_for(&self, version: ProtocolVersion) -> Vec<u8> {
+        let resp3 = version == ProtocolVersion::Resp3;
+        match self {
+            Payload::SimpleString(value) => format!("+{}{}", value, DELIMITER).into_bytes(),
+            Payload::BulkString(value) => {
+                let mut f = format!("${}{}", value.len(), DELIMITER).into_bytes();
+                f.extend_from_slice(value);
+                f.extend_from_slice(DELIMITER.as_bytes());
+                f
+            }
+            Payload::Error(message) => format!("-{}{}", message, DELIMITER).into_bytes(),
+            Payload::Integer(value) => format!(":{}{}", value, DELIMITER).into_bytes(),
+            Payload::Array(elements) | Payload::SetType(elements) | Payload::Push(elements) => {
+                let prefix = match self {
+                    Payload::SetType(_) if resp3 => '~',
+                    Payload::Push(_) if resp3 => '>',
+                    _ => '*',
+                };
+                let mut f = format!("{}{}{}", prefix, elements.len(), DELIMITER).into_bytes();
+                for item in elements {
+                    f.extend(item.redis_encode_for(version));
+                }
+                f
+            }
+            Payload::Map(pairs) => {
+                let mut f = if resp3 {
+                    format!("%{}{}", pairs.len(), DELIMITER)
+                } else {
+                    format!("*{}{}", pairs.len() * 2, DELIMITER)
+                }
+                .into_bytes();
+                for (key, value) in pairs {
+                    f.extend(key.redis_encode_for(version));
+                    f.extend(value.redis_encode_for(version));
+                }
+                f
+            }
+            Payload::Null if resp3 => format!("_{}", DELIMITER).into_bytes(),
+            Payload::Null => format!("$-1{}", DELIMITER).into_bytes(),
+            Payload::RdbFile(data) => {
+                let mut f = format!("${}{}", data.len(), DELIMITER).into_bytes();
+                f.extend_from_slice(data);
+                f
+            }
+        }
+    }
+    /// Returns the raw content of the payload, without any RESP framing.
     ///
-    /// # Examples
-    /// ```
-    /// let payload = Payload::BulkString("SET".to_string());
-    /// assert!(payload.is_command());
-    /// ```
-    pub fn is_command(&self) -> bool {
+    /// Unlike `to_string`, this keeps the exact bytes of a bulk string, so it must be used
+    /// wherever a value has to stay binary-safe.
+    pub fn to_bytes(&self) -> Vec<u8> {
         match self {
-            Self::BulkString(value) => Command::parse(value).is_some(),
-            _ => false
+            Self::BulkString(value) | Self::RdbFile(value) => value.clone(),
+            _ => self.to_string().into_bytes(),
         }
-
     }
     /// Extracts command and value content from the payload, handling command identification.
     ///
@@ -130,7 +228,7 @@ impl Payload {
     ///
     /// # Examples
     /// ```
-    /// let payload = Payload::BulkString("GET key".to_string());
+    /// let payload = Payload::BulkString(b"GET key".to_vec());
     /// let (command, value) = payload.retrieve_content().unwrap();
     /// assert_eq!(command, Some(Command::Get));
     /// assert_eq!(value, Value::String("key".to_string()));
@@ -138,13 +236,14 @@ impl Payload {
     pub fn retrieve_content(self) -> Result<(Option<Command>, Value)> {
         match self {
             Self::BulkString(s) => {
+                let s = String::from_utf8_lossy(&s).to_string();
                 let command = Command::parse(&s);
-                let value = command.map_or(Value::String(s.to_string()), |_| Value::Empty);
+                let value = command.map_or(Value::String(s), |_| Value::Empty);
                 Ok((command, value))
             }
             Self::Array(v) => {
                 if let Some(Self::BulkString(s)) = v.first() {
-                    let command = Command::parse(s);
+                    let command = Command::parse(&String::from_utf8_lossy(s));
                     let value = command.map_or_else(
                         || Value::Array(v.clone()),
                         |_| Value::Array(v[1..].to_vec()),
@@ -159,15 +258,16 @@ impl Payload {
             _ => Err(anyhow!("Payload type not supported for content retrieval")),
         }
     }
-    /// Parses a payload based on the first byte and the subsequent text content.
+    /// Parses a payload based on the first byte and the subsequent raw content.
     ///
     /// This method interprets the first byte of the payload to determine its type according to
-    /// the Redis Serialization Protocol (RESP). It supports simple strings, bulk strings, and arrays,
-    /// delegating to specific parsing methods based on the byte encountered.
+    /// the Redis Serialization Protocol (RESP). It supports simple strings, errors, integers, bulk
+    /// strings, and arrays, delegating to specific parsing methods based on the byte encountered.
+    /// The input is handled as raw bytes, since bulk strings are binary-safe and need not be UTF-8.
     ///
     /// # Parameters
     /// - `byte`: The first byte of the payload, indicating the RESP data type.
-    /// - `payload`: The remainder of the string after the type specifier.
+    /// - `payload`: The payload bytes, starting with the type specifier.
     ///
     /// # Returns
     /// - A `Result` containing a tuple of the parsed `Payload` and the number of bytes consumed
@@ -175,97 +275,130 @@ impl Payload {
     ///
     /// # Errors
     /// - Returns an error if the payload type is unimplemented or unrecognized.
+    /// - Returns an [`Incomplete`] error if `payload` ends before the payload it starts does.
     ///
     /// # Examples
     /// ```
-    /// let input = "+OK\r\n";
-    /// let result = Payload::from_byte(b'+', &input[1..]);
+    /// let input = b"+OK\r\n";
+    /// let result = Payload::from_byte(b'+', input);
     /// assert!(result.is_ok());
     /// let (payload, consumed) = result.unwrap();
     /// assert_eq!(payload, Payload::SimpleString("OK".to_string()));
     /// assert_eq!(consumed, 5);
     /// ```
-    pub fn from_byte(byte: u8, payload: &str) -> Result<(Self, usize)> {
-        println!("parsing from byte: {}, with payload: {}", byte, payload);
+    pub fn from_byte(byte: u8, payload: &[u8]) -> Result<(Self, usize)> {
         match byte {
             b'+' => Self::from_simple_string(payload),
+            b'-' => Self::from_error(payload),
+            b':' => Self::from_integer(payload),
             b'*' => Payload::from_array(payload),
             b'$' => Payload::from_bulk_string(payload),
+            b'%' => Payload::from_map(payload),
+            b'~' => Payload::from_set(payload),
+            b'>' => Payload::from_push(payload),
+            b'_' => Payload::from_null(payload),
             e => bail!("Unimplemented payload type {}", e),
         }
     }
-    /// Similar to `from_byte`, but initializes parsing from a character instead of a byte.
+    /// Parses a simple string from a given RESP formatted input.
     ///
-    /// This method functions identically to `from_byte`, translating the initial character
-    /// into the appropriate payload parsing function. This is used when dealing with character-
-    /// oriented input sources.
+    /// Simple strings are identified by a leading '+' and end with "\r\n".
+    /// This method extracts the content of a simple string, excluding its type specifier and delimiter.
     ///
     /// # Parameters
-    /// - `c`: The first character of the payload string indicating the RESP data type.
-    /// - `payload`: The rest of the payload string after the type specifier.
+    /// - `s`: The payload bytes starting with the '+' specifier.
     ///
     /// # Returns
-    /// - A `Result` containing a tuple of the parsed `Payload` and the number of bytes consumed,
-    ///   or an error if the character does not correspond to a recognized payload type.
+    /// - A `Result` containing a tuple of the parsed `Payload::SimpleString` and the total bytes consumed.
     ///
     /// # Errors
-    /// - Returns an error if the payload type is unimplemented or unrecognized.
+    /// - Returns an error if the ending delimiter is missing.
     ///
     /// # Examples
     /// ```
-    /// let input = "*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
-    /// let result = Payload::from_char('*', &input[1..]);
+    /// let input = b"+OK\r\n";
+    /// let result = Payload::from_simple_string(input);
     /// assert!(result.is_ok());
+    /// let (payload, length) = result.unwrap();
+    /// assert_eq!(payload, Payload::SimpleString("OK".to_string()));
+    /// assert_eq!(length, 5); // Including + and \r\n
     /// ```
-    pub fn from_char(c: char, payload: &str) -> Result<(Self, usize)> {
-        println!("parsing from char {}", c);
-        match c {
-            '+' => Self::from_simple_string(payload),
-            '*' => Payload::from_array(payload),
-            '$' => Payload::from_bulk_string(payload),
-            e => bail!("Unimplemented payload type {}", e),
-        }
+    pub(super) fn from_simple_string(s: &[u8]) -> Result<(Self, usize)> {
+        let (payload, _) = split_line(&s[TYPE_SPECIFIER_LEN..]).ok_or(Incomplete)?;
+        Ok((
+            Payload::SimpleString(String::from_utf8_lossy(payload).to_string()),
+            TYPE_SPECIFIER_LEN + payload.len() + DELIMITER.len(),
+        ))
     }
-    /// Parses a simple string from a given RESP formatted input.
+    /// Parses an error from a given RESP formatted input.
     ///
-    /// Simple strings are identified by a leading '+' and end with "\r\n".
-    /// This method extracts the content of a simple string, excluding its type specifier and delimiter.
+    /// Errors are identified by a leading '-' and end with "\r\n", the content being the error
+    /// message.
     ///
     /// # Parameters
-    /// - `s`: The payload string after the '+' specifier.
+    /// - `s`: The payload bytes starting with the '-' specifier.
     ///
     /// # Returns
-    /// - A `Result` containing a tuple of the parsed `Payload::SimpleString` and the total bytes consumed.
+    /// - A `Result` containing a tuple of the parsed `Payload::Error` and the total bytes consumed.
     ///
     /// # Errors
     /// - Returns an error if the ending delimiter is missing.
     ///
     /// # Examples
     /// ```
-    /// let input = "OK\r\n";
-    /// let result = Payload::from_simple_string(input);
-    /// assert!(result.is_ok());
-    /// let (payload, length) = result.unwrap();
-    /// assert_eq!(payload, Payload::SimpleString("OK".to_string()));
-    /// assert_eq!(length, 5); // Including + and \r\n
+    /// let input = b"-ERR unknown command\r\n";
+    /// let (payload, consumed) = Payload::from_error(input).unwrap();
+    /// assert_eq!(payload, Payload::Error("ERR unknown command".to_string()));
+    /// assert_eq!(consumed, 22);
+    /// ```
+    pub(super) fn from_error(s: &[u8]) -> Result<(Self, usize)> {
+        let (message, _) = split_line(&s[TYPE_SPECIFIER_LEN..]).ok_or(Incomplete)?;
+        Ok((
+            Payload::Error(String::from_utf8_lossy(message).to_string()),
+            TYPE_SPECIFIER_LEN + message.len() + DELIMITER.len(),
+        ))
+    }
+    /// Parses an integer from a given RESP formatted input.
+    ///
+    /// Integers are identified by a leading ':' followed by an optionally signed decimal number
+    /// and "\r\n".
+    ///
+    /// # Parameters
+    /// - `s`: The payload bytes starting with the ':' specifier.
+    ///
+    /// # Returns
+    /// - A `Result` containing a tuple of the parsed `Payload::Integer` and the total bytes consumed.
+    ///
+    /// # Errors
+    /// - Returns an error if the ending delimiter is missing or the number does not fit an `i64`.
+    ///
+    /// # Examples
+    /// ```
+    /// let input = b":-42\r\n";
+    /// let (payload, consumed) = Payload::from_integer(input).unwrap();
+    /// assert_eq!(payload, Payload::Integer(-42));
+    /// assert_eq!(consumed, 6);
     /// ```
-    pub(super) fn from_simple_string(s: &str) -> Result<(Self, usize)> {
-        let (payload, _) = s[TYPE_SPECIFIER_LEN..]
-            .split_once(DELIMITER)
-            .context("No ending delimiter")?;
+    pub(super) fn from_integer(s: &[u8]) -> Result<(Self, usize)> {
+        let (digits, _) = split_line(&s[TYPE_SPECIFIER_LEN..]).ok_or(Incomplete)?;
+        let value = std::str::from_utf8(digits)
+            .ok()
+            .and_then(|digits| digits.parse::<i64>().ok())
+            .context("Failed to parse integer as i64")?;
         Ok((
-            Payload::SimpleString(payload.to_string()),
-            payload.len() + 3,
+            Payload::Integer(value),
+            TYPE_SPECIFIER_LEN + digits.len() + DELIMITER.len(),
         ))
     }
-    // Parses a bulk string from a given RESP formatted input.
+    /// Parses a bulk string from a given RESP formatted input.
     ///
     /// Bulk strings start with a '$' followed by the length of the string, a "\r\n",
     /// the string content, and another "\r\n". This method parses the bulk string according
-    /// to these specifications, verifying the length and extracting the string.
+    /// to these specifications, verifying the length and slicing exactly that many bytes, so the
+    /// content may contain arbitrary binary data, including "\r\n" itself.
     ///
     /// # Parameters
-    /// - `s`: The payload string after the '$' specifier.
+    /// - `s`: The payload bytes starting with the '$' specifier.
     ///
     /// # Returns
     /// - A `Result` containing a tuple of the parsed `Payload::BulkString` and the total bytes consumed.
@@ -276,44 +409,48 @@ impl Payload {
     ///
     /// # Examples
     /// ```
-    /// let input = "4\r\nPING\r\n";
+    /// let input = b"$4\r\nPING\r\n";
     /// let result = Payload::from_bulk_string(input);
     /// assert!(result.is_ok());
     /// let (payload, consumed) = result.unwrap();
-    /// assert_eq!(payload, Payload::BulkString("PING".to_string()));
+    /// assert_eq!(payload, Payload::BulkString(b"PING".to_vec()));
     /// assert_eq!(consumed, 10); // Including $, length, both \r\n, and string content
     /// ```
-    pub(super) fn from_bulk_string(s: &str) -> Result<(Self, usize)> {
-        println!("parsing from bulk string");
-        let (length_str, rest) = &s[TYPE_SPECIFIER_LEN..]
-            .split_once(DELIMITER)
-            .context("Failed splitting at delimiter.")?;
-        let length = length_str
-            .parse::<usize>()
+    pub(super) fn from_bulk_string(s: &[u8]) -> Result<(Self, usize)> {
+        if let Some(consumed) = Self::null_length(s)? {
+            return Ok((Payload::Null, consumed));
+        }
+        let (length_str, rest) = split_line(&s[TYPE_SPECIFIER_LEN..]).ok_or(Incomplete)?;
+        let length = std::str::from_utf8(length_str)
+            .ok()
+            .and_then(|length| length.parse::<usize>().ok())
             .context("Failed to parse len as usize")?;
 
-        let start_index = length_str.len() + 2;
-
-        if rest.len() < length {
-            bail!("The data segment is shorter than the specified length.");
+        if rest.len() < length + DELIMITER.len() {
+            bail!(Incomplete);
+        }
+        // A length that doesn't match the data would leave the rest of the stream misread.
+        if &rest[length..length + DELIMITER.len()] != DELIMITER.as_bytes() {
+            bail!("ERR Protocol error: expected '\\r\\n' after bulk string");
         }
 
         let data = &rest[..length];
-        let total_consumed = TYPE_SPECIFIER_LEN + start_index + length + 2;
+        let total_consumed =
+            TYPE_SPECIFIER_LEN + length_str.len() + DELIMITER.len() + length + DELIMITER.len();
 
-        println!("Returning Payload::BulkString");
-        Ok((Payload::BulkString(data.to_string()), total_consumed))
+        Ok((Payload::BulkString(data.to_vec()), total_consumed))
     }
     /// Parses an array from a given RESP formatted input.
     ///
     /// Arrays in RESP are prefixed with an asterisk '*' followed by the number of elements in the array
     /// and a CRLF ("\r\n"). Each element of the array is then encoded according to its own data type
     /// rules (e.g., simple strings, bulk strings). This method parses the array, recursively handling
-    /// each element according to the RESP rules.
+    /// each element according to the RESP rules. The null array, `*-1\r\n`, parses as
+    /// `Payload::Null`.
     ///
     /// # Parameters
-    /// - `s`: The payload string after the '*' specifier, which should start with the number of elements
-    ///        followed by each element's data.
+    /// - `s`: The payload bytes starting with the '*' specifier, followed by the number of elements
+    ///   and each element's data.
     ///
     /// # Returns
     /// - A `Result` containing a tuple of the parsed `Payload::Array` and the total bytes consumed
@@ -325,93 +462,184 @@ impl Payload {
     ///
     /// # Examples
     /// ```
-    /// let input = "2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
+    /// let input = b"*2\r\n$3\r\nfoo\r\n$3\r\nbar\r\n";
     /// let result = Payload::from_array(input);
     /// assert!(result.is_ok());
     /// let (payload, consumed) = result.unwrap();
     /// match payload {
     ///     Payload::Array(elements) => {
     ///         assert_eq!(elements.len(), 2);
-    ///         assert_eq!(elements[0], Payload::BulkString("foo".to_string()));
-    ///         assert_eq!(elements[1], Payload::BulkString("bar".to_string()));
+    ///         assert_eq!(elements[0], Payload::BulkString(b"foo".to_vec()));
+    ///         assert_eq!(elements[1], Payload::BulkString(b"bar".to_vec()));
     ///     },
     ///     _ => panic!("Expected Payload::Array"),
     /// }
     /// assert_eq!(consumed, 23); // Total bytes including all elements and metadata
     /// ```
-    pub(super) fn from_array(s: &str) -> Result<(Self, usize)> {
-        let (number_of_elements_str, mut rest) = s[TYPE_SPECIFIER_LEN..]
-            .split_once(DELIMITER)
-            .context("Failed splitting at delimiter.")?;
+    pub(super) fn from_array(s: &[u8]) -> Result<(Self, usize)> {
+        if let Some(consumed) = Self::null_length(s)? {
+            return Ok((Payload::Null, consumed));
+        }
+        let (elements, consumed) = Self::parse_elements(s, 1)?;
+        Ok((Payload::Array(elements), consumed))
+    }
+    /// Parses a RESP3 set, which is framed like an array but with a leading '~'.
+    pub(super) fn from_set(s: &[u8]) -> Result<(Self, usize)> {
+        let (elements, consumed) = Self::parse_elements(s, 1)?;
+        Ok((Payload::SetType(elements), consumed))
+    }
+    /// Parses a RESP3 push, which is framed like an array but with a leading '>'.
+    pub(super) fn from_push(s: &[u8]) -> Result<(Self, usize)> {
+        let (elements, consumed) = Self::parse_elements(s, 1)?;
+        Ok((Payload::Push(elements), consumed))
+    }
+    /// Parses a RESP3 map: a '%' followed by the number of pairs, then each key and its value.
+    pub(super) fn from_map(s: &[u8]) -> Result<(Self, usize)> {
+        let (elements, consumed) = Self::parse_elements(s, 2)?;
+        let mut elements = elements.into_iter();
+        let mut pairs = vec![];
+        while let (Some(key), Some(value)) = (elements.next(), elements.next()) {
+            pairs.push((key, value));
+        }
+        Ok((Payload::Map(pairs), consumed))
+    }
+    /// Parses the RESP3 null, "_\r\n".
+    pub(super) fn from_null(s: &[u8]) -> Result<(Self, usize)> {
+        let (line, _) = split_line(&s[TYPE_SPECIFIER_LEN..]).ok_or(Incomplete)?;
+        if !line.is_empty() {
+            bail!("Unexpected data after null");
+        }
+        Ok((Payload::Null, TYPE_SPECIFIER_LEN + DELIMITER.len()))
+    }
+    /// Returns the bytes taken up by a RESP2 null, an array or bulk string with a length of -1,
+    /// or `None` if `s` starts with anything else.
+    fn null_length(s: &[u8]) -> Result<Option<usize>> {
+        let (length_str, _) = split_line(&s[TYPE_SPECIFIER_LEN..]).ok_or(Incomplete)?;
+        Ok(
+            (length_str == b"-1")
+                .then_some(TYPE_SPECIFIER_LEN + length_str.len() + DELIMITER.len()),
+        )
+    }
+    /// Parses the elements of an aggregate whose header counts entries of `per_entry` payloads
+    /// each, returning them along with the bytes consumed, header included.
+    fn parse_elements(s: &[u8], per_entry: usize) -> Result<(Vec<Payload>, usize)> {
+        let (number_of_elements_str, mut rest) =
+            split_line(&s[TYPE_SPECIFIER_LEN..]).ok_or(Incomplete)?;
 
-        let number_of_elements = number_of_elements_str.parse::<usize>()?;
-        let mut parsed_elements = Vec::with_capacity(number_of_elements);
+        let number_of_elements = std::str::from_utf8(number_of_elements_str)?
+            .parse::<usize>()
+            .ok()
+            .filter(|len| *len <= MAX_AGGREGATE_LEN)
+            .and_then(|len| len.checked_mul(per_entry))
+            .context("ERR Protocol error: invalid multibulk length")?;
+        // The elements may never arrive, so the announced length isn't trusted up front.
+        let mut parsed_elements = Vec::with_capacity(number_of_elements.min(1024));
         let mut cumulative_offset = 0;
 
         for _ in 0..number_of_elements {
-            let payload_type = rest.chars().next().context("Payload empty")?;
+            let payload_type = *rest.first().ok_or(Incomplete)?;
 
-            let (parsed_payload, step) = Payload::from_char(payload_type, rest)?;
+            let (parsed_payload, step) = Payload::from_byte(payload_type, rest)?;
             parsed_elements.push(parsed_payload);
 
             rest = &rest[step..];
             cumulative_offset += step;
         }
         cumulative_offset += TYPE_SPECIFIER_LEN + number_of_elements_str.len() + DELIMITER.len();
-        Ok((Payload::Array(parsed_elements), cumulative_offset))
+        Ok((parsed_elements, cumulative_offset))
+    }
+    /// Parses the RDB snapshot a master sends after `FULLRESYNC`.
+    ///
+    /// The snapshot is framed like a bulk string, a '$' followed by its length and "\r\n", but
+    /// unlike a bulk string it is not terminated by another "\r\n".
+    ///
+    /// # Parameters
+    /// - `s`: The payload bytes starting with the '$' specifier.
+    ///
+    /// # Returns
+    /// - A `Result` containing a tuple of the parsed `Payload::RdbFile` and the total bytes consumed.
+    ///
+    /// # Errors
+    /// - Returns an error if the length specifier is invalid, or an [`Incomplete`] error if the
+    ///   snapshot has not been fully received yet.
+    ///
+    /// # Examples
+    /// ```
+    /// let (payload, consumed) = Payload::from_rdb_file(b"$3\r\nRDB*1\r\n").unwrap();
+    /// assert_eq!(payload, Payload::RdbFile(b"RDB".to_vec()));
+    /// assert_eq!(consumed, 8);
+    /// ```
+    pub fn from_rdb_file(s: &[u8]) -> Result<(Self, usize)> {
+        let (length_str, rest) = split_line(&s[TYPE_SPECIFIER_LEN..]).ok_or(Incomplete)?;
+        let length = std::str::from_utf8(length_str)
+            .ok()
+            .and_then(|length| length.parse::<usize>().ok())
+            .context("Failed to parse len as usize")?;
+        if rest.len() < length {
+            bail!(Incomplete);
+        }
+        let total_consumed = TYPE_SPECIFIER_LEN + length_str.len() + DELIMITER.len() + length;
+        Ok((Payload::RdbFile(rest[..length].to_vec()), total_consumed))
     }
 }
 
 impl Display for Payload {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            Payload::BulkString(s) => write!(f, "{}", s),
+            Payload::BulkString(s) => write!(f, "{}", String::from_utf8_lossy(s)),
+            Payload::Integer(n) => write!(f, "{}", n),
+            Payload::Error(message) => write!(f, "{}", message),
             _ => write!(f, "unimplemented!"),
         }
     }
 }
 
 impl RedisEncodable for Payload {
-    fn redis_encode(&self) -> String {
-        match self {
-            Payload::SimpleString(value) => format!("+{}{}", value, DELIMITER),
-            Payload::BulkString(value) => {
-                format!("${}{}{}{}", value.len(), DELIMITER, value, DELIMITER)
-            }
-            Payload::Array(elements) => {
-                let mut f = format!("*{}{}", elements.len(), DELIMITER);
-                for item in elements {
-                    write!(f, "{}", item.redis_encode())
-                        .expect("Writing to string created just beforehand should never fail");
-                }
-                f
-            }
-            _ => unimplemented!(),
-        }
+    /// Encodes the payload as RESP2, which every connection speaks until it negotiates RESP3.
+    fn redis_encode(&self) -> Vec<u8> {
+        self.redis_encode_for(ProtocolVersion::default())
     }
 }
 
-pub struct PayloadVec(pub Vec<Payload>);
+/// Error returned by the payload parsers when the input ends before the payload does, meaning
+/// more data has to be read before it can be parsed.
+#[derive(Debug)]
+pub struct Incomplete;
 
-impl RedisEncodable for PayloadVec {
-    fn redis_encode(&self) -> String {
-        let payloads = self
-            .0
-            .iter()
-            .map(|p| p.redis_encode())
-            .collect::<Vec<String>>()
-            .join(", ");
-        payloads
+impl Display for Incomplete {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Incomplete payload")
     }
 }
 
-#[derive(Debug)]
+impl std::error::Error for Incomplete {}
+
+/// Splits `s` at the first "\r\n", returning the line before it and the bytes after it.
+fn split_line(s: &[u8]) -> Option<(&[u8], &[u8])> {
+    let end = s
+        .windows(DELIMITER.len())
+        .position(|window| window == DELIMITER.as_bytes())?;
+    Some((&s[..end], &s[end + DELIMITER.len()..]))
+}
+
+#[derive(Debug, Clone)]
 pub enum Value {
     Array(Vec<Payload>),
     String(String),
     Empty,
 }
 
+impl Value {
+    /// Flattens the command arguments into their string representations.
+    pub fn into_args(self) -> Vec<String> {
+        match self {
+            Value::Array(x) => x.iter().map(|p| p.to_string()).collect(),
+            Value::String(s) => vec![s],
+            Value::Empty => vec![],
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -419,30 +647,41 @@ mod tests {
     #[test]
     fn test_from_simple_string() {
         let input = format!("+OK{}", DELIMITER);
-        let result = Payload::from_simple_string(&input);
+        let result = Payload::from_simple_string(input.as_bytes());
         assert!(result.is_ok());
         let (payload, length) = result.unwrap();
         assert_eq!(payload, Payload::SimpleString("OK".to_string()));
         assert_eq!(length, 5);
     }
 
+    #[test]
+    fn test_back_to_back_simple_strings() {
+        let input = b"+OK\r\n+PONG\r\n";
+        let (first, consumed) = Payload::from_byte(b'+', input).unwrap();
+        assert_eq!(first, Payload::SimpleString("OK".into()));
+        assert_eq!(consumed, 5);
+        let (second, rest) = Payload::from_byte(b'+', &input[consumed..]).unwrap();
+        assert_eq!(second, Payload::SimpleString("PONG".into()));
+        assert_eq!(consumed + rest, input.len());
+    }
+
     #[test]
     fn test_from_bulk_string() {
         let input = format!("$4{}PING{}", DELIMITER, DELIMITER);
-        let result = Payload::from_bulk_string(&input);
+        let result = Payload::from_bulk_string(input.as_bytes());
         assert!(result.is_ok());
         let (payload, consumed) = result.unwrap();
-        assert_eq!(payload, Payload::BulkString("PING".to_string()));
+        assert_eq!(payload, Payload::BulkString("PING".into()));
         assert_eq!(consumed, 10);
     }
 
     #[test]
     fn test_bulk_string_correct_length() {
         let input = format!("$4{}PING{}", DELIMITER, DELIMITER);
-        let result = Payload::from_bulk_string(&input);
+        let result = Payload::from_bulk_string(input.as_bytes());
         assert!(result.is_ok());
         let (payload, consumed) = result.unwrap();
-        assert_eq!(payload, Payload::BulkString("PING".to_string()));
+        assert_eq!(payload, Payload::BulkString("PING".into()));
         assert_eq!(consumed, 10);
     }
 
@@ -452,18 +691,245 @@ mod tests {
             "*2{delim}$4{delim}ECHO{delim}$5{delim}mykey{delim}",
             delim = DELIMITER
         );
-        let result = Payload::from_array(&input);
-        println!("result is {:?}", result);
+        let result = Payload::from_array(input.as_bytes());
         assert!(result.is_ok());
         let (payload, consumed) = result.unwrap();
         match payload {
             Payload::Array(elements) => {
                 assert_eq!(elements.len(), 2);
-                assert_eq!(elements[0], Payload::BulkString("ECHO".to_string()));
-                assert_eq!(elements[1], Payload::BulkString("mykey".to_string()));
+                assert_eq!(elements[0], Payload::BulkString("ECHO".into()));
+                assert_eq!(elements[1], Payload::BulkString("mykey".into()));
             }
             _ => panic!("Expected Payload::Array"),
         }
         assert_eq!(consumed, 25);
     }
+
+    #[test]
+    fn test_rdb_file_has_no_trailing_delimiter() {
+        let input = b"$3\r\nRDB*1\r\n";
+        let (payload, consumed) = Payload::from_rdb_file(input).unwrap();
+        assert_eq!(payload, Payload::RdbFile(b"RDB".to_vec()));
+        assert_eq!(&input[consumed..], b"*1\r\n");
+        assert!(Payload::from_rdb_file(b"$3\r\nRD").is_err());
+    }
+
+    #[test]
+    fn test_from_integer() {
+        let input = format!(":1000{}", DELIMITER);
+        let (payload, consumed) = Payload::from_integer(input.as_bytes()).unwrap();
+        assert_eq!(payload, Payload::Integer(1000));
+        assert_eq!(consumed, 7);
+    }
+
+    #[test]
+    fn test_from_negative_integer() {
+        let input = format!(":-42{}", DELIMITER);
+        let (payload, consumed) = Payload::from_byte(b':', input.as_bytes()).unwrap();
+        assert_eq!(payload, Payload::Integer(-42));
+        assert_eq!(consumed, 6);
+    }
+
+    #[test]
+    fn test_integer_missing_delimiter() {
+        assert!(Payload::from_integer(b":12").is_err());
+        assert!(Payload::from_integer(format!(":12a{}", DELIMITER).as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_integer_round_trip() {
+        let encoded = Payload::Integer(-7).redis_encode();
+        assert_eq!(encoded, format!(":-7{}", DELIMITER).into_bytes());
+        let (payload, consumed) = Payload::from_byte(b':', &encoded).unwrap();
+        assert_eq!(payload, Payload::Integer(-7));
+        assert_eq!(consumed, encoded.len());
+    }
+
+    #[test]
+    fn test_array_with_integer_element() {
+        let input = format!("*2{d}:1{d}$3{d}foo{d}", d = DELIMITER);
+        let (payload, consumed) = Payload::from_array(input.as_bytes()).unwrap();
+        assert_eq!(
+            payload,
+            Payload::Array(vec![Payload::Integer(1), Payload::BulkString("foo".into())])
+        );
+        assert_eq!(consumed, input.len());
+    }
+
+    #[test]
+    fn test_nested_array() {
+        let input = b"*2\r\n:1\r\n*1\r\n$3\r\nfoo\r\n";
+        let (payload, consumed) = Payload::from_byte(b'*', input).unwrap();
+        assert_eq!(
+            payload,
+            Payload::Array(vec![
+                Payload::Integer(1),
+                Payload::Array(vec![Payload::BulkString(b"foo".to_vec())]),
+            ])
+        );
+        assert_eq!(consumed, input.len());
+
+        let input = format!("*3{d}-ERR oops{d}*0{d}+OK{d}:2", d = DELIMITER);
+        let (payload, consumed) = Payload::from_array(input.as_bytes()).unwrap();
+        assert_eq!(
+            payload,
+            Payload::Array(vec![
+                Payload::Error("ERR oops".into()),
+                Payload::Array(vec![]),
+                Payload::SimpleString("OK".into()),
+            ])
+        );
+        assert_eq!(&input[consumed..], ":2");
+    }
+
+    #[test]
+    fn test_resp2_nulls() {
+        let input = b"*3\r\n*-1\r\n$-1\r\n$1\r\na\r\n";
+        let (payload, consumed) = Payload::from_array(input).unwrap();
+        assert_eq!(
+            payload,
+            Payload::Array(vec![
+                Payload::Null,
+                Payload::Null,
+                Payload::BulkString(b"a".to_vec())
+            ])
+        );
+        assert_eq!(consumed, input.len());
+        assert_eq!(Payload::from_array(b"*-1\r\n").unwrap(), (Payload::Null, 5));
+    }
+
+    #[test]
+    fn test_from_error() {
+        let input = format!("-ERR unknown command{}", DELIMITER);
+        let (payload, consumed) = Payload::from_byte(b'-', input.as_bytes()).unwrap();
+        assert_eq!(payload, Payload::Error("ERR unknown command".to_string()));
+        assert_eq!(consumed, input.len());
+        assert!(Payload::from_error(b"-ERR oops").is_err());
+    }
+
+    #[test]
+    fn test_error_encoding() {
+        assert_eq!(
+            Payload::error("syntax error").redis_encode(),
+            format!("-ERR syntax error{}", DELIMITER).into_bytes()
+        );
+        assert_eq!(
+            Payload::error("WRONGTYPE Operation against a key").redis_encode(),
+            format!("-WRONGTYPE Operation against a key{}", DELIMITER).into_bytes()
+        );
+        assert_eq!(
+            Payload::error("Incorrect input type.").redis_encode(),
+            format!("-ERR Incorrect input type.{}", DELIMITER).into_bytes()
+        );
+    }
+
+    #[test]
+    fn test_binary_bulk_string_round_trip() {
+        let input = b"$5\r\na\xff\r\nb\r\n";
+        let (payload, consumed) = Payload::from_byte(b'$', input).unwrap();
+        assert_eq!(payload, Payload::BulkString(b"a\xff\r\nb".to_vec()));
+        assert_eq!(consumed, input.len());
+        assert_eq!(payload.redis_encode(), input.to_vec());
+    }
+
+    #[test]
+    fn test_map_round_trip_in_both_protocols() {
+        let map = Payload::Map(vec![
+            (
+                Payload::BulkString(b"field".to_vec()),
+                Payload::BulkString(b"value".to_vec()),
+            ),
+            (Payload::BulkString(b"missing".to_vec()), Payload::Null),
+        ]);
+
+        let resp3 = map.redis_encode_for(ProtocolVersion::Resp3);
+        assert_eq!(
+            resp3,
+            b"%2\r\n$5\r\nfield\r\n$5\r\nvalue\r\n$7\r\nmissing\r\n_\r\n".to_vec()
+        );
+        assert_eq!(
+            Payload::from_byte(b'%', &resp3).unwrap(),
+            (map.clone(), resp3.len())
+        );
+
+        // RESP2 has no map or null, so the reply comes back as the equivalent flat array.
+        let resp2 = map.redis_encode_for(ProtocolVersion::Resp2);
+        assert_eq!(
+            resp2,
+            b"*4\r\n$5\r\nfield\r\n$5\r\nvalue\r\n$7\r\nmissing\r\n$-1\r\n".to_vec()
+        );
+        assert_eq!(map.redis_encode(), resp2);
+    }
+
+    #[test]
+    fn test_set_push_and_null_encodings() {
+        let set = Payload::SetType(vec![Payload::BulkString(b"a".to_vec())]);
+        let resp3 = set.redis_encode_for(ProtocolVersion::Resp3);
+        assert_eq!(resp3, b"~1\r\n$1\r\na\r\n".to_vec());
+        assert_eq!(
+            Payload::from_byte(b'~', &resp3).unwrap(),
+            (set.clone(), resp3.len())
+        );
+        assert_eq!(set.redis_encode(), b"*1\r\n$1\r\na\r\n".to_vec());
+
+        let push = Payload::Push(vec![Payload::BulkString(b"a".to_vec())]);
+        let resp3 = push.redis_encode_for(ProtocolVersion::Resp3);
+        assert_eq!(resp3, b">1\r\n$1\r\na\r\n".to_vec());
+        assert_eq!(
+            Payload::from_byte(b'>', &resp3).unwrap(),
+            (push.clone(), resp3.len())
+        );
+        assert_eq!(push.redis_encode(), b"*1\r\n$1\r\na\r\n".to_vec());
+
+        assert_eq!(
+            Payload::from_byte(b'_', b"_\r\n").unwrap(),
+            (Payload::Null, 3)
+        );
+        assert_eq!(Payload::Null.redis_encode(), b"$-1\r\n".to_vec());
+    }
+
+    #[test]
+    fn test_oversized_aggregate_lengths_are_rejected() {
+        for input in [&b"*100000000000\r\n"[..], b"%1048577\r\n", b"*-2\r\n"] {
+            let err = Payload::from_byte(input[0], input).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "ERR Protocol error: invalid multibulk length"
+            );
+        }
+        let err = Payload::from_byte(b'*', b"*1048576\r\n").unwrap_err();
+        assert!(err.is::<Incomplete>());
+    }
+
+    #[test]
+    fn test_bulk_string_must_end_where_its_length_says() {
+        for input in [&b"$3\r\nPING\r\n"[..], b"$5\r\nPING\r\n\r\n", b"$0\r\nab"] {
+            let err = Payload::from_byte(b'$', input).unwrap_err();
+            assert_eq!(
+                err.to_string(),
+                "ERR Protocol error: expected '\\r\\n' after bulk string"
+            );
+        }
+        let err = Payload::from_byte(b'*', b"*1\r\n$3\r\nPING\r\n").unwrap_err();
+        assert!(!err.is::<Incomplete>());
+        assert!(Payload::from_byte(b'$', b"$4\r\nPI")
+            .unwrap_err()
+            .is::<Incomplete>());
+    }
+
+    #[test]
+    fn test_map_encoding_depends_on_protocol() {
+        let map = Payload::Array(vec![Payload::Map(vec![(
+            Payload::BulkString(b"proto".to_vec()),
+            Payload::Integer(3),
+        )])]);
+        assert_eq!(
+            map.redis_encode_for(ProtocolVersion::Resp3),
+            b"*1\r\n%1\r\n$5\r\nproto\r\n:3\r\n".to_vec()
+        );
+        assert_eq!(
+            map.redis_encode_for(ProtocolVersion::Resp2),
+            b"*1\r\n*2\r\n$5\r\nproto\r\n:3\r\n".to_vec()
+        );
+    }
 }
@@ -1,82 +1,356 @@
+use crate::parser::payload::Incomplete;
 use crate::parser::Payload;
-use std::io::{BufRead, Read};
+use tokio::io::{AsyncRead, AsyncReadExt};
 
-use anyhow::{anyhow, Result};
+use anyhow::{bail, Result};
 
 /// A parser for handling Redis Protocol messages.
 ///
 /// The `RedisProtocolParser` is responsible for parsing messages
 /// based on the Redis Serialization Protocol (RESP). It processes
-/// input from a `Read` and `BufRead` source and transforms it into
-/// structured payloads.
+/// input buffered from a connection one payload at a time and
+/// transforms it into structured payloads.
 pub struct RedisProtocolParser;
 
 impl RedisProtocolParser {
-    /// Parses the data from the reader and organizes it into structured payloads.
-    ///
-    /// This method reads all available data from the given reader, expects it to
-    /// be in RESP format, and converts it into a vector of `Payload` items.
-    /// Each `Payload` may consist of multiple nested payloads if the input data
-    /// represents an array of commands or data elements.
-    ///
-    /// # Parameters
-    /// - `reader`: A mutable reference to any object that implements `Read` and `BufRead`.
+    /// Parses the first payload in `buffer`, if the buffer already holds all of it.
     ///
     /// # Returns
-    /// - A `Result` containing either:
-    ///   - A `Vec<Payload>` on success, representing the parsed payloads.
-    ///   - An `anyhow::Error` on failure, for instance if the buffer is empty or data is malformed.
+    /// - `Ok(Some((payload, consumed)))` with the payload and the number of bytes it took up,
+    ///   so the caller can keep whatever follows for the next call.
+    /// - `Ok(None)` if the buffer is empty or ends in the middle of a payload.
+    /// - An error if the data is malformed.
     ///
-    /// # Examples
-    /// ```rust
-    /// use std::io::Cursor;
-    /// use your_crate::RedisProtocolParser;
+    /// Blank lines before the payload are skipped and counted as consumed along with it.
+    pub fn parse_frame(buffer: &[u8]) -> Result<Option<(Payload, usize)>> {
+        let skipped = blank_lines_len(buffer);
+        let buffer = &buffer[skipped..];
+        let Some(&payload_type) = buffer.first() else {
+            return Ok(None);
+        };
+        let parsed = if !TYPE_SPECIFIERS.contains(&payload_type) {
+            Self::parse_inline(buffer)?
+        } else {
+            match Payload::from_byte(payload_type, buffer) {
+                Ok(parsed) => Some(parsed),
+                Err(e) if e.is::<Incomplete>() => None,
+                Err(e) => return Err(e),
+            }
+        };
+        Ok(parsed.map(|(payload, consumed)| (payload, skipped + consumed)))
+    }
+
+    /// Parses an inline command, the plain-text form `redis-cli` and telnet sessions send: a
+    /// single line whose arguments are separated by whitespace, returned as the array of bulk
+    /// strings the same command would have been sent as. Arguments may be wrapped in double
+    /// quotes, which understand the usual backslash escapes, or in single quotes, which only
+    /// understand `\'`.
     ///
-    /// let data = Cursor::new("+OK\r\n-ERR some error\r\n:1234\r\n$6\r\nfoobar\r\n");
-    /// let payloads = RedisProtocolParser::parse(&mut data).unwrap();
-    /// assert_eq!(payloads.len(), 5);
-    /// ```
-    pub fn parse<R: Read + BufRead>(reader: &mut R) -> Result<Vec<Payload>> {
-        let payload_type = reader
-            .fill_buf()?
-            .first()
-            .copied()
-            .ok_or_else(|| anyhow!("Empty buffer"))?;
-
-        let mut payload: Vec<u8> = vec![];
-        reader.read_to_end(&mut payload)?;
-        println!("Payload data: {:?}", payload);
-        let payload = std::str::from_utf8(&payload)?;
-        println!("parsing payload: {:?}", payload);
-        let (payload, _) = Payload::from_byte(payload_type, payload)?;
-        let payloads = match payload {
-            Payload::Array(arr) => {
-        let mut result = Vec::new();
-        let mut current_group = Vec::new();
-
-        for val in arr.iter() {
-            if val.is_command() {
-                if !current_group.is_empty() {
-                    result.push(Payload::Array(current_group));
-                    current_group = Vec::new();
-                }
-                current_group.push(val.clone());
-            } else {
-                current_group.push(val.clone());
+    /// A line still unterminated past `MAX_INLINE_LEN` bytes is an error, so a client can't
+    /// make the server buffer it forever.
+    fn parse_inline(buffer: &[u8]) -> Result<Option<(Payload, usize)>> {
+        let Some(end) = buffer.iter().position(|&byte| byte == b'\n') else {
+            if buffer.len() > MAX_INLINE_LEN {
+                bail!("ERR Protocol error: too big inline request")
+            }
+            return Ok(None);
+        };
+        let line = buffer[..end].strip_suffix(b"\r").unwrap_or(&buffer[..end]);
+        let args = split_inline_args(line)?;
+        Ok(Some((Payload::build_bulk_string_array(args), end + 1)))
+    }
+
+    /// Like `parse_frame`, but for the RDB snapshot a master sends right after `FULLRESYNC`.
+    pub fn parse_rdb_frame(buffer: &[u8]) -> Result<Option<(Payload, usize)>> {
+        if buffer.is_empty() {
+            return Ok(None);
+        }
+        match Payload::from_rdb_file(buffer) {
+            Ok(parsed) => Ok(Some(parsed)),
+            Err(e) if e.is::<Incomplete>() => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads from `reader` until `buffer` holds a complete payload, then parses it and drains
+    /// it from the buffer. Any bytes following the payload stay buffered for the next call, so
+    /// commands split across several reads or pipelined into a single one are both handled.
+    ///
+    /// Returns `None` once the reader reaches end of file.
+    pub async fn read_frame<R: AsyncRead + Unpin>(
+        reader: &mut R,
+        buffer: &mut Vec<u8>,
+    ) -> Result<Option<Payload>> {
+        loop {
+            // Blank lines are dropped as they arrive, so a stream of them can't pile up.
+            buffer.drain(..blank_lines_len(buffer));
+            if let Some((payload, consumed)) = Self::parse_frame(buffer)? {
+                buffer.drain(..consumed);
+                return Ok(Some(payload));
+            }
+            let mut chunk = [0; 1024];
+            let read_bytes = reader.read(&mut chunk).await?;
+            if read_bytes == 0 {
+                return Ok(None);
             }
+            buffer.extend_from_slice(&chunk[..read_bytes]);
         }
+    }
+}
 
-        if !current_group.is_empty() {
-            result.push(Payload::Array(current_group));
+/// Longest inline command buffered while waiting for its end of line, as in Redis.
+const MAX_INLINE_LEN: usize = 64 * 1024;
+
+/// The first bytes of the payload types `Payload::from_byte` understands. Anything else starts
+/// an inline command.
+const TYPE_SPECIFIERS: &[u8] = b"+-:*$%~_";
+
+/// The length of the complete lines holding nothing but whitespace at the start of `buffer`,
+/// which are skipped between commands.
+fn blank_lines_len(buffer: &[u8]) -> usize {
+    let mut len = 0;
+    while let Some(end) = buffer[len..].iter().position(|&byte| byte == b'\n') {
+        if !buffer[len..len + end].iter().all(u8::is_ascii_whitespace) {
+            break;
         }
+        len += end + 1;
+    }
+    len
+}
 
-        result
-    },
-            _ => vec!(payload),
+/// Splits an inline command line into its arguments, honouring quotes and escapes.
+fn split_inline_args(line: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut args = vec![];
+    let mut rest = line;
+    loop {
+        rest = trim_start(rest);
+        let Some(&first) = rest.first() else {
+            return Ok(args);
         };
-        println!("Parsed payload: {:?}", payloads);
-        
+        let mut arg = vec![];
+        match first {
+            b'"' => {
+                let mut i = 1;
+                loop {
+                    match rest.get(i..) {
+                        Some([b'\\', b'x', high, low, ..])
+                            if high.is_ascii_hexdigit() && low.is_ascii_hexdigit() =>
+                        {
+                            let hex = std::str::from_utf8(&rest[i + 2..i + 4])?;
+                            arg.push(u8::from_str_radix(hex, 16)?);
+                            i += 4;
+                        }
+                        Some([b'\\', escaped, ..]) => {
+                            arg.push(match escaped {
+                                b'n' => b'\n',
+                                b'r' => b'\r',
+                                b't' => b'\t',
+                                b'b' => 0x08,
+                                b'a' => 0x07,
+                                other => *other,
+                            });
+                            i += 2;
+                        }
+                        Some([b'"', ..]) => break,
+                        Some([byte, ..]) => {
+                            arg.push(*byte);
+                            i += 1;
+                        }
+                        _ => bail!(UNBALANCED_QUOTES),
+                    }
+                }
+                rest = closing_quote(&rest[i + 1..])?;
+            }
+            b'\'' => {
+                let mut i = 1;
+                loop {
+                    match rest.get(i..) {
+                        Some([b'\\', b'\'', ..]) => {
+                            arg.push(b'\'');
+                            i += 2;
+                        }
+                        Some([b'\'', ..]) => break,
+                        Some([byte, ..]) => {
+                            arg.push(*byte);
+                            i += 1;
+                        }
+                        _ => bail!(UNBALANCED_QUOTES),
+                    }
+                }
+                rest = closing_quote(&rest[i + 1..])?;
+            }
+            _ => {
+                let end = rest
+                    .iter()
+                    .position(|byte| byte.is_ascii_whitespace())
+                    .unwrap_or(rest.len());
+                arg.extend_from_slice(&rest[..end]);
+                rest = &rest[end..];
+            }
+        }
+        args.push(arg);
+    }
+}
+
+const UNBALANCED_QUOTES: &str = "ERR Protocol error: unbalanced quotes in request";
+
+/// Checks that a closing quote is followed by whitespace or the end of the line, as an argument
+/// like `"foo"bar` is ambiguous.
+fn closing_quote(rest: &[u8]) -> Result<&[u8]> {
+    match rest.first() {
+        Some(byte) if !byte.is_ascii_whitespace() => bail!(UNBALANCED_QUOTES),
+        _ => Ok(rest),
+    }
+}
+
+fn trim_start(s: &[u8]) -> &[u8] {
+    let start = s
+        .iter()
+        .position(|byte| !byte.is_ascii_whitespace())
+        .unwrap_or(s.len());
+    &s[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::RedisEncodable;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+    use tokio::io::ReadBuf;
+
+    /// Reader handing out its data one byte per read, like a peer sending tiny packets.
+    struct OneByteReader<'a>(&'a [u8]);
+
+    impl AsyncRead for OneByteReader<'_> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<std::io::Result<()>> {
+            if let Some((&byte, rest)) = self.0.split_first() {
+                buf.put_slice(&[byte]);
+                self.0 = rest;
+            }
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_read_frame_across_single_byte_reads() {
+        let mut reader = OneByteReader(b"*2\r\n$4\r\nECHO\r\n$5\r\nhello\r\n$3\r\nfo");
+        let mut buffer = vec![];
+
+        let payload = RedisProtocolParser::read_frame(&mut reader, &mut buffer)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            payload,
+            Payload::build_bulk_string_array(vec!["ECHO", "hello"])
+        );
+        assert!(buffer.is_empty());
+
+        let eof = RedisProtocolParser::read_frame(&mut reader, &mut buffer)
+            .await
+            .unwrap();
+        assert!(eof.is_none());
+        assert_eq!(buffer, b"$3\r\nfo");
+    }
+
+    #[test]
+    fn test_arguments_naming_commands_stay_in_one_command() {
+        let input = Payload::build_bulk_string_array(vec!["CONFIG", "GET", "dir"]).redis_encode();
+        let (payload, _) = RedisProtocolParser::parse_frame(&input).unwrap().unwrap();
+        assert_eq!(
+            payload,
+            Payload::build_bulk_string_array(vec!["CONFIG", "GET", "dir"])
+        );
+    }
+
+    #[test]
+    fn test_inline_command_matches_its_array_form() {
+        let array = Payload::build_bulk_string_array(vec!["ECHO", "hello"]).redis_encode();
+        let (expected, _) = RedisProtocolParser::parse_frame(&array).unwrap().unwrap();
+        let input = b"ECHO hello\r\n";
+        let (payload, consumed) = RedisProtocolParser::parse_frame(input).unwrap().unwrap();
+        assert_eq!(payload, expected);
+        assert_eq!(consumed, input.len());
+    }
+
+    #[test]
+    fn test_inline_command_with_quoted_arguments() {
+        let input = b"\r\n  SET \"hello world\"  'it\\'s' \"\\x41\\n\"\nPI";
+        let (payload, consumed) = RedisProtocolParser::parse_frame(input).unwrap().unwrap();
+        assert_eq!(
+            payload,
+            Payload::build_bulk_string_array(vec!["SET", "hello world", "it's", "A\n"])
+        );
+        assert_eq!(&input[consumed..], b"PI");
+        assert!(RedisProtocolParser::parse_frame(b"PI").unwrap().is_none());
+
+        for unbalanced in [&b"ECHO \"hello\r\n"[..], b"ECHO 'a'b\r\n"] {
+            let err = RedisProtocolParser::parse_frame(unbalanced).unwrap_err();
+            assert!(err.to_string().contains("unbalanced quotes"));
+        }
+    }
+
+    #[test]
+    fn test_many_blank_lines_are_skipped() {
+        let mut input = b"PING\r\n".to_vec();
+        input.extend(std::iter::repeat_n(b'\n', 200_000));
+        let (payload, consumed) = RedisProtocolParser::parse_frame(&input).unwrap().unwrap();
+        assert_eq!(payload, Payload::build_bulk_string_array(vec!["PING"]));
+        assert!(RedisProtocolParser::parse_frame(&input[consumed..])
+            .unwrap()
+            .is_none());
+
+        input.extend_from_slice(b" \r\n*1\r\n$4\r\nPING\r\n");
+        let (payload, rest) = RedisProtocolParser::parse_frame(&input[consumed..])
+            .unwrap()
+            .unwrap();
+        assert_eq!(payload, Payload::build_bulk_string_array(vec!["PING"]));
+        assert_eq!(consumed + rest, input.len());
+    }
+
+    #[test]
+    fn test_unterminated_inline_command_is_capped() {
+        let mut input = vec![b'a'; MAX_INLINE_LEN];
+        assert!(RedisProtocolParser::parse_frame(&input).unwrap().is_none());
+        input.push(b'a');
+        let err = RedisProtocolParser::parse_frame(&input).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "ERR Protocol error: too big inline request"
+        );
+    }
+
+    #[test]
+    fn test_parse_frame_stops_before_half_written_command() {
+        let mut input =
+            Payload::build_bulk_string_array(vec!["SET", "key", "value"]).redis_encode();
+        let complete = input.len();
+        input.extend_from_slice(b"*2\r\n$3\r\nGET\r\n$3\r\nke");
+
+        let (payload, consumed) = RedisProtocolParser::parse_frame(&input).unwrap().unwrap();
+        assert_eq!(
+            payload,
+            Payload::build_bulk_string_array(vec!["SET", "key", "value"])
+        );
+        assert_eq!(consumed, complete);
+        assert!(RedisProtocolParser::parse_frame(&input[consumed..])
+            .unwrap()
+            .is_none());
+    }
 
-        Ok(payloads)
+    #[test]
+    fn test_parse_frame_keeps_pipelined_remainder() {
+        let input = b"$4\r\nPING\r\n$4\r\nPI";
+        let (payload, consumed) = RedisProtocolParser::parse_frame(input).unwrap().unwrap();
+        assert_eq!(payload, Payload::BulkString(b"PING".to_vec()));
+        assert_eq!(&input[consumed..], b"$4\r\nPI");
+        assert!(RedisProtocolParser::parse_frame(&input[consumed..])
+            .unwrap()
+            .is_none());
     }
 }
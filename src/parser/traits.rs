@@ -10,8 +10,9 @@
 /// bulk strings should have their length prefixed, etc.
 ///
 /// # Required Methods
-/// - `redis_encode`: Returns a `String` that represents the encoded format of the type,
+/// - `redis_encode`: Returns the bytes that represent the encoded format of the type,
 ///   suitable for transmission to a Redis server or storage within Redis data structures.
+///   Bytes rather than a `String` are returned because bulk strings are binary-safe.
 ///
 /// # Examples
 /// Implementation for a custom struct `MyData`:
@@ -22,13 +23,13 @@
 /// }
 ///
 /// impl RedisEncodable for MyData {
-///     fn redis_encode(&self) -> String {
-///         format!("${}{}\r\n{}{}\r\n", self.key.len(), self.key, self.value.to_string().len(), self.value)
+///     fn redis_encode(&self) -> Vec<u8> {
+///         format!("${}{}\r\n{}{}\r\n", self.key.len(), self.key, self.value.to_string().len(), self.value).into_bytes()
 ///     }
 /// }
 ///
 /// let my_data = MyData { key: "age".to_string(), value: 30 };
-/// assert_eq!(my_data.redis_encode(), "$3\r\nage\r\n2\r\n30\r\n");
+/// assert_eq!(my_data.redis_encode(), b"$3\r\nage\r\n2\r\n30\r\n");
 /// ```
 pub trait RedisEncodable {
     /// Encodes the implementing type into a Redis-compatible string format.
@@ -38,6 +39,6 @@ pub trait RedisEncodable {
     /// Proper RESP formatting must be ensured by the implementor.
     ///
     /// # Returns
-    /// A `String` representing the Redis-encoded format of the type.
-    fn redis_encode(&self) -> String;
+    /// The bytes of the Redis-encoded format of the type.
+    fn redis_encode(&self) -> Vec<u8>;
 }
@@ -0,0 +1,178 @@
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::net::SocketAddr;
+use std::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::client::ClientWrite;
+use crate::parser::ProtocolVersion;
+use crate::store::glob::glob_match;
+
+type Subscribers = HashMap<SocketAddr, Subscriber>;
+type Registry = HashMap<String, Subscribers>;
+
+/// How many shards the channels are spread over.
+const SHARDS: usize = 16;
+
+/// A connection listening on a channel or pattern: where to write messages, and the protocol
+/// they are encoded in, which decides whether they are pushes or plain arrays.
+#[derive(Clone)]
+pub struct Subscriber {
+    pub stream: ClientWrite,
+    pub protocol: ProtocolVersion,
+}
+
+/// The channels clients subscribed to with `SUBSCRIBE`, the patterns they subscribed to with
+/// `PSUBSCRIBE`, and the connections listening on each.
+///
+/// Subscribers are keyed by their address, which is unique per connection, so a connection
+/// subscribing to the same channel twice is only registered once. Patterns are kept apart from
+/// channels so that finding a channel's direct subscribers stays a single lookup.
+///
+/// Channels are spread over shards by the hash of their name, each behind its own lock, so
+/// that subscribing and publishing to different channels don't contend with each other. Every
+/// publish has to check every pattern, so they share a lock of their own. None of the locks is
+/// ever held across an await, which is why they are not async.
+pub struct PubSub {
+    channels: Vec<RwLock<Registry>>,
+    patterns: RwLock<Registry>,
+}
+
+impl Default for PubSub {
+    fn default() -> Self {
+        Self {
+            channels: (0..SHARDS).map(|_| RwLock::default()).collect(),
+            patterns: RwLock::default(),
+        }
+    }
+}
+
+impl PubSub {
+    /// Registers the connection at `addr` as a subscriber of `channel`.
+    pub fn subscribe(&self, channel: &str, addr: SocketAddr, subscriber: Subscriber) {
+        add(&mut write(self.shard(channel)), channel, addr, subscriber);
+    }
+
+    /// Registers the connection at `addr` as a subscriber of every channel matching `pattern`.
+    pub fn psubscribe(&self, pattern: &str, addr: SocketAddr, subscriber: Subscriber) {
+        add(&mut write(&self.patterns), pattern, addr, subscriber);
+    }
+
+    /// Removes the connection at `addr` from the subscribers of `channel`.
+    pub fn unsubscribe(&self, channel: &str, addr: &SocketAddr) {
+        remove(&mut write(self.shard(channel)), channel, addr);
+    }
+
+    /// Removes the connection at `addr` from the subscribers of `pattern`.
+    pub fn punsubscribe(&self, pattern: &str, addr: &SocketAddr) {
+        remove(&mut write(&self.patterns), pattern, addr);
+    }
+
+    /// Removes the connection at `addr` from every channel and pattern, for when it goes away.
+    pub fn remove_subscriber(&self, addr: &SocketAddr) {
+        for registry in self.channels.iter().chain([&self.patterns]) {
+            write(registry).retain(|_, subscribers| {
+                subscribers.remove(addr);
+                !subscribers.is_empty()
+            });
+        }
+    }
+
+    /// Switches the messages to the connection at `addr` over to `protocol`, for when it
+    /// negotiates another one with `HELLO` while subscribed.
+    pub fn set_protocol(&self, addr: &SocketAddr, protocol: ProtocolVersion) {
+        for registry in self.channels.iter().chain([&self.patterns]) {
+            for subscribers in write(registry).values_mut() {
+                if let Some(subscriber) = subscribers.get_mut(addr) {
+                    subscriber.protocol = protocol;
+                }
+            }
+        }
+    }
+
+    /// Returns the connections subscribed to `channel`, so messages can be written to them
+    /// without holding on to the registry.
+    pub fn subscribers(&self, channel: &str) -> Vec<(SocketAddr, Subscriber)> {
+        read(self.shard(channel))
+            .get(channel)
+            .map(|subscribers| {
+                subscribers
+                    .iter()
+                    .map(|(addr, subscriber)| (*addr, subscriber.clone()))
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Returns every pattern matching `channel` along with the connections subscribed to it. A
+    /// connection subscribed to several matching patterns is listed once per pattern.
+    pub fn pattern_subscribers(&self, channel: &str) -> Vec<(String, SocketAddr, Subscriber)> {
+        read(&self.patterns)
+            .iter()
+            .filter(|(pattern, _)| glob_match(pattern.as_bytes(), channel.as_bytes()))
+            .flat_map(|(pattern, subscribers)| {
+                subscribers
+                    .iter()
+                    .map(|(addr, subscriber)| (pattern.clone(), *addr, subscriber.clone()))
+            })
+            .collect()
+    }
+
+    fn shard(&self, channel: &str) -> &RwLock<Registry> {
+        let mut hasher = DefaultHasher::new();
+        channel.hash(&mut hasher);
+        &self.channels[hasher.finish() as usize % SHARDS]
+    }
+}
+
+/// Locks `registry` for reading. A panic while it was held can't leave it half updated, so a
+/// poisoned lock is used all the same.
+fn read(registry: &RwLock<Registry>) -> RwLockReadGuard<'_, Registry> {
+    registry
+        .read()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn write(registry: &RwLock<Registry>) -> RwLockWriteGuard<'_, Registry> {
+    registry
+        .write()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+fn add(map: &mut Registry, name: &str, addr: SocketAddr, subscriber: Subscriber) {
+    map.entry(name.to_string())
+        .or_default()
+        .insert(addr, subscriber);
+}
+
+/// Removes `addr` from the subscribers of `name`, dropping the entry once nobody listens on it.
+fn remove(map: &mut Registry, name: &str, addr: &SocketAddr) {
+    if let Some(subscribers) = map.get_mut(name) {
+        subscribers.remove(addr);
+        if subscribers.is_empty() {
+            map.remove(name);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_channels_in_other_shards_stay_available() {
+        let pubsub = PubSub::default();
+        let busy = "news";
+        let other = (0..)
+            .map(|i| format!("channel-{}", i))
+            .find(|channel| !std::ptr::eq(pubsub.shard(channel), pubsub.shard(busy)))
+            .unwrap();
+
+        // While a publish to one channel holds its shard, another channel's is still free.
+        let _publishing = pubsub.shard(busy).write().unwrap();
+        assert!(pubsub.shard(busy).try_read().is_err());
+        assert!(pubsub.shard(&other).try_write().is_ok());
+        assert!(pubsub.subscribers(&other).is_empty());
+        assert!(pubsub.pattern_subscribers(busy).is_empty());
+    }
+}
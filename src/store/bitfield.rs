@@ -0,0 +1,333 @@
+use anyhow::{bail, Context, Result};
+
+/// Highest bit offset addressable by `BITFIELD`, mirroring Redis' 512MB string limit.
+const MAX_BIT_OFFSET: u64 = 512 * 1024 * 1024 * 8 - 1;
+
+/// Behaviour applied by `SET` and `INCRBY` when the result does not fit the field type.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Overflow {
+    Wrap,
+    Sat,
+    Fail,
+}
+
+/// An integer field type such as `u8` or `i16`.
+///
+/// Signed types support widths of 1 to 64 bits, unsigned ones 1 to 63 bits, so that every
+/// value can be returned as a RESP integer.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BitFieldType {
+    signed: bool,
+    bits: u32,
+}
+
+impl BitFieldType {
+    /// Parses a type specifier of the form `i<bits>` or `u<bits>`.
+    pub fn parse(s: &str) -> Result<Self> {
+        let invalid = "ERR Invalid bitfield type. Use something like i16 u8. Note that u64 is not supported but i64 is.";
+        let (signed, bits) = match s.split_at_checked(1) {
+            Some(("i" | "I", bits)) => (true, bits),
+            Some(("u" | "U", bits)) => (false, bits),
+            _ => bail!(invalid),
+        };
+        let bits = bits.parse::<u32>().context(invalid)?;
+        let max_bits = if signed { 64 } else { 63 };
+        if bits == 0 || bits > max_bits {
+            bail!(invalid)
+        }
+        Ok(Self { signed, bits })
+    }
+
+    fn bounds(&self) -> (i128, i128) {
+        if self.signed {
+            (-(1 << (self.bits - 1)), (1 << (self.bits - 1)) - 1)
+        } else {
+            (0, (1 << self.bits) - 1)
+        }
+    }
+
+    fn decode(&self, raw: u64) -> i64 {
+        if self.signed && self.bits < 64 && (raw >> (self.bits - 1)) & 1 == 1 {
+            (raw | (u64::MAX << self.bits)) as i64
+        } else {
+            raw as i64
+        }
+    }
+
+    fn encode(&self, value: i64) -> u64 {
+        if self.bits == 64 {
+            value as u64
+        } else {
+            value as u64 & ((1 << self.bits) - 1)
+        }
+    }
+
+    /// Fits `value` into the type according to the overflow policy, returning `None` when
+    /// the policy is `FAIL` and the value is out of range.
+    fn fit(&self, value: i128, overflow: Overflow) -> Option<i64> {
+        let (min, max) = self.bounds();
+        if (min..=max).contains(&value) {
+            return Some(value as i64);
+        }
+        match overflow {
+            Overflow::Fail => None,
+            Overflow::Sat => Some(value.clamp(min, max) as i64),
+            Overflow::Wrap => Some(self.decode(value.rem_euclid(1 << self.bits) as u64)),
+        }
+    }
+}
+
+/// A single `BITFIELD` subcommand, with the active `OVERFLOW` policy already resolved.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BitFieldOp {
+    Get {
+        ty: BitFieldType,
+        offset: u64,
+    },
+    Set {
+        ty: BitFieldType,
+        offset: u64,
+        value: i64,
+        overflow: Overflow,
+    },
+    IncrBy {
+        ty: BitFieldType,
+        offset: u64,
+        increment: i64,
+        overflow: Overflow,
+    },
+}
+
+impl BitFieldOp {
+    /// Parses the subcommand list following the key of a `BITFIELD` command.
+    ///
+    /// `OVERFLOW` does not produce an operation of its own; it changes the policy of every
+    /// `SET` and `INCRBY` that follows it.
+    ///
+    /// # Examples
+    /// ```
+    /// let args = ["OVERFLOW", "SAT", "INCRBY", "i8", "#1", "100"].map(String::from);
+    /// let ops = BitFieldOp::parse_all(&args).unwrap();
+    /// assert_eq!(ops.len(), 1);
+    /// ```
+    pub fn parse_all(args: &[String]) -> Result<Vec<Self>> {
+        let mut ops = vec![];
+        let mut overflow = Overflow::Wrap;
+        let mut args = args.iter();
+        let mut next = || args.next().context("ERR syntax error");
+
+        while let Ok(subcommand) = next() {
+            match subcommand.to_lowercase().as_str() {
+                "get" => {
+                    let ty = BitFieldType::parse(next()?)?;
+                    let offset = parse_offset(next()?, ty)?;
+                    ops.push(Self::Get { ty, offset });
+                }
+                "set" => {
+                    let ty = BitFieldType::parse(next()?)?;
+                    let offset = parse_offset(next()?, ty)?;
+                    let value = parse_integer(next()?)?;
+                    ops.push(Self::Set {
+                        ty,
+                        offset,
+                        value,
+                        overflow,
+                    });
+                }
+                "incrby" => {
+                    let ty = BitFieldType::parse(next()?)?;
+                    let offset = parse_offset(next()?, ty)?;
+                    let increment = parse_integer(next()?)?;
+                    ops.push(Self::IncrBy {
+                        ty,
+                        offset,
+                        increment,
+                        overflow,
+                    });
+                }
+                "overflow" => {
+                    overflow = match next()?.to_lowercase().as_str() {
+                        "wrap" => Overflow::Wrap,
+                        "sat" => Overflow::Sat,
+                        "fail" => Overflow::Fail,
+                        _ => bail!("ERR Invalid OVERFLOW type specified"),
+                    }
+                }
+                _ => bail!("ERR syntax error"),
+            }
+        }
+        Ok(ops)
+    }
+
+    /// Whether the operation may modify the underlying string.
+    pub fn is_write(&self) -> bool {
+        !matches!(self, Self::Get { .. })
+    }
+
+    /// Executes the operation against `bytes`, growing them with zero bytes when a write
+    /// reaches past the end of the string.
+    ///
+    /// Returns the value to report for this subcommand: the field value for `GET`, the
+    /// previous value for `SET`, the new value for `INCRBY`, or `None` when an overflow
+    /// with the `FAIL` policy prevented the write.
+    pub fn apply(&self, bytes: &mut Vec<u8>) -> Option<i64> {
+        match *self {
+            Self::Get { ty, offset } => Some(ty.decode(read_bits(bytes, offset, ty.bits))),
+            Self::Set {
+                ty,
+                offset,
+                value,
+                overflow,
+            } => {
+                grow_to_fit(bytes, offset + ty.bits as u64);
+                let previous = ty.decode(read_bits(bytes, offset, ty.bits));
+                let value = ty.fit(value as i128, overflow)?;
+                write_bits(bytes, offset, ty.bits, ty.encode(value));
+                Some(previous)
+            }
+            Self::IncrBy {
+                ty,
+                offset,
+                increment,
+                overflow,
+            } => {
+                grow_to_fit(bytes, offset + ty.bits as u64);
+                let current = ty.decode(read_bits(bytes, offset, ty.bits));
+                let value = ty.fit(current as i128 + increment as i128, overflow)?;
+                write_bits(bytes, offset, ty.bits, ty.encode(value));
+                Some(value)
+            }
+        }
+    }
+}
+
+/// Parses a bit offset, where the `#N` form addresses the N-th field of the given type.
+fn parse_offset(s: &str, ty: BitFieldType) -> Result<u64> {
+    let invalid = "ERR bit offset is not an integer or out of range";
+    let offset = match s.strip_prefix('#') {
+        Some(index) => index
+            .parse::<u64>()
+            .ok()
+            .and_then(|index| index.checked_mul(ty.bits as u64)),
+        None => s.parse::<u64>().ok(),
+    }
+    .context(invalid)?;
+    if offset.saturating_add(ty.bits as u64 - 1) > MAX_BIT_OFFSET {
+        bail!(invalid)
+    }
+    Ok(offset)
+}
+
+fn parse_integer(s: &str) -> Result<i64> {
+    s.parse::<i64>()
+        .context("ERR value is not an integer or out of range")
+}
+
+fn grow_to_fit(bytes: &mut Vec<u8>, end_bit: u64) {
+    let len = end_bit.div_ceil(8) as usize;
+    if bytes.len() < len {
+        bytes.resize(len, 0);
+    }
+}
+
+/// Reads `bits` bits starting at `offset`, where bit 0 is the most significant bit of the
+/// first byte. Bits past the end of the string read as zero.
+fn read_bits(bytes: &[u8], offset: u64, bits: u32) -> u64 {
+    (offset..offset + bits as u64).fold(0, |acc, pos| {
+        let bit = bytes
+            .get((pos / 8) as usize)
+            .map_or(0, |byte| (byte >> (7 - pos % 8)) & 1);
+        (acc << 1) | bit as u64
+    })
+}
+
+fn write_bits(bytes: &mut [u8], offset: u64, bits: u32, value: u64) {
+    for i in 0..bits as u64 {
+        let pos = offset + i;
+        let mask = 1 << (7 - pos % 8);
+        let byte = &mut bytes[(pos / 8) as usize];
+        if (value >> (bits as u64 - 1 - i)) & 1 == 1 {
+            *byte |= mask;
+        } else {
+            *byte &= !mask;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ops(args: &[&str]) -> Vec<BitFieldOp> {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        BitFieldOp::parse_all(&args).unwrap()
+    }
+
+    fn run(bytes: &mut Vec<u8>, args: &[&str]) -> Vec<Option<i64>> {
+        ops(args).iter().map(|op| op.apply(bytes)).collect()
+    }
+
+    #[test]
+    fn test_signed_incrby_saturates() {
+        let mut bytes = vec![];
+        let results = run(
+            &mut bytes,
+            &[
+                "OVERFLOW", "SAT", "INCRBY", "i8", "0", "100", "INCRBY", "i8", "0", "100",
+            ],
+        );
+        assert_eq!(results, vec![Some(100), Some(127)]);
+
+        let results = run(
+            &mut bytes,
+            &["OVERFLOW", "SAT", "INCRBY", "i8", "0", "-300"],
+        );
+        assert_eq!(results, vec![Some(-128)]);
+    }
+
+    #[test]
+    fn test_get_of_set_field() {
+        let mut bytes = vec![];
+        let results = run(&mut bytes, &["SET", "u8", "#1", "200", "GET", "u8", "#1"]);
+        assert_eq!(results, vec![Some(0), Some(200)]);
+        assert_eq!(bytes, vec![0, 200]);
+
+        let results = run(&mut bytes, &["GET", "i8", "8", "GET", "u4", "8"]);
+        assert_eq!(results, vec![Some(-56), Some(12)]);
+    }
+
+    #[test]
+    fn test_fail_overflow_returns_null() {
+        let mut bytes = vec![];
+        let results = run(
+            &mut bytes,
+            &[
+                "OVERFLOW", "FAIL", "INCRBY", "u2", "0", "4", "SET", "u2", "0", "3", "INCRBY",
+                "u2", "0", "1",
+            ],
+        );
+        assert_eq!(results, vec![None, Some(0), None]);
+        assert_eq!(run(&mut bytes, &["GET", "u2", "0"]), vec![Some(3)]);
+    }
+
+    #[test]
+    fn test_wrap_is_default() {
+        let mut bytes = vec![];
+        let results = run(
+            &mut bytes,
+            &["INCRBY", "u8", "0", "300", "INCRBY", "i8", "8", "130"],
+        );
+        assert_eq!(results, vec![Some(44), Some(-126)]);
+    }
+
+    #[test]
+    fn test_invalid_types() {
+        for ty in ["u64", "i65", "i0", "x8", ""] {
+            let args = vec!["GET".to_string(), ty.to_string(), "0".to_string()];
+            assert!(
+                BitFieldOp::parse_all(&args).is_err(),
+                "{ty} should be rejected"
+            );
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use anyhow::{bail, Result};
+use chrono::{DateTime, Utc};
+
+/// The conditions `NX`, `XX`, `GT` and `LT` an `EXPIRE`-family command may put on replacing
+/// the expiry of a key.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ExpireCondition {
+    /// `NX`: only set an expiry on a key that has none.
+    pub if_none: bool,
+    /// `XX`: only replace an expiry the key already has.
+    pub if_some: bool,
+    /// `GT`: only extend the expiry. A key without one never expires, so it is never extended.
+    pub if_later: bool,
+    /// `LT`: only shorten the expiry, which a key without one always allows.
+    pub if_earlier: bool,
+}
+
+impl ExpireCondition {
+    /// Parses the flags following the key and time of an `EXPIRE`-family command.
+    ///
+    /// `NX` cannot be combined with any other flag, and `GT` and `LT` are mutually exclusive.
+    ///
+    /// # Examples
+    /// ```
+    /// let args = ["XX", "GT"].map(String::from);
+    /// let condition = ExpireCondition::parse(&args).unwrap();
+    /// assert!(condition.if_some && condition.if_later);
+    /// ```
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut condition = Self::default();
+        for flag in args {
+            match flag.to_lowercase().as_str() {
+                "nx" => condition.if_none = true,
+                "xx" => condition.if_some = true,
+                "gt" => condition.if_later = true,
+                "lt" => condition.if_earlier = true,
+                _ => bail!("ERR Unsupported option {}", flag),
+            }
+        }
+        if condition.if_none && (condition.if_some || condition.if_later || condition.if_earlier) {
+            bail!("ERR NX and XX, GT or LT options at the same time are not compatible")
+        }
+        if condition.if_later && condition.if_earlier {
+            bail!("ERR GT and LT options at the same time are not compatible")
+        }
+        Ok(condition)
+    }
+
+    /// Whether a key whose expiry is `current` may be given the expiry `new`.
+    pub fn allows(&self, current: Option<DateTime<Utc>>, new: DateTime<Utc>) -> bool {
+        match current {
+            None => !self.if_some && !self.if_later,
+            Some(current) => {
+                !self.if_none
+                    && (!self.if_later || new > current)
+                    && (!self.if_earlier || new < current)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn parse(args: &[&str]) -> Result<ExpireCondition> {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        ExpireCondition::parse(&args)
+    }
+
+    #[test]
+    fn test_each_flag_passes_and_fails() {
+        let now = Utc::now();
+        let (earlier, later) = (now - Duration::seconds(10), now + Duration::seconds(10));
+
+        let nx = parse(&["nx"]).unwrap();
+        assert!(nx.allows(None, now));
+        assert!(!nx.allows(Some(later), now));
+
+        let xx = parse(&["XX"]).unwrap();
+        assert!(xx.allows(Some(later), now));
+        assert!(!xx.allows(None, now));
+
+        let gt = parse(&["GT"]).unwrap();
+        assert!(gt.allows(Some(earlier), now));
+        assert!(!gt.allows(Some(later), now));
+        assert!(!gt.allows(Some(now), now));
+        assert!(!gt.allows(None, now));
+
+        let lt = parse(&["LT"]).unwrap();
+        assert!(lt.allows(Some(later), now));
+        assert!(!lt.allows(Some(earlier), now));
+        assert!(lt.allows(None, now));
+
+        let xx_lt = parse(&["XX", "LT"]).unwrap();
+        assert!(xx_lt.allows(Some(later), now));
+        assert!(!xx_lt.allows(None, now));
+        assert!(ExpireCondition::default().allows(None, now));
+    }
+
+    #[test]
+    fn test_parse_rejects_incompatible_flags() {
+        for args in [&["NX", "XX"][..], &["NX", "GT"], &["LT", "NX"]] {
+            assert!(parse(args)
+                .unwrap_err()
+                .to_string()
+                .starts_with("ERR NX and XX, GT or LT"));
+        }
+        assert!(parse(&["GT", "LT"]).is_err());
+        assert_eq!(
+            parse(&["SOMETIMES"]).unwrap_err().to_string(),
+            "ERR Unsupported option SOMETIMES"
+        );
+    }
+}
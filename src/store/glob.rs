@@ -0,0 +1,101 @@
+/// Matches `string` against a Redis glob `pattern`, as used by `KEYS`.
+///
+/// Supports `*` (any run of characters), `?` (any single character), character classes such as
+/// `[abc]`, `[a-z]` and `[^a]`, and `\` to match the next pattern character literally.
+pub fn glob_match(pattern: &[u8], string: &[u8]) -> bool {
+    let (mut p, mut s) = (0, 0);
+    // The pattern position following the last `*` seen, and the string position that `*` has
+    // consumed up to. On a mismatch the `*` swallows one more character and matching resumes.
+    let mut backtrack: Option<(usize, usize)> = None;
+
+    while s < string.len() {
+        let consumed = match pattern.get(p) {
+            Some(b'*') => {
+                p += 1;
+                backtrack = Some((p, s));
+                continue;
+            }
+            Some(b'?') => Some(1),
+            Some(b'[') => match_class(&pattern[p + 1..], string[s]).map(|len| len + 1),
+            Some(b'\\') if p + 1 < pattern.len() => (pattern[p + 1] == string[s]).then_some(2),
+            Some(&c) => (c == string[s]).then_some(1),
+            None => None,
+        };
+        match (consumed, backtrack) {
+            (Some(len), _) => {
+                p += len;
+                s += 1;
+            }
+            (None, Some((star_p, star_s))) => {
+                p = star_p;
+                s = star_s + 1;
+                backtrack = Some((star_p, s));
+            }
+            (None, None) => return false,
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+/// Matches `c` against the character class starting right after its `[`, returning how many
+/// pattern bytes the rest of the class takes up (including the closing `]`) if it matches.
+///
+/// A class left unterminated extends to the end of the pattern, as in Redis.
+fn match_class(class: &[u8], c: u8) -> Option<usize> {
+    let negated = class.first() == Some(&b'^');
+    let mut i = negated as usize;
+    let mut matched = false;
+
+    while i < class.len() && class[i] != b']' {
+        if class[i] == b'\\' && i + 1 < class.len() {
+            matched |= class[i + 1] == c;
+            i += 2;
+        } else if i + 2 < class.len() && class[i + 1] == b'-' && class[i + 2] != b']' {
+            let (low, high) = (class[i].min(class[i + 2]), class[i].max(class[i + 2]));
+            matched |= (low..=high).contains(&c);
+            i += 3;
+        } else {
+            matched |= class[i] == c;
+            i += 1;
+        }
+    }
+    let len = (i + 1).min(class.len());
+    (matched != negated).then_some(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, string: &str) -> bool {
+        glob_match(pattern.as_bytes(), string.as_bytes())
+    }
+
+    #[test]
+    fn test_wildcards() {
+        assert!(matches("*", ""));
+        assert!(matches("*", "anything"));
+        assert!(matches("user:*", "user:42"));
+        assert!(!matches("user:*", "session:42"));
+        assert!(matches("*:42", "user:42"));
+        assert!(matches("u*r*2", "user:42"));
+        assert!(!matches("u*r*3", "user:42"));
+        assert!(matches("h?llo", "hello"));
+        assert!(!matches("h?llo", "hllo"));
+        assert!(!matches("hello", "hello!"));
+    }
+
+    #[test]
+    fn test_classes_and_escapes() {
+        assert!(matches("h[ae]llo", "hallo"));
+        assert!(!matches("h[ae]llo", "hillo"));
+        assert!(matches("h[^e]llo", "hallo"));
+        assert!(!matches("h[^e]llo", "hello"));
+        assert!(matches("key[0-9]", "key7"));
+        assert!(matches("key[9-0]", "key7"));
+        assert!(!matches("key[0-9]", "keyx"));
+        assert!(matches("what\\?", "what?"));
+        assert!(!matches("what\\?", "whatx"));
+        assert!(matches("[\\]]", "]"));
+    }
+}
@@ -0,0 +1,138 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+/// The fields of a hash along with their values, and the expiry of the fields that were given
+/// one with `HEXPIRE`.
+///
+/// Like keys, fields expire lazily: an expired field is hidden from every read, and removed by
+/// the next write to the hash.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Hash {
+    fields: HashMap<String, String>,
+    expiries: HashMap<String, DateTime<Utc>>,
+}
+
+impl Hash {
+    pub fn len(&self) -> usize {
+        if self.expiries.is_empty() {
+            self.fields.len()
+        } else {
+            self.iter().count()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn get(&self, field: &str) -> Option<&String> {
+        let now = Utc::now();
+        self.fields
+            .get(field)
+            .filter(|_| !self.is_expired_at(field, now))
+    }
+
+    /// Iterates over the fields that have not expired and their values, in no particular
+    /// order.
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &String)> {
+        let now = Utc::now();
+        self.fields
+            .iter()
+            .filter(move |(field, _)| !self.is_expired_at(field, now))
+    }
+
+    /// Sets `field` to `value`, discarding any expiry it had, and returns its previous value.
+    pub fn insert(&mut self, field: String, value: String) -> Option<String> {
+        self.evict_expired();
+        self.expiries.remove(&field);
+        self.fields.insert(field, value)
+    }
+
+    /// Removes `field`, returning its value.
+    pub fn remove(&mut self, field: &str) -> Option<String> {
+        self.evict_expired();
+        self.expiries.remove(field);
+        self.fields.remove(field)
+    }
+
+    /// The expiry of `field`, if it has one.
+    pub fn expiry(&self, field: &str) -> Option<DateTime<Utc>> {
+        self.expiries.get(field).copied()
+    }
+
+    /// Sets the expiry of `field`, which has to exist.
+    pub fn set_expiry(&mut self, field: &str, expire_at: DateTime<Utc>) {
+        self.expiries.insert(field.to_string(), expire_at);
+    }
+
+    /// Removes the expiry of `field`, returning whether it had one.
+    pub fn persist(&mut self, field: &str) -> bool {
+        self.expiries.remove(field).is_some()
+    }
+
+    fn is_expired_at(&self, field: &str, now: DateTime<Utc>) -> bool {
+        self.expiries
+            .get(field)
+            .is_some_and(|expiry| *expiry <= now)
+    }
+
+    fn evict_expired(&mut self) {
+        if self.expiries.is_empty() {
+            return;
+        }
+        let now = Utc::now();
+        let fields = &mut self.fields;
+        self.expiries.retain(|field, expiry| {
+            let expired = *expiry <= now;
+            if expired {
+                fields.remove(field);
+            }
+            !expired
+        });
+    }
+}
+
+impl FromIterator<(String, String)> for Hash {
+    fn from_iter<T: IntoIterator<Item = (String, String)>>(iter: T) -> Self {
+        Self {
+            fields: iter.into_iter().collect(),
+            expiries: HashMap::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_expired_fields_are_hidden_and_then_removed() {
+        let mut hash: Hash = [("a", "1"), ("b", "2")]
+            .map(|(field, value)| (field.to_string(), value.to_string()))
+            .into_iter()
+            .collect();
+        hash.set_expiry("a", Utc::now() - Duration::milliseconds(1));
+        hash.set_expiry("b", Utc::now() + Duration::milliseconds(60_000));
+
+        assert_eq!(hash.get("a"), None);
+        assert_eq!(hash.get("b").map(String::as_str), Some("2"));
+        assert_eq!(hash.len(), 1);
+        assert_eq!(hash.iter().count(), 1);
+        assert!(hash.fields.contains_key("a"));
+
+        assert_eq!(hash.insert("c".to_string(), "3".to_string()), None);
+        assert!(!hash.fields.contains_key("a"));
+        assert!(hash.expiry("b").is_some());
+
+        // Setting a field discards its expiry.
+        assert_eq!(
+            hash.insert("b".to_string(), "4".to_string()).as_deref(),
+            Some("2")
+        );
+        assert_eq!(hash.expiry("b"), None);
+        assert!(!hash.persist("b"));
+        assert_eq!(hash.len(), 2);
+    }
+}
@@ -1,6 +1,19 @@
+pub mod bitfield;
+pub mod expire_options;
+pub mod glob;
+pub mod hash;
+pub mod rdb;
 pub mod redis_type;
 pub mod replica;
+pub mod set_options;
+pub mod sorted_set;
+#[allow(clippy::module_inception)]
 pub mod store;
 
+pub use bitfield::BitFieldOp;
+pub use expire_options::ExpireCondition;
+pub use hash::Hash;
 pub use redis_type::RedisType;
+pub use set_options::{SetCondition, SetOptions};
+pub use sorted_set::{SortedSet, ZAddOptions};
 pub use store::KeyValueStore;
@@ -0,0 +1,409 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use log::warn;
+
+use super::{Hash, RedisType, SortedSet};
+
+pub const MAGIC: &[u8] = b"REDIS";
+const VERSION: &[u8] = b"0011";
+
+const OPCODE_AUX: u8 = 0xFA;
+const OPCODE_RESIZEDB: u8 = 0xFB;
+const OPCODE_EXPIRETIME_MS: u8 = 0xFC;
+const OPCODE_EXPIRETIME: u8 = 0xFD;
+const OPCODE_SELECTDB: u8 = 0xFE;
+const OPCODE_EOF: u8 = 0xFF;
+
+const TYPE_STRING: u8 = 0;
+const TYPE_LIST: u8 = 1;
+const TYPE_SET: u8 = 2;
+const TYPE_HASH: u8 = 4;
+const TYPE_ZSET_2: u8 = 5;
+
+/// A key loaded from an RDB snapshot.
+#[derive(Clone)]
+pub struct RdbEntry {
+    /// Index of the database the key belongs to.
+    pub db: usize,
+    pub key: String,
+    pub value: RedisType,
+    pub expire_at: Option<DateTime<Utc>>,
+}
+
+/// Serializes the given keys, each along with the index of its database, into an RDB snapshot.
+///
+/// Strings, lists, sets, hashes and sorted sets are written using the plain encodings every Redis version
+/// can load. Streams have no such encoding and are left out with a warning.
+pub fn encode<'a>(
+    entries: impl IntoIterator<Item = (usize, &'a str, &'a RedisType, Option<DateTime<Utc>>)>,
+) -> Vec<u8> {
+    let mut databases: BTreeMap<usize, Vec<_>> = BTreeMap::new();
+    for (db, key, value, expire_at) in entries {
+        if matches!(value, RedisType::Stream(_)) {
+            warn!("[RDB] - Leaving stream '{}' out of the snapshot.", key);
+            continue;
+        }
+        databases
+            .entry(db)
+            .or_default()
+            .push((key, value, expire_at));
+    }
+
+    let mut rdb = [MAGIC, VERSION].concat();
+    for (db, entries) in databases {
+        let expiring = entries.iter().filter(|(_, _, expiry)| expiry.is_some());
+        rdb.push(OPCODE_SELECTDB);
+        write_length(&mut rdb, db as u64);
+        rdb.push(OPCODE_RESIZEDB);
+        write_length(&mut rdb, entries.len() as u64);
+        write_length(&mut rdb, expiring.count() as u64);
+        write_entries(&mut rdb, entries);
+    }
+
+    rdb.push(OPCODE_EOF);
+    let checksum = crc64(&rdb);
+    rdb.extend_from_slice(&checksum.to_le_bytes());
+    rdb
+}
+
+fn write_entries(rdb: &mut Vec<u8>, entries: Vec<(&str, &RedisType, Option<DateTime<Utc>>)>) {
+    for (key, value, expire_at) in entries {
+        if let Some(expire_at) = expire_at {
+            rdb.push(OPCODE_EXPIRETIME_MS);
+            rdb.extend_from_slice(&expire_at.timestamp_millis().to_le_bytes());
+        }
+        rdb.push(value_type(value));
+        write_string(rdb, key.as_bytes());
+        write_value(rdb, value);
+    }
+}
+
+/// Returns how many bytes `value` takes up in a snapshot, not counting its key and type, or
+/// `None` for a stream, which snapshots leave out.
+pub fn serialized_length(value: &RedisType) -> Option<usize> {
+    if matches!(value, RedisType::Stream(_)) {
+        return None;
+    }
+    let mut serialized = vec![];
+    write_value(&mut serialized, value);
+    Some(serialized.len())
+}
+
+fn value_type(value: &RedisType) -> u8 {
+    match value {
+        RedisType::String(_) => TYPE_STRING,
+        RedisType::List(_) => TYPE_LIST,
+        RedisType::Set(_) => TYPE_SET,
+        RedisType::Hash(_) => TYPE_HASH,
+        RedisType::SortedSet(_) => TYPE_ZSET_2,
+        RedisType::Stream(_) => unreachable!("streams are filtered out by `encode`"),
+    }
+}
+
+fn write_value(rdb: &mut Vec<u8>, value: &RedisType) {
+    match value {
+        RedisType::String(bytes) => write_string(rdb, bytes),
+        RedisType::List(list) => {
+            write_length(rdb, list.len() as u64);
+            list.iter()
+                .for_each(|item| write_string(rdb, item.as_bytes()));
+        }
+        RedisType::Set(set) => {
+            write_length(rdb, set.len() as u64);
+            set.iter()
+                .for_each(|member| write_string(rdb, member.as_bytes()));
+        }
+        RedisType::Hash(hash) => {
+            // Only the fields are saved: the expiries `HEXPIRE` gives them need the hash
+            // metadata encoding, which is not supported.
+            write_length(rdb, hash.len() as u64);
+            for (field, value) in hash.iter() {
+                write_string(rdb, field.as_bytes());
+                write_string(rdb, value.as_bytes());
+            }
+        }
+        RedisType::SortedSet(set) => {
+            write_length(rdb, set.len() as u64);
+            for (member, score) in set.iter() {
+                write_string(rdb, member.as_bytes());
+                rdb.extend_from_slice(&score.to_le_bytes());
+            }
+        }
+        RedisType::Stream(_) => unreachable!("streams are filtered out by `encode`"),
+    }
+}
+
+/// Parses an RDB snapshot, returning the keys of every database in it.
+///
+/// The checksum is verified unless it is zero, which Redis writes when checksums are disabled.
+/// Only the value types `encode` writes are supported.
+pub fn decode(rdb: &[u8]) -> Result<Vec<RdbEntry>> {
+    decode_prefix(rdb).map(|(entries, _)| entries)
+}
+
+/// Like `decode`, but for a snapshot followed by other data: also returns the length of the
+/// snapshot, so the caller knows where the rest starts.
+pub fn decode_prefix(rdb: &[u8]) -> Result<(Vec<RdbEntry>, usize)> {
+    let mut reader = Reader { rdb, position: 0 };
+    if reader.take(MAGIC.len())? != MAGIC {
+        bail!("Not an RDB file")
+    }
+    reader.take(VERSION.len())?;
+
+    let mut entries = Vec::new();
+    let mut db = 0;
+    let mut expire_at = None;
+    loop {
+        match reader.byte()? {
+            OPCODE_EOF => break,
+            OPCODE_AUX => {
+                reader.string()?;
+                reader.string()?;
+            }
+            OPCODE_SELECTDB => {
+                db = reader.length()?;
+            }
+            OPCODE_RESIZEDB => {
+                reader.length()?;
+                reader.length()?;
+            }
+            OPCODE_EXPIRETIME_MS => {
+                let millis = i64::from_le_bytes(reader.take(8)?.try_into()?);
+                expire_at =
+                    Some(DateTime::from_timestamp_millis(millis).context("Invalid expiry")?);
+            }
+            OPCODE_EXPIRETIME => {
+                let seconds = u32::from_le_bytes(reader.take(4)?.try_into()?);
+                expire_at =
+                    Some(DateTime::from_timestamp(seconds.into(), 0).context("Invalid expiry")?);
+            }
+            value_type => {
+                let key = String::from_utf8_lossy(&reader.string()?).to_string();
+                let value = reader.value(value_type)?;
+                entries.push(RdbEntry {
+                    db,
+                    key,
+                    value,
+                    expire_at: expire_at.take(),
+                });
+            }
+        }
+    }
+
+    let checksum_start = reader.position;
+    let checksum = u64::from_le_bytes(reader.take(8)?.try_into()?);
+    if checksum != 0 && checksum != crc64(&rdb[..checksum_start]) {
+        bail!("RDB checksum mismatch")
+    }
+    Ok((entries, reader.position))
+}
+
+/// Writes a length using the RDB variable-length encoding.
+fn write_length(rdb: &mut Vec<u8>, length: u64) {
+    match length {
+        0..=0x3F => rdb.push(length as u8),
+        0x40..=0x3FFF => rdb.extend_from_slice(&(0x4000 | length as u16).to_be_bytes()),
+        0x4000..=0xFFFF_FFFF => {
+            rdb.push(0x80);
+            rdb.extend_from_slice(&(length as u32).to_be_bytes());
+        }
+        _ => {
+            rdb.push(0x81);
+            rdb.extend_from_slice(&length.to_be_bytes());
+        }
+    }
+}
+
+fn write_string(rdb: &mut Vec<u8>, bytes: &[u8]) {
+    write_length(rdb, bytes.len() as u64);
+    rdb.extend_from_slice(bytes);
+}
+
+/// A cursor over the bytes of an RDB snapshot.
+struct Reader<'a> {
+    rdb: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .rdb
+            .get(self.position..self.position + len)
+            .context("Unexpected end of RDB file")?;
+        self.position += len;
+        Ok(bytes)
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    /// Reads a variable-length encoded length, or the format of a specially encoded string,
+    /// which is signalled by the two high bits of the first byte both being set.
+    fn length_or_format(&mut self) -> Result<(u64, bool)> {
+        let first = self.byte()?;
+        match first >> 6 {
+            0 => Ok(((first & 0x3F).into(), false)),
+            1 => Ok((
+                (u64::from(first & 0x3F) << 8) | u64::from(self.byte()?),
+                false,
+            )),
+            3 => Ok(((first & 0x3F).into(), true)),
+            _ => match first {
+                0x80 => Ok((u32::from_be_bytes(self.take(4)?.try_into()?).into(), false)),
+                0x81 => Ok((u64::from_be_bytes(self.take(8)?.try_into()?), false)),
+                _ => bail!("Invalid RDB length encoding {:#04x}", first),
+            },
+        }
+    }
+
+    fn length(&mut self) -> Result<usize> {
+        match self.length_or_format()? {
+            (length, false) => Ok(length.try_into()?),
+            (_, true) => bail!("Expected a length, found a string encoding"),
+        }
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        match self.length_or_format()? {
+            (length, false) => Ok(self.take(length.try_into()?)?.to_vec()),
+            (0, true) => Ok((self.byte()? as i8).to_string().into_bytes()),
+            (1, true) => Ok(i16::from_le_bytes(self.take(2)?.try_into()?)
+                .to_string()
+                .into_bytes()),
+            (2, true) => Ok(i32::from_le_bytes(self.take(4)?.try_into()?)
+                .to_string()
+                .into_bytes()),
+            (format, true) => bail!("Unsupported RDB string encoding {}", format),
+        }
+    }
+
+    fn utf8_string(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(&self.string()?).to_string())
+    }
+
+    fn value(&mut self, value_type: u8) -> Result<RedisType> {
+        match value_type {
+            TYPE_STRING => Ok(RedisType::String(self.string()?)),
+            TYPE_LIST => {
+                let len = self.length()?;
+                let list = (0..len)
+                    .map(|_| self.utf8_string())
+                    .collect::<Result<VecDeque<_>>>()?;
+                Ok(RedisType::List(list))
+            }
+            TYPE_SET => {
+                let len = self.length()?;
+                let set = (0..len)
+                    .map(|_| self.utf8_string())
+                    .collect::<Result<HashSet<_>>>()?;
+                Ok(RedisType::Set(set))
+            }
+            TYPE_HASH => {
+                let len = self.length()?;
+                let hash = (0..len)
+                    .map(|_| Ok((self.utf8_string()?, self.utf8_string()?)))
+                    .collect::<Result<Hash>>()?;
+                Ok(RedisType::Hash(hash))
+            }
+            TYPE_ZSET_2 => {
+                let len = self.length()?;
+                let set = (0..len)
+                    .map(|_| {
+                        let member = self.utf8_string()?;
+                        Ok((member, f64::from_le_bytes(self.take(8)?.try_into()?)))
+                    })
+                    .collect::<Result<SortedSet>>()?;
+                Ok(RedisType::SortedSet(set))
+            }
+            _ => bail!("Unsupported RDB value type {}", value_type),
+        }
+    }
+}
+
+/// The CRC-64/Jones checksum Redis appends to RDB files.
+fn crc64(bytes: &[u8]) -> u64 {
+    const POLY: u64 = 0x95AC_9329_AC4B_C9B5;
+    bytes.iter().fold(0, |crc, &byte| {
+        (0..8).fold(crc ^ u64::from(byte), |crc, _| {
+            if crc & 1 == 1 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hex_literal::hex;
+
+    #[test]
+    fn test_crc64_matches_redis() {
+        assert_eq!(crc64(b"123456789"), 0xe9c6d914c4b8d9ca);
+    }
+
+    #[test]
+    fn test_decode_snapshot_written_by_redis() {
+        // An empty snapshot from Redis 7.2, holding only auxiliary fields.
+        let rdb = hex!("524544495330303131fa0972656469732d76657205372e322e30fa0a72656469732d62697473c040fa056374696d65c26d08bc65fa08757365642d6d656dc2b0c41000fa08616f662d62617365c000fff06e3bfec0ff5aa2");
+        assert!(decode(&rdb).unwrap().is_empty());
+
+        let mut corrupted = rdb;
+        corrupted[20] ^= 1;
+        assert!(decode(&corrupted).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let expire_at = DateTime::from_timestamp_millis(4_102_444_800_000).unwrap();
+        let long_value = RedisType::String(vec![b'x'; 20_000]);
+        let list = RedisType::List(["a", "b"].map(String::from).into());
+        let hash = RedisType::Hash([("f".to_string(), "v".to_string())].into_iter().collect());
+        let stream = RedisType::Stream(Default::default());
+        let zset = [("b", 1.5), ("a", f64::NEG_INFINITY)].map(|(m, s)| (m.to_string(), s));
+        let zset = RedisType::SortedSet(zset.into_iter().collect());
+        let rdb = encode([
+            (0, "long", &long_value, None),
+            (0, "list", &list, Some(expire_at)),
+            (3, "hash", &hash, None),
+            (0, "stream", &stream, None),
+            (3, "zset", &zset, None),
+        ]);
+
+        let entries = decode(&rdb).unwrap();
+        let keys: Vec<&str> = entries.iter().map(|entry| entry.key.as_str()).collect();
+        assert_eq!(keys, vec!["long", "list", "hash", "zset"]);
+        let dbs: Vec<usize> = entries.iter().map(|entry| entry.db).collect();
+        assert_eq!(dbs, vec![0, 0, 3, 3]);
+        assert!(matches!(&entries[0].value, RedisType::String(bytes) if bytes.len() == 20_000));
+        assert!(matches!(&entries[1].value, RedisType::List(list) if list == &["a", "b"]));
+        assert_eq!(entries[1].expire_at, Some(expire_at));
+        assert!(
+            matches!(&entries[2].value, RedisType::Hash(hash) if hash.get("f").unwrap() == "v")
+        );
+        assert!(
+            matches!((&entries[3].value, &zset), (RedisType::SortedSet(a), RedisType::SortedSet(b)) if a == b)
+        );
+        assert_eq!(entries[0].expire_at, None);
+    }
+
+    #[test]
+    fn test_length_encoding_boundaries() {
+        for length in [0, 0x3F, 0x40, 0x3FFF, 0x4000, 0xFFFF_FFFF, 0x1_0000_0000] {
+            let mut rdb = vec![];
+            write_length(&mut rdb, length);
+            let mut reader = Reader {
+                rdb: &rdb,
+                position: 0,
+            };
+            assert_eq!(reader.length_or_format().unwrap(), (length, false));
+            assert_eq!(reader.position, rdb.len());
+        }
+    }
+}
@@ -1,47 +1,399 @@
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
 
-use crate::parser::{Payload, DELIMITER};
+use anyhow::{bail, Context, Result};
+use chrono::Utc;
+
+use super::{Hash, SortedSet};
+
+/// Longest string Redis stores inline with its object header.
+const MAX_EMBSTR_LEN: usize = 44;
+/// Most elements, and longest element, a list, hash, set or sorted set keeps in a listpack.
+const MAX_LISTPACK_ENTRIES: usize = 128;
+const MAX_LISTPACK_VALUE: usize = 64;
+/// Most integer members a set keeps in an intset.
+const MAX_INTSET_ENTRIES: usize = 512;
+
+pub const INVALID_STREAM_ID: &str = "ERR Invalid stream ID specified as stream command argument";
+const ID_NOT_GREATER: &str =
+    "ERR The ID specified in XADD is equal or smaller than the target stream top item";
 
 #[derive(Clone)]
 pub enum RedisType {
-    String(String),
+    String(Vec<u8>),
     Stream(Stream),
+    List(VecDeque<String>),
+    Hash(Hash),
+    Set(HashSet<String>),
+    SortedSet(SortedSet),
 }
 impl RedisType {
-    pub fn as_inner(&self) -> &str {
+    /// The type name `TYPE` reports for the value.
+    pub fn type_str(&self) -> &'static str {
         match self {
-            RedisType::String(s) => s,
-            RedisType::Stream(_) => "Invalid call for stream.",
+            RedisType::String(_) => "string",
+            RedisType::Stream(_) => "stream",
+            RedisType::List(_) => "list",
+            RedisType::Hash(_) => "hash",
+            RedisType::Set(_) => "set",
+            RedisType::SortedSet(_) => "zset",
         }
     }
 
-    pub fn type_str(&self) -> String {
+    /// The internal encoding `OBJECT ENCODING` reports for the value, chosen with the same
+    /// size thresholds Redis uses by default to switch to its larger representations.
+    pub fn encoding(&self) -> &'static str {
         match self {
-            RedisType::String(_) => format!("+string{}", DELIMITER),
-            RedisType::Stream(_) => format!("+stream{}", DELIMITER),
+            RedisType::String(bytes) => {
+                let is_int = bytes.len() <= 20
+                    && std::str::from_utf8(bytes).is_ok_and(|s| s.parse::<i64>().is_ok());
+                if is_int {
+                    "int"
+                } else if bytes.len() <= MAX_EMBSTR_LEN {
+                    "embstr"
+                } else {
+                    "raw"
+                }
+            }
+            RedisType::Stream(_) => "stream",
+            RedisType::List(list) => {
+                if fits_listpack(list.len(), list.iter().map(String::len)) {
+                    "listpack"
+                } else {
+                    "quicklist"
+                }
+            }
+            RedisType::Hash(hash) => {
+                let sizes = hash.iter().flat_map(|(k, v)| [k.len(), v.len()]);
+                if fits_listpack(hash.len(), sizes) {
+                    "listpack"
+                } else {
+                    "hashtable"
+                }
+            }
+            RedisType::Set(set) => {
+                if set.len() <= MAX_INTSET_ENTRIES && set.iter().all(|m| m.parse::<i64>().is_ok()) {
+                    "intset"
+                } else if fits_listpack(set.len(), set.iter().map(String::len)) {
+                    "listpack"
+                } else {
+                    "hashtable"
+                }
+            }
+            RedisType::SortedSet(set) => {
+                if fits_listpack(set.len(), set.iter().map(|(member, _)| member.len())) {
+                    "listpack"
+                } else {
+                    "skiplist"
+                }
+            }
+        }
+    }
+}
+
+/// Whether a collection of `len` elements, with elements of the given sizes, is small enough
+/// to be kept in a listpack.
+fn fits_listpack(len: usize, mut sizes: impl Iterator<Item = usize>) -> bool {
+    len <= MAX_LISTPACK_ENTRIES && sizes.all(|size| size <= MAX_LISTPACK_VALUE)
+}
+
+/// The end of a list that a push or pop operates on.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ListEnd {
+    Left,
+    Right,
+}
+
+/// How `SINTER`, `SUNION` and `SDIFF` combine the sets they are given.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetOperation {
+    Intersection,
+    Union,
+    /// The members of the first set that are in none of the others.
+    Difference,
+}
+
+/// The ID of a stream entry: a millisecond timestamp and a sequence number to tell apart
+/// entries added within the same millisecond. Entries are ordered by ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct StreamId {
+    pub ms: u64,
+    pub seq: u64,
+}
+
+impl StreamId {
+    pub const MIN: Self = Self { ms: 0, seq: 0 };
+    pub const MAX: Self = Self {
+        ms: u64::MAX,
+        seq: u64::MAX,
+    };
+
+    /// Parses an `<ms>-<seq>` ID. The sequence number may be left out, in which case
+    /// `default_seq` is used, so that a bare timestamp can stand for the first or last entry of
+    /// that millisecond.
+    pub fn parse(id: &str, default_seq: u64) -> Result<Self> {
+        let (ms, seq) = match id.split_once('-') {
+            Some((ms, seq)) => (ms, seq.parse().context(INVALID_STREAM_ID)?),
+            None => (id, default_seq),
+        };
+        let ms = ms.parse().context(INVALID_STREAM_ID)?;
+        Ok(Self { ms, seq })
+    }
+
+    /// Parses an `XRANGE` bound, where `-` and `+` stand for the smallest and largest IDs.
+    pub fn parse_range_bound(bound: &str, default_seq: u64) -> Result<Self> {
+        match bound {
+            "-" => Ok(Self::MIN),
+            "+" => Ok(Self::MAX),
+            _ => Self::parse(bound, default_seq),
         }
     }
 }
 
-#[allow(dead_code)]
-#[derive(Debug, Clone)]
+impl fmt::Display for StreamId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}-{}", self.ms, self.seq)
+    }
+}
+
+/// The ID requested for a new stream entry by `XADD`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NewStreamId {
+    /// `*`: generate the whole ID from the current time.
+    Auto,
+    /// `<ms>-*`: generate the sequence number within the given millisecond.
+    AutoSeq(u64),
+    Explicit(StreamId),
+}
+
+impl NewStreamId {
+    pub fn parse(id: &str) -> Result<Self> {
+        if id == "*" {
+            return Ok(Self::Auto);
+        }
+        match id.strip_suffix("-*") {
+            Some(ms) => Ok(Self::AutoSeq(ms.parse().context(INVALID_STREAM_ID)?)),
+            None => Ok(Self::Explicit(StreamId::parse(id, 0)?)),
+        }
+    }
+}
+
+/// The fields of a stream entry. They are kept as an ordered list of pairs, since Redis
+/// returns them in the order they were added.
+pub type StreamEntry = Vec<(String, String)>;
+
+/// A stream, holding its entries ordered by ID.
+#[derive(Debug, Clone, Default)]
 pub struct Stream {
-    key: String,
-    entries: HashMap<String, String>,
+    entries: BTreeMap<StreamId, StreamEntry>,
 }
 
 impl Stream {
-    pub fn new(key: &str, args: &mut Vec<Payload>) -> Self {
-        let mut entries = HashMap::new();
-        let mut args_iter = args.drain(..);
+    /// Resolves the ID of the next entry, which has to be greater than every ID already in
+    /// the stream.
+    pub fn next_id(&self, requested: NewStreamId) -> Result<StreamId> {
+        let last = self.last_id();
+        let id = match requested {
+            NewStreamId::Explicit(id) => id,
+            NewStreamId::AutoSeq(ms) => match last {
+                Some(last) if last.ms == ms => StreamId {
+                    ms,
+                    seq: last.seq.checked_add(1).context(ID_NOT_GREATER)?,
+                },
+                // 0-0 is never a valid ID, so the first entry of millisecond 0 is 0-1.
+                _ => StreamId {
+                    ms,
+                    seq: (ms == 0) as u64,
+                },
+            },
+            NewStreamId::Auto => {
+                let now = Utc::now().timestamp_millis().max(0) as u64;
+                match last {
+                    // Keep IDs increasing even if the clock went backwards.
+                    Some(last) if last.ms >= now => StreamId {
+                        ms: last.ms,
+                        seq: last.seq.checked_add(1).context(ID_NOT_GREATER)?,
+                    },
+                    _ => StreamId { ms: now, seq: 0 },
+                }
+            }
+        };
+        if id == StreamId::MIN {
+            bail!("ERR The ID specified in XADD must be greater than 0-0")
+        }
+        if last.is_some_and(|last| id <= last) {
+            bail!(ID_NOT_GREATER)
+        }
+        Ok(id)
+    }
+
+    /// The ID of the entry added last, which is also the greatest.
+    pub fn last_id(&self) -> Option<StreamId> {
+        self.entries.keys().next_back().copied()
+    }
+
+    pub fn add(&mut self, id: StreamId, entry: StreamEntry) {
+        self.entries.insert(id, entry);
+    }
+
+    /// Returns the entries with IDs between `start` and `end`, both inclusive.
+    pub fn range(
+        &self,
+        start: StreamId,
+        end: StreamId,
+    ) -> impl Iterator<Item = (&StreamId, &StreamEntry)> {
+        // `BTreeMap::range` panics when start is after end, which is just an empty range here.
+        (start <= end)
+            .then(|| self.entries.range(start..=end))
+            .into_iter()
+            .flatten()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(fields: &[(&str, &str)]) -> StreamEntry {
+        fields
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_type_names() {
+        let stream = RedisType::Stream(Stream::default());
+        assert_eq!(RedisType::String(b"v".to_vec()).type_str(), "string");
+        assert_eq!(stream.type_str(), "stream");
+        assert_eq!(RedisType::List(VecDeque::new()).type_str(), "list");
+        assert_eq!(RedisType::Hash(Hash::default()).type_str(), "hash");
+        assert_eq!(RedisType::Set(HashSet::new()).type_str(), "set");
+        assert_eq!(
+            RedisType::SortedSet(SortedSet::default()).type_str(),
+            "zset"
+        );
+    }
+
+    #[test]
+    fn test_string_encodings() {
+        let encoding = |s: &str| RedisType::String(s.as_bytes().to_vec()).encoding();
+        assert_eq!(encoding("12345"), "int");
+        assert_eq!(encoding("-9223372036854775808"), "int");
+        assert_eq!(encoding("9223372036854775808"), "embstr");
+        assert_eq!(encoding("12.5"), "embstr");
+        assert_eq!(encoding(""), "embstr");
+        assert_eq!(encoding(&"a".repeat(44)), "embstr");
+        assert_eq!(encoding(&"a".repeat(45)), "raw");
+    }
+
+    #[test]
+    fn test_collection_encodings() {
+        let list = |n: usize, len: usize| RedisType::List(vec!["a".repeat(len); n].into());
+        assert_eq!(list(128, 64).encoding(), "listpack");
+        assert_eq!(list(129, 1).encoding(), "quicklist");
+        assert_eq!(list(1, 65).encoding(), "quicklist");
+
+        let hash =
+            |n: usize| RedisType::Hash((0..n).map(|i| (i.to_string(), "v".into())).collect());
+        assert_eq!(hash(128).encoding(), "listpack");
+        assert_eq!(hash(129).encoding(), "hashtable");
+
+        let set = |members: Vec<String>| RedisType::Set(members.into_iter().collect());
+        assert_eq!(
+            set((0..512).map(|i| i.to_string()).collect()).encoding(),
+            "intset"
+        );
+        assert_eq!(
+            set((0..513).map(|i| i.to_string()).collect()).encoding(),
+            "hashtable"
+        );
+        assert_eq!(set(vec!["a".into(), "1".into()]).encoding(), "listpack");
+        assert_eq!(
+            set((0..129).map(|i| format!("m{}", i)).collect()).encoding(),
+            "hashtable"
+        );
+
+        let zset = |members: Vec<String>| {
+            RedisType::SortedSet(members.into_iter().map(|member| (member, 1.0)).collect())
+        };
+        let members = |n: usize| (0..n).map(|i| format!("m{}", i)).collect::<Vec<_>>();
+        assert_eq!(zset(members(128)).encoding(), "listpack");
+        assert_eq!(zset(members(129)).encoding(), "skiplist");
+        assert_eq!(zset(vec!["a".repeat(64)]).encoding(), "listpack");
+        assert_eq!(zset(vec!["a".repeat(65)]).encoding(), "skiplist");
+    }
 
-        while let (Some(k), Some(v)) = (args_iter.next(), args_iter.next()) {
-            entries.insert(k.to_string(), v.to_string());
+    #[test]
+    fn test_stream_id_parsing() {
+        assert_eq!(
+            StreamId::parse("5-3", 0).unwrap(),
+            StreamId { ms: 5, seq: 3 }
+        );
+        assert_eq!(
+            StreamId::parse("5", u64::MAX).unwrap(),
+            StreamId {
+                ms: 5,
+                seq: u64::MAX
+            }
+        );
+        assert_eq!(StreamId::parse_range_bound("-", 0).unwrap(), StreamId::MIN);
+        assert_eq!(StreamId::parse_range_bound("+", 0).unwrap(), StreamId::MAX);
+        assert!(StreamId::parse("5-x", 0).is_err());
+        assert!(StreamId::parse("-1", 0).is_err());
+        assert_eq!(StreamId { ms: 12, seq: 0 }.to_string(), "12-0");
+    }
+
+    #[test]
+    fn test_next_id_validates_and_generates() {
+        let mut stream = Stream::default();
+        let next = |stream: &Stream, id: &str| {
+            stream
+                .next_id(NewStreamId::parse(id).unwrap())
+                .map(|id| id.to_string())
+        };
+        assert!(next(&stream, "0-0").is_err());
+        assert_eq!(next(&stream, "0-*").unwrap(), "0-1");
+        assert_eq!(next(&stream, "5-*").unwrap(), "5-0");
+
+        stream.add(StreamId { ms: 5, seq: 3 }, entry(&[("a", "1")]));
+        assert_eq!(next(&stream, "5-*").unwrap(), "5-4");
+        assert_eq!(next(&stream, "6-*").unwrap(), "6-0");
+        assert_eq!(next(&stream, "5-4").unwrap(), "5-4");
+        for id in ["5-3", "5-2", "4-*"] {
+            assert_eq!(next(&stream, id).unwrap_err().to_string(), ID_NOT_GREATER);
         }
 
-        Self {
-            key: key.to_string(),
-            entries,
+        let auto = stream.next_id(NewStreamId::Auto).unwrap();
+        assert!(auto.ms >= 1_700_000_000_000 && auto.seq == 0);
+        stream.add(StreamId::MAX, entry(&[("a", "1")]));
+        assert!(stream.next_id(NewStreamId::Auto).is_err());
+        assert!(NewStreamId::parse("x-*").is_err());
+    }
+
+    #[test]
+    fn test_stream_range_is_ordered_by_id() {
+        let mut stream = Stream::default();
+        for (ms, seq) in [(10, 0), (2, 1), (2, 0)] {
+            stream.add(StreamId { ms, seq }, entry(&[("b", "1"), ("a", "2")]));
         }
+
+        let ids: Vec<String> = stream
+            .range(StreamId::MIN, StreamId::MAX)
+            .map(|(id, _)| id.to_string())
+            .collect();
+        assert_eq!(ids, vec!["2-0", "2-1", "10-0"]);
+        let (_, fields) = stream.range(StreamId::MIN, StreamId::MAX).next().unwrap();
+        assert_eq!(fields, &entry(&[("b", "1"), ("a", "2")]));
+
+        let start = StreamId::parse("2", 0).unwrap();
+        let end = StreamId::parse("2", u64::MAX).unwrap();
+        assert_eq!(stream.range(start, end).count(), 2);
+        assert_eq!(stream.range(end, start).count(), 0);
+        assert_eq!(stream.len(), 3);
     }
 }
@@ -0,0 +1,123 @@
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Duration, Utc};
+
+use super::store::NOT_AN_INTEGER;
+
+/// Condition under which `SET` stores its value.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum SetCondition {
+    /// `NX`: only set the key if it does not exist.
+    IfAbsent,
+    /// `XX`: only set the key if it already exists.
+    IfPresent,
+}
+
+/// The options of a `SET` command following the value.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SetOptions {
+    /// When the key expires, resolved from `EX`, `PX`, `EXAT` or `PXAT`.
+    pub expire_at: Option<DateTime<Utc>>,
+    pub condition: Option<SetCondition>,
+    /// `KEEPTTL`: retain the expiry the key already has.
+    pub keep_ttl: bool,
+}
+
+impl SetOptions {
+    /// Parses the arguments following the key and value of a `SET` command.
+    ///
+    /// At most one of `EX`, `PX`, `EXAT`, `PXAT` and `KEEPTTL` may be given, and `NX` and
+    /// `XX` are mutually exclusive.
+    ///
+    /// # Examples
+    /// ```
+    /// let args = ["NX", "EX", "10"].map(String::from);
+    /// let options = SetOptions::parse(&args).unwrap();
+    /// assert_eq!(options.condition, Some(SetCondition::IfAbsent));
+    /// ```
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut options = Self::default();
+        let mut args = args.iter();
+
+        while let Some(option) = args.next() {
+            let option = option.to_lowercase();
+            let has_expiry = options.expire_at.is_some() || options.keep_ttl;
+            match option.as_str() {
+                "nx" if options.condition.is_none() => {
+                    options.condition = Some(SetCondition::IfAbsent)
+                }
+                "xx" if options.condition.is_none() => {
+                    options.condition = Some(SetCondition::IfPresent)
+                }
+                "keepttl" if !has_expiry => options.keep_ttl = true,
+                "ex" | "px" | "exat" | "pxat" if !has_expiry => {
+                    let invalid = "ERR invalid expire time in 'set' command";
+                    let value = args
+                        .next()
+                        .context("ERR syntax error")?
+                        .parse::<i64>()
+                        .context(NOT_AN_INTEGER)?;
+                    if value <= 0 {
+                        bail!(invalid)
+                    }
+                    let millis = match option.as_str() {
+                        "ex" | "exat" => value.checked_mul(1000).context(invalid)?,
+                        _ => value,
+                    };
+                    let expire_at = match option.as_str() {
+                        "ex" | "px" => {
+                            Utc::now().checked_add_signed(Duration::milliseconds(millis))
+                        }
+                        _ => DateTime::from_timestamp_millis(millis),
+                    };
+                    options.expire_at = Some(expire_at.context(invalid)?);
+                }
+                _ => bail!("ERR syntax error"),
+            }
+        }
+        Ok(options)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<SetOptions> {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        SetOptions::parse(&args)
+    }
+
+    #[test]
+    fn test_parse_expiry_options() {
+        let in_ten_seconds = parse(&["ex", "10"]).unwrap().expire_at.unwrap() - Utc::now();
+        assert!((9_000..=10_000).contains(&in_ten_seconds.num_milliseconds()));
+
+        let at = parse(&["PXAT", "1700000000123"])
+            .unwrap()
+            .expire_at
+            .unwrap();
+        assert_eq!(at.timestamp_millis(), 1_700_000_000_123);
+        let at = parse(&["EXAT", "1700000000"]).unwrap().expire_at.unwrap();
+        assert_eq!(at.timestamp_millis(), 1_700_000_000_000);
+
+        let options = parse(&["XX", "KEEPTTL"]).unwrap();
+        assert_eq!(options.condition, Some(SetCondition::IfPresent));
+        assert!(options.keep_ttl);
+    }
+
+    #[test]
+    fn test_parse_rejects_invalid_combinations() {
+        for args in [
+            &["NX", "XX"][..],
+            &["EX", "10", "PX", "100"],
+            &["KEEPTTL", "EX", "10"],
+            &["EX"],
+            &["FOREVER"],
+        ] {
+            assert_eq!(parse(args).unwrap_err().to_string(), "ERR syntax error");
+        }
+        assert!(parse(&["EX", "0"]).is_err());
+        assert!(parse(&["PX", "ten"]).is_err());
+        assert!(parse(&["EX", &i64::MAX.to_string()]).is_err());
+    }
+}
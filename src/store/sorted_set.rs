@@ -0,0 +1,663 @@
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::ops::RangeInclusive;
+
+use anyhow::{bail, Context, Result};
+
+use super::store::NOT_AN_INTEGER;
+
+/// A score that orders like the `f64` it wraps. Scores are never NaN, which makes the order
+/// total.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Score(f64);
+
+impl Eq for Score {}
+
+impl PartialOrd for Score {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Score {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.0.total_cmp(&other.0)
+    }
+}
+
+/// The members of a sorted set along with their scores, ordered by score and then by member,
+/// so that members with equal scores are ordered lexicographically as in Redis.
+///
+/// Members are indexed both by their place in that order and by name, so that ranges are read
+/// in order while a member's score is a single lookup.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SortedSet {
+    ordered: BTreeSet<(Score, String)>,
+    scores: HashMap<String, f64>,
+}
+
+impl SortedSet {
+    pub fn len(&self) -> usize {
+        self.scores.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.scores.is_empty()
+    }
+
+    pub fn score(&self, member: &str) -> Option<f64> {
+        self.scores.get(member).copied()
+    }
+
+    /// Sets the score of `member`, adding it if it is new, and returns its previous score.
+    pub fn insert(&mut self, member: &str, score: f64) -> Option<f64> {
+        // Zero and negative zero compare equal, so they must not be ordered apart.
+        let score = if score == 0.0 { 0.0 } else { score };
+        let previous = self.scores.insert(member.to_string(), score);
+        if let Some(previous) = previous {
+            self.ordered.remove(&(Score(previous), member.to_string()));
+        }
+        self.ordered.insert((Score(score), member.to_string()));
+        previous
+    }
+
+    /// The position of `member` in the set, counting from the lowest score.
+    pub fn rank(&self, member: &str) -> Option<usize> {
+        let score = self.score(member)?;
+        Some(
+            self.ordered
+                .range(..(Score(score), member.to_string()))
+                .count(),
+        )
+    }
+
+    /// Iterates over the members and their scores, from the lowest score to the highest.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, f64)> {
+        self.ordered
+            .iter()
+            .map(|(score, member)| (member.as_str(), score.0))
+    }
+
+    /// Iterates over the members whose rank falls within `ranks`.
+    pub fn range(&self, ranks: RangeInclusive<usize>) -> impl Iterator<Item = (&str, f64)> {
+        let (start, end) = ranks.into_inner();
+        self.iter().skip(start).take(end + 1 - start)
+    }
+
+    /// Removes up to `count` members from the given end of the set, returning them with their
+    /// scores in the order they were removed.
+    pub fn pop(&mut self, count: usize, end: ScoreEnd) -> ScoredMembers {
+        (0..count)
+            .map_while(|_| {
+                let (score, member) = match end {
+                    ScoreEnd::Min => self.ordered.pop_first(),
+                    ScoreEnd::Max => self.ordered.pop_last(),
+                }?;
+                self.scores.remove(&member);
+                Some((member, score.0))
+            })
+            .collect()
+    }
+
+    /// Iterates over the members whose score lies between `min` and `max`, in order.
+    pub fn range_by_score(
+        &self,
+        min: ScoreBound,
+        max: ScoreBound,
+    ) -> impl Iterator<Item = (&str, f64)> {
+        // An empty member sorts before every other with the same score.
+        self.ordered
+            .range((Score(min.score()), String::new())..)
+            .map(|(score, member)| (member.as_str(), score.0))
+            .skip_while(move |(_, score)| !min.admits_from_below(*score))
+            .take_while(move |(_, score)| max.admits_from_above(*score))
+    }
+
+    /// Iterates over the members between `min` and `max` in lexicographical order, which is
+    /// only meaningful when every member has the same score.
+    pub fn range_by_lex(&self, min: LexBound, max: LexBound) -> impl Iterator<Item = (&str, f64)> {
+        self.iter()
+            .skip_while(move |(member, _)| !min.admits_from_below(member))
+            .take_while(move |(member, _)| max.admits_from_above(member))
+    }
+}
+
+/// Members along with their scores, in the order a command replies with them.
+pub type ScoredMembers = Vec<(String, f64)>;
+
+/// The end of a sorted set that a pop operates on: its lowest or its highest scores.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreEnd {
+    Min,
+    Max,
+}
+
+/// One end of a `ZRANGEBYSCORE` range: a score, exclusive when written as `(5`, which may be
+/// `-inf` or `+inf`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScoreBound {
+    Inclusive(f64),
+    Exclusive(f64),
+}
+
+impl ScoreBound {
+    pub fn parse(bound: &str) -> Result<Self> {
+        let (bound, exclusive) = match bound.strip_prefix('(') {
+            Some(score) => (score, true),
+            None => (bound, false),
+        };
+        match bound.parse::<f64>() {
+            Ok(score) if score.is_nan() => bail!("ERR min or max is not a float"),
+            Ok(score) if exclusive => Ok(Self::Exclusive(score)),
+            Ok(score) => Ok(Self::Inclusive(score)),
+            Err(_) => bail!("ERR min or max is not a float"),
+        }
+    }
+
+    fn score(&self) -> f64 {
+        match self {
+            Self::Inclusive(score) | Self::Exclusive(score) => *score,
+        }
+    }
+
+    /// Whether `score` is within a range that has this bound as its minimum.
+    fn admits_from_below(&self, score: f64) -> bool {
+        match self {
+            Self::Inclusive(min) => score >= *min,
+            Self::Exclusive(min) => score > *min,
+        }
+    }
+
+    /// Whether `score` is within a range that has this bound as its maximum.
+    fn admits_from_above(&self, score: f64) -> bool {
+        match self {
+            Self::Inclusive(max) => score <= *max,
+            Self::Exclusive(max) => score < *max,
+        }
+    }
+}
+
+/// One end of a `ZRANGEBYLEX` range: `[member` or `(member` for an inclusive or exclusive
+/// member, or `-` and `+` for the lowest and highest possible ones.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexBound {
+    Lowest,
+    Highest,
+    Inclusive(String),
+    Exclusive(String),
+}
+
+impl LexBound {
+    pub fn parse(bound: &str) -> Result<Self> {
+        match bound.split_at_checked(1) {
+            Some(("-", "")) => Ok(Self::Lowest),
+            Some(("+", "")) => Ok(Self::Highest),
+            Some(("[", member)) => Ok(Self::Inclusive(member.to_string())),
+            Some(("(", member)) => Ok(Self::Exclusive(member.to_string())),
+            _ => bail!("ERR min or max not valid string range item"),
+        }
+    }
+
+    /// Whether `member` is within a range that has this bound as its minimum.
+    fn admits_from_below(&self, member: &str) -> bool {
+        match self {
+            Self::Lowest => true,
+            Self::Highest => false,
+            Self::Inclusive(min) => member >= min.as_str(),
+            Self::Exclusive(min) => member > min.as_str(),
+        }
+    }
+
+    /// Whether `member` is within a range that has this bound as its maximum.
+    fn admits_from_above(&self, member: &str) -> bool {
+        match self {
+            Self::Lowest => false,
+            Self::Highest => true,
+            Self::Inclusive(max) => member <= max.as_str(),
+            Self::Exclusive(max) => member < max.as_str(),
+        }
+    }
+}
+
+/// The options following the bounds of a `ZRANGEBYSCORE` or `ZRANGEBYLEX` command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RangeOptions {
+    /// `WITHSCORES`: reply with each member's score after it.
+    pub with_scores: bool,
+    /// The members to skip with `LIMIT offset count`.
+    pub offset: usize,
+    /// The most members to reply with, which a negative `LIMIT` count leaves unlimited.
+    pub count: Option<usize>,
+}
+
+impl RangeOptions {
+    /// Parses the options, of which `ZRANGEBYLEX` does not accept `WITHSCORES`.
+    ///
+    /// # Examples
+    /// ```
+    /// let args = ["WITHSCORES", "LIMIT", "1", "-1"].map(String::from);
+    /// let options = RangeOptions::parse(&args, true).unwrap();
+    /// assert_eq!((options.offset, options.count), (1, None));
+    /// ```
+    pub fn parse(args: &[String], allow_scores: bool) -> Result<Self> {
+        let mut options = Self {
+            with_scores: false,
+            offset: 0,
+            count: None,
+        };
+        let mut rest = args;
+        loop {
+            match rest {
+                [] => return Ok(options),
+                [option, after @ ..]
+                    if allow_scores && option.eq_ignore_ascii_case("withscores") =>
+                {
+                    options.with_scores = true;
+                    rest = after;
+                }
+                [option, offset, count, after @ ..] if option.eq_ignore_ascii_case("limit") => {
+                    let offset = offset.parse::<i64>().context(NOT_AN_INTEGER)?;
+                    let count = count.parse::<i64>().context(NOT_AN_INTEGER)?;
+                    // A negative offset skips every member.
+                    options.offset = usize::try_from(offset).unwrap_or(usize::MAX);
+                    options.count = usize::try_from(count).ok();
+                    rest = after;
+                }
+                _ => bail!("ERR syntax error"),
+            }
+        }
+    }
+}
+
+impl FromIterator<(String, f64)> for SortedSet {
+    fn from_iter<T: IntoIterator<Item = (String, f64)>>(iter: T) -> Self {
+        let mut set = Self::default();
+        for (member, score) in iter {
+            set.insert(&member, score);
+        }
+        set
+    }
+}
+
+/// The flags of a `ZADD` command preceding its scores and members.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ZAddOptions {
+    /// `NX`: only add new members.
+    pub nx: bool,
+    /// `XX`: only update members that already exist.
+    pub xx: bool,
+    /// `GT`: only update a member if its new score is greater. New members are still added.
+    pub gt: bool,
+    /// `LT`: only update a member if its new score is lower. New members are still added.
+    pub lt: bool,
+    /// `CH`: count the members whose score changed along with the new ones.
+    pub ch: bool,
+    /// `INCR`: add the score to the member's current one, like `ZINCRBY`.
+    pub incr: bool,
+}
+
+impl ZAddOptions {
+    /// Parses the flags at the start of the arguments following the key of a `ZADD` command,
+    /// returning them along with the arguments after them.
+    ///
+    /// # Examples
+    /// ```
+    /// let args = ["XX", "CH", "1", "member"].map(String::from);
+    /// let (options, rest) = ZAddOptions::parse(&args).unwrap();
+    /// assert!(options.xx && options.ch);
+    /// assert_eq!(rest, ["1", "member"]);
+    /// ```
+    pub fn parse(args: &[String]) -> Result<(Self, &[String])> {
+        let mut options = Self::default();
+        let mut rest = args;
+        while let Some((flag, after)) = rest.split_first() {
+            match flag.to_lowercase().as_str() {
+                "nx" => options.nx = true,
+                "xx" => options.xx = true,
+                "gt" => options.gt = true,
+                "lt" => options.lt = true,
+                "ch" => options.ch = true,
+                "incr" => options.incr = true,
+                _ => break,
+            }
+            rest = after;
+        }
+        if options.nx && options.xx {
+            bail!("ERR XX and NX options at the same time are not compatible")
+        }
+        if [options.nx, options.gt, options.lt]
+            .iter()
+            .filter(|set| **set)
+            .count()
+            > 1
+        {
+            bail!("ERR GT, LT, and/or NX options at the same time are not compatible")
+        }
+        Ok((options, rest))
+    }
+
+    /// Works out the score `member` ends up with when `ZADD` gives it `score`, or `None` if the
+    /// flags leave it alone. `current` is its score before, if it is in the set.
+    pub fn resolve(&self, current: Option<f64>, score: f64) -> Result<Option<f64>> {
+        if (self.nx && current.is_some()) || (self.xx && current.is_none()) {
+            return Ok(None);
+        }
+        let score = match (self.incr, current) {
+            (true, Some(current)) => current + score,
+            _ => score,
+        };
+        if score.is_nan() {
+            bail!("ERR resulting score is not a number (NaN)")
+        }
+        match current {
+            Some(current) if (self.gt && score <= current) || (self.lt && score >= current) => {
+                Ok(None)
+            }
+            _ => Ok(Some(score)),
+        }
+    }
+}
+
+/// How `ZUNION` and `ZINTER` combine the scores a member has in several sets.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Aggregate {
+    #[default]
+    Sum,
+    Min,
+    Max,
+}
+
+impl Aggregate {
+    pub fn combine(self, a: f64, b: f64) -> f64 {
+        match self {
+            // Infinities of opposite signs add up to NaN, which Redis turns into zero.
+            Self::Sum => zero_if_nan(a + b),
+            Self::Min => a.min(b),
+            Self::Max => a.max(b),
+        }
+    }
+}
+
+/// The keys and options of `ZUNION`, `ZINTER` and `ZDIFF`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ZSetOperationArgs {
+    pub keys: Vec<String>,
+    /// `WEIGHTS`: what the scores of each set are multiplied by, one for every key.
+    pub weights: Vec<f64>,
+    /// `AGGREGATE SUM|MIN|MAX`: how the scores of a member found in several sets combine.
+    pub aggregate: Aggregate,
+    /// `WITHSCORES`: reply with each member's score after it.
+    pub with_scores: bool,
+}
+
+impl ZSetOperationArgs {
+    /// Parses `numkeys key [key ...]` and the options following the keys. `ZDIFF` does not
+    /// accept `WEIGHTS` or `AGGREGATE`, as the scores of the first set are kept as they are.
+    ///
+    /// # Examples
+    /// ```
+    /// let args = ["2", "a", "b", "WEIGHTS", "1", "2", "AGGREGATE", "MAX"].map(String::from);
+    /// let parsed = ZSetOperationArgs::parse(&args, true).unwrap();
+    /// assert_eq!(parsed.weights, vec![1.0, 2.0]);
+    /// assert_eq!(parsed.aggregate, Aggregate::Max);
+    /// ```
+    pub fn parse(args: &[String], allow_weights: bool) -> Result<Self> {
+        let numkeys = args[0]
+            .parse::<usize>()
+            .ok()
+            .filter(|numkeys| *numkeys > 0)
+            .context("ERR numkeys should be greater than 0")?;
+        let Some((keys, mut rest)) = args[1..].split_at_checked(numkeys) else {
+            bail!("ERR syntax error")
+        };
+        let mut parsed = Self {
+            keys: keys.to_vec(),
+            weights: vec![1.0; numkeys],
+            aggregate: Aggregate::default(),
+            with_scores: false,
+        };
+        loop {
+            match rest {
+                [] => return Ok(parsed),
+                [option, after @ ..] if option.eq_ignore_ascii_case("withscores") => {
+                    parsed.with_scores = true;
+                    rest = after;
+                }
+                [option, after @ ..]
+                    if allow_weights
+                        && option.eq_ignore_ascii_case("weights")
+                        && after.len() >= numkeys =>
+                {
+                    let (weights, after) = after.split_at(numkeys);
+                    parsed.weights = weights
+                        .iter()
+                        .map(|weight| match weight.parse::<f64>() {
+                            Ok(weight) if !weight.is_nan() => Ok(weight),
+                            _ => bail!("ERR weight value is not a float"),
+                        })
+                        .collect::<Result<_>>()?;
+                    rest = after;
+                }
+                [option, aggregate, after @ ..]
+                    if allow_weights && option.eq_ignore_ascii_case("aggregate") =>
+                {
+                    parsed.aggregate = match aggregate.to_lowercase().as_str() {
+                        "sum" => Aggregate::Sum,
+                        "min" => Aggregate::Min,
+                        "max" => Aggregate::Max,
+                        _ => bail!("ERR syntax error"),
+                    };
+                    rest = after;
+                }
+                _ => bail!("ERR syntax error"),
+            }
+        }
+    }
+}
+
+/// Multiplies `score` by the weight of its set, where an infinite score weighted by zero
+/// counts as zero rather than NaN.
+pub fn weighted(score: f64, weight: f64) -> f64 {
+    zero_if_nan(score * weight)
+}
+
+fn zero_if_nan(score: f64) -> f64 {
+    if score.is_nan() {
+        0.0
+    } else {
+        score
+    }
+}
+
+/// Parses a score, which may also be `inf` or `-inf`, but never NaN.
+pub fn parse_score(score: &str) -> Result<f64> {
+    match score.parse::<f64>() {
+        Ok(score) if !score.is_nan() => Ok(score),
+        _ => bail!("ERR value is not a valid float"),
+    }
+}
+
+/// Formats a score the way Redis replies with it: as the shortest representation that parses
+/// back to the same value, with infinities written as `inf` and `-inf`.
+pub fn format_score(score: f64) -> String {
+    score.to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn members(set: &SortedSet) -> Vec<&str> {
+        set.iter().map(|(member, _)| member).collect()
+    }
+
+    #[test]
+    fn test_equal_scores_are_ordered_by_member() {
+        let mut set = SortedSet::default();
+        set.insert("c", 1.0);
+        set.insert("b", 1.0);
+        set.insert("z", 0.5);
+        set.insert("a", 2.0);
+        assert_eq!(members(&set), vec!["z", "b", "c", "a"]);
+        assert_eq!(set.rank("c"), Some(2));
+        assert_eq!(set.rank("missing"), None);
+
+        assert_eq!(set.insert("a", -1.0), Some(2.0));
+        assert_eq!(members(&set), vec!["a", "z", "b", "c"]);
+        assert_eq!(set.len(), 4);
+        let range: Vec<_> = set.range(1..=2).collect();
+        assert_eq!(range, vec![("z", 0.5), ("b", 1.0)]);
+    }
+
+    #[test]
+    fn test_pop_from_either_end() {
+        let mut set: SortedSet = [("a", 1.0), ("b", 1.0), ("c", 2.0)]
+            .map(|(member, score)| (member.to_string(), score))
+            .into_iter()
+            .collect();
+        assert_eq!(set.pop(1, ScoreEnd::Max), vec![("c".to_string(), 2.0)]);
+        assert_eq!(
+            set.pop(5, ScoreEnd::Min),
+            vec![("a".to_string(), 1.0), ("b".to_string(), 1.0)]
+        );
+        assert!(set.is_empty());
+        assert_eq!(set.score("a"), None);
+    }
+
+    #[test]
+    fn test_negative_zero_ties_with_zero() {
+        let mut set = SortedSet::default();
+        set.insert("b", 0.0);
+        set.insert("a", -0.0);
+        assert_eq!(members(&set), vec!["a", "b"]);
+        assert_eq!(format_score(set.score("a").unwrap()), "0");
+    }
+
+    #[test]
+    fn test_set_operation_options() {
+        let parse = |args: &[&str], allow_weights| {
+            let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+            ZSetOperationArgs::parse(&args, allow_weights)
+        };
+        let parsed = parse(&["2", "a", "b", "WITHSCORES", "AGGREGATE", "min"], true).unwrap();
+        assert_eq!(parsed.keys, vec!["a", "b"]);
+        assert_eq!(parsed.weights, vec![1.0, 1.0]);
+        assert_eq!(parsed.aggregate, Aggregate::Min);
+        assert!(parsed.with_scores);
+
+        assert!(parse(&["0", "a"], true).is_err());
+        assert!(parse(&["3", "a", "b"], true).is_err());
+        assert!(parse(&["2", "a", "b", "WEIGHTS", "1"], true).is_err());
+        assert!(parse(&["1", "a", "WEIGHTS", "nan"], true).is_err());
+        assert!(parse(&["1", "a", "AGGREGATE", "avg"], true).is_err());
+        assert!(parse(&["1", "a", "WEIGHTS", "2"], false).is_err());
+
+        assert_eq!(
+            Aggregate::Sum.combine(f64::INFINITY, f64::NEG_INFINITY),
+            0.0
+        );
+        assert_eq!(weighted(f64::INFINITY, 0.0), 0.0);
+    }
+
+    #[test]
+    fn test_resolve_flags() {
+        let parse = |args: &[&str]| {
+            let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+            ZAddOptions::parse(&args).map(|(options, _)| options)
+        };
+        let gt = parse(&["GT"]).unwrap();
+        assert_eq!(gt.resolve(Some(2.0), 1.0).unwrap(), None);
+        assert_eq!(gt.resolve(Some(2.0), 3.0).unwrap(), Some(3.0));
+        assert_eq!(gt.resolve(None, 1.0).unwrap(), Some(1.0));
+        let lt_incr = parse(&["lt", "incr"]).unwrap();
+        assert_eq!(lt_incr.resolve(Some(2.0), -1.0).unwrap(), Some(1.0));
+        assert_eq!(lt_incr.resolve(Some(2.0), 1.0).unwrap(), None);
+        let nx = parse(&["NX"]).unwrap();
+        assert_eq!(nx.resolve(Some(2.0), 1.0).unwrap(), None);
+        let xx = parse(&["XX"]).unwrap();
+        assert_eq!(xx.resolve(None, 1.0).unwrap(), None);
+        let incr = parse(&["INCR"]).unwrap();
+        assert!(incr
+            .resolve(Some(f64::INFINITY), f64::NEG_INFINITY)
+            .is_err());
+
+        assert!(parse(&["NX", "XX"]).is_err());
+        assert!(parse(&["NX", "GT"]).is_err());
+        assert!(parse(&["GT", "LT"]).is_err());
+    }
+
+    #[test]
+    fn test_range_by_score_bounds() {
+        let set: SortedSet = [("a", 1.0), ("b", 2.0), ("c", 2.0), ("d", 3.0)]
+            .map(|(member, score)| (member.to_string(), score))
+            .into_iter()
+            .collect();
+        let range = |min: &str, max: &str| -> Vec<&str> {
+            let (min, max) = (
+                ScoreBound::parse(min).unwrap(),
+                ScoreBound::parse(max).unwrap(),
+            );
+            set.range_by_score(min, max)
+                .map(|(member, _)| member)
+                .collect()
+        };
+        assert_eq!(range("-inf", "+inf"), vec!["a", "b", "c", "d"]);
+        assert_eq!(range("2", "2"), vec!["b", "c"]);
+        assert_eq!(range("(1", "(3"), vec!["b", "c"]);
+        assert_eq!(range("(2", "3"), vec!["d"]);
+        assert!(range("3", "1").is_empty());
+        assert!(range("(2", "(2").is_empty());
+        assert!(ScoreBound::parse("(").is_err());
+        assert!(ScoreBound::parse("nan").is_err());
+    }
+
+    #[test]
+    fn test_range_by_lex_bounds() {
+        let set: SortedSet = ["a", "b", "bb", "c"]
+            .map(|member| (member.to_string(), 0.0))
+            .into_iter()
+            .collect();
+        let range = |min: &str, max: &str| -> Vec<&str> {
+            let (min, max) = (LexBound::parse(min).unwrap(), LexBound::parse(max).unwrap());
+            set.range_by_lex(min, max)
+                .map(|(member, _)| member)
+                .collect()
+        };
+        assert_eq!(range("-", "+"), vec!["a", "b", "bb", "c"]);
+        assert_eq!(range("[b", "(c"), vec!["b", "bb"]);
+        assert_eq!(range("(b", "+"), vec!["bb", "c"]);
+        assert!(range("+", "-").is_empty());
+        assert!(LexBound::parse("b").is_err());
+        assert!(LexBound::parse("").is_err());
+    }
+
+    #[test]
+    fn test_parse_range_options() {
+        let parse = |args: &[&str], allow_scores| {
+            let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+            RangeOptions::parse(&args, allow_scores)
+        };
+        let options = parse(&["LIMIT", "1", "2", "withscores"], true).unwrap();
+        assert!(options.with_scores);
+        assert_eq!((options.offset, options.count), (1, Some(2)));
+        assert_eq!(
+            parse(&["limit", "-1", "2"], false).unwrap().offset,
+            usize::MAX
+        );
+        assert!(parse(&["WITHSCORES"], false).is_err());
+        assert!(parse(&["LIMIT", "1"], true).is_err());
+        assert!(parse(&["LIMIT", "one", "2"], true).is_err());
+    }
+
+    #[test]
+    fn test_parse_and_format_scores() {
+        assert_eq!(parse_score("1.5").unwrap(), 1.5);
+        assert_eq!(parse_score("-inf").unwrap(), f64::NEG_INFINITY);
+        assert!(parse_score("nan").is_err());
+        assert!(parse_score("one").is_err());
+        assert_eq!(format_score(1.0), "1");
+        assert_eq!(format_score(0.1), "0.1");
+        assert_eq!(format_score(f64::INFINITY), "inf");
+        assert_eq!(format_score(f64::NEG_INFINITY), "-inf");
+    }
+}
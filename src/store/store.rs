@@ -1,14 +1,40 @@
-use crate::{parser::RedisEncodable, store::RedisType};
-use std::collections::{BTreeMap, HashMap};
+use crate::store::{
+    glob::glob_match,
+    rdb,
+    redis_type::{ListEnd, NewStreamId, SetOperation, Stream, StreamEntry, StreamId},
+    sorted_set::{
+        weighted, LexBound, RangeOptions, ScoreBound, ScoreEnd, ScoredMembers, ZSetOperationArgs,
+    },
+    BitFieldOp, ExpireCondition, Hash, RedisType, SetCondition, SetOptions, SortedSet, ZAddOptions,
+};
+use std::collections::hash_map::{DefaultHasher, RandomState};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::hash::{BuildHasher, Hash as _, Hasher};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicU64, Ordering};
 
-use crate::parser::{Payload, DELIMITER};
-use anyhow::Result;
+use crate::parser::Payload;
+use anyhow::{bail, Context, Result};
 use chrono::{DateTime, Duration, Utc};
 
-#[derive(Clone)]
+pub const WRONG_TYPE: &str = "WRONGTYPE Operation against a key holding the wrong kind of value";
+pub const NOT_AN_INTEGER: &str = "ERR value is not an integer or out of range";
+/// Largest string `SETRANGE` may grow a value to, mirroring Redis' 512MB limit.
+const MAX_STRING_LEN: usize = 512 * 1024 * 1024;
+
 pub struct KeyValueStore {
     data: HashMap<String, RedisType>,
     expiries: BTreeMap<DateTime<Utc>, Vec<String>>,
+    /// The expiry of every key that has one, mirroring `expiries` for per-key lookups.
+    key_expiries: HashMap<String, DateTime<Utc>>,
+    /// Whether expired keys are deleted by this store. Replicas only hide them on reads and
+    /// leave the deletion to the master, so both sides agree on the keyspace.
+    evict_expired: bool,
+    /// Keys deleted for having expired since `take_evicted` was last called, which the master
+    /// propagates as `DEL`s.
+    evicted: Vec<String>,
+    keyspace_hits: AtomicU64,
+    keyspace_misses: AtomicU64,
 }
 
 impl KeyValueStore {
@@ -16,60 +42,2381 @@ impl KeyValueStore {
         Self {
             data: HashMap::new(),
             expiries: BTreeMap::new(),
+            key_expiries: HashMap::new(),
+            evict_expired: true,
+            evicted: vec![],
+            keyspace_hits: AtomicU64::new(0),
+            keyspace_misses: AtomicU64::new(0),
         }
     }
-    pub fn set(&mut self, key: &str, value: RedisType, expiry_ms: Option<i64>) -> Result<String> {
-        println!("Setting k:{}, v:{}", key, value.type_str());
-        if let Some(expiry) = expiry_ms {
-            let _ = self.set_expiry(key, expiry);
-        };
+
+    pub fn new_replica() -> Self {
+        Self {
+            evict_expired: false,
+            ..Self::new()
+        }
+    }
+    /// Stores `value` at `key` unless the `NX`/`XX` condition of `options` fails, returning
+    /// whether it was stored. A previous expiry is discarded unless `KEEPTTL` was given.
+    pub fn set(&mut self, key: &str, value: RedisType, options: SetOptions) -> Result<bool> {
+        let exists = self.live(key).is_some();
+        match options.condition {
+            Some(SetCondition::IfAbsent) if exists => return Ok(false),
+            Some(SetCondition::IfPresent) if !exists => return Ok(false),
+            _ => {}
+        }
+        if !(options.keep_ttl && exists) {
+            self.remove_expiry(key);
+        }
+        if let Some(expire_at) = options.expire_at {
+            self.set_expiry_at(key, expire_at);
+        }
         self.data.insert(key.to_string(), value);
-        Ok(format!("+OK{}", DELIMITER))
+        Ok(true)
+    }
+
+    /// Reads the string at `key` as a bulk string reply. Only a shared reference is needed, so
+    /// concurrent reads can proceed under a read lock; expired keys read as null and are left
+    /// for `clean_expiries` to remove.
+    pub fn get(&self, key: &str) -> Result<Payload> {
+        match self.lookup(key) {
+            Some(RedisType::String(value)) => Ok(Payload::BulkString(value.clone())),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(Payload::Null),
+        }
+    }
+
+    /// Appends `value` to the string at `key`, creating the key if it does not exist, and
+    /// returns the new length.
+    pub fn append(&mut self, key: &str, value: &[u8]) -> Result<usize> {
+        self.clean_expiries()?;
+        let RedisType::String(bytes) = self.get_or_insert_with(key, || RedisType::String(vec![]))
+        else {
+            bail!(WRONG_TYPE)
+        };
+        bytes.extend_from_slice(value);
+        Ok(bytes.len())
+    }
+
+    /// Returns the string at `key` and deletes the key, or `None` if it does not exist.
+    pub fn getdel(&mut self, key: &str) -> Result<Option<Vec<u8>>> {
+        let value = match self.lookup(key) {
+            Some(RedisType::String(bytes)) => bytes.clone(),
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(None),
+        };
+        self.del(&[key.to_string()]);
+        Ok(Some(value))
+    }
+
+    /// Returns the string at `key`, or `None` if it does not exist. An existing key is given
+    /// the expiry `expire_at`, or loses its expiry if `persist` is set.
+    pub fn getex(
+        &mut self,
+        key: &str,
+        expire_at: Option<DateTime<Utc>>,
+        persist: bool,
+    ) -> Result<Option<Vec<u8>>> {
+        let value = match self.lookup(key) {
+            Some(RedisType::String(bytes)) => bytes.clone(),
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(None),
+        };
+        if let Some(expire_at) = expire_at {
+            self.set_expiry_at(key, expire_at);
+        } else if persist {
+            self.persist(key);
+        }
+        Ok(Some(value))
+    }
+
+    pub fn strlen(&self, key: &str) -> Result<usize> {
+        match self.lookup(key) {
+            Some(RedisType::String(bytes)) => Ok(bytes.len()),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(0),
+        }
+    }
+
+    /// Returns the bytes of the string at `key` from `start` to `end`, both inclusive. Negative
+    /// indices count from the end of the string.
+    pub fn getrange(&self, key: &str, start: i64, end: i64) -> Result<Vec<u8>> {
+        let bytes = match self.lookup(key) {
+            Some(RedisType::String(bytes)) => bytes,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(vec![]),
+        };
+        match resolve_range(bytes.len(), start, end) {
+            Some(range) => Ok(bytes[range].to_vec()),
+            None => Ok(vec![]),
+        }
     }
 
-    pub fn get(&mut self, key: &str) -> String {
-        if let Err(failed) = self.clean_expiries() {
-            panic!(
-                "Failed cleaning expired records due to an error: {}",
-                failed
-            )
+    /// Overwrites the string at `key` with `value` starting at `offset`, padding it with zero
+    /// bytes if it is shorter than `offset`, and returns the new length. A missing key is
+    /// treated as an empty string, but is not created if `value` is empty.
+    pub fn setrange(&mut self, key: &str, offset: usize, value: &[u8]) -> Result<usize> {
+        self.clean_expiries()?;
+        if value.is_empty() {
+            return self.strlen(key);
+        }
+        if offset + value.len() > MAX_STRING_LEN {
+            bail!("ERR string exceeds maximum allowed size (proto-max-bulk-len)")
         }
-        println!("Getting k:{}", key);
-        match self.data.get(key) {
-            Some(value) => Payload::BulkString(value.as_inner().to_string()).redis_encode(),
-            None => format!("$-1{}", DELIMITER),
+        let RedisType::String(bytes) = self.get_or_insert_with(key, || RedisType::String(vec![]))
+        else {
+            bail!(WRONG_TYPE)
+        };
+        let end = offset + value.len();
+        if bytes.len() < end {
+            bytes.resize(end, 0);
         }
+        bytes[offset..end].copy_from_slice(value);
+        Ok(bytes.len())
+    }
+
+    /// Sets every key/value pair, clearing any expiry the keys had. Taking `&mut self` once for
+    /// the whole batch means no other client sees only part of it applied.
+    pub fn mset(&mut self, pairs: Vec<(String, Vec<u8>)>) {
+        for (key, value) in pairs {
+            self.remove_expiry(&key);
+            self.data.insert(key, RedisType::String(value));
+        }
+    }
+
+    /// Reads the strings at `keys`, with `None` for every key that is missing, expired or not
+    /// a string.
+    pub fn mget(&self, keys: &[String]) -> Vec<Option<Vec<u8>>> {
+        keys.iter()
+            .map(|key| match self.lookup(key) {
+                Some(RedisType::String(value)) => Some(value.clone()),
+                _ => None,
+            })
+            .collect()
     }
 
-    pub fn set_expiry(&mut self, key: &str, expiry_ms: i64) -> Result<String> {
-        let expiry_time = Utc::now() + Duration::milliseconds(expiry_ms);
-        println!("Setting k:{}, with expiry {}", key, expiry_time);
+    fn set_expiry_at(&mut self, key: &str, expiry_time: DateTime<Utc>) {
+        self.remove_expiry(key);
+        self.key_expiries.insert(key.to_string(), expiry_time);
         self.expiries
             .entry(expiry_time)
             .or_default()
             .push(key.to_string());
-        Ok(format!("+OK{}", DELIMITER))
+    }
+
+    /// Sets the expiry of an existing key to `expire_at`, replacing any previous one, if
+    /// `condition` allows it. Returns whether it was set, which a missing key never is. A time
+    /// already in the past deletes the key right away, as if it had just expired.
+    pub fn set_expiry_on_existing(
+        &mut self,
+        key: &str,
+        expire_at: DateTime<Utc>,
+        condition: ExpireCondition,
+    ) -> bool {
+        let _ = self.clean_expiries();
+        if self.live(key).is_none()
+            || !condition.allows(self.key_expiries.get(key).copied(), expire_at)
+        {
+            return false;
+        }
+        if expire_at <= Utc::now() {
+            self.remove_expired(key);
+        } else {
+            self.set_expiry_at(key, expire_at);
+        }
+        true
+    }
+
+    /// Removes the expiry of `key`, returning whether the key exists and had one.
+    pub fn persist(&mut self, key: &str) -> bool {
+        if self.live(key).is_none() || !self.key_expiries.contains_key(key) {
+            return false;
+        }
+        self.remove_expiry(key);
+        true
     }
 
     pub fn clean_expiries(&mut self) -> Result<()> {
-        let now = Utc::now();
-        let keys_to_remove: Vec<String> = self
-            .expiries
-            .range(..=now)
-            .flat_map(|(_expiry, keys)| keys.clone())
+        if !self.evict_expired {
+            return Ok(());
+        }
+        let evicted = self.evict_expired_at(Utc::now());
+        self.evicted.extend(evicted);
+        Ok(())
+    }
+
+    /// Deletes every key whose expiry is at or before `now`, returning the deleted keys.
+    fn evict_expired_at(&mut self, now: DateTime<Utc>) -> Vec<String> {
+        let pending = self.expiries.split_off(&(now + Duration::nanoseconds(1)));
+        let expired = std::mem::replace(&mut self.expiries, pending);
+
+        let mut evicted = vec![];
+        for key in expired.into_values().flatten() {
+            // Only trust the bucket if it still matches the key's current expiry.
+            if self
+                .key_expiries
+                .get(&key)
+                .is_some_and(|expiry| *expiry <= now)
+            {
+                self.data.remove(&key);
+                self.key_expiries.remove(&key);
+                evicted.push(key);
+            }
+        }
+        evicted
+    }
+
+    /// Returns the keys deleted for having expired since the last call, for the master to tell
+    /// its replicas about.
+    pub fn take_evicted(&mut self) -> Vec<String> {
+        std::mem::take(&mut self.evicted)
+    }
+
+    /// Sets or clears the bit at `offset` of the string at `key`, counting from the most
+    /// significant bit of the first byte, and returns the bit's previous value. The string is
+    /// created or padded with zero bytes as needed.
+    pub fn setbit(&mut self, key: &str, offset: u64, bit: bool) -> Result<bool> {
+        self.clean_expiries()?;
+        let index = usize::try_from(offset / 8)
+            .ok()
+            .filter(|index| *index < MAX_STRING_LEN)
+            .context("ERR bit offset is not an integer or out of range")?;
+        let RedisType::String(bytes) = self.get_or_insert_with(key, || RedisType::String(vec![]))
+        else {
+            bail!(WRONG_TYPE)
+        };
+        if bytes.len() <= index {
+            bytes.resize(index + 1, 0);
+        }
+        let mask = 0x80 >> (offset % 8);
+        let previous = bytes[index] & mask != 0;
+        if bit {
+            bytes[index] |= mask;
+        } else {
+            bytes[index] &= !mask;
+        }
+        Ok(previous)
+    }
+
+    /// Returns the bit at `offset` of the string at `key`, which is clear past its end.
+    pub fn getbit(&self, key: &str, offset: u64) -> Result<bool> {
+        let bytes = match self.lookup(key) {
+            Some(RedisType::String(bytes)) => bytes,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(false),
+        };
+        let byte = usize::try_from(offset / 8)
+            .ok()
+            .and_then(|index| bytes.get(index));
+        Ok(byte.is_some_and(|byte| byte & (0x80 >> (offset % 8)) != 0))
+    }
+
+    /// Counts the set bits of the string at `key` between `start` and `end` inclusive, which
+    /// are byte indices, or bit indices when `in_bits` is set. Negative indices count from the
+    /// end of the string.
+    pub fn bitcount(&self, key: &str, start: i64, end: i64, in_bits: bool) -> Result<usize> {
+        let bytes = match self.lookup(key) {
+            Some(RedisType::String(bytes)) => bytes,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(0),
+        };
+        if !in_bits {
+            return Ok(resolve_range(bytes.len(), start, end).map_or(0, |range| {
+                bytes[range]
+                    .iter()
+                    .map(|byte| byte.count_ones() as usize)
+                    .sum()
+            }));
+        }
+        Ok(
+            resolve_range(bytes.len() * 8, start, end).map_or(0, |range| {
+                range
+                    .filter(|bit| bytes[bit / 8] & (0x80 >> (bit % 8)) != 0)
+                    .count()
+            }),
+        )
+    }
+
+    /// Deletes the given keys along with their expiries, returning how many of them existed.
+    pub fn del(&mut self, keys: &[String]) -> usize {
+        self.unlink(keys).len()
+    }
+
+    /// Removes the given keys like `del`, but hands back the values of those that existed so
+    /// the caller decides where the memory they hold is freed.
+    pub fn unlink(&mut self, keys: &[String]) -> Vec<RedisType> {
+        let _ = self.clean_expiries();
+        keys.iter()
+            .filter_map(|key| {
+                if self.live(key).is_none() {
+                    self.remove_expired(key);
+                    return None;
+                }
+                let value = self.data.remove(key.as_str());
+                self.remove_expiry(key);
+                value
+            })
+            .collect()
+    }
+
+    /// Counts how many of the given keys exist, counting repeated keys every time they appear.
+    /// Expired keys are treated as missing even if they have not been evicted yet.
+    pub fn exists(&self, keys: &[String]) -> usize {
+        keys.iter().filter(|key| self.live(key).is_some()).count()
+    }
+
+    /// Returns every key matching the glob `pattern`, in no particular order. Expired keys are
+    /// left out even if they have not been evicted yet.
+    pub fn keys(&self, pattern: &str) -> Vec<String> {
+        self.data
+            .keys()
+            .filter(|key| glob_match(pattern.as_bytes(), key.as_bytes()))
+            .filter(|key| self.live(key).is_some())
+            .cloned()
+            .collect()
+    }
+
+    /// Returns up to `count` keys starting at `cursor`, along with the cursor to continue from,
+    /// which is 0 once the scan is complete. Keys not matching `pattern` are then filtered out,
+    /// so a batch may come back with fewer keys, or none, before the scan is over.
+    ///
+    /// The cursor is a position in the order of the keys' hashes rather than an index, so keys
+    /// added or removed between calls do not shift it: every key present for the whole scan is
+    /// returned at least once.
+    pub fn scan(&self, cursor: u64, pattern: Option<&str>, count: usize) -> (u64, Vec<String>) {
+        let mut candidates: Vec<(u64, &String)> = self
+            .data
+            .keys()
+            .map(|key| (scan_hash(key), key))
+            .filter(|(hash, _)| *hash >= cursor)
+            .collect();
+        candidates.sort_unstable();
+
+        // Keys sharing a hash have to land in the same batch, as the cursor cannot point
+        // between them.
+        let mut end = count.min(candidates.len());
+        while end > 0 && end < candidates.len() && candidates[end].0 == candidates[end - 1].0 {
+            end += 1;
+        }
+        let next_cursor = match candidates.get(end) {
+            Some((hash, _)) => *hash,
+            None => 0,
+        };
+        let keys = candidates[..end]
+            .iter()
+            .map(|(_, key)| *key)
+            .filter(|key| pattern.is_none_or(|p| glob_match(p.as_bytes(), key.as_bytes())))
+            .filter(|key| self.live(key).is_some())
+            .cloned()
             .collect();
+        (next_cursor, keys)
+    }
+
+    /// Adds `delta` to the integer stored at `key`, treating a missing key as 0, and returns the
+    /// new value. The key keeps its expiry.
+    ///
+    /// Fails if the value is not a string holding an `i64`, or if the result would overflow.
+    pub fn incr_by(&mut self, key: &str, delta: i64) -> Result<i64> {
+        self.clean_expiries()?;
+        let current = match self.live(key) {
+            Some(RedisType::String(bytes)) => std::str::from_utf8(bytes)
+                .ok()
+                .and_then(|s| s.parse::<i64>().ok())
+                .context(NOT_AN_INTEGER)?,
+            Some(_) => bail!(WRONG_TYPE),
+            None => 0,
+        };
+        let value = current.checked_add(delta).context(NOT_AN_INTEGER)?;
+        self.data.insert(
+            key.to_string(),
+            RedisType::String(value.to_string().into_bytes()),
+        );
+        Ok(value)
+    }
+
+    /// Pushes `elements` one after the other onto the given end of the list at `key`, creating
+    /// the list if the key does not exist, and returns the new length of the list.
+    pub fn push(&mut self, key: &str, elements: &[String], end: ListEnd) -> Result<usize> {
+        self.clean_expiries()?;
+        let RedisType::List(list) =
+            self.get_or_insert_with(key, || RedisType::List(VecDeque::new()))
+        else {
+            bail!(WRONG_TYPE)
+        };
+        for element in elements {
+            match end {
+                ListEnd::Left => list.push_front(element.clone()),
+                ListEnd::Right => list.push_back(element.clone()),
+            }
+        }
+        Ok(list.len())
+    }
+
+    /// Pops up to `count` elements from the given end of the list at `key`, deleting the key
+    /// once the list is empty. Returns `None` if the key does not exist.
+    pub fn pop(&mut self, key: &str, count: usize, end: ListEnd) -> Result<Option<Vec<String>>> {
+        self.clean_expiries()?;
+        let list = match self.live_mut(key) {
+            Some(RedisType::List(list)) => list,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(None),
+        };
+        let count = count.min(list.len());
+        let popped = match end {
+            ListEnd::Left => list.drain(..count).collect(),
+            ListEnd::Right => list.drain(list.len() - count..).rev().collect(),
+        };
+        if list.is_empty() {
+            self.data.remove(key);
+            self.remove_expiry(key);
+        }
+        Ok(Some(popped))
+    }
+
+    /// Pops an element from the `from` end of the list at `source` and pushes it onto the `to`
+    /// end of the list at `destination`, returning it, or `None` if `source` does not exist.
+    /// Moving within a single list rotates it in place, keeping its expiry.
+    pub fn lmove(
+        &mut self,
+        source: &str,
+        destination: &str,
+        from: ListEnd,
+        to: ListEnd,
+    ) -> Result<Option<String>> {
+        self.clean_expiries()?;
+        match self.lookup(source) {
+            Some(RedisType::List(_)) => {}
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(None),
+        }
+        if source == destination {
+            let Some(RedisType::List(list)) = self.live_mut(source) else {
+                unreachable!("checked to be a list above")
+            };
+            let element = match from {
+                ListEnd::Left => list.pop_front(),
+                ListEnd::Right => list.pop_back(),
+            }
+            .expect("lists are never empty");
+            match to {
+                ListEnd::Left => list.push_front(element.clone()),
+                ListEnd::Right => list.push_back(element.clone()),
+            }
+            return Ok(Some(element));
+        }
+        if !matches!(self.lookup(destination), Some(RedisType::List(_)) | None) {
+            bail!(WRONG_TYPE);
+        }
+        let element = self
+            .pop(source, 1, from)?
+            .and_then(|popped| popped.into_iter().next())
+            .expect("lists are never empty");
+        self.push(destination, std::slice::from_ref(&element), to)?;
+        Ok(Some(element))
+    }
+
+    /// Pops an element from the given end of the first of `keys` holding a list, returning it
+    /// along with its key, or `None` if none of them exist.
+    pub fn pop_first(&mut self, keys: &[String], end: ListEnd) -> Result<Option<(String, String)>> {
+        Ok(self
+            .lmpop(keys, end, 1)?
+            .and_then(|(key, popped)| Some((key, popped.into_iter().next()?))))
+    }
 
-        for key in keys_to_remove {
-            self.data.remove(&key);
+    /// Pops up to `count` elements from the given end of the first of `keys` holding a list,
+    /// returning them along with its key, or `None` if none of them exist.
+    pub fn lmpop(
+        &mut self,
+        keys: &[String],
+        end: ListEnd,
+        count: usize,
+    ) -> Result<Option<(String, Vec<String>)>> {
+        for key in keys {
+            if let Some(popped) = self.pop(key, count, end)? {
+                return Ok(Some((key.clone(), popped)));
+            }
         }
+        Ok(None)
+    }
+
+    /// Returns the elements of the list at `key` between `start` and `stop`, both inclusive.
+    /// Negative indices count from the end of the list, and out of range indices are clamped.
+    pub fn lrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<String>> {
+        let list = match self.lookup(key) {
+            Some(RedisType::List(list)) => list,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(vec![]),
+        };
+        match resolve_range(list.len(), start, stop) {
+            Some(range) => Ok(list.range(range).cloned().collect()),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Returns the length of the list at `key`, or 0 if the key does not exist.
+    pub fn llen(&self, key: &str) -> Result<usize> {
+        match self.lookup(key) {
+            Some(RedisType::List(list)) => Ok(list.len()),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(0),
+        }
+    }
 
-        self.expiries = self.expiries.split_off(&now);
+    /// Returns the indices of the elements equal to `element` in the list at `key`, counted from
+    /// the head. The search starts at the `rank`th match, from the tail if `rank` is negative,
+    /// and stops after `count` matches, where 0 means all of them.
+    pub fn lpos(&self, key: &str, element: &str, rank: i64, count: usize) -> Result<Vec<usize>> {
+        let list = match self.lookup(key) {
+            Some(RedisType::List(list)) => list,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(vec![]),
+        };
+        let matches = list.iter().enumerate().filter(|(_, item)| *item == element);
+        let skip = rank.unsigned_abs() as usize - 1;
+        let limit = if count == 0 { usize::MAX } else { count };
+        let indices = if rank > 0 {
+            matches.map(|(i, _)| i).skip(skip).take(limit).collect()
+        } else {
+            matches
+                .rev()
+                .map(|(i, _)| i)
+                .skip(skip)
+                .take(limit)
+                .collect()
+        };
+        Ok(indices)
+    }
+
+    /// Inserts `element` next to the first occurrence of `pivot` in the list at `key`, before it
+    /// or after it. Returns the new length of the list, `-1` if `pivot` is not in it, or 0 if
+    /// the key does not exist.
+    pub fn linsert(&mut self, key: &str, before: bool, pivot: &str, element: &str) -> Result<i64> {
+        self.clean_expiries()?;
+        let list = match self.live_mut(key) {
+            Some(RedisType::List(list)) => list,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(0),
+        };
+        let Some(position) = list.iter().position(|item| item == pivot) else {
+            return Ok(-1);
+        };
+        let index = if before { position } else { position + 1 };
+        list.insert(index, element.to_string());
+        Ok(list.len() as i64)
+    }
+
+    /// Replaces the element at `index` of the list at `key`, where a negative index counts from
+    /// the end of the list.
+    pub fn lset(&mut self, key: &str, index: i64, element: &str) -> Result<()> {
+        self.clean_expiries()?;
+        let list = match self.live_mut(key) {
+            Some(RedisType::List(list)) => list,
+            Some(_) => bail!(WRONG_TYPE),
+            None => bail!("ERR no such key"),
+        };
+        let index = if index < 0 {
+            index.checked_add(list.len() as i64)
+        } else {
+            Some(index)
+        };
+        let item = index
+            .and_then(|index| usize::try_from(index).ok())
+            .and_then(|index| list.get_mut(index))
+            .context("ERR index out of range")?;
+        *item = element.to_string();
         Ok(())
     }
-    pub fn get_type(&self, key: &str) -> String {
-        match self.data.get(key) {
-            Some(value) => value.type_str(),
-            None => format!("+none{}", DELIMITER),
+
+    /// Sets the given field/value pairs of the hash at `key`, creating the hash if the key does
+    /// not exist, and returns how many of the fields are new.
+    pub fn hset(&mut self, key: &str, pairs: &[(String, String)]) -> Result<usize> {
+        self.clean_expiries()?;
+        let RedisType::Hash(hash) =
+            self.get_or_insert_with(key, || RedisType::Hash(Hash::default()))
+        else {
+            bail!(WRONG_TYPE)
+        };
+        Ok(pairs
+            .iter()
+            .filter(|(field, value)| hash.insert(field.clone(), value.clone()).is_none())
+            .count())
+    }
+
+    pub fn hget(&self, key: &str, field: &str) -> Result<Option<String>> {
+        match self.lookup(key) {
+            Some(RedisType::Hash(hash)) => Ok(hash.get(field).cloned()),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns every field/value pair of the hash at `key`, in no particular order.
+    pub fn hgetall(&self, key: &str) -> Result<Vec<(String, String)>> {
+        match self.lookup(key) {
+            Some(RedisType::Hash(hash)) => Ok(hash
+                .iter()
+                .map(|(field, value)| (field.clone(), value.clone()))
+                .collect()),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Removes the given fields from the hash at `key`, deleting the key once the hash is
+    /// empty, and returns how many of the fields existed.
+    pub fn hdel(&mut self, key: &str, fields: &[String]) -> Result<usize> {
+        self.clean_expiries()?;
+        let hash = match self.live_mut(key) {
+            Some(RedisType::Hash(hash)) => hash,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(0),
+        };
+        let removed = fields
+            .iter()
+            .filter(|field| hash.remove(field.as_str()).is_some())
+            .count();
+        if hash.is_empty() {
+            self.data.remove(key);
+            self.remove_expiry(key);
+        }
+        Ok(removed)
+    }
+
+    /// Sets the expiry of each of `fields` of the hash at `key` to `expire_at`, where
+    /// `condition` allows it. Returns what `HEXPIRE` replies with for each field: -2 if it does
+    /// not exist, 0 if the condition was not met, 1 if the expiry was set, and 2 if the field
+    /// was deleted right away for a time already in the past.
+    pub fn hexpire(
+        &mut self,
+        key: &str,
+        fields: &[String],
+        expire_at: DateTime<Utc>,
+        condition: ExpireCondition,
+    ) -> Result<Vec<i64>> {
+        self.clean_expiries()?;
+        let hash = match self.live_mut(key) {
+            Some(RedisType::Hash(hash)) => hash,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(vec![-2; fields.len()]),
+        };
+        let mut statuses = Vec::with_capacity(fields.len());
+        for field in fields {
+            let status = if hash.get(field).is_none() {
+                -2
+            } else if !condition.allows(hash.expiry(field), expire_at) {
+                0
+            } else if expire_at <= Utc::now() {
+                hash.remove(field);
+                2
+            } else {
+                hash.set_expiry(field, expire_at);
+                1
+            };
+            statuses.push(status);
+        }
+        if hash.is_empty() {
+            self.data.remove(key);
+            self.remove_expiry(key);
+        }
+        Ok(statuses)
+    }
+
+    /// Returns the milliseconds each of `fields` of the hash at `key` has left to live, -1 for
+    /// a field without an expiry, or -2 for one that does not exist.
+    pub fn hfield_ttl_ms(&self, key: &str, fields: &[String]) -> Result<Vec<i64>> {
+        let hash = match self.lookup(key) {
+            Some(RedisType::Hash(hash)) => hash,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(vec![-2; fields.len()]),
+        };
+        Ok(fields
+            .iter()
+            .map(|field| match (hash.get(field), hash.expiry(field)) {
+                (None, _) => -2,
+                (Some(_), None) => -1,
+                (Some(_), Some(expiry)) => (expiry - Utc::now()).num_milliseconds().max(0),
+            })
+            .collect())
+    }
+
+    /// Removes the expiry of each of `fields` of the hash at `key`. Returns what `HPERSIST`
+    /// replies with for each field: -2 if it does not exist, -1 if it had no expiry, and 1 if
+    /// its expiry was removed.
+    pub fn hpersist(&mut self, key: &str, fields: &[String]) -> Result<Vec<i64>> {
+        self.clean_expiries()?;
+        let hash = match self.live_mut(key) {
+            Some(RedisType::Hash(hash)) => hash,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(vec![-2; fields.len()]),
+        };
+        Ok(fields
+            .iter()
+            .map(|field| {
+                if hash.get(field).is_none() {
+                    -2
+                } else if hash.persist(field) {
+                    1
+                } else {
+                    -1
+                }
+            })
+            .collect())
+    }
+
+    pub fn hlen(&self, key: &str) -> Result<usize> {
+        match self.lookup(key) {
+            Some(RedisType::Hash(hash)) => Ok(hash.len()),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(0),
+        }
+    }
+
+    /// Adds the given members to the set at `key`, creating the set if the key does not exist,
+    /// and returns how many of them were not members yet.
+    pub fn sadd(&mut self, key: &str, members: &[String]) -> Result<usize> {
+        self.clean_expiries()?;
+        let RedisType::Set(set) = self.get_or_insert_with(key, || RedisType::Set(HashSet::new()))
+        else {
+            bail!(WRONG_TYPE)
+        };
+        Ok(members
+            .iter()
+            .filter(|member| set.insert(member.to_string()))
+            .count())
+    }
+
+    /// Removes the given members from the set at `key`, deleting the key once the set is
+    /// empty, and returns how many of them were members.
+    pub fn srem(&mut self, key: &str, members: &[String]) -> Result<usize> {
+        self.clean_expiries()?;
+        let set = match self.live_mut(key) {
+            Some(RedisType::Set(set)) => set,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(0),
+        };
+        let removed = members
+            .iter()
+            .filter(|member| set.remove(member.as_str()))
+            .count();
+        if set.is_empty() {
+            self.data.remove(key);
+            self.remove_expiry(key);
+        }
+        Ok(removed)
+    }
+
+    /// Returns every member of the set at `key`, in no particular order.
+    pub fn smembers(&self, key: &str) -> Result<Vec<String>> {
+        match self.lookup(key) {
+            Some(RedisType::Set(set)) => Ok(set.iter().cloned().collect()),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(vec![]),
+        }
+    }
+
+    pub fn sismember(&self, key: &str, member: &str) -> Result<bool> {
+        match self.lookup(key) {
+            Some(RedisType::Set(set)) => Ok(set.contains(member)),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(false),
         }
     }
+
+    pub fn scard(&self, key: &str) -> Result<usize> {
+        match self.lookup(key) {
+            Some(RedisType::Set(set)) => Ok(set.len()),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(0),
+        }
+    }
+
+    /// Adds the given score/member pairs to the sorted set at `key` as `ZADD` would with
+    /// `options`, and returns how many members were added, or also changed with `CH`, along
+    /// with the score the last member ended up with, which `INCR` replies with.
+    pub fn zadd(
+        &mut self,
+        key: &str,
+        pairs: &[(f64, String)],
+        options: &ZAddOptions,
+    ) -> Result<(usize, Option<f64>)> {
+        self.clean_expiries()?;
+        let RedisType::SortedSet(set) =
+            self.get_or_insert_with(key, || RedisType::SortedSet(SortedSet::default()))
+        else {
+            bail!(WRONG_TYPE)
+        };
+        let (mut added, mut changed, mut last) = (0, 0, None);
+        let mut result = Ok(());
+        for (score, member) in pairs {
+            let current = set.score(member);
+            last = match options.resolve(current, *score) {
+                Ok(score) => score,
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            };
+            if let Some(score) = last {
+                match set.insert(member, score) {
+                    None => added += 1,
+                    Some(previous) if previous != score => changed += 1,
+                    Some(_) => {}
+                }
+            }
+        }
+        // `XX`, or an error, may leave a new key without members.
+        if set.is_empty() {
+            self.data.remove(key);
+            self.remove_expiry(key);
+        }
+        result?;
+        Ok((if options.ch { added + changed } else { added }, last))
+    }
+
+    /// Pops up to `count` members from the given end of the sorted set at `key`, deleting the
+    /// key once the set is empty. Returns `None` if the key does not exist.
+    pub fn zpop(
+        &mut self,
+        key: &str,
+        count: usize,
+        end: ScoreEnd,
+    ) -> Result<Option<ScoredMembers>> {
+        self.clean_expiries()?;
+        let set = match self.live_mut(key) {
+            Some(RedisType::SortedSet(set)) => set,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(None),
+        };
+        let popped = set.pop(count, end);
+        if set.is_empty() {
+            self.data.remove(key);
+            self.remove_expiry(key);
+        }
+        Ok(Some(popped))
+    }
+
+    /// Pops up to `count` members from the given end of the first of `keys` holding a sorted
+    /// set, returning them along with its key, or `None` if none of them exist.
+    pub fn zmpop(
+        &mut self,
+        keys: &[String],
+        end: ScoreEnd,
+        count: usize,
+    ) -> Result<Option<(String, ScoredMembers)>> {
+        for key in keys {
+            if let Some(popped) = self.zpop(key, count, end)? {
+                return Ok(Some((key.clone(), popped)));
+            }
+        }
+        Ok(None)
+    }
+
+    pub fn zscore(&self, key: &str, member: &str) -> Result<Option<f64>> {
+        match self.lookup(key) {
+            Some(RedisType::SortedSet(set)) => Ok(set.score(member)),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the members of the sorted set at `key` whose ranks fall between `start` and
+    /// `stop` inclusive, with their scores. Negative ranks count from the highest score.
+    pub fn zrange(&self, key: &str, start: i64, stop: i64) -> Result<Vec<(String, f64)>> {
+        let set = match self.lookup(key) {
+            Some(RedisType::SortedSet(set)) => set,
+            Some(_) => bail!(WRONG_TYPE),
+            None => return Ok(vec![]),
+        };
+        match resolve_range(set.len(), start, stop) {
+            Some(range) => Ok(set
+                .range(range)
+                .map(|(member, score)| (member.to_string(), score))
+                .collect()),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Returns the members of the sorted set at `key` whose score lies between `min` and
+    /// `max`, with their scores, after applying the `LIMIT` of `options`.
+    pub fn zrange_by_score(
+        &self,
+        key: &str,
+        min: ScoreBound,
+        max: ScoreBound,
+        options: &RangeOptions,
+    ) -> Result<Vec<(String, f64)>> {
+        match self.lookup(key) {
+            Some(RedisType::SortedSet(set)) => Ok(limit(set.range_by_score(min, max), options)),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(vec![]),
+        }
+    }
+
+    /// Returns the members of the sorted set at `key` between `min` and `max` in lexicographical
+    /// order, with their scores, after applying the `LIMIT` of `options`.
+    pub fn zrange_by_lex(
+        &self,
+        key: &str,
+        min: LexBound,
+        max: LexBound,
+        options: &RangeOptions,
+    ) -> Result<Vec<(String, f64)>> {
+        match self.lookup(key) {
+            Some(RedisType::SortedSet(set)) => Ok(limit(set.range_by_lex(min, max), options)),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(vec![]),
+        }
+    }
+
+    pub fn zrank(&self, key: &str, member: &str) -> Result<Option<usize>> {
+        match self.lookup(key) {
+            Some(RedisType::SortedSet(set)) => Ok(set.rank(member)),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(None),
+        }
+    }
+
+    pub fn zcard(&self, key: &str) -> Result<usize> {
+        match self.lookup(key) {
+            Some(RedisType::SortedSet(set)) => Ok(set.len()),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(0),
+        }
+    }
+
+    /// Combines the sets at `keys` with `operation`, treating missing keys as empty sets.
+    pub fn set_operation(
+        &self,
+        operation: SetOperation,
+        keys: &[String],
+    ) -> Result<HashSet<String>> {
+        let mut sets = Vec::with_capacity(keys.len());
+        for key in keys {
+            match self.lookup(key) {
+                Some(RedisType::Set(set)) => sets.push(Some(set)),
+                Some(_) => bail!(WRONG_TYPE),
+                None => sets.push(None),
+            }
+        }
+        let Some((first, rest)) = sets.split_first() else {
+            return Ok(HashSet::new());
+        };
+        let mut result = first.cloned().unwrap_or_default();
+        for set in rest {
+            match (operation, set) {
+                (SetOperation::Intersection, Some(set)) => {
+                    result.retain(|member| set.contains(member))
+                }
+                (SetOperation::Intersection, None) => result.clear(),
+                (SetOperation::Union, Some(set)) => result.extend(set.iter().cloned()),
+                (SetOperation::Difference, Some(set)) => {
+                    result.retain(|member| !set.contains(member))
+                }
+                (SetOperation::Union | SetOperation::Difference, None) => {}
+            }
+        }
+        Ok(result)
+    }
+
+    /// Stores the result of combining the sets at `keys` with `operation` at `destination`,
+    /// replacing whatever it held, and returns its size. An empty result deletes `destination`.
+    pub fn set_operation_store(
+        &mut self,
+        operation: SetOperation,
+        destination: &str,
+        keys: &[String],
+    ) -> Result<usize> {
+        self.clean_expiries()?;
+        let result = self.set_operation(operation, keys)?;
+        let len = result.len();
+        self.data.remove(destination);
+        self.remove_expiry(destination);
+        if !result.is_empty() {
+            self.data
+                .insert(destination.to_string(), RedisType::Set(result));
+        }
+        Ok(len)
+    }
+
+    /// Combines the sorted sets at `args.keys` with `operation`, multiplying the scores of each
+    /// set by its weight and combining the scores of a member found in several sets with the
+    /// aggregate function. Plain sets count as sorted sets with every score 1, and missing keys
+    /// as empty sets. A difference keeps the scores of the first set.
+    pub fn zset_operation(
+        &self,
+        operation: SetOperation,
+        args: &ZSetOperationArgs,
+    ) -> Result<SortedSet> {
+        let mut sets = Vec::with_capacity(args.keys.len());
+        for (key, weight) in args.keys.iter().zip(&args.weights) {
+            let scores: HashMap<&str, f64> = match self.lookup(key) {
+                Some(RedisType::SortedSet(set)) => set
+                    .iter()
+                    .map(|(member, score)| (member, weighted(score, *weight)))
+                    .collect(),
+                Some(RedisType::Set(set)) => set
+                    .iter()
+                    .map(|member| (member.as_str(), weighted(1.0, *weight)))
+                    .collect(),
+                Some(_) => bail!(WRONG_TYPE),
+                None => HashMap::new(),
+            };
+            sets.push(scores);
+        }
+        let mut sets = sets.into_iter();
+        let mut result = sets.next().unwrap_or_default();
+        for set in sets {
+            match operation {
+                SetOperation::Intersection => {
+                    result.retain(|member, score| match set.get(member) {
+                        Some(other) => {
+                            *score = args.aggregate.combine(*score, *other);
+                            true
+                        }
+                        None => false,
+                    })
+                }
+                SetOperation::Union => {
+                    for (member, other) in set {
+                        result
+                            .entry(member)
+                            .and_modify(|score| *score = args.aggregate.combine(*score, other))
+                            .or_insert(other);
+                    }
+                }
+                SetOperation::Difference => result.retain(|member, _| !set.contains_key(member)),
+            }
+        }
+        Ok(result
+            .into_iter()
+            .map(|(member, score)| (member.to_string(), score))
+            .collect())
+    }
+
+    /// Stores the result of combining the sorted sets at `args.keys` with `operation` at
+    /// `destination`, replacing whatever it held, and returns its size. An empty result
+    /// deletes `destination`.
+    pub fn zset_operation_store(
+        &mut self,
+        operation: SetOperation,
+        destination: &str,
+        args: &ZSetOperationArgs,
+    ) -> Result<usize> {
+        self.clean_expiries()?;
+        let result = self.zset_operation(operation, args)?;
+        let len = result.len();
+        self.data.remove(destination);
+        self.remove_expiry(destination);
+        if !result.is_empty() {
+            self.data
+                .insert(destination.to_string(), RedisType::SortedSet(result));
+        }
+        Ok(len)
+    }
+
+    /// Appends an entry to the stream at `key`, creating the stream if the key does not exist,
+    /// and returns the ID the entry was given.
+    pub fn xadd(&mut self, key: &str, id: NewStreamId, entry: StreamEntry) -> Result<StreamId> {
+        self.clean_expiries()?;
+        // Resolve the ID first, so a rejected ID does not leave an empty stream behind.
+        let id = match self.live(key) {
+            Some(RedisType::Stream(stream)) => stream.next_id(id)?,
+            Some(_) => bail!(WRONG_TYPE),
+            None => Stream::default().next_id(id)?,
+        };
+        let RedisType::Stream(stream) =
+            self.get_or_insert_with(key, || RedisType::Stream(Stream::default()))
+        else {
+            bail!(WRONG_TYPE)
+        };
+        stream.add(id, entry);
+        Ok(id)
+    }
+
+    /// Returns the entries of the stream at `key` with IDs between `start` and `end`, both
+    /// inclusive, in ID order.
+    pub fn xrange(
+        &self,
+        key: &str,
+        start: StreamId,
+        end: StreamId,
+    ) -> Result<Vec<(StreamId, StreamEntry)>> {
+        match self.lookup(key) {
+            Some(RedisType::Stream(stream)) => Ok(stream
+                .range(start, end)
+                .map(|(id, entry)| (*id, entry.clone()))
+                .collect()),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(vec![]),
+        }
+    }
+
+    pub fn xlen(&self, key: &str) -> Result<usize> {
+        match self.lookup(key) {
+            Some(RedisType::Stream(stream)) => Ok(stream.len()),
+            Some(_) => bail!(WRONG_TYPE),
+            None => Ok(0),
+        }
+    }
+
+    /// Returns every live key along with its value and expiry, for serializing the store.
+    pub fn entries(&self) -> impl Iterator<Item = (&str, &RedisType, Option<DateTime<Utc>>)> {
+        self.data
+            .iter()
+            .filter(|(key, _)| self.live(key).is_some())
+            .map(|(key, value)| (key.as_str(), value, self.key_expiries.get(key).copied()))
+    }
+
+    /// Deletes every key, along with its expiry.
+    pub fn flush(&mut self) {
+        self.data.clear();
+        self.expiries.clear();
+        self.key_expiries.clear();
+    }
+
+    /// Stores `value` at `key` as it was serialized, replacing any previous value and expiry.
+    pub fn restore(&mut self, key: String, value: RedisType, expire_at: Option<DateTime<Utc>>) {
+        self.remove_expiry(&key);
+        if let Some(expire_at) = expire_at {
+            self.set_expiry_at(&key, expire_at);
+        }
+        self.data.insert(key, value);
+    }
+
+    /// Returns a copy of the value at `key` and its expiry, or `None` if it does not exist.
+    pub fn copy_of(&self, key: &str) -> Option<(RedisType, Option<DateTime<Utc>>)> {
+        let value = self.live(key)?.clone();
+        Some((value, self.key_expiries.get(key).copied()))
+    }
+
+    /// Removes `key`, returning its value and expiry, or `None` if it does not exist.
+    pub fn take(&mut self, key: &str) -> Option<(RedisType, Option<DateTime<Utc>>)> {
+        self.live(key)?;
+        let expire_at = self.key_expiries.get(key).copied();
+        self.remove_expiry(key);
+        self.data.remove(key).map(|value| (value, expire_at))
+    }
+
+    /// Returns the value at `key` for modification, first storing `default()` there if the key
+    /// does not exist or has expired.
+    fn get_or_insert_with(
+        &mut self,
+        key: &str,
+        default: impl FnOnce() -> RedisType,
+    ) -> &mut RedisType {
+        if self.live(key).is_none() {
+            self.remove_expired(key);
+        }
+        self.data.entry(key.to_string()).or_insert_with(default)
+    }
+
+    /// Deletes `key`, which has expired if it exists at all, recording it for `take_evicted`
+    /// unless this store leaves deleting expired keys to its master.
+    fn remove_expired(&mut self, key: &str) {
+        if self.data.remove(key).is_some() && self.evict_expired {
+            self.evicted.push(key.to_string());
+        }
+        self.remove_expiry(key);
+    }
+
+    /// Like `live`, but returns the value for modification.
+    fn live_mut(&mut self, key: &str) -> Option<&mut RedisType> {
+        self.live(key)?;
+        self.data.get_mut(key)
+    }
+
+    fn remove_expiry(&mut self, key: &str) {
+        let Some(expiry) = self.key_expiries.remove(key) else {
+            return;
+        };
+        if let Some(keys) = self.expiries.get_mut(&expiry) {
+            keys.retain(|k| k != key);
+            if keys.is_empty() {
+                self.expiries.remove(&expiry);
+            }
+        }
+    }
+
+    /// Returns the remaining time to live of `key` in milliseconds, `-1` if the key exists but
+    /// has no expiry, or `-2` if it does not exist.
+    pub fn ttl_ms(&self, key: &str) -> i64 {
+        if self.lookup(key).is_none() {
+            return -2;
+        }
+        match self.key_expiries.get(key) {
+            Some(expiry) => (*expiry - Utc::now()).num_milliseconds().max(0),
+            None => -1,
+        }
+    }
+
+    /// Looks up a key, treating it as missing once its expiry has passed even if it has not
+    /// been deleted yet. The same goes for a hash whose fields have all expired.
+    fn live(&self, key: &str) -> Option<&RedisType> {
+        let expired = self
+            .key_expiries
+            .get(key)
+            .is_some_and(|expiry| *expiry <= Utc::now());
+        if expired {
+            None
+        } else {
+            self.data
+                .get(key)
+                .filter(|value| !matches!(value, RedisType::Hash(hash) if hash.is_empty()))
+        }
+    }
+
+    /// Looks up a key on behalf of a read command, recording a keyspace hit or miss.
+    fn lookup(&self, key: &str) -> Option<&RedisType> {
+        let value = self.live(key);
+        let counter = match value {
+            Some(_) => &self.keyspace_hits,
+            None => &self.keyspace_misses,
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        value
+    }
+
+    /// Returns a live key picked uniformly at random, or `None` if there are none.
+    pub fn random_key(&self) -> Option<String> {
+        let len = self.dbsize();
+        if len == 0 {
+            return None;
+        }
+        // Every `RandomState` is seeded with new random keys, so what it hashes to is random.
+        let index = (RandomState::new().build_hasher().finish() % len as u64) as usize;
+        self.data
+            .keys()
+            .filter(|key| self.live(key).is_some())
+            .nth(index)
+            .cloned()
+    }
+
+    /// Returns the number of live keys.
+    pub fn dbsize(&self) -> usize {
+        self.data
+            .keys()
+            .filter(|key| self.live(key).is_some())
+            .count()
+    }
+
+    /// Renders the line of the `INFO keyspace` section for this store, database `db`. Like in
+    /// Redis, an empty database is not listed.
+    pub fn keyspace(&self, db: usize) -> Option<String> {
+        let keys = self.dbsize();
+        if keys == 0 {
+            return None;
+        }
+        let expires = self
+            .key_expiries
+            .keys()
+            .filter(|key| self.live(key).is_some())
+            .count();
+        Some(format!(
+            "db{}:keys={},expires={},avg_ttl=0",
+            db, keys, expires
+        ))
+    }
+
+    /// Returns the keyspace hits and misses counted so far.
+    pub fn keyspace_stats(&self) -> (u64, u64) {
+        (
+            self.keyspace_hits.load(Ordering::Relaxed),
+            self.keyspace_misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Returns the internal encoding of the value at `key`, or `None` if it does not exist.
+    pub fn object_encoding(&self, key: &str) -> Option<&'static str> {
+        self.live(key).map(RedisType::encoding)
+    }
+
+    /// Returns the encoding of the value at `key` along with its length in an RDB snapshot and,
+    /// for a stream, the ID of its last entry, for `DEBUG OBJECT`, or `None` if the key does not
+    /// exist.
+    pub fn debug_object(&self, key: &str) -> Option<(&'static str, usize, Option<StreamId>)> {
+        let value = self.live(key)?;
+        let last_id = match value {
+            RedisType::Stream(stream) => stream.last_id(),
+            _ => None,
+        };
+        let length = rdb::serialized_length(value).unwrap_or(0);
+        Some((value.encoding(), length, last_id))
+    }
+
+    /// Returns the type name of the value at `key`, or `none` if it does not exist.
+    pub fn get_type(&self, key: &str) -> &'static str {
+        self.lookup(key).map_or("none", RedisType::type_str)
+    }
+
+    /// Runs the `BITFIELD` operations against the string stored at `key`.
+    ///
+    /// A missing key reads as an empty string and is only created when at least one
+    /// operation writes to it. Existing expiries are preserved.
+    pub fn bitfield(&mut self, key: &str, ops: &[BitFieldOp]) -> Result<Vec<Option<i64>>> {
+        self.clean_expiries()?;
+        let mut bytes = match self.lookup(key) {
+            Some(RedisType::String(bytes)) => bytes.clone(),
+            Some(_) => bail!(WRONG_TYPE),
+            None => vec![],
+        };
+
+        let results = ops.iter().map(|op| op.apply(&mut bytes)).collect();
+        if ops.iter().any(BitFieldOp::is_write) {
+            self.data.insert(key.to_string(), RedisType::String(bytes));
+        }
+        Ok(results)
+    }
+}
+
+/// Skips and takes the members of a sorted set range as its `LIMIT offset count` asks.
+fn limit<'a>(
+    members: impl Iterator<Item = (&'a str, f64)>,
+    options: &RangeOptions,
+) -> Vec<(String, f64)> {
+    members
+        .skip(options.offset)
+        .take(options.count.unwrap_or(usize::MAX))
+        .map(|(member, score)| (member.to_string(), score))
+        .collect()
+}
+
+/// Resolves `start` and `stop` indices into a sequence of length `len`, where negative indices
+/// count from the end, clamping them to the sequence. Returns `None` for an empty range.
+fn resolve_range(len: usize, start: i64, stop: i64) -> Option<RangeInclusive<usize>> {
+    let len = len as i64;
+    let start = if start < 0 {
+        (len + start).max(0)
+    } else {
+        start
+    };
+    let stop = if stop < 0 {
+        len + stop
+    } else {
+        stop.min(len - 1)
+    };
+    (start <= stop).then_some(start as usize..=stop as usize)
+}
+
+/// Hashes a key to its position in the `SCAN` order. `DefaultHasher::new` always uses the same
+/// keys, so positions are stable for the lifetime of the server.
+fn scan_hash(key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::RedisEncodable;
+    use std::sync::Arc;
+    use tokio::sync::{Barrier, RwLock};
+
+    fn string(s: &str) -> RedisType {
+        RedisType::String(s.as_bytes().to_vec())
+    }
+
+    fn expiring_in(expiry_ms: i64) -> SetOptions {
+        SetOptions {
+            expire_at: Some(Utc::now() + Duration::milliseconds(expiry_ms)),
+            ..SetOptions::default()
+        }
+    }
+
+    #[test]
+    fn test_del_counts_existing_keys_and_prunes_expiries() {
+        let mut store = KeyValueStore::new();
+        store.set("a", string("1"), expiring_in(60_000)).unwrap();
+        store.set("b", string("2"), SetOptions::default()).unwrap();
+        store.set("c", string("3"), expiring_in(60_000)).unwrap();
+
+        let keys = ["a", "b", "missing", "a"].map(String::from);
+        assert_eq!(store.del(&keys), 2);
+        assert_eq!(store.get("a").unwrap().redis_encode(), b"$-1\r\n");
+        assert!(!store.data.contains_key("b"));
+        assert_eq!(
+            store.expiries.values().flatten().collect::<Vec<_>>(),
+            vec!["c"]
+        );
+    }
+
+    #[test]
+    fn test_random_key_picks_every_live_key() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(store.random_key(), None);
+        store.set("a", string("1"), SetOptions::default()).unwrap();
+        store.set("b", string("2"), SetOptions::default()).unwrap();
+        store.set("expired", string("3"), expiring_in(-1)).unwrap();
+        let picked: HashSet<String> = (0..200).filter_map(|_| store.random_key()).collect();
+        assert_eq!(picked, HashSet::from(["a".to_string(), "b".to_string()]));
+    }
+
+    #[test]
+    fn test_unlink_returns_live_values() {
+        let mut store = KeyValueStore::new();
+        store.push("list", &strings(&["a"]), ListEnd::Left).unwrap();
+        store.set("expired", string("1"), expiring_in(-1)).unwrap();
+        let values = store.unlink(&strings(&["list", "expired", "missing", "list"]));
+        assert!(matches!(values.as_slice(), [RedisType::List(list)] if list.len() == 1));
+        assert!(store.data.is_empty());
+        assert!(store.expiries.is_empty());
+    }
+
+    #[test]
+    fn test_exists_counts_duplicates_and_skips_expired_keys() {
+        let mut store = KeyValueStore::new();
+        store.set("a", string("1"), SetOptions::default()).unwrap();
+        store.set("expired", string("2"), expiring_in(-1)).unwrap();
+
+        assert_eq!(store.exists(&["a", "a"].map(String::from)), 2);
+        assert_eq!(
+            store.exists(&["a", "expired", "missing"].map(String::from)),
+            1
+        );
+    }
+
+    #[test]
+    fn test_incr_fresh_key() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(store.incr_by("counter", 1).unwrap(), 1);
+        assert_eq!(store.incr_by("other", -1).unwrap(), -1);
+        assert_eq!(store.get("counter").unwrap().redis_encode(), b"$1\r\n1\r\n");
+    }
+
+    #[test]
+    fn test_incr_existing_number() {
+        let mut store = KeyValueStore::new();
+        store
+            .set("counter", string("41"), SetOptions::default())
+            .unwrap();
+        assert_eq!(store.incr_by("counter", 1).unwrap(), 42);
+        assert_eq!(store.incr_by("counter", -50).unwrap(), -8);
+    }
+
+    #[test]
+    fn test_incr_non_numeric_and_overflow() {
+        let mut store = KeyValueStore::new();
+        store
+            .set("text", string("abc"), SetOptions::default())
+            .unwrap();
+        store
+            .set("max", string(&i64::MAX.to_string()), SetOptions::default())
+            .unwrap();
+        let err = store.incr_by("text", 1).unwrap_err();
+        assert_eq!(err.to_string(), NOT_AN_INTEGER);
+        assert!(store.incr_by("max", 1).is_err());
+        assert_eq!(store.incr_by("max", -1).unwrap(), i64::MAX - 1);
+    }
+
+    #[test]
+    fn test_ttl_ms() {
+        let mut store = KeyValueStore::new();
+        store
+            .set("persistent", string("1"), SetOptions::default())
+            .unwrap();
+        store
+            .set("volatile", string("2"), expiring_in(60_000))
+            .unwrap();
+        store.set("expired", string("3"), expiring_in(-1)).unwrap();
+
+        assert_eq!(store.ttl_ms("missing"), -2);
+        assert_eq!(store.ttl_ms("expired"), -2);
+        assert_eq!(store.ttl_ms("persistent"), -1);
+        assert!((59_000..=60_000).contains(&store.ttl_ms("volatile")));
+
+        store
+            .set("volatile", string("4"), SetOptions::default())
+            .unwrap();
+        assert_eq!(store.ttl_ms("volatile"), -1);
+        assert!(store.expiries.values().flatten().all(|k| k != "volatile"));
+    }
+
+    #[test]
+    fn test_set_expiry_on_existing_replaces_previous_expiry() {
+        let mut store = KeyValueStore::new();
+        store.set("key", string("1"), expiring_in(60_000)).unwrap();
+
+        let in_two_minutes = Utc::now() + Duration::milliseconds(120_000);
+        let always = ExpireCondition::default();
+        assert!(store.set_expiry_on_existing("key", in_two_minutes, always));
+        assert!(!store.set_expiry_on_existing("missing", in_two_minutes, always));
+        assert!(store.ttl_ms("key") > 60_000);
+        assert_eq!(store.expiries.values().flatten().count(), 1);
+        assert!(!store.data.contains_key("missing"));
+
+        let only_earlier = ExpireCondition {
+            if_earlier: true,
+            ..Default::default()
+        };
+        let in_three_minutes = in_two_minutes + Duration::milliseconds(60_000);
+        assert!(!store.set_expiry_on_existing("key", in_three_minutes, only_earlier));
+        assert!(store.ttl_ms("key") <= 120_000);
+
+        assert!(store.set_expiry_on_existing("key", Utc::now(), always));
+        assert!(!store.data.contains_key("key"));
+        assert!(store.expiries.is_empty());
+    }
+
+    #[test]
+    fn test_getdel_and_getex() {
+        let mut store = KeyValueStore::new();
+        store
+            .set("key", string("value"), expiring_in(60_000))
+            .unwrap();
+        store.push("list", &strings(&["a"]), ListEnd::Left).unwrap();
+
+        assert_eq!(
+            store.getex("key", None, true).unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(store.ttl_ms("key"), -1);
+        let expire_at = Utc::now() + Duration::seconds(100);
+        assert_eq!(
+            store.getex("key", Some(expire_at), false).unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert!(store.ttl_ms("key") > 90_000);
+        assert_eq!(
+            store.getex("missing", Some(expire_at), false).unwrap(),
+            None
+        );
+        assert!(!store.data.contains_key("missing"));
+
+        assert_eq!(store.getdel("key").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(store.ttl_ms("key"), -2);
+        assert!(store.expiries.is_empty());
+        assert_eq!(store.getdel("key").unwrap(), None);
+        assert!(store.getdel("list").is_err());
+        assert_eq!(store.llen("list").unwrap(), 1);
+    }
+
+    #[test]
+    fn test_persist_removes_expiry() {
+        let mut store = KeyValueStore::new();
+        store.set("key", string("1"), expiring_in(60_000)).unwrap();
+        store
+            .set("plain", string("1"), SetOptions::default())
+            .unwrap();
+        store.set("expired", string("1"), expiring_in(-1)).unwrap();
+
+        assert!(store.persist("key"));
+        assert_eq!(store.ttl_ms("key"), -1);
+        assert!(!store.persist("key"));
+        assert!(!store.persist("plain"));
+        assert!(!store.persist("expired"));
+        assert!(!store.persist("missing"));
+        assert_eq!(store.expiries.values().flatten().count(), 1);
+    }
+
+    #[test]
+    fn test_set_conditions_and_keep_ttl() {
+        let mut store = KeyValueStore::new();
+        let nx = SetOptions {
+            condition: Some(SetCondition::IfAbsent),
+            ..SetOptions::default()
+        };
+        let xx_keep_ttl = SetOptions {
+            condition: Some(SetCondition::IfPresent),
+            keep_ttl: true,
+            ..SetOptions::default()
+        };
+
+        assert!(!store.set("key", string("1"), xx_keep_ttl.clone()).unwrap());
+        assert!(store.set("key", string("1"), expiring_in(60_000)).unwrap());
+        assert!(!store.set("key", string("2"), nx).unwrap());
+        assert!(store.set("key", string("3"), xx_keep_ttl).unwrap());
+        assert_eq!(store.get("key").unwrap().redis_encode(), b"$1\r\n3\r\n");
+        assert!(store.ttl_ms("key") > 0);
+    }
+
+    #[test]
+    fn test_evict_expired_at_keeps_future_keys() {
+        let mut store = KeyValueStore::new();
+        let now = Utc::now();
+        let expiring_at = |offset: Duration| SetOptions {
+            expire_at: Some(now + offset),
+            ..SetOptions::default()
+        };
+        store
+            .set("past", string("1"), expiring_at(Duration::seconds(-1)))
+            .unwrap();
+        store
+            .set("now", string("2"), expiring_at(Duration::zero()))
+            .unwrap();
+        store
+            .set("next", string("3"), expiring_at(Duration::nanoseconds(1)))
+            .unwrap();
+        store
+            .set("future", string("4"), expiring_at(Duration::seconds(1)))
+            .unwrap();
+
+        store.evict_expired_at(now);
+        let mut keys: Vec<&String> = store.data.keys().collect();
+        keys.sort();
+        assert_eq!(keys, vec!["future", "next"]);
+        assert_eq!(store.expiries.len(), 2);
+        assert_eq!(store.key_expiries.len(), 2);
+    }
+
+    fn strings(elements: &[&str]) -> Vec<String> {
+        elements.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_lrange_negative_indices() {
+        let mut store = KeyValueStore::new();
+        let elements = strings(&["a", "b", "c", "d"]);
+        assert_eq!(store.push("list", &elements, ListEnd::Right).unwrap(), 4);
+
+        assert_eq!(store.lrange("list", 0, -1).unwrap(), elements);
+        assert_eq!(store.lrange("list", -3, -2).unwrap(), strings(&["b", "c"]));
+        assert_eq!(store.lrange("list", -100, 1).unwrap(), strings(&["a", "b"]));
+        assert_eq!(store.lrange("list", 2, 100).unwrap(), strings(&["c", "d"]));
+        assert!(store.lrange("list", 3, 1).unwrap().is_empty());
+        assert!(store.lrange("list", 5, 10).unwrap().is_empty());
+        assert!(store.lrange("missing", 0, -1).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_push_and_pop_both_ends() {
+        let mut store = KeyValueStore::new();
+        store
+            .push("list", &strings(&["a", "b"]), ListEnd::Left)
+            .unwrap();
+        store
+            .push("list", &strings(&["c"]), ListEnd::Right)
+            .unwrap();
+        assert_eq!(
+            store.lrange("list", 0, -1).unwrap(),
+            strings(&["b", "a", "c"])
+        );
+
+        let popped = store.pop("list", 2, ListEnd::Right).unwrap();
+        assert_eq!(popped, Some(strings(&["c", "a"])));
+        assert_eq!(store.llen("list").unwrap(), 1);
+        let popped = store.pop("list", 5, ListEnd::Left).unwrap();
+        assert_eq!(popped, Some(strings(&["b"])));
+        assert!(!store.data.contains_key("list"));
+        assert_eq!(store.pop("list", 1, ListEnd::Left).unwrap(), None);
+    }
+
+    #[test]
+    fn test_lmove_between_and_within_lists() {
+        let mut store = KeyValueStore::new();
+        store
+            .push("src", &strings(&["a", "b", "c"]), ListEnd::Right)
+            .unwrap();
+
+        let moved = store.lmove("src", "dst", ListEnd::Right, ListEnd::Left);
+        assert_eq!(moved.unwrap(), Some("c".to_string()));
+        let moved = store.lmove("src", "dst", ListEnd::Left, ListEnd::Right);
+        assert_eq!(moved.unwrap(), Some("a".to_string()));
+        assert_eq!(store.lrange("dst", 0, -1).unwrap(), strings(&["c", "a"]));
+
+        // Rotating a single element list leaves it in place rather than deleting it.
+        let in_a_minute = Utc::now() + Duration::milliseconds(60_000);
+        assert!(store.set_expiry_on_existing("src", in_a_minute, ExpireCondition::default()));
+        let moved = store.lmove("src", "src", ListEnd::Left, ListEnd::Right);
+        assert_eq!(moved.unwrap(), Some("b".to_string()));
+        assert_eq!(store.lrange("src", 0, -1).unwrap(), strings(&["b"]));
+        assert!(store.key_expiries.contains_key("src"));
+
+        let moved = store.lmove("dst", "dst", ListEnd::Left, ListEnd::Right);
+        assert_eq!(moved.unwrap(), Some("c".to_string()));
+        assert_eq!(store.lrange("dst", 0, -1).unwrap(), strings(&["a", "c"]));
+
+        let moved = store.lmove("src", "dst", ListEnd::Left, ListEnd::Left);
+        assert_eq!(moved.unwrap(), Some("b".to_string()));
+        assert!(!store.data.contains_key("src"));
+        let moved = store.lmove("src", "dst", ListEnd::Left, ListEnd::Left);
+        assert_eq!(moved.unwrap(), None);
+    }
+
+    #[test]
+    fn test_lpos_ranks_and_counts() {
+        let mut store = KeyValueStore::new();
+        let elements = strings(&["a", "b", "c", "b", "b"]);
+        store.push("list", &elements, ListEnd::Right).unwrap();
+
+        assert_eq!(store.lpos("list", "b", 1, 1).unwrap(), vec![1]);
+        assert_eq!(store.lpos("list", "b", 2, 0).unwrap(), vec![3, 4]);
+        assert_eq!(store.lpos("list", "b", -1, 2).unwrap(), vec![4, 3]);
+        assert_eq!(store.lpos("list", "b", -3, 0).unwrap(), vec![1]);
+        assert!(store.lpos("list", "b", 4, 0).unwrap().is_empty());
+        assert!(store.lpos("list", "z", 1, 0).unwrap().is_empty());
+        assert!(store.lpos("missing", "a", 1, 0).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_linsert_and_lset() {
+        let mut store = KeyValueStore::new();
+        store
+            .push("list", &strings(&["a", "c"]), ListEnd::Right)
+            .unwrap();
+
+        assert_eq!(store.linsert("list", true, "c", "b").unwrap(), 3);
+        assert_eq!(store.linsert("list", false, "c", "d").unwrap(), 4);
+        assert_eq!(store.linsert("list", true, "missing", "x").unwrap(), -1);
+        assert_eq!(store.linsert("missing", true, "a", "x").unwrap(), 0);
+        assert!(!store.data.contains_key("missing"));
+
+        store.lset("list", 0, "A").unwrap();
+        store.lset("list", -1, "D").unwrap();
+        assert_eq!(
+            store.lrange("list", 0, -1).unwrap(),
+            strings(&["A", "b", "c", "D"])
+        );
+        for index in [4, -5, i64::MIN] {
+            let err = store.lset("list", index, "x").unwrap_err();
+            assert_eq!(err.to_string(), "ERR index out of range");
+        }
+        let err = store.lset("missing", 0, "x").unwrap_err();
+        assert_eq!(err.to_string(), "ERR no such key");
+    }
+
+    #[test]
+    fn test_list_commands_reject_wrong_type() {
+        let mut store = KeyValueStore::new();
+        store
+            .set("string", string("1"), SetOptions::default())
+            .unwrap();
+        store.push("list", &strings(&["a"]), ListEnd::Left).unwrap();
+
+        let err = store
+            .push("string", &strings(&["a"]), ListEnd::Left)
+            .unwrap_err();
+        assert_eq!(err.to_string(), WRONG_TYPE);
+        assert!(store.pop("string", 1, ListEnd::Left).is_err());
+        let err = store
+            .lmove("list", "string", ListEnd::Left, ListEnd::Left)
+            .unwrap_err();
+        assert_eq!(err.to_string(), WRONG_TYPE);
+        assert_eq!(store.llen("list").unwrap(), 1);
+        assert!(store
+            .lmove("string", "list", ListEnd::Left, ListEnd::Left)
+            .is_err());
+        assert!(store.lrange("string", 0, -1).is_err());
+        assert!(store.llen("string").is_err());
+        assert!(store.get("list").is_err());
+        assert_eq!(store.get_type("list"), "list");
+    }
+
+    #[test]
+    fn test_hash_fields() {
+        let mut store = KeyValueStore::new();
+        let pairs = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+            pairs
+                .iter()
+                .map(|(f, v)| (f.to_string(), v.to_string()))
+                .collect()
+        };
+        assert_eq!(
+            store
+                .hset("hash", &pairs(&[("a", "1"), ("b", "2")]))
+                .unwrap(),
+            2
+        );
+        assert_eq!(
+            store
+                .hset("hash", &pairs(&[("a", "3"), ("c", "4")]))
+                .unwrap(),
+            1
+        );
+        assert_eq!(store.hget("hash", "a").unwrap(), Some("3".to_string()));
+        assert_eq!(store.hget("hash", "z").unwrap(), None);
+        assert_eq!(store.hlen("hash").unwrap(), 3);
+
+        let mut all = store.hgetall("hash").unwrap();
+        all.sort();
+        assert_eq!(all, pairs(&[("a", "3"), ("b", "2"), ("c", "4")]));
+
+        assert_eq!(store.hdel("hash", &strings(&["a", "z"])).unwrap(), 1);
+        assert_eq!(store.hdel("hash", &strings(&["b", "c"])).unwrap(), 2);
+        assert!(!store.data.contains_key("hash"));
+        assert_eq!(store.get_type("hash"), "none");
+
+        store.hset("hash", &pairs(&[("a", "1")])).unwrap();
+        assert_eq!(store.get_type("hash"), "hash");
+        store
+            .set("string", string("1"), SetOptions::default())
+            .unwrap();
+        let err = store.hset("string", &pairs(&[("a", "1")])).unwrap_err();
+        assert_eq!(err.to_string(), WRONG_TYPE);
+        assert!(store.hget("string", "a").is_err());
+        assert!(store.hdel("string", &strings(&["a"])).is_err());
+        assert!(store.get("hash").is_err());
+    }
+
+    #[test]
+    fn test_set_members() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(store.sadd("set", &strings(&["a", "b", "a"])).unwrap(), 2);
+        assert_eq!(store.sadd("set", &strings(&["b", "c"])).unwrap(), 1);
+        assert!(store.sismember("set", "a").unwrap());
+        assert!(!store.sismember("set", "z").unwrap());
+        assert_eq!(store.scard("set").unwrap(), 3);
+        assert_eq!(store.get_type("set"), "set");
+
+        let mut members = store.smembers("set").unwrap();
+        members.sort();
+        assert_eq!(members, strings(&["a", "b", "c"]));
+
+        assert_eq!(store.srem("set", &strings(&["a", "z"])).unwrap(), 1);
+        assert_eq!(store.srem("set", &strings(&["b", "c"])).unwrap(), 2);
+        assert!(!store.data.contains_key("set"));
+        assert!(store.smembers("set").unwrap().is_empty());
+
+        store.push("list", &strings(&["a"]), ListEnd::Left).unwrap();
+        let err = store.sadd("list", &strings(&["a"])).unwrap_err();
+        assert_eq!(err.to_string(), WRONG_TYPE);
+        assert!(store.sismember("list", "a").is_err());
+        assert!(store.scard("list").is_err());
+    }
+
+    #[test]
+    fn test_multi_key_pops_skip_missing_keys() {
+        let mut store = KeyValueStore::new();
+        store
+            .push("second", &strings(&["a", "b", "c"]), ListEnd::Right)
+            .unwrap();
+        let keys = strings(&["first", "second"]);
+        let popped = store.lmpop(&keys, ListEnd::Right, 2).unwrap();
+        assert_eq!(popped, Some(("second".to_string(), strings(&["c", "b"]))));
+        let popped = store.lmpop(&keys, ListEnd::Left, 5).unwrap();
+        assert_eq!(popped, Some(("second".to_string(), strings(&["a"]))));
+        assert!(!store.data.contains_key("second"));
+        assert_eq!(store.lmpop(&keys, ListEnd::Left, 1).unwrap(), None);
+
+        let pairs = [(1.0, "a".to_string()), (2.0, "b".to_string())];
+        store
+            .zadd("second", &pairs, &ZAddOptions::default())
+            .unwrap();
+        let popped = store.zmpop(&keys, ScoreEnd::Max, 1).unwrap();
+        assert_eq!(
+            popped,
+            Some(("second".to_string(), vec![("b".to_string(), 2.0)]))
+        );
+        store.zmpop(&keys, ScoreEnd::Min, 1).unwrap();
+        assert!(!store.data.contains_key("second"));
+
+        store
+            .set("first", string("v"), SetOptions::default())
+            .unwrap();
+        let err = store.zmpop(&keys, ScoreEnd::Min, 1).unwrap_err();
+        assert_eq!(err.to_string(), WRONG_TYPE);
+    }
+
+    #[test]
+    fn test_set_operations() {
+        let mut store = KeyValueStore::new();
+        store.sadd("a", &strings(&["1", "2", "3", "4"])).unwrap();
+        store.sadd("b", &strings(&["2", "3", "5"])).unwrap();
+        store.sadd("c", &strings(&["3", "4", "2"])).unwrap();
+        let sorted = |members: HashSet<String>| {
+            let mut members: Vec<String> = members.into_iter().collect();
+            members.sort();
+            members
+        };
+        let keys = strings(&["a", "b", "c"]);
+        let inter = store.set_operation(SetOperation::Intersection, &keys);
+        assert_eq!(sorted(inter.unwrap()), strings(&["2", "3"]));
+        let union = store.set_operation(SetOperation::Union, &keys);
+        assert_eq!(sorted(union.unwrap()), strings(&["1", "2", "3", "4", "5"]));
+        let diff = store.set_operation(SetOperation::Difference, &strings(&["a", "b"]));
+        assert_eq!(sorted(diff.unwrap()), strings(&["1", "4"]));
+
+        let with_missing = strings(&["a", "missing"]);
+        let inter = store.set_operation(SetOperation::Intersection, &with_missing);
+        assert!(inter.unwrap().is_empty());
+        let diff = store.set_operation(SetOperation::Difference, &with_missing);
+        assert_eq!(diff.unwrap().len(), 4);
+
+        let stored = store.set_operation_store(SetOperation::Union, "a", &strings(&["b", "c"]));
+        assert_eq!(stored.unwrap(), 4);
+        assert_eq!(store.scard("a").unwrap(), 4);
+        store.set("dest", string("v"), expiring_in(10_000)).unwrap();
+        let stored = store.set_operation_store(SetOperation::Difference, "dest", &keys);
+        assert_eq!(stored.unwrap(), 0);
+        assert!(!store.data.contains_key("dest"));
+        assert_eq!(store.ttl_ms("dest"), -2);
+
+        store.push("list", &strings(&["a"]), ListEnd::Left).unwrap();
+        let err = store.set_operation(SetOperation::Union, &strings(&["a", "list"]));
+        assert_eq!(err.unwrap_err().to_string(), WRONG_TYPE);
+    }
+
+    #[test]
+    fn test_sorted_set_operations() {
+        let pairs = |pairs: &[(f64, &str)]| -> Vec<(f64, String)> {
+            pairs.iter().map(|(s, m)| (*s, m.to_string())).collect()
+        };
+        let mut store = KeyValueStore::new();
+        let options = ZAddOptions::default();
+        let a = pairs(&[(1.0, "x"), (2.0, "y"), (3.0, "z")]);
+        store.zadd("a", &a, &options).unwrap();
+        store
+            .zadd("b", &pairs(&[(4.0, "y"), (1.0, "w")]), &options)
+            .unwrap();
+        store.sadd("set", &strings(&["z", "v"])).unwrap();
+        let run = |operation, args: &[&str], allow_weights| {
+            let args = ZSetOperationArgs::parse(&strings(args), allow_weights).unwrap();
+            let result = store.zset_operation(operation, &args).unwrap();
+            result
+                .iter()
+                .map(|(member, score)| (member.to_string(), score))
+                .collect::<Vec<_>>()
+        };
+        let scored = |pairs: &[(&str, f64)]| -> Vec<(String, f64)> {
+            pairs.iter().map(|(m, s)| (m.to_string(), *s)).collect()
+        };
+
+        let union = run(
+            SetOperation::Union,
+            &["2", "a", "b", "WEIGHTS", "3", "1", "AGGREGATE", "MAX"],
+            true,
+        );
+        let expected = [("w", 1.0), ("x", 3.0), ("y", 6.0), ("z", 9.0)];
+        assert_eq!(union, scored(&expected));
+        let inter = run(
+            SetOperation::Intersection,
+            &["3", "a", "b", "missing"],
+            true,
+        );
+        assert!(inter.is_empty());
+        let inter = run(SetOperation::Intersection, &["2", "a", "b"], true);
+        assert_eq!(inter, scored(&[("y", 6.0)]));
+        let inter = run(SetOperation::Intersection, &["2", "a", "set"], true);
+        assert_eq!(inter, scored(&[("z", 4.0)]));
+        let diff = run(SetOperation::Difference, &["3", "a", "b", "set"], false);
+        assert_eq!(diff, scored(&[("x", 1.0)]));
+
+        let args = ZSetOperationArgs::parse(&strings(&["2", "a", "set"]), true).unwrap();
+        let stored = store.zset_operation_store(SetOperation::Union, "a", &args);
+        assert_eq!(stored.unwrap(), 4);
+        assert_eq!(store.zscore("a", "z").unwrap(), Some(4.0));
+        store.set("dest", string("v"), expiring_in(10_000)).unwrap();
+        let args = ZSetOperationArgs::parse(&strings(&["2", "set", "a"]), false).unwrap();
+        let stored = store.zset_operation_store(SetOperation::Difference, "dest", &args);
+        assert_eq!(stored.unwrap(), 0);
+        assert!(!store.data.contains_key("dest"));
+        assert_eq!(store.ttl_ms("dest"), -2);
+
+        store.push("list", &strings(&["a"]), ListEnd::Left).unwrap();
+        let args = ZSetOperationArgs::parse(&strings(&["2", "a", "list"]), true).unwrap();
+        let err = store
+            .zset_operation(SetOperation::Union, &args)
+            .unwrap_err();
+        assert_eq!(err.to_string(), WRONG_TYPE);
+    }
+
+    #[test]
+    fn test_sorted_set_members() {
+        let pairs = |pairs: &[(f64, &str)]| -> Vec<(f64, String)> {
+            pairs.iter().map(|(s, m)| (*s, m.to_string())).collect()
+        };
+        let mut store = KeyValueStore::new();
+        let options = ZAddOptions::default();
+        let added = store.zadd("z", &pairs(&[(1.0, "b"), (1.0, "a"), (0.5, "c")]), &options);
+        assert_eq!(added.unwrap(), (3, Some(0.5)));
+        assert_eq!(store.zcard("z").unwrap(), 3);
+        assert_eq!(store.get_type("z"), "zset");
+        let members: Vec<String> = store
+            .zrange("z", 0, -1)
+            .unwrap()
+            .into_iter()
+            .map(|(m, _)| m)
+            .collect();
+        assert_eq!(members, strings(&["c", "a", "b"]));
+        assert_eq!(
+            store.zrange("z", -2, 5).unwrap(),
+            vec![("a".to_string(), 1.0), ("b".to_string(), 1.0)]
+        );
+        assert!(store.zrange("z", 2, 1).unwrap().is_empty());
+        assert_eq!(store.zrank("z", "b").unwrap(), Some(2));
+        assert_eq!(store.zscore("z", "c").unwrap(), Some(0.5));
+        assert_eq!(store.zscore("z", "missing").unwrap(), None);
+
+        let ch = ZAddOptions {
+            ch: true,
+            ..Default::default()
+        };
+        let changed = store.zadd("z", &pairs(&[(2.0, "a"), (1.0, "b"), (3.0, "d")]), &ch);
+        assert_eq!(changed.unwrap().0, 2);
+        let incr = ZAddOptions {
+            incr: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            store.zadd("z", &pairs(&[(1.5, "a")]), &incr).unwrap(),
+            (0, Some(3.5))
+        );
+
+        let xx = ZAddOptions {
+            xx: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            store.zadd("new", &pairs(&[(1.0, "a")]), &xx).unwrap(),
+            (0, None)
+        );
+        assert!(!store.data.contains_key("new"));
+        let nan = store.zadd("nan", &pairs(&[(f64::INFINITY, "a")]), &incr);
+        assert_eq!(nan.unwrap(), (1, Some(f64::INFINITY)));
+        assert!(store
+            .zadd("nan", &pairs(&[(f64::NEG_INFINITY, "a")]), &incr)
+            .is_err());
+
+        store.push("list", &strings(&["a"]), ListEnd::Left).unwrap();
+        let err = store
+            .zadd("list", &pairs(&[(1.0, "a")]), &options)
+            .unwrap_err();
+        assert_eq!(err.to_string(), WRONG_TYPE);
+        assert!(store.zrange("list", 0, -1).is_err());
+        assert!(store.zcard("list").is_err());
+    }
+
+    #[test]
+    fn test_keys_matches_live_keys() {
+        let mut store = KeyValueStore::new();
+        for key in ["user:1", "user:2", "user:10", "session:1"] {
+            store.set(key, string("v"), SetOptions::default()).unwrap();
+        }
+        store.set("user:3", string("v"), expiring_in(-1)).unwrap();
+
+        let mut keys = store.keys("user:*");
+        keys.sort();
+        assert_eq!(keys, strings(&["user:1", "user:10", "user:2"]));
+        let mut keys = store.keys("user:?");
+        keys.sort();
+        assert_eq!(keys, strings(&["user:1", "user:2"]));
+        assert_eq!(store.keys("*").len(), 4);
+        assert!(store.keys("nothing*").is_empty());
+    }
+
+    #[test]
+    fn test_flush_removes_keys_and_expiries() {
+        let mut store = KeyValueStore::new();
+        store
+            .set("plain", string("v"), SetOptions::default())
+            .unwrap();
+        store
+            .set("expiring", string("v"), expiring_in(60_000))
+            .unwrap();
+
+        store.flush();
+        assert!(store.keys("*").is_empty());
+        assert_eq!(store.ttl_ms("expiring"), -2);
+        store
+            .set("expiring", string("v"), SetOptions::default())
+            .unwrap();
+        assert_eq!(store.ttl_ms("expiring"), -1);
+    }
+
+    #[test]
+    fn test_dbsize_and_keyspace_count_live_keys() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(store.dbsize(), 0);
+        assert_eq!(store.keyspace(0), None);
+
+        store
+            .set("plain", string("v"), SetOptions::default())
+            .unwrap();
+        store
+            .set("expiring", string("v"), expiring_in(60_000))
+            .unwrap();
+        store.set("expired", string("v"), expiring_in(-1)).unwrap();
+        assert_eq!(store.dbsize(), 2);
+        assert_eq!(store.keyspace(3).unwrap(), "db3:keys=2,expires=1,avg_ttl=0");
+    }
+
+    #[test]
+    fn test_scan_covers_every_key() {
+        let mut store = KeyValueStore::new();
+        for i in 0..1000 {
+            store
+                .set(&format!("key:{}", i), string("v"), SetOptions::default())
+                .unwrap();
+        }
+
+        let mut seen = HashSet::new();
+        let mut cursor = 0;
+        let mut batches = 0;
+        loop {
+            let (next, keys) = store.scan(cursor, None, 100);
+            assert!(keys.len() <= 100);
+            seen.extend(keys);
+            batches += 1;
+            // Keys added mid-scan must not make the scan miss any of the original ones.
+            store
+                .set(
+                    &format!("new:{}", batches),
+                    string("v"),
+                    SetOptions::default(),
+                )
+                .unwrap();
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert!(batches >= 10);
+        assert!((0..1000).all(|i| seen.contains(&format!("key:{}", i))));
+
+        let (_, mut keys) = store.scan(0, Some("key:99?"), 2000);
+        keys.sort();
+        assert_eq!(keys.len(), 10);
+        assert_eq!(keys[0], "key:990");
+    }
+
+    #[test]
+    fn test_mset_and_mget() {
+        let mut store = KeyValueStore::new();
+        store.set("a", string("old"), expiring_in(10_000)).unwrap();
+        store.push("list", &strings(&["x"]), ListEnd::Left).unwrap();
+        store.mset(vec![
+            ("a".to_string(), b"1".to_vec()),
+            ("b".to_string(), b"2".to_vec()),
+        ]);
+        assert_eq!(store.ttl_ms("a"), -1);
+
+        let values = store.mget(&strings(&["a", "missing", "b", "list"]));
+        assert_eq!(
+            values,
+            vec![Some(b"1".to_vec()), None, Some(b"2".to_vec()), None]
+        );
+    }
+
+    #[test]
+    fn test_string_ranges() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(store.append("s", b"Hello").unwrap(), 5);
+        assert_eq!(store.append("s", b" World").unwrap(), 11);
+        assert_eq!(store.strlen("s").unwrap(), 11);
+        assert_eq!(store.strlen("missing").unwrap(), 0);
+
+        assert_eq!(store.getrange("s", 0, 4).unwrap(), b"Hello");
+        assert_eq!(store.getrange("s", -5, -1).unwrap(), b"World");
+        assert_eq!(store.getrange("s", 6, 100).unwrap(), b"World");
+        assert!(store.getrange("s", 5, 2).unwrap().is_empty());
+        assert!(store.getrange("missing", 0, -1).unwrap().is_empty());
+
+        assert_eq!(store.setrange("s", 6, b"Redis").unwrap(), 11);
+        assert_eq!(
+            store.get("s").unwrap().redis_encode(),
+            b"$11\r\nHello Redis\r\n"
+        );
+    }
+
+    #[test]
+    fn test_setrange_pads_with_zero_bytes() {
+        let mut store = KeyValueStore::new();
+        assert_eq!(store.setrange("s", 3, b"ab").unwrap(), 5);
+        assert_eq!(store.getrange("s", 0, -1).unwrap(), b"\0\0\0ab");
+        assert_eq!(store.setrange("s", 7, b"c").unwrap(), 8);
+        assert_eq!(store.getrange("s", 0, -1).unwrap(), b"\0\0\0ab\0\0c");
+
+        assert_eq!(store.setrange("missing", 5, b"").unwrap(), 0);
+        assert!(!store.data.contains_key("missing"));
+        assert!(store.setrange("s", MAX_STRING_LEN, b"x").is_err());
+
+        store.push("list", &strings(&["a"]), ListEnd::Left).unwrap();
+        let err = store.setrange("list", 0, b"x").unwrap_err();
+        assert_eq!(err.to_string(), WRONG_TYPE);
+        assert!(store.append("list", b"x").is_err());
+        assert!(store.strlen("list").is_err());
+        assert!(store.getrange("list", 0, 1).is_err());
+    }
+
+    #[test]
+    fn test_bits_count_from_the_most_significant() {
+        let mut store = KeyValueStore::new();
+        assert!(!store.setbit("bits", 7, true).unwrap());
+        assert_eq!(store.getrange("bits", 0, -1).unwrap(), b"\x01");
+        assert!(store.getbit("bits", 7).unwrap());
+        assert!(!store.getbit("bits", 0).unwrap());
+        assert!(!store.getbit("bits", 1_000).unwrap());
+        assert!(store.setbit("bits", 7, false).unwrap());
+        assert!(!store.setbit("bits", 17, true).unwrap());
+        assert_eq!(store.getrange("bits", 0, -1).unwrap(), b"\0\0\x40");
+        assert!(store
+            .setbit("bits", MAX_STRING_LEN as u64 * 8, true)
+            .is_err());
+
+        store
+            .set("foobar", string("foobar"), SetOptions::default())
+            .unwrap();
+        assert_eq!(store.bitcount("foobar", 0, -1, false).unwrap(), 26);
+        assert_eq!(store.bitcount("foobar", 1, 1, false).unwrap(), 6);
+        assert_eq!(store.bitcount("foobar", -2, -1, false).unwrap(), 7);
+        assert_eq!(store.bitcount("foobar", 5, 30, true).unwrap(), 17);
+        assert_eq!(store.bitcount("foobar", 3, 2, false).unwrap(), 0);
+        assert_eq!(store.bitcount("missing", 0, -1, false).unwrap(), 0);
+
+        store.push("list", &strings(&["a"]), ListEnd::Left).unwrap();
+        let err = store.setbit("list", 0, true).unwrap_err();
+        assert_eq!(err.to_string(), WRONG_TYPE);
+        assert!(store.getbit("list", 0).is_err());
+        assert!(store.bitcount("list", 0, -1, false).is_err());
+    }
+
+    #[test]
+    fn test_entries_restore_into_another_store() {
+        let mut store = KeyValueStore::new();
+        store
+            .set("plain", string("1"), SetOptions::default())
+            .unwrap();
+        store
+            .set("expiring", string("2"), expiring_in(60_000))
+            .unwrap();
+        store.set("expired", string("3"), expiring_in(-1)).unwrap();
+        store
+            .push("list", &strings(&["a", "b"]), ListEnd::Right)
+            .unwrap();
+
+        let mut replica = KeyValueStore::new_replica();
+        replica
+            .set("list", string("stale"), expiring_in(1_000))
+            .unwrap();
+        for (key, value, expire_at) in store.entries() {
+            replica.restore(key.to_string(), value.clone(), expire_at);
+        }
+
+        assert_eq!(replica.get("plain").unwrap().redis_encode(), b"$1\r\n1\r\n");
+        assert_eq!(
+            replica.get("expiring").unwrap().redis_encode(),
+            b"$1\r\n2\r\n"
+        );
+        assert!((59_000..=60_000).contains(&replica.ttl_ms("expiring")));
+        assert_eq!(replica.lrange("list", 0, -1).unwrap(), strings(&["a", "b"]));
+        assert_eq!(replica.ttl_ms("list"), -1);
+        assert!(!replica.data.contains_key("expired"));
+    }
+
+    #[test]
+    fn test_take_removes_key_with_its_expiry() {
+        let mut store = KeyValueStore::new();
+        store
+            .set("expiring", string("1"), expiring_in(60_000))
+            .unwrap();
+        store.set("expired", string("2"), expiring_in(-1)).unwrap();
+
+        let (value, expire_at) = store.take("expiring").unwrap();
+        assert!(matches!(value, RedisType::String(bytes) if bytes == b"1"));
+        assert!(expire_at.is_some());
+        assert_eq!(store.ttl_ms("expiring"), -2);
+        assert!(store.take("expired").is_none());
+        assert!(store.take("missing").is_none());
+    }
+
+    #[test]
+    fn test_copy_of_is_independent() {
+        let mut store = KeyValueStore::new();
+        store
+            .push("list", &strings(&["a", "b"]), ListEnd::Right)
+            .unwrap();
+        store.set_expiry_at("list", Utc::now() + Duration::seconds(60));
+        let (value, expire_at) = store.copy_of("list").unwrap();
+        assert!(expire_at.is_some());
+        store.restore("copy".to_string(), value, expire_at);
+
+        store
+            .push("list", &strings(&["c"]), ListEnd::Right)
+            .unwrap();
+        store.pop("copy", 1, ListEnd::Left).unwrap();
+        assert_eq!(
+            store.lrange("list", 0, -1).unwrap(),
+            strings(&["a", "b", "c"])
+        );
+        assert_eq!(store.lrange("copy", 0, -1).unwrap(), strings(&["b"]));
+        assert!(store.ttl_ms("copy") > 0);
+        assert!(store.copy_of("missing").is_none());
+    }
+
+    #[test]
+    fn test_master_deletes_expired_keys() {
+        let mut store = KeyValueStore::new();
+        store.set("key", string("value"), expiring_in(-1)).unwrap();
+        assert_eq!(store.get("key").unwrap().redis_encode(), b"$-1\r\n");
+        assert!(store.data.contains_key("key"));
+
+        store.clean_expiries().unwrap();
+        assert!(!store.data.contains_key("key"));
+    }
+
+    #[test]
+    fn test_evicted_keys_are_recorded_for_the_master_only() {
+        let mut store = KeyValueStore::new();
+        let mut replica = KeyValueStore::new_replica();
+        for store in [&mut store, &mut replica] {
+            store.set("a", string("1"), expiring_in(-1)).unwrap();
+            store.set("b", string("2"), expiring_in(-1)).unwrap();
+            store.set("kept", string("3"), expiring_in(60_000)).unwrap();
+            store.clean_expiries().unwrap();
+            store.set("b", string("4"), SetOptions::default()).unwrap();
+        }
+        let mut evicted = store.take_evicted();
+        evicted.sort();
+        assert_eq!(evicted, strings(&["a", "b"]));
+        assert!(store.take_evicted().is_empty());
+        assert_eq!(store.dbsize(), 2);
+
+        // A replica deletes expired keys only as its master's writes reach them.
+        assert!(replica.take_evicted().is_empty());
+        assert_eq!(replica.data.len(), 3);
+
+        // Expiring a key in the past deletes it as if it had just expired.
+        let past = Utc::now() - chrono::Duration::seconds(1);
+        let condition = ExpireCondition::default();
+        assert!(store.set_expiry_on_existing("kept", past, condition));
+        assert_eq!(store.take_evicted(), strings(&["kept"]));
+        assert_eq!(store.dbsize(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_gets_share_the_read_lock() {
+        let store = Arc::new(RwLock::new(KeyValueStore::new()));
+        store
+            .write()
+            .await
+            .set("key", string("value"), SetOptions::default())
+            .unwrap();
+        let barrier = Arc::new(Barrier::new(2));
+
+        let readers = (0..2).map(|_| {
+            let (store, barrier) = (store.clone(), barrier.clone());
+            tokio::spawn(async move {
+                let store = store.read().await;
+                // Both readers only get past the barrier if they hold the lock at once.
+                barrier.wait().await;
+                store.get("key").unwrap().redis_encode()
+            })
+        });
+        let replies = tokio::time::timeout(
+            std::time::Duration::from_secs(1),
+            futures::future::join_all(readers),
+        )
+        .await
+        .expect("readers should not exclude each other");
+        for reply in replies {
+            assert_eq!(reply.unwrap(), b"$5\r\nvalue\r\n");
+        }
+    }
+
+    #[test]
+    fn test_replica_hides_expired_keys_without_deleting() {
+        let mut store = KeyValueStore::new_replica();
+        store.set("key", string("value"), expiring_in(-1)).unwrap();
+        store
+            .set("other", string("value"), expiring_in(60_000))
+            .unwrap();
+
+        assert_eq!(store.get("key").unwrap().redis_encode(), b"$-1\r\n");
+        assert_eq!(store.get_type("key"), "none");
+        assert!(store.data.contains_key("key"));
+        assert_eq!(store.data.len(), 2);
+        assert_eq!(
+            store.get("other").unwrap().redis_encode(),
+            b"$5\r\nvalue\r\n"
+        );
+    }
 }
@@ -0,0 +1,120 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::client::ClientWrite;
+use crate::parser::ProtocolVersion;
+
+/// A connection that turned client-side caching on with `CLIENT TRACKING on`, and where its
+/// invalidation messages go.
+#[derive(Clone)]
+pub struct TrackingClient {
+    pub stream: ClientWrite,
+    /// The connection's protocol: invalidations can only be pushed to it under RESP3.
+    pub protocol: ProtocolVersion,
+    /// The connection to send the invalidations to instead, through its subscription to
+    /// `__redis__:invalidate`, as asked with `REDIRECT`.
+    pub redirect: Option<u64>,
+    /// Set with `NOLOOP`, so that the connection isn't told about its own writes.
+    pub noloop: bool,
+    /// Set with `BCAST`, so that the connection is told about every key that changes and
+    /// starts with one of `prefixes`, rather than about the keys it read.
+    pub bcast: bool,
+    /// The prefixes given with `PREFIX`, where none means every key.
+    pub prefixes: Vec<String>,
+}
+
+impl TrackingClient {
+    /// Whether a broadcasting connection is to be told about changes to `key`.
+    fn broadcasts(&self, key: &str) -> bool {
+        self.bcast && (self.prefixes.is_empty() || self.prefixes.iter().any(|p| key.starts_with(p)))
+    }
+}
+
+/// The keys read by connections with tracking on, so that each is told once one of the keys
+/// it may have cached changes.
+///
+/// A key is forgotten as soon as its readers are told about it, and a connection only tracks
+/// it again by reading it again. Broadcasting connections track no keys: they are told about
+/// every change to those matching their prefixes.
+#[derive(Default)]
+pub struct Tracking {
+    clients: HashMap<u64, TrackingClient>,
+    /// The connections that read each key since it last changed, by their id.
+    keys: HashMap<String, HashSet<u64>>,
+}
+
+impl Tracking {
+    /// Turns tracking on for the connection `id`, replacing its options if it was on already.
+    pub fn enable(&mut self, id: u64, client: TrackingClient) {
+        self.clients.insert(id, client);
+    }
+
+    /// Turns tracking off for the connection `id`, forgetting the keys it read.
+    pub fn disable(&mut self, id: u64) {
+        if self.clients.remove(&id).is_none() {
+            return;
+        }
+        self.keys.retain(|_, readers| {
+            readers.remove(&id);
+            !readers.is_empty()
+        });
+    }
+
+    pub fn set_protocol(&mut self, id: u64, protocol: ProtocolVersion) {
+        if let Some(client) = self.clients.get_mut(&id) {
+            client.protocol = protocol;
+        }
+    }
+
+    /// Whether no connection has tracking on, in which case writes have nobody to tell.
+    pub fn is_empty(&self) -> bool {
+        self.clients.is_empty()
+    }
+
+    /// Records that the connection `id` read `keys`.
+    pub fn track<'a>(&mut self, id: u64, keys: impl IntoIterator<Item = &'a String>) {
+        if self.clients.get(&id).is_none_or(|client| client.bcast) {
+            return;
+        }
+        for key in keys {
+            self.keys.entry(key.clone()).or_default().insert(id);
+        }
+    }
+
+    /// Forgets `keys`, which the connection `writer` just modified, returning the connections
+    /// that read any of them along with the keys each is to be told about.
+    pub fn invalidate<'a>(
+        &mut self,
+        keys: impl IntoIterator<Item = &'a String>,
+        writer: u64,
+    ) -> Vec<(TrackingClient, Vec<String>)> {
+        let mut invalidated: HashMap<u64, Vec<String>> = HashMap::new();
+        for key in keys {
+            for id in self.keys.remove(key).into_iter().flatten() {
+                invalidated.entry(id).or_default().push(key.clone());
+            }
+            for (id, client) in &self.clients {
+                if client.broadcasts(key) {
+                    invalidated.entry(*id).or_default().push(key.clone());
+                }
+            }
+        }
+        invalidated
+            .into_iter()
+            .filter_map(|(id, keys)| {
+                let client = self.clients.get(&id)?;
+                (!(client.noloop && id == writer)).then(|| (client.clone(), keys))
+            })
+            .collect()
+    }
+
+    /// Forgets every key, for when the whole keyspace is flushed, returning every connection
+    /// that read any, along with every broadcasting one.
+    pub fn invalidate_all(&mut self) -> Vec<TrackingClient> {
+        let readers: HashSet<u64> = self.keys.drain().flat_map(|(_, ids)| ids).collect();
+        self.clients
+            .iter()
+            .filter(|(id, client)| client.bcast || readers.contains(id))
+            .map(|(_, client)| client.clone())
+            .collect()
+    }
+}